@@ -5,7 +5,7 @@ use crevice::std140::AsStd140;
 
 use vrv::wrap_vulkan::{
     buffers::MappedDeviceBuffer,
-    geometry::{Mesh, MeshBuffers},
+    geometry::{Mesh, MeshBuffers, MeshMemory},
     Context,
 };
 
@@ -31,7 +31,7 @@ pub struct Buffer<UniformMatrices> {
 }
 
 impl<UniformMatrices> Buffer<UniformMatrices> {
-    pub fn new(context: &Context, name: String) -> Result<Self> {
+    pub fn new(context: &Context, debug_mesh: &Mesh, name: String) -> Result<Self> {
         let matrix_buffer = MappedDeviceBuffer::new(
             context,
             BufferUsageFlags::UNIFORM_BUFFER,
@@ -39,14 +39,14 @@ impl<UniformMatrices> Buffer<UniformMatrices> {
             format!("{}Matrices", name),
         )?;
 
-        let debug_mesh = Mesh::load_gltf("examples/simple/untitled.glb")?;
         let mut mesh_buffers = MeshBuffers::new(
             context,
             debug_mesh.vertices.len(),
             debug_mesh.indices.len(),
+            MeshMemory::for_static_mesh(debug_mesh),
             format!("{}MeshBuffers", name),
         )?;
-        mesh_buffers.write(context, &debug_mesh)?;
+        mesh_buffers.write(context, debug_mesh)?;
 
         Ok(Self {
             matrix_buffer,
@@ -6,27 +6,30 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
-use ash::{
-    vk::{
-        CommandBuffer, DescriptorSet, DescriptorType, DynamicState, Extent2D, Fence, Semaphore,
-        ShaderStageFlags,
-    },
-    Device,
-};
-use cgmath::{perspective, Deg, EuclideanSpace, Matrix4, Point3, SquareMatrix, Vector3};
-use openxr::{EventDataBuffer, SessionState, ViewConfigurationType};
+use ash::vk::{DescriptorSet, DescriptorType, DynamicState, SampleCountFlags, ShaderStageFlags};
+use cgmath::{perspective, Deg, Matrix4, Rad};
+use openxr::{Posef, Vector3f};
 use simplelog::{Config, SimpleLogger};
 use vk_shader_macros::include_glsl;
 use vrv::{
+    camera::{fov_to_projection, pose_to_matrix_inverse, CameraInput, OrbitCamera},
+    context::{
+        locomotion::{Locomotion, LocomotionSettings},
+        post_hmd::PostSettingsHMD,
+        render_window::RenderOutcome,
+    },
+    projection::ProjectionParams,
     wrap_vulkan::{
         create_pipeline, create_pipeline_layout,
         descriptors::{DescriptorRelated, Usage},
-        pipeline::create_shader_module,
-        sync::{create_fence, create_semaphore, wait_and_reset},
+        geometry::Mesh,
+        pipeline::{BlendMode, RasterOptions, ShaderModuleGuard},
+        FrameRing,
     },
-    Context,
+    Context, VrvEvent,
 };
 use winit::{
     event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
@@ -34,86 +37,42 @@ use winit::{
     window::WindowBuilder,
 };
 
-use crate::{
-    buffer::{Buffer, UniformMatricesHMD, UniformMatricesWindow},
-    camera::{fov_to_projection, pose_to_matrix_inverse, KeyMap, SphereCoords},
-};
+use crate::buffer::{Buffer, UniformMatricesHMD, UniformMatricesWindow};
 
 mod buffer;
-mod camera;
-
-struct Double<UniformMatrices> {
-    buffer: Buffer<UniformMatrices>,
-    command: CommandBuffer,
-    semaphore: Semaphore, // not used in HMD case
-    fence: Fence,
-    device: Device,
-}
 
-impl<T> Drop for Double<T> {
-    fn drop(&mut self) {
-        unsafe {
-            self.device.destroy_semaphore(self.semaphore, None);
-            self.device.destroy_fence(self.fence, None);
-        }
+// WASD orbits, Q and E zoom
+fn camera_input(pressed_keys: &HashSet<VirtualKeyCode>) -> CameraInput {
+    let axis = |negative, positive| {
+        pressed_keys.contains(&positive) as i32 as f32
+            - pressed_keys.contains(&negative) as i32 as f32
+    };
+    CameraInput {
+        horizontal: axis(VirtualKeyCode::A, VirtualKeyCode::D),
+        vertical: axis(VirtualKeyCode::S, VirtualKeyCode::W),
+        zoom: axis(VirtualKeyCode::Q, VirtualKeyCode::E),
     }
 }
 
-impl<UniformMatrices> Double<UniformMatrices>
-where
-    UniformMatrices: std::fmt::Debug,
-{
-    fn create_front_and_back(context: &Context, prefix: String) -> Result<Vec<Self>> {
-        Ok(["Front", "Back"]
+fn make_descriptors<UniformMatrices>(
+    context: &Context,
+    buffers: &[&Buffer<UniformMatrices>],
+    prefix: String,
+) -> Result<(DescriptorRelated, Vec<DescriptorSet>)> {
+    DescriptorRelated::new_with_sets(
+        &context.vulkan,
+        [(
+            0,
+            (DescriptorType::UNIFORM_BUFFER, ShaderStageFlags::VERTEX, 1),
+        )]
+        .into(),
+        &buffers
             .iter()
-            .map(|front_or_back| {
-                let buffer = Buffer::<UniformMatrices>::new(
-                    &context.vulkan,
-                    format!("{}{}Resource", prefix, front_or_back),
-                )?;
-                let command = context.vulkan.alloc_command_buffers(
-                    1,
-                    format!("{}{}CommandBuffer", prefix, front_or_back),
-                )?[0];
-                let semaphore =
-                    create_semaphore(&context.vulkan, format!("{}RenderingFinished", prefix))?;
-                let fence = create_fence(
-                    &context.vulkan,
-                    true,
-                    format!("{}RenderingFinished", prefix),
-                )?;
-
-                Ok(Double::<UniformMatrices> {
-                    buffer,
-                    command,
-                    semaphore,
-                    fence,
-                    device: context.vulkan.device.clone(),
-                })
-            })
-            .collect::<Result<Vec<Self>>>()?)
-    }
-
-    fn make_descriptors(
-        context: &Context,
-        front_back: &[Self],
-        prefix: String,
-    ) -> Result<(DescriptorRelated, Vec<DescriptorSet>)> {
-        DescriptorRelated::new_with_sets(
-            &context.vulkan,
-            [(
-                0,
-                (DescriptorType::UNIFORM_BUFFER, ShaderStageFlags::VERTEX),
-            )]
-            .into(),
-            &front_back
-                .iter()
-                .map(|front_or_back| front_or_back.buffer.matrix_buffer.handle())
-                .map(|handle| [(0, Usage::Buffer(handle))].into())
-                .collect::<Vec<_>>(),
-            format!("{}Descriptor", prefix),
-        )
-    }
+            .map(|buffer| buffer.matrix_buffer.handle())
+            .map(|handle| [(0, Usage::Buffer(handle))].into())
+            .collect::<Vec<_>>(),
+        format!("{}Descriptor", prefix),
+    )
 }
 
 fn main() {
@@ -124,25 +83,67 @@ fn main() {
 
     let mut context = ManuallyDrop::new(Context::new(&window).unwrap());
 
-    let mut hmd_front_back =
-        Double::<UniformMatricesHMD>::create_front_and_back(&context, "HMD".to_string()).unwrap();
-    let (hmd_descriptor, hmd_descriptor_sets) = Double::<UniformMatricesHMD>::make_descriptors(
+    // pass --post for FXAA and sharpening on the HMD images
+    if std::env::args().any(|arg| arg == "--post") {
+        context
+            .set_hmd_post(Some(PostSettingsHMD::default()))
+            .unwrap();
+    }
+
+    // pass --cube to show the built-in cube instead of the glTF file
+    let debug_mesh = if std::env::args().any(|arg| arg == "--cube") {
+        Mesh::cube(0.5)
+    } else {
+        // next to this file, wherever the example is run from
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/simple/untitled.glb");
+        Mesh::load_gltf(path).unwrap_or_else(|e| {
+            log::error!("{}, showing a cube instead", e);
+            Mesh::cube(0.5)
+        })
+    };
+    let start = Instant::now();
+
+    // command buffers and fences for the HMD are managed by the context
+    let mut hmd_buffers = (0..context.get_frames_in_flight_hmd())
+        .map(|i| {
+            Buffer::<UniformMatricesHMD>::new(
+                &context.vulkan,
+                &debug_mesh,
+                format!("HMDResource_{}", i),
+            )
+        })
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    let (hmd_descriptor, hmd_descriptor_sets) = make_descriptors(
         &context,
-        &hmd_front_back,
+        &hmd_buffers.iter().collect::<Vec<_>>(),
         "HMD".to_string(),
     )
     .unwrap();
 
-    let mut window_front_back =
-        Double::<UniformMatricesWindow>::create_front_and_back(&context, "Window".to_string())
-            .unwrap();
-    let (window_descriptor, window_descriptor_sets) =
-        Double::<UniformMatricesWindow>::make_descriptors(
-            &context,
-            &window_front_back,
-            "Window".to_string(),
-        )
-        .unwrap();
+    // the window frames are ours, the ring waits on their fences
+    let mut window_ring = Some(
+        FrameRing::new(&context.vulkan, 2, "Window".to_string(), |i| {
+            Buffer::<UniformMatricesWindow>::new(
+                &context.vulkan,
+                &debug_mesh,
+                format!("WindowResource_{}", i),
+            )
+        })
+        .unwrap(),
+    );
+    let (window_descriptor, window_descriptor_sets) = make_descriptors(
+        &context,
+        &window_ring
+            .as_ref()
+            .unwrap()
+            .frames()
+            .iter()
+            .map(|frame| &frame.data)
+            .collect::<Vec<_>>(),
+        "Window".to_string(),
+    )
+    .unwrap();
 
     const HMD_VERT: &[u32] = include_glsl!("shaders/example_hmd.vert");
     const HMD_FRAG: &[u32] = include_glsl!("shaders/example_hmd.frag");
@@ -151,37 +152,45 @@ fn main() {
     const WINDOW_FRAG: &[u32] = include_glsl!("shaders/example_window.frag");
 
     let hmd_module_vert =
-        create_shader_module(&context.vulkan, HMD_VERT, "HMDShaderVert".to_string()).unwrap();
+        ShaderModuleGuard::new(&context.vulkan, HMD_VERT, "HMDShaderVert".to_string()).unwrap();
     let hmd_module_frag =
-        create_shader_module(&context.vulkan, HMD_FRAG, "HMDShaderFrag".to_string()).unwrap();
+        ShaderModuleGuard::new(&context.vulkan, HMD_FRAG, "HMDShaderFrag".to_string()).unwrap();
 
     let window_module_vert =
-        create_shader_module(&context.vulkan, WINDOW_VERT, "WindowShaderVert".to_string()).unwrap();
+        ShaderModuleGuard::new(&context.vulkan, WINDOW_VERT, "WindowShaderVert".to_string())
+            .unwrap();
     let window_module_frag =
-        create_shader_module(&context.vulkan, WINDOW_FRAG, "WindowShaderFrag".to_string()).unwrap();
+        ShaderModuleGuard::new(&context.vulkan, WINDOW_FRAG, "WindowShaderFrag".to_string())
+            .unwrap();
 
     let hmd_pipeline_layout = create_pipeline_layout(
         &context.vulkan,
-        hmd_descriptor.layout,
+        &[hmd_descriptor.layout],
+        &[],
         "HMDPipelineLayout".to_string(),
     )
     .unwrap();
 
     let hmd_pipeline = create_pipeline(
         &context.vulkan,
-        context.hmd.render_pass,
+        context.hmd.as_ref().unwrap().render_pass,
         hmd_pipeline_layout,
-        hmd_module_vert,
-        hmd_module_frag,
-        context.openxr.get_resolution().unwrap(),
+        &hmd_module_vert,
+        &hmd_module_frag,
+        context.hmd.as_ref().unwrap().swapchain.extent,
         &[], // no dynamic state for now
+        SampleCountFlags::TYPE_1,
+        // counter-clockwise like the loaded glTF, the projections flip y accordingly
+        RasterOptions::default(),
+        BlendMode::Opaque,
         "HMDPipeline".to_string(),
     )
     .unwrap();
 
     let window_pipeline_layout = create_pipeline_layout(
         &context.vulkan,
-        window_descriptor.layout,
+        &[window_descriptor.layout],
+        &[],
         "WindowPipelineLayout".to_string(),
     )
     .unwrap();
@@ -189,38 +198,19 @@ fn main() {
         &context.vulkan,
         context.window.render_pass,
         window_pipeline_layout,
-        window_module_vert,
-        window_module_frag,
-        Extent2D {
-            width: window.inner_size().width,
-            height: window.inner_size().height,
-        },
+        &window_module_vert,
+        &window_module_frag,
+        context.window_extent(),
         &[DynamicState::VIEWPORT, DynamicState::SCISSOR], // allow for resize
+        context.get_window_samples(),
+        RasterOptions::default(),
+        BlendMode::Opaque,
         "WindowPipeline".to_string(),
     )
     .unwrap();
 
-    unsafe {
-        context
-            .vulkan
-            .device
-            .destroy_shader_module(hmd_module_vert, None);
-        context
-            .vulkan
-            .device
-            .destroy_shader_module(hmd_module_frag, None);
-
-        context
-            .vulkan
-            .device
-            .destroy_shader_module(window_module_vert, None);
-        context
-            .vulkan
-            .device
-            .destroy_shader_module(window_module_frag, None);
-    }
-
-    let mut spherical_coords = SphereCoords::new();
+    let mut camera = OrbitCamera::new();
+    let mut last_camera_update = Instant::now();
 
     let mut pressed_keys: HashSet<VirtualKeyCode> = HashSet::new();
 
@@ -234,206 +224,179 @@ fn main() {
         .expect("setting Ctrl-C handler");
     }
 
-    let mut xr_event_storage = EventDataBuffer::new();
-    let mut xr_session_running = false;
-    let mut xr_focused = false;
+    let mut locomotion = Locomotion::new(LocomotionSettings::default());
 
-    let mut hmd_flip_flop = 0;
-    let mut window_flip_flop = 0;
+    // shared by the HMD views and the window
+    let projection = ProjectionParams::default();
 
     // not sure if this is the way I want it...
     // it is an honest approach in the sense that the window is "on top"
     event_loop.run(move |event, _, control_flow| match event {
         Event::LoopDestroyed => {
             context.vulkan.wait_idle().unwrap();
-            hmd_front_back.clear();
-            window_front_back.clear();
-            unsafe {
-                ManuallyDrop::drop(&mut context);
+            hmd_buffers.clear();
+            window_ring = None;
+            if let Err(e) = unsafe { ManuallyDrop::take(&mut context) }.destroy() {
+                log::error!("Failed to tear down context: {}", e);
             }
         }
         Event::MainEventsCleared => {
-            if ctrlc.load(Ordering::Relaxed) {
+            if ctrlc.swap(false, Ordering::Relaxed) {
                 log::warn!("Exiting through Ctrl-C");
 
-                *control_flow = ControlFlow::Exit;
-
-                match context.hmd.session.request_exit() {
-                    Ok(()) => {}
-                    Err(openxr::sys::Result::ERROR_SESSION_NOT_RUNNING) => {}
-                    Err(e) => panic!("{}", e),
+                // a running session winds down first and reports ShouldExit below
+                if context.is_running() {
+                    context.request_exit().unwrap();
+                } else {
+                    *control_flow = ControlFlow::Exit;
+                    return;
                 }
+            }
 
-                return;
+            // begins and ends the session as the runtime asks for it
+            for event in context.poll_events().unwrap() {
+                log::warn!("{:?}", event);
+                if event == VrvEvent::ShouldExit {
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
             }
 
-            // handle OpenXR events
-            while let Some(event) = context
-                .openxr
-                .instance
-                .poll_event(&mut xr_event_storage)
-                .unwrap()
+            // the same for both views, half a turn every 2π seconds
+            let spin = Matrix4::from_angle_y(Rad(start.elapsed().as_secs_f32() * 0.5));
+
             {
-                use openxr::Event::*;
-                match event {
-                    SessionStateChanged(e) => {
-                        // Session state change is where we can begin and end sessions, as well as
-                        // find quit messages!
-                        log::warn!("entered state {:?}", e.state());
-                        xr_focused = false;
-                        match e.state() {
-                            SessionState::READY => {
-                                context
-                                    .hmd
-                                    .session
-                                    .begin(ViewConfigurationType::PRIMARY_STEREO)
-                                    .unwrap();
-                                xr_session_running = true;
-                            }
-                            SessionState::STOPPING => {
-                                context.hmd.session.end().unwrap();
-                                xr_session_running = false;
-                            }
-                            SessionState::FOCUSED => {
-                                xr_focused = true;
-                            }
-                            SessionState::EXITING | SessionState::LOSS_PENDING => {
-                                *control_flow = ControlFlow::Exit;
-                                return;
-                            }
-                            _ => {}
-                        }
+                // skips the frame when dropped without submitting
+                let mut hmd_frame = context.begin_frame_hmd().unwrap();
+                let frame_state = hmd_frame.pre_render_info().frame_state;
+
+                if hmd_frame.is_focused() {
+                    let input_state = hmd_frame.input_state().unwrap();
+                    if input_state.trigger_clicks[0].current_state {
+                        log::warn!(
+                            "Pressed left trigger, hand is at {:?}",
+                            input_state.hand_poses[0].position
+                        );
                     }
-                    InstanceLossPending(_) => {
-                        *control_flow = ControlFlow::Exit;
-                        return;
+                    if input_state.trigger_clicks[1].current_state {
+                        log::warn!(
+                            "Pressed right trigger, hand is at {:?}",
+                            input_state.hand_poses[1].position
+                        );
                     }
-                    EventsLost(e) => {
-                        log::error!("lost {} events", e.lost_event_count());
+                    // a short buzz when a trigger goes down
+                    for (hand, click) in input_state.trigger_clicks.iter().enumerate() {
+                        if click.current_state && click.changed_since_last_sync {
+                            hmd_frame
+                                .pulse(hand, 0.5, Duration::from_millis(50), 0.0)
+                                .unwrap();
+                        }
                     }
-                    _ => {}
-                }
-            }
 
-            let hmd_pre_render_info = context.pre_render_hmd().unwrap();
-
-            if xr_focused {
-                let input_state = context
-                    .hmd
-                    .actions
-                    .get_state(
-                        &context.hmd.stage,
-                        hmd_pre_render_info.frame_state.predicted_display_time,
-                    )
-                    .unwrap();
-                if input_state.trigger_clicks[0].current_state {
-                    log::warn!(
-                        "Pressed left trigger, hand is at {:?}",
-                        input_state.hand_poses[0].position
-                    );
+                    // the head sits between the eyes
+                    let eyes = hmd_frame.stereo_views().unwrap();
+                    let head = Posef {
+                        orientation: eyes[0].pose.orientation,
+                        position: Vector3f {
+                            x: (eyes[0].pose.position.x + eyes[1].pose.position.x) / 2.0,
+                            y: (eyes[0].pose.position.y + eyes[1].pose.position.y) / 2.0,
+                            z: (eyes[0].pose.position.z + eyes[1].pose.position.z) / 2.0,
+                        },
+                    };
+                    let delta_seconds =
+                        frame_state.predicted_display_period.as_nanos() as f32 / 1e9;
+                    if locomotion.update(&input_state, head, delta_seconds) {
+                        hmd_frame.apply_locomotion(&locomotion).unwrap();
+                    }
+                } else {
+                    log::warn!("Not focused!");
                 }
-                if input_state.trigger_clicks[1].current_state {
-                    log::warn!(
-                        "Pressed right trigger, hand is at {:?}",
-                        input_state.hand_poses[1].position
-                    );
+
+                if hmd_frame.should_render() {
+                    let in_flight = hmd_frame.next_in_flight().unwrap();
+                    hmd_frame
+                        .record(
+                            hmd_pipeline_layout,
+                            hmd_pipeline,
+                            &hmd_buffers[in_flight.index].mesh_buffers,
+                            hmd_descriptor_sets[in_flight.index],
+                            in_flight.command_buffer,
+                            in_flight.fence,
+                        )
+                        .unwrap();
+                    let views = hmd_frame.stereo_views().unwrap();
+
+                    hmd_buffers[in_flight.index]
+                        .matrix_buffer
+                        .write(&[UniformMatricesHMD {
+                            model: spin,
+                            view_left: pose_to_matrix_inverse(views[0].pose).into(),
+                            view_right: pose_to_matrix_inverse(views[1].pose).into(),
+                            proj_left: fov_to_projection(views[0].fov, projection).into(),
+                            proj_right: fov_to_projection(views[1].fov, projection).into(),
+                        }]);
+
+                    hmd_frame
+                        .submit(&views, in_flight.command_buffer, in_flight.fence)
+                        .unwrap();
                 }
-            } else {
-                log::warn!("Not focused!");
             }
 
-            if hmd_pre_render_info.image_index.is_some() {
-                context
-                    .record_hmd(
-                        hmd_pre_render_info,
-                        hmd_pipeline_layout,
-                        hmd_pipeline,
-                        &hmd_front_back[hmd_flip_flop].buffer.mesh_buffers,
-                        hmd_descriptor_sets[hmd_flip_flop],
-                        hmd_front_back[hmd_flip_flop].command,
-                        hmd_front_back[hmd_flip_flop].fence,
-                    )
-                    .unwrap();
-                let views = context
-                    .get_views(hmd_pre_render_info.frame_state.predicted_display_time)
-                    .unwrap();
-
-                hmd_front_back[hmd_flip_flop]
-                    .buffer
-                    .matrix_buffer
-                    .write(&[UniformMatricesHMD {
-                        model: Matrix4::identity(),
-                        view_left: pose_to_matrix_inverse(views[0].pose),
-                        view_right: pose_to_matrix_inverse(views[1].pose),
-                        proj_left: fov_to_projection(views[0].fov),
-                        proj_right: fov_to_projection(views[1].fov),
+            // skipped while minimized, the fence would never be signaled otherwise
+            if context.is_window_renderable() {
+                let window_pre_render_info = context.pre_render_window().unwrap();
+
+                // out of date before anything was acquired
+                if window_pre_render_info.image_index.is_none() {
+                    if context.window_needs_resize() {
+                        context.resize(&window).unwrap();
+                    }
+                } else {
+                    camera.update(
+                        last_camera_update.elapsed().as_secs_f32(),
+                        camera_input(&pressed_keys),
+                    );
+                    last_camera_update = Instant::now();
+
+                    // waits before writing to resources used in window rendering
+                    let frame = window_ring.as_mut().unwrap().next(&context.vulkan).unwrap();
+
+                    frame.data.matrix_buffer.write(&[UniformMatricesWindow {
+                        model: spin,
+                        view: camera.view_matrix().into(),
+                        proj: {
+                            // undo y inversion
+                            let extent = context.window_extent();
+                            let mut tmp = perspective(
+                                Deg(45.0),
+                                extent.width as f32 / extent.height as f32,
+                                projection.near,
+                                projection.far,
+                            );
+                            tmp[1][1] *= -1.0;
+                            tmp
+                        },
                     }]);
 
-                context
-                    .submit_hmd(
-                        hmd_pre_render_info,
-                        &views,
-                        hmd_front_back[hmd_flip_flop].command,
-                        hmd_front_back[hmd_flip_flop].fence,
-                    )
-                    .unwrap();
-
-                hmd_flip_flop += 1;
-                hmd_flip_flop %= 2;
+                    let outcome = context
+                        .render_window(
+                            window_pre_render_info,
+                            window_pipeline_layout,
+                            window_pipeline,
+                            &frame.data.mesh_buffers,
+                            window_descriptor_sets[frame.index],
+                            frame.command_buffer,
+                            frame.fence,
+                            frame.semaphore,
+                        )
+                        .unwrap();
+                    if outcome == RenderOutcome::NeedsResize {
+                        context.resize(&window).unwrap();
+                    }
+                }
             }
 
-            let window_pre_render_info = context.pre_render_window().unwrap();
-
-            spherical_coords.update(
-                &pressed_keys
-                    .iter()
-                    .map(|&k| k.into())
-                    .collect::<Vec<KeyMap>>(),
-            );
-
-            // waite before writing to resources used in window rendering
-            wait_and_reset(&context.vulkan, window_front_back[window_flip_flop].fence).unwrap();
-
-            window_front_back[window_flip_flop]
-                .buffer
-                .matrix_buffer
-                .write(&[UniformMatricesWindow {
-                    model: Matrix4::identity(),
-                    view: Matrix4::look_at_rh(
-                        spherical_coords.to_coords(),
-                        Point3::origin(),
-                        Vector3::unit_y(),
-                    ),
-                    proj: {
-                        // undo y inversion
-                        let mut tmp = perspective(
-                            Deg(45.0),
-                            window.inner_size().width as f32 / window.inner_size().height as f32,
-                            0.1,
-                            100.0,
-                        );
-                        tmp[1][1] *= -1.0;
-                        tmp
-                    },
-                }]);
-
-            context
-                .render_window(
-                    window_pre_render_info,
-                    window_pipeline_layout,
-                    window_pipeline,
-                    &window_front_back[window_flip_flop].buffer.mesh_buffers,
-                    window_descriptor_sets[window_flip_flop],
-                    window_front_back[window_flip_flop].command,
-                    window_front_back[window_flip_flop].fence,
-                    window_front_back[window_flip_flop].semaphore,
-                )
-                .unwrap();
-
             window.request_redraw();
-
-            window_flip_flop += 1;
-            window_flip_flop %= 2;
         }
         Event::WindowEvent {
             ref event,
@@ -451,8 +414,7 @@ fn main() {
                     ..
                 } => *control_flow = ControlFlow::Exit,
                 WindowEvent::Resized(new_inner_size) => {
-                    // TODO if the window is minimized, size is 0,0
-                    // we need to make vulkan chill
+                    // minimizing gives 0x0, the context pauses the window then
                     log::info!("Resizing to {:?}", new_inner_size);
                     context.resize(&window).unwrap();
                 }
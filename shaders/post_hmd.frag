@@ -0,0 +1,86 @@
+#version 450
+
+#extension GL_EXT_multiview : require
+
+// FXAA (the cheap variant without edge search) followed by a 4-tap sharpen,
+// the sharpening counteracts the blur of the lens distortion.
+
+layout(binding = 0) uniform sampler2DArray scene;
+
+layout(push_constant) uniform Params {
+    vec2 texel; // 1 / extent
+    float sharpen; // 0 disables
+    uint fxaa; // 0 disables
+} params;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outColor;
+
+const float FXAA_SPAN_MAX = 8.0;
+const float FXAA_REDUCE_MUL = 1.0 / 8.0;
+const float FXAA_REDUCE_MIN = 1.0 / 128.0;
+
+vec4 fetch(vec2 uv) {
+    return texture(scene, vec3(uv, gl_ViewIndex));
+}
+
+vec4 fetchOffset(vec2 offset) {
+    return fetch(inUV + offset * params.texel);
+}
+
+float luma(vec3 color) {
+    return dot(color, vec3(0.299, 0.587, 0.114));
+}
+
+vec3 fxaa(vec3 center) {
+    float lumaNW = luma(fetchOffset(vec2(-1.0, -1.0)).rgb);
+    float lumaNE = luma(fetchOffset(vec2(1.0, -1.0)).rgb);
+    float lumaSW = luma(fetchOffset(vec2(-1.0, 1.0)).rgb);
+    float lumaSE = luma(fetchOffset(vec2(1.0, 1.0)).rgb);
+    float lumaM = luma(center);
+
+    float lumaMin = min(lumaM, min(min(lumaNW, lumaNE), min(lumaSW, lumaSE)));
+    float lumaMax = max(lumaM, max(max(lumaNW, lumaNE), max(lumaSW, lumaSE)));
+
+    // perpendicular to the luma gradient, i.e. along the edge
+    vec2 dir = vec2(
+        -((lumaNW + lumaNE) - (lumaSW + lumaSE)),
+        (lumaNW + lumaSW) - (lumaNE + lumaSE)
+    );
+    float dirReduce = max(
+        (lumaNW + lumaNE + lumaSW + lumaSE) * 0.25 * FXAA_REDUCE_MUL,
+        FXAA_REDUCE_MIN
+    );
+    float rcpDirMin = 1.0 / (min(abs(dir.x), abs(dir.y)) + dirReduce);
+    dir = clamp(dir * rcpDirMin, -FXAA_SPAN_MAX, FXAA_SPAN_MAX) * params.texel;
+
+    vec3 rgbA = 0.5 * (
+        fetch(inUV + dir * (1.0 / 3.0 - 0.5)).rgb +
+        fetch(inUV + dir * (2.0 / 3.0 - 0.5)).rgb
+    );
+    vec3 rgbB = rgbA * 0.5 + 0.25 * (
+        fetch(inUV + dir * -0.5).rgb +
+        fetch(inUV + dir * 0.5).rgb
+    );
+
+    // the wider blend crossed another edge
+    float lumaB = luma(rgbB);
+    return (lumaB < lumaMin || lumaB > lumaMax) ? rgbA : rgbB;
+}
+
+void main() {
+    vec4 center = fetch(inUV);
+    vec3 color = params.fxaa != 0u ? fxaa(center.rgb) : center.rgb;
+
+    if (params.sharpen > 0.0) {
+        vec3 neighbours =
+            fetchOffset(vec2(0.0, -1.0)).rgb +
+            fetchOffset(vec2(-1.0, 0.0)).rgb +
+            fetchOffset(vec2(1.0, 0.0)).rgb +
+            fetchOffset(vec2(0.0, 1.0)).rgb;
+        color += params.sharpen * (4.0 * center.rgb - neighbours);
+    }
+
+    outColor = vec4(clamp(color, 0.0, 1.0), center.a);
+}
@@ -0,0 +1,13 @@
+#version 450
+
+// For Mesh::thick_lines, the alpha is uv.x - |uv.y|: at least 1 inside the line
+// and falling off to 0 at the edges, see ThickLineSettings::falloff.
+
+layout(location = 0) in vec3 fragColor;
+layout(location = 1) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    outColor = vec4(fragColor, clamp(fragUV.x - abs(fragUV.y), 0.0, 1.0));
+}
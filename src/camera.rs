@@ -0,0 +1,117 @@
+use std::f32::consts::PI;
+
+use openxr::{Posef, Quaternionf};
+
+use crate::math::{cross, dot, normalize, sub};
+
+pub use crate::projection::fov_to_projection;
+
+// What moves the camera this update, each axis in -1..1.
+// Filled from keys, a gamepad or anything else, the camera doesn't care.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CameraInput {
+    pub horizontal: f32, // positive orbits right
+    pub vertical: f32,   // positive orbits up
+    pub zoom: f32,       // positive moves away from the target
+}
+
+// Circles around target on a sphere, for looking at a model in the window.
+// phi goes around the Y axis, theta is measured from +Y.
+#[derive(Copy, Clone, Debug)]
+pub struct OrbitCamera {
+    pub phi: f32,
+    pub theta: f32,
+    pub radius: f32,
+    pub speed: f32, // radians or meters per second at full input
+    pub target: [f32; 3],
+}
+
+impl Default for OrbitCamera {
+    fn default() -> Self {
+        Self {
+            phi: 0.0,
+            theta: std::f32::consts::FRAC_PI_8,
+            radius: 4.0,
+            speed: 2.0,
+            target: [0.0; 3],
+        }
+    }
+}
+
+impl OrbitCamera {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, delta_seconds: f32, input: CameraInput) {
+        let d = delta_seconds * self.speed;
+
+        self.theta -= input.vertical * d;
+        self.phi -= input.horizontal * d;
+        self.radius += input.zoom * d;
+
+        // stay away from the poles and the target, the view matrix degenerates there
+        self.theta = self.theta.clamp(0.1, PI - 0.1);
+        self.phi %= 2.0 * PI;
+        self.radius = self.radius.clamp(0.1, 100.0);
+    }
+
+    pub fn position(&self) -> [f32; 3] {
+        [
+            self.target[0] + self.radius * self.phi.cos() * self.theta.sin(),
+            self.target[1] + self.radius * self.theta.cos(),
+            self.target[2] + self.radius * self.phi.sin() * self.theta.sin(),
+        ]
+    }
+
+    // Column-major, right-handed with +Y up, like cgmath's Matrix4::look_at_rh.
+    pub fn view_matrix(&self) -> [[f32; 4]; 4] {
+        let eye = self.position();
+        // update keeps the camera off the target and the poles, the fallbacks are for
+        // fields set directly
+        let f = normalize(sub(self.target, eye)).unwrap_or([0.0, 0.0, -1.0]);
+        let s = normalize(cross(f, [0.0, 1.0, 0.0])).unwrap_or([1.0, 0.0, 0.0]);
+        let u = cross(s, f);
+        [
+            [s[0], u[0], -f[0], 0.0],
+            [s[1], u[1], -f[1], 0.0],
+            [s[2], u[2], -f[2], 0.0],
+            [-dot(eye, s), -dot(eye, u), dot(eye, f), 1.0],
+        ]
+    }
+}
+
+// The view matrix of an OpenXR view, the inverse of the pose's transform. Column-major.
+pub fn pose_to_matrix_inverse(pose: Posef) -> [[f32; 4]; 4] {
+    let r = rotation_columns(pose.orientation);
+    let p = [pose.position.x, pose.position.y, pose.position.z];
+    // the transposed rotation undoes it, then the translation is rotated along
+    [
+        [r[0][0], r[1][0], r[2][0], 0.0],
+        [r[0][1], r[1][1], r[2][1], 0.0],
+        [r[0][2], r[1][2], r[2][2], 0.0],
+        [-dot(r[0], p), -dot(r[1], p), -dot(r[2], p), 1.0],
+    ]
+}
+
+// columns of the rotation matrix of a unit quaternion
+fn rotation_columns(q: Quaternionf) -> [[f32; 3]; 3] {
+    let Quaternionf { x, y, z, w } = q;
+    [
+        [
+            1.0 - 2.0 * (y * y + z * z),
+            2.0 * (x * y + w * z),
+            2.0 * (x * z - w * y),
+        ],
+        [
+            2.0 * (x * y - w * z),
+            1.0 - 2.0 * (x * x + z * z),
+            2.0 * (y * z + w * x),
+        ],
+        [
+            2.0 * (x * z + w * y),
+            2.0 * (y * z - w * x),
+            1.0 - 2.0 * (x * x + y * y),
+        ],
+    ]
+}
@@ -1,43 +1,122 @@
-use anyhow::Result;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
 
 use openxr::{
-    Action, ActionSet, ActionState, ActiveActionSet, Binding, Instance, Path, Posef, Session,
-    Space, Time, Vulkan, USER_HAND_LEFT, USER_HAND_RIGHT,
+    sys, Action, ActionSet, ActionState, ActionTy, ActiveActionSet, Binding, Haptic,
+    HapticVibration, Instance, Path, Posef, Session, Space, Time, Vulkan, USER_HAND_LEFT,
+    USER_HAND_RIGHT,
 };
 
-use super::Context;
-
 pub struct State {
+    // grip poses, where the controller is held
     pub hand_poses: [Posef; 2],
+    // pointing poses, forward is -Z, for rays
+    pub aim_poses: [Posef; 2],
     pub trigger_clicks: [ActionState<bool>; 2],
     pub a_clicks: [ActionState<bool>; 2],
     pub b_clicks: [ActionState<bool>; 2],
     pub pad_or_stick_click: [ActionState<bool>; 2],
     pub pad_or_stick_position_x: [ActionState<f32>; 2],
     pub pad_or_stick_position_y: [ActionState<f32>; 2],
+    // only bound for controllers that report it, zero (and inactive) for the others
+    pub squeeze_force: [ActionState<f32>; 2],
+}
+
+// radial, rescaled so the output still covers the full range
+pub(crate) fn apply_deadzone(x: f32, y: f32, deadzone: f32) -> (f32, f32) {
+    let magnitude = (x * x + y * y).sqrt();
+    if magnitude <= deadzone {
+        return (0.0, 0.0);
+    }
+    let scale = ((magnitude - deadzone) / (1.0 - deadzone)).min(1.0) / magnitude;
+    (x * scale, y * scale)
+}
+
+impl State {
+    // Only the sticks are active, e.g. for testing the helpers built on top of them
+    #[cfg(test)]
+    pub(crate) fn with_sticks(sticks: [[f32; 2]; 2]) -> Self {
+        fn state<T: openxr::ActionInput>(current_state: T, is_active: bool) -> ActionState<T> {
+            ActionState {
+                current_state,
+                changed_since_last_sync: false,
+                last_change_time: Time::from_nanos(0),
+                is_active,
+            }
+        }
+        Self {
+            hand_poses: [Posef::IDENTITY; 2],
+            aim_poses: [Posef::IDENTITY; 2],
+            trigger_clicks: [state(false, false); 2],
+            a_clicks: [state(false, false); 2],
+            b_clicks: [state(false, false); 2],
+            pad_or_stick_click: [state(false, false); 2],
+            pad_or_stick_position_x: sticks.map(|[x, _]| state(x, true)),
+            pad_or_stick_position_y: sticks.map(|[_, y]| state(y, true)),
+            squeeze_force: [state(0.0, false); 2],
+        }
+    }
+
+    // Pad or stick of hand (0 left, 1 right) as [x, y], up is positive y.
+    // Inside the radial deadzone (0.0 to 1.0) it is zero, outside rescaled to still reach 1.
+    // Zero as well if either axis isn't bound, e.g. on the simple controller.
+    pub fn stick(&self, hand: usize, deadzone: f32) -> Result<[f32; 2]> {
+        if hand >= self.pad_or_stick_position_x.len() {
+            bail!("No hand with index {}", hand);
+        }
+        let x = &self.pad_or_stick_position_x[hand];
+        let y = &self.pad_or_stick_position_y[hand];
+        if !x.is_active || !y.is_active {
+            return Ok([0.0; 2]);
+        }
+        let (x, y) = apply_deadzone(x.current_state, y.current_state, deadzone);
+        Ok([x, y])
+    }
 }
 
 pub struct Actions {
     session: Session<Vulkan>,
     general_action_set: ActionSet,
     action_hand_pose: Action<Posef>,
+    action_aim_pose: Action<Posef>,
     action_trigger_click: Action<bool>,
     action_a_click: Action<bool>,
     action_b_click: Action<bool>,
     action_pad_or_stick_click: Action<bool>,
     action_pad_or_stick_position_x: Action<f32>,
     action_pad_or_stick_position_y: Action<f32>,
+    action_squeeze_force: Action<f32>,
+    action_haptic: Action<Haptic>,
     hand_pose_spaces: [Space; 2],
+    aim_pose_spaces: [Space; 2],
     subaction_paths: [Path; 2],
 }
 
 fn left_right_paths(instance: &Instance, suffix: &str) -> Result<[Path; 2]> {
+    per_hand_paths(instance, suffix, suffix)
+}
+
+// for inputs named differently on each hand, like x/y and a/b on Touch controllers
+fn per_hand_paths(instance: &Instance, left_suffix: &str, right_suffix: &str) -> Result<[Path; 2]> {
     Ok([
-        instance.string_to_path(&format!("{}{}", USER_HAND_LEFT, suffix))?,
-        instance.string_to_path(&format!("{}{}", USER_HAND_RIGHT, suffix))?,
+        instance.string_to_path(&format!("{}{}", USER_HAND_LEFT, left_suffix))?,
+        instance.string_to_path(&format!("{}{}", USER_HAND_RIGHT, right_suffix))?,
     ])
 }
 
+// suggesting a path the profile doesn't have fails the whole suggestion, so those are None
+fn bind_pair<'a, T: ActionTy>(
+    bindings: &mut Vec<Binding<'a>>,
+    action: &'a Action<T>,
+    paths: Option<[Path; 2]>,
+) {
+    if let Some(paths) = paths {
+        bindings.push(Binding::new(action, paths[0]));
+        bindings.push(Binding::new(action, paths[1]));
+    }
+}
+
 impl Actions {
     pub fn new(instance: &Instance, session: Session<Vulkan>) -> Result<Self> {
         let subaction_paths = left_right_paths(instance, "")?;
@@ -47,6 +126,8 @@ impl Actions {
 
         let action_hand_pose =
             general_action_set.create_action("hand_pose", "Hand Pose", &subaction_paths)?;
+        let action_aim_pose =
+            general_action_set.create_action("aim_pose", "Aim Pose", &subaction_paths)?;
         let action_trigger_click =
             general_action_set.create_action("trigger_click", "Trigger Click", &subaction_paths)?;
         let action_a_click =
@@ -68,74 +149,98 @@ impl Actions {
             "Pad or Stick Position Y",
             &subaction_paths,
         )?;
+        let action_squeeze_force =
+            general_action_set.create_action("squeeze_force", "Squeeze Force", &subaction_paths)?;
+        let action_haptic =
+            general_action_set.create_action("haptic", "Haptic", &subaction_paths)?;
 
         let hand_pose_spaces = [
             action_hand_pose.create_space(session.clone(), subaction_paths[0], Posef::IDENTITY)?,
             action_hand_pose.create_space(session.clone(), subaction_paths[1], Posef::IDENTITY)?,
         ];
+        let aim_pose_spaces = [
+            action_aim_pose.create_space(session.clone(), subaction_paths[0], Posef::IDENTITY)?,
+            action_aim_pose.create_space(session.clone(), subaction_paths[1], Posef::IDENTITY)?,
+        ];
 
         let actions = Self {
             session,
             general_action_set,
             action_hand_pose,
+            action_aim_pose,
             action_trigger_click,
             action_a_click,
             action_b_click,
             action_pad_or_stick_click,
             action_pad_or_stick_position_x,
             action_pad_or_stick_position_y,
+            action_squeeze_force,
+            action_haptic,
             hand_pose_spaces,
+            aim_pose_spaces,
             subaction_paths,
         };
 
         let suggest = |suggestion: Suggestion| {
-            instance.suggest_interaction_profile_bindings(
-                suggestion.platform_path,
-                &[
-                    Binding::new(&actions.action_hand_pose, suggestion.pose_paths[0]),
-                    Binding::new(&actions.action_hand_pose, suggestion.pose_paths[1]),
-                    Binding::new(
-                        &actions.action_trigger_click,
-                        suggestion.trigger_click_paths[0],
-                    ),
-                    Binding::new(
-                        &actions.action_trigger_click,
-                        suggestion.trigger_click_paths[1],
-                    ),
-                    Binding::new(&actions.action_a_click, suggestion.a_click_paths[0]),
-                    Binding::new(&actions.action_a_click, suggestion.a_click_paths[1]),
-                    Binding::new(&actions.action_b_click, suggestion.b_click_paths[0]),
-                    Binding::new(&actions.action_b_click, suggestion.b_click_paths[1]),
-                    Binding::new(
-                        &actions.action_pad_or_stick_click,
-                        suggestion.pad_or_stick_click_paths[0],
-                    ),
-                    Binding::new(
-                        &actions.action_pad_or_stick_click,
-                        suggestion.pad_or_stick_click_paths[1],
-                    ),
-                    Binding::new(
-                        &actions.action_pad_or_stick_position_x,
-                        suggestion.pad_or_stick_position_x_paths[0],
-                    ),
-                    Binding::new(
-                        &actions.action_pad_or_stick_position_x,
-                        suggestion.pad_or_stick_position_x_paths[1],
-                    ),
-                    Binding::new(
-                        &actions.action_pad_or_stick_position_y,
-                        suggestion.pad_or_stick_position_y_paths[0],
-                    ),
-                    Binding::new(
-                        &actions.action_pad_or_stick_position_y,
-                        suggestion.pad_or_stick_position_y_paths[1],
-                    ),
-                ],
-            )
+            let mut bindings = Vec::new();
+            bind_pair(
+                &mut bindings,
+                &actions.action_hand_pose,
+                Some(suggestion.pose_paths),
+            );
+            bind_pair(
+                &mut bindings,
+                &actions.action_aim_pose,
+                Some(suggestion.aim_pose_paths),
+            );
+            bind_pair(
+                &mut bindings,
+                &actions.action_trigger_click,
+                Some(suggestion.trigger_click_paths),
+            );
+            bind_pair(
+                &mut bindings,
+                &actions.action_a_click,
+                Some(suggestion.a_click_paths),
+            );
+            bind_pair(
+                &mut bindings,
+                &actions.action_b_click,
+                suggestion.b_click_paths,
+            );
+            bind_pair(
+                &mut bindings,
+                &actions.action_pad_or_stick_click,
+                suggestion.pad_or_stick_click_paths,
+            );
+            bind_pair(
+                &mut bindings,
+                &actions.action_pad_or_stick_position_x,
+                suggestion.pad_or_stick_position_x_paths,
+            );
+            bind_pair(
+                &mut bindings,
+                &actions.action_pad_or_stick_position_y,
+                suggestion.pad_or_stick_position_y_paths,
+            );
+            bind_pair(
+                &mut bindings,
+                &actions.action_squeeze_force,
+                suggestion.squeeze_force_paths,
+            );
+            bind_pair(
+                &mut bindings,
+                &actions.action_haptic,
+                Some(suggestion.haptic_paths),
+            );
+            instance.suggest_interaction_profile_bindings(suggestion.platform_path, &bindings)
         };
 
         suggest(Suggestion::index(instance)?)?;
         suggest(Suggestion::vive(instance)?)?;
+        suggest(Suggestion::touch(instance)?)?;
+        // the fallback every runtime has to support, used if none of the above fits
+        suggest(Suggestion::simple(instance)?)?;
 
         actions
             .session
@@ -144,6 +249,52 @@ impl Actions {
         Ok(actions)
     }
 
+    // hand is 0 for left and 1 for right, like the arrays in State
+    // frequency in Hz, 0.0 lets the runtime pick, amplitude from 0.0 to 1.0
+    pub fn pulse(
+        &self,
+        hand: usize,
+        amplitude: f32,
+        duration: Duration,
+        frequency: f32,
+    ) -> Result<()> {
+        if hand >= self.subaction_paths.len() {
+            bail!("No hand with index {}", hand);
+        }
+        let vibration = HapticVibration::new()
+            .amplitude(amplitude.clamp(0.0, 1.0))
+            .duration(openxr::Duration::from_nanos(duration.as_nanos() as i64))
+            .frequency(frequency);
+        match self.action_haptic.apply_feedback(
+            &self.session,
+            self.subaction_paths[hand],
+            &vibration,
+        ) {
+            // e.g. a profile without haptics, nothing to feel then
+            Err(sys::Result::ERROR_PATH_UNSUPPORTED) => {
+                log::trace!("Haptic output not bound for hand {}", hand);
+                Ok(())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    // hand is 0 for left and 1 for right, e.g. "/interaction_profiles/valve/index_controller"
+    // None until the runtime has picked a profile, which can happen any time after attaching.
+    // Changes are announced with VrvEvent::InteractionProfileChanged.
+    pub fn current_profile(&self, hand: usize) -> Result<Option<String>> {
+        if hand >= self.subaction_paths.len() {
+            bail!("No hand with index {}", hand);
+        }
+        let profile = self
+            .session
+            .current_interaction_profile(self.subaction_paths[hand])?;
+        if profile == Path::NULL {
+            return Ok(None);
+        }
+        Ok(Some(self.session.instance().path_to_string(profile)?))
+    }
+
     pub fn get_state(&self, reference: &Space, time: Time) -> Result<State> {
         let active_action_set = ActiveActionSet::new(&self.general_action_set);
         self.session.sync_actions(&[active_action_set])?;
@@ -151,6 +302,10 @@ impl Actions {
             self.hand_pose_spaces[0].locate(reference, time)?.pose,
             self.hand_pose_spaces[1].locate(reference, time)?.pose,
         ];
+        let aim_poses = [
+            self.aim_pose_spaces[0].locate(reference, time)?.pose,
+            self.aim_pose_spaces[1].locate(reference, time)?.pose,
+        ];
         let trigger_clicks = [
             self.action_trigger_click
                 .state(&self.session, self.subaction_paths[0])?,
@@ -187,15 +342,23 @@ impl Actions {
             self.action_pad_or_stick_position_y
                 .state(&self.session, self.subaction_paths[1])?,
         ];
+        let squeeze_force = [
+            self.action_squeeze_force
+                .state(&self.session, self.subaction_paths[0])?,
+            self.action_squeeze_force
+                .state(&self.session, self.subaction_paths[1])?,
+        ];
 
         Ok(State {
             hand_poses,
+            aim_poses,
             trigger_clicks,
             a_clicks,
             b_clicks,
             pad_or_stick_click,
             pad_or_stick_position_x,
             pad_or_stick_position_y,
+            squeeze_force,
         })
     }
 }
@@ -203,12 +366,15 @@ impl Actions {
 struct Suggestion {
     platform_path: Path,
     pose_paths: [Path; 2],
+    aim_pose_paths: [Path; 2],
     trigger_click_paths: [Path; 2],
     a_click_paths: [Path; 2],
-    b_click_paths: [Path; 2],
-    pad_or_stick_click_paths: [Path; 2],
-    pad_or_stick_position_x_paths: [Path; 2],
-    pad_or_stick_position_y_paths: [Path; 2],
+    b_click_paths: Option<[Path; 2]>,
+    pad_or_stick_click_paths: Option<[Path; 2]>,
+    pad_or_stick_position_x_paths: Option<[Path; 2]>,
+    pad_or_stick_position_y_paths: Option<[Path; 2]>,
+    squeeze_force_paths: Option<[Path; 2]>,
+    haptic_paths: [Path; 2],
 }
 
 impl Suggestion {
@@ -218,12 +384,15 @@ impl Suggestion {
             platform_path: instance
                 .string_to_path("/interaction_profiles/valve/index_controller")?,
             pose_paths: left_right_paths(instance, "/input/grip/pose")?,
+            aim_pose_paths: left_right_paths(instance, "/input/aim/pose")?,
             trigger_click_paths: left_right_paths(instance, "/input/trigger/click")?,
             a_click_paths: left_right_paths(instance, "/input/a/click")?,
-            b_click_paths: left_right_paths(instance, "/input/b/click")?,
-            pad_or_stick_click_paths: left_right_paths(instance, "/input/thumbstick/click")?,
-            pad_or_stick_position_x_paths: left_right_paths(instance, "/input/thumbstick/x")?,
-            pad_or_stick_position_y_paths: left_right_paths(instance, "/input/thumbstick/y")?,
+            b_click_paths: Some(left_right_paths(instance, "/input/b/click")?),
+            pad_or_stick_click_paths: Some(left_right_paths(instance, "/input/thumbstick/click")?),
+            pad_or_stick_position_x_paths: Some(left_right_paths(instance, "/input/thumbstick/x")?),
+            pad_or_stick_position_y_paths: Some(left_right_paths(instance, "/input/thumbstick/y")?),
+            squeeze_force_paths: Some(left_right_paths(instance, "/input/squeeze/force")?),
+            haptic_paths: left_right_paths(instance, "/output/haptic")?,
         })
     }
 
@@ -233,12 +402,91 @@ impl Suggestion {
         Ok(Self {
             platform_path: instance.string_to_path("/interaction_profiles/htc/vive_controller")?,
             pose_paths: left_right_paths(instance, "/input/grip/pose")?,
+            aim_pose_paths: left_right_paths(instance, "/input/aim/pose")?,
             trigger_click_paths: left_right_paths(instance, "/input/trigger/click")?,
             a_click_paths: left_right_paths(instance, "/input/squeeze/click")?,
-            b_click_paths: left_right_paths(instance, "/input/trackpad/click")?, // same as trackpad ? :P
-            pad_or_stick_click_paths: left_right_paths(instance, "/input/trackpad/click")?,
-            pad_or_stick_position_x_paths: left_right_paths(instance, "/input/trackpad/x")?,
-            pad_or_stick_position_y_paths: left_right_paths(instance, "/input/trackpad/y")?,
+            b_click_paths: Some(left_right_paths(instance, "/input/trackpad/click")?), // same as trackpad ? :P
+            pad_or_stick_click_paths: Some(left_right_paths(instance, "/input/trackpad/click")?),
+            pad_or_stick_position_x_paths: Some(left_right_paths(instance, "/input/trackpad/x")?),
+            pad_or_stick_position_y_paths: Some(left_right_paths(instance, "/input/trackpad/y")?),
+            squeeze_force_paths: None, // squeeze is only a click
+            haptic_paths: left_right_paths(instance, "/output/haptic")?,
+        })
+    }
+
+    fn touch(instance: &Instance) -> Result<Self> {
+        // https://www.khronos.org/registry/OpenXR/specs/1.0/html/xrspec.html#_oculus_touch_controller_profile
+        Ok(Self {
+            platform_path: instance
+                .string_to_path("/interaction_profiles/oculus/touch_controller")?,
+            pose_paths: left_right_paths(instance, "/input/grip/pose")?,
+            aim_pose_paths: left_right_paths(instance, "/input/aim/pose")?,
+            // there is no click, the runtime thresholds the value
+            trigger_click_paths: left_right_paths(instance, "/input/trigger/value")?,
+            // the lower button of each hand is X on the left and A on the right
+            a_click_paths: per_hand_paths(instance, "/input/x/click", "/input/a/click")?,
+            b_click_paths: Some(per_hand_paths(
+                instance,
+                "/input/y/click",
+                "/input/b/click",
+            )?),
+            pad_or_stick_click_paths: Some(left_right_paths(instance, "/input/thumbstick/click")?),
+            pad_or_stick_position_x_paths: Some(left_right_paths(instance, "/input/thumbstick/x")?),
+            pad_or_stick_position_y_paths: Some(left_right_paths(instance, "/input/thumbstick/y")?),
+            // analog grip without a force sensor, close enough
+            squeeze_force_paths: Some(left_right_paths(instance, "/input/squeeze/value")?),
+            haptic_paths: left_right_paths(instance, "/output/haptic")?,
         })
     }
+
+    fn simple(instance: &Instance) -> Result<Self> {
+        // https://www.khronos.org/registry/OpenXR/specs/1.0/html/xrspec.html#_khronos_simple_controller_profile
+        Ok(Self {
+            platform_path: instance
+                .string_to_path("/interaction_profiles/khr/simple_controller")?,
+            pose_paths: left_right_paths(instance, "/input/grip/pose")?,
+            aim_pose_paths: left_right_paths(instance, "/input/aim/pose")?,
+            trigger_click_paths: left_right_paths(instance, "/input/select/click")?,
+            a_click_paths: left_right_paths(instance, "/input/menu/click")?,
+            // only select and menu, the rest stays inactive
+            b_click_paths: None,
+            pad_or_stick_click_paths: None,
+            pad_or_stick_position_x_paths: None,
+            pad_or_stick_position_y_paths: None,
+            squeeze_force_paths: None,
+            haptic_paths: left_right_paths(instance, "/output/haptic")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stick_inside_the_deadzone_is_centered() {
+        let state = State::with_sticks([[0.1, -0.1], [0.0, 0.2]]);
+        assert_eq!(state.stick(0, 0.2).unwrap(), [0.0, 0.0]);
+        assert_eq!(state.stick(1, 0.2).unwrap(), [0.0, 0.0]);
+
+        // right outside it starts from zero again, full deflection stays full
+        let state = State::with_sticks([[0.0, 0.3], [1.0, 0.0]]);
+        let [x, y] = state.stick(0, 0.2).unwrap();
+        assert_eq!(x, 0.0);
+        assert!((y - 0.125).abs() < 1e-6);
+        assert_eq!(state.stick(1, 0.2).unwrap(), [1.0, 0.0]);
+    }
+
+    #[test]
+    fn stick_of_a_missing_hand_fails() {
+        let state = State::with_sticks([[1.0, 0.0]; 2]);
+        assert!(state.stick(2, 0.2).is_err());
+    }
+
+    #[test]
+    fn unbound_stick_is_centered() {
+        let mut state = State::with_sticks([[1.0, 0.0]; 2]);
+        state.pad_or_stick_position_y[0].is_active = false;
+        assert_eq!(state.stick(0, 0.2).unwrap(), [0.0, 0.0]);
+    }
 }
@@ -0,0 +1,294 @@
+use anyhow::{bail, Result};
+use ash::{
+    vk::{
+        AccessFlags, CommandBuffer, DependencyFlags, Extent2D, Extent3D, Format, Handle, Image,
+        ImageAspectFlags, ImageCopy, ImageLayout, ImageMemoryBarrier, ImageSubresourceLayers,
+        ImageSubresourceRange, PipelineStageFlags, QUEUE_FAMILY_IGNORED,
+    },
+    Device,
+};
+use openxr::{
+    sys, Extent2Di, Offset2Di, Rect2Di, Session, SwapchainCreateFlags, SwapchainCreateInfo,
+    SwapchainUsageFlags, Vulkan,
+};
+
+use crate::{
+    projection::ProjectionParams,
+    wrap_vulkan::{self, context::depth_aspect_flags},
+    VrvError,
+};
+
+use super::Context;
+
+fn depth_barrier(
+    image: Image,
+    aspect_mask: ImageAspectFlags,
+    layer_count: u32,
+    old_layout: ImageLayout,
+    new_layout: ImageLayout,
+    src_access_mask: AccessFlags,
+    dst_access_mask: AccessFlags,
+) -> ImageMemoryBarrier {
+    ImageMemoryBarrier::builder()
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_queue_family_index(QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(
+            ImageSubresourceRange::builder()
+                .aspect_mask(aspect_mask)
+                .level_count(1)
+                .layer_count(layer_count)
+                .build(),
+        )
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .build()
+}
+
+// The scene is still rendered into SwapchainHMD::depth_image, which is copied into
+// a depth swapchain of the runtime after the render pass. That way the frame buffers
+// don't depend on which depth image the runtime hands out.
+pub struct DepthLayerHMD {
+    // has to match the projection used for rendering, reverse-Z is passed on as near > far
+    pub params: ProjectionParams,
+    swapchain: openxr::Swapchain<Vulkan>,
+    images: Vec<Image>,
+    acquired: Option<usize>,
+    extent: Extent2D,
+    view_count: u32,
+    // of the format shared with SwapchainHMD::depth_image
+    aspect_flags: ImageAspectFlags,
+    device: Device,
+}
+
+impl DepthLayerHMD {
+    pub fn new(
+        context: &wrap_vulkan::Context,
+        session: &Session<Vulkan>,
+        extent: Extent2D,
+        view_count: u32,
+        format: Format,
+        params: ProjectionParams,
+    ) -> Result<Self> {
+        if !session
+            .enumerate_swapchain_formats()?
+            .contains(&(format.as_raw() as _))
+        {
+            bail!("Runtime doesn't support {:?} depth swapchains", format);
+        }
+
+        let swapchain = session.create_swapchain(&SwapchainCreateInfo {
+            create_flags: SwapchainCreateFlags::EMPTY,
+            usage_flags: SwapchainUsageFlags::DEPTH_STENCIL_ATTACHMENT
+                | SwapchainUsageFlags::TRANSFER_DST,
+            format: format.as_raw() as _,
+            sample_count: 1,
+            width: extent.width,
+            height: extent.height,
+            face_count: 1,
+            array_size: view_count,
+            mip_count: 1,
+        })?;
+        let images = swapchain
+            .enumerate_images()?
+            .into_iter()
+            .enumerate()
+            .map(|(i, xr_image_handle)| {
+                let image = Image::from_raw(xr_image_handle);
+                context.name_object(image, format!("HMDDepthLayerImage_{}", i))?;
+                Ok(image)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            params,
+            swapchain,
+            images,
+            acquired: None,
+            extent,
+            view_count,
+            aspect_flags: depth_aspect_flags(format),
+            device: context.device.clone(),
+        })
+    }
+
+    // together with the color image, so both are released with the frame
+    pub fn acquire(&mut self) -> Result<()> {
+        self.release()?;
+        let image_index = self.swapchain.acquire_image()?;
+        self.swapchain.wait_image(openxr::Duration::INFINITE)?;
+        self.acquired = Some(image_index as usize);
+        Ok(())
+    }
+
+    pub fn release(&mut self) -> Result<()> {
+        if self.acquired.take().is_some() {
+            self.swapchain.release_image()?;
+        }
+        Ok(())
+    }
+
+    // after the scene render pass, which leaves src in DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+    pub unsafe fn record_copy(&self, command_buffer: CommandBuffer, src: Image) -> Result<()> {
+        let dst = match self.acquired {
+            Some(image_index) => self.images[image_index],
+            None => bail!("No HMD depth layer image acquired"),
+        };
+        let d = &self.device;
+
+        d.cmd_pipeline_barrier(
+            command_buffer,
+            PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            PipelineStageFlags::TRANSFER,
+            DependencyFlags::empty(),
+            &[],
+            &[],
+            &[
+                depth_barrier(
+                    src,
+                    self.aspect_flags,
+                    self.view_count,
+                    ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    AccessFlags::TRANSFER_READ,
+                ),
+                // the runtime hands out depth images in this layout and wants them back in it
+                depth_barrier(
+                    dst,
+                    self.aspect_flags,
+                    self.view_count,
+                    ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    AccessFlags::empty(),
+                    AccessFlags::TRANSFER_WRITE,
+                ),
+            ],
+        );
+        // same format on both sides, a combined one copies the stencil along
+        let subresource = ImageSubresourceLayers::builder()
+            .aspect_mask(self.aspect_flags)
+            .layer_count(self.view_count)
+            .build();
+        d.cmd_copy_image(
+            command_buffer,
+            src,
+            ImageLayout::TRANSFER_SRC_OPTIMAL,
+            dst,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[ImageCopy::builder()
+                .src_subresource(subresource)
+                .dst_subresource(subresource)
+                .extent(Extent3D {
+                    width: self.extent.width,
+                    height: self.extent.height,
+                    depth: 1,
+                })
+                .build()],
+        );
+        d.cmd_pipeline_barrier(
+            command_buffer,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::BOTTOM_OF_PIPE,
+            DependencyFlags::empty(),
+            &[],
+            &[],
+            &[depth_barrier(
+                dst,
+                self.aspect_flags,
+                self.view_count,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                AccessFlags::TRANSFER_WRITE,
+                AccessFlags::empty(),
+            )],
+        );
+        Ok(())
+    }
+
+    // one per view, in the order of the array layers
+    pub fn depth_infos(&self) -> Vec<sys::CompositionLayerDepthInfoKHR> {
+        let (near_z, far_z) = if self.params.reverse_z {
+            (self.params.far, self.params.near)
+        } else {
+            (self.params.near, self.params.far)
+        };
+        (0..self.view_count)
+            .map(|view| sys::CompositionLayerDepthInfoKHR {
+                ty: sys::CompositionLayerDepthInfoKHR::TYPE,
+                next: std::ptr::null(),
+                sub_image: sys::SwapchainSubImage {
+                    swapchain: self.swapchain.as_raw(),
+                    image_rect: Rect2Di {
+                        offset: Offset2Di::default(),
+                        extent: Extent2Di {
+                            width: self.extent.width as i32,
+                            height: self.extent.height as i32,
+                        },
+                    },
+                    image_array_index: view,
+                },
+                min_depth: 0.0,
+                max_depth: 1.0,
+                near_z,
+                far_z,
+            })
+            .collect()
+    }
+}
+
+impl Context {
+    pub fn depth_layer_supported(&self) -> bool {
+        self.openxr.as_ref().map_or(false, |openxr| {
+            openxr.enabled_extensions().khr_composition_layer_depth
+        })
+    }
+
+    pub fn get_hmd_depth_layer(&self) -> Option<ProjectionParams> {
+        self.hmd
+            .as_ref()
+            .and_then(|hmd| hmd.depth_layer.as_ref())
+            .map(|depth| depth.params)
+    }
+
+    // Some submits the depth of every frame alongside the color, which improves
+    // the runtime's reprojection. The params have to be the ones the projection was built with.
+    pub fn set_hmd_depth_layer(&mut self, params: Option<ProjectionParams>) -> Result<()> {
+        if let Some(params) = params {
+            if params.reverse_z != self.vulkan.reverse_z {
+                bail!("The depth layer's reverse_z has to match VulkanSettings::reverse_z");
+            }
+        }
+        let supported = self.depth_layer_supported();
+        let hmd = match (&mut self.hmd, params) {
+            (Some(hmd), _) => hmd,
+            (None, Some(_)) => return Err(VrvError::NoHmd.into()),
+            (None, None) => return Ok(()),
+        };
+        match (params, &mut hmd.depth_layer) {
+            (Some(params), Some(depth)) => depth.params = params,
+            (Some(params), None) => {
+                if !supported {
+                    bail!("XR_KHR_composition_layer_depth not supported by the runtime");
+                }
+                hmd.depth_layer = Some(DepthLayerHMD::new(
+                    &self.vulkan,
+                    &hmd.session,
+                    hmd.swapchain.extent,
+                    hmd.swapchain.view_count,
+                    self.vulkan.find_supported_depth_stencil_format()?,
+                    params,
+                )?);
+            }
+            (None, Some(_)) => {
+                // recorded command buffers might still copy into it
+                self.vulkan.wait_idle()?;
+                hmd.depth_layer = None;
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+}
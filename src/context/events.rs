@@ -0,0 +1,124 @@
+use anyhow::Result;
+use openxr::{sys, Event, SessionState};
+
+use super::Context;
+
+// What happened since the last Context::poll_events, the session transitions are already done.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VrvEvent {
+    // every change, the ones below are derived from it
+    SessionStateChanged(SessionState),
+    // the session was begun on READY, frames are expected from now on
+    SessionBegan,
+    // the session was ended on STOPPING, no frames until it is READY again
+    SessionEnded,
+    // input is available, see Context::is_focused
+    Focused,
+    // e.g. the user opened the runtime's dashboard
+    Unfocused,
+    // the session is EXITING or about to be lost, or the instance is, leave the event loop
+    ShouldExit,
+    // the runtime's queue overflowed, states might have been skipped
+    EventsLost(u32),
+    // e.g. other controllers were picked up, query the new ones with Actions::current_profile
+    InteractionProfileChanged,
+}
+
+impl Context {
+    // Polls the OpenXR events, call once per frame before begin_frame_hmd.
+    // Begins and ends the session when the runtime asks for it and tracks the session state.
+    // Without an HMD there are never any events.
+    pub fn poll_events(&mut self) -> Result<Vec<VrvEvent>> {
+        let mut events = Vec::new();
+        let (openxr, hmd) = match (&self.openxr, &mut self.hmd) {
+            (Some(openxr), Some(hmd)) => (openxr, hmd),
+            _ => return Ok(events),
+        };
+
+        while let Some(event) = openxr.instance.poll_event(&mut hmd.event_storage)? {
+            match event {
+                Event::SessionStateChanged(e) => {
+                    let state = e.state();
+                    log::info!("Entered session state {:?}", state);
+                    if hmd.session_state == SessionState::FOCUSED {
+                        events.push(VrvEvent::Unfocused);
+                    }
+                    hmd.session_state = state;
+                    events.push(VrvEvent::SessionStateChanged(state));
+                    match state {
+                        SessionState::READY => {
+                            hmd.session.begin(hmd.view_configuration)?;
+                            hmd.session_running = true;
+                            events.push(VrvEvent::SessionBegan);
+                        }
+                        SessionState::STOPPING if hmd.session_running => {
+                            hmd.session.end()?;
+                            hmd.session_running = false;
+                            events.push(VrvEvent::SessionEnded);
+                        }
+                        SessionState::FOCUSED => events.push(VrvEvent::Focused),
+                        SessionState::EXITING | SessionState::LOSS_PENDING => {
+                            hmd.should_exit = true;
+                            events.push(VrvEvent::ShouldExit);
+                        }
+                        _ => {}
+                    }
+                }
+                Event::InstanceLossPending(_) => {
+                    hmd.should_exit = true;
+                    events.push(VrvEvent::ShouldExit);
+                }
+                Event::InteractionProfileChanged(_) => {
+                    // only logged, failing to query it mustn't drop the remaining events
+                    for hand in 0..2 {
+                        match hmd.actions.current_profile(hand) {
+                            Ok(profile) => {
+                                log::info!("Interaction profile of hand {}: {:?}", hand, profile)
+                            }
+                            Err(e) => log::warn!(
+                                "Failed to query the interaction profile of hand {}: {}",
+                                hand,
+                                e
+                            ),
+                        }
+                    }
+                    events.push(VrvEvent::InteractionProfileChanged);
+                }
+                Event::EventsLost(e) => {
+                    log::error!("Lost {} OpenXR events", e.lost_event_count());
+                    events.push(VrvEvent::EventsLost(e.lost_event_count()));
+                }
+                _ => {}
+            }
+        }
+        Ok(events)
+    }
+
+    // Asks the runtime to end the session, e.g. on Ctrl-C. It goes through STOPPING and
+    // EXITING, so keep calling poll_events until ShouldExit. Fine if the session isn't running.
+    pub fn request_exit(&self) -> Result<()> {
+        let hmd = match &self.hmd {
+            Some(hmd) => hmd,
+            None => return Ok(()),
+        };
+        match hmd.session.request_exit() {
+            Ok(()) | Err(sys::Result::ERROR_SESSION_NOT_RUNNING) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // begun and not yet ended, frames are only expected while running
+    pub fn is_running(&self) -> bool {
+        self.hmd.as_ref().map_or(false, |hmd| hmd.session_running)
+    }
+
+    // input and rendering are only worth it while focused
+    pub fn is_focused(&self) -> bool {
+        self.get_session_state() == SessionState::FOCUSED
+    }
+
+    // stays true once ShouldExit was reported
+    pub fn should_exit(&self) -> bool {
+        self.hmd.as_ref().map_or(false, |hmd| hmd.should_exit)
+    }
+}
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use anyhow::{Error, Result};
+use ash::vk::{CommandBuffer, DescriptorSet, Fence, Pipeline, PipelineLayout};
+use openxr::View;
+
+use crate::{
+    wrap_vulkan::geometry::{DrawCall, MeshBuffers},
+    Context, VrvError,
+};
+
+use super::{actions::State, locomotion::Locomotion, InFlightHMD, PreRenderInfoHMD};
+
+// Holds the context for the duration of one HMD frame, so the OpenXR sequence
+// wait -> begin -> acquire -> wait image -> release -> end can't be messed up.
+// If the frame isn't submitted, dropping it releases the image and ends the frame without layers.
+pub struct FrameHMD<'a> {
+    context: &'a mut Context,
+    pre_render_info: PreRenderInfoHMD,
+    finished: bool,
+}
+
+impl Drop for FrameHMD<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.context.skip_render_hmd(self.pre_render_info) {
+                log::error!("Failed to skip HMD frame: {}", e);
+            }
+        }
+    }
+}
+
+impl Context {
+    pub fn begin_frame_hmd(&mut self) -> Result<FrameHMD> {
+        let pre_render_info = self.pre_render_hmd()?;
+        Ok(FrameHMD {
+            context: self,
+            pre_render_info,
+            finished: false,
+        })
+    }
+}
+
+impl<'a> FrameHMD<'a> {
+    pub fn pre_render_info(&self) -> PreRenderInfoHMD {
+        self.pre_render_info
+    }
+
+    pub fn should_render(&self) -> bool {
+        self.context.should_render(&self.pre_render_info)
+    }
+
+    // The context stays borrowed for the whole frame, these are the parts that are fine
+    // to use while it's going on. Anything else waits until the frame is submitted or dropped.
+
+    pub fn is_focused(&self) -> bool {
+        self.context.is_focused()
+    }
+
+    // synced and located at the predicted display time, in the stage space
+    pub fn input_state(&self) -> Result<State> {
+        let hmd = self.context.hmd.as_ref().ok_or(VrvError::NoHmd)?;
+        hmd.actions.get_state(
+            &hmd.stage,
+            self.pre_render_info.frame_state.predicted_display_time,
+        )
+    }
+
+    // see Actions::pulse
+    pub fn pulse(
+        &self,
+        hand: usize,
+        amplitude: f32,
+        duration: Duration,
+        frequency: f32,
+    ) -> Result<()> {
+        let hmd = self.context.hmd.as_ref().ok_or(VrvError::NoHmd)?;
+        hmd.actions.pulse(hand, amplitude, duration, frequency)
+    }
+
+    // moves the stage before the views are located, so this frame already uses it
+    pub fn apply_locomotion(&mut self, locomotion: &Locomotion) -> Result<()> {
+        self.context.apply_locomotion(locomotion)
+    }
+
+    pub fn next_in_flight(&mut self) -> Result<InFlightHMD> {
+        self.context.next_in_flight_hmd()
+    }
+
+    pub fn views(&self) -> Result<Vec<View>> {
+        self.context
+            .get_views(self.pre_render_info.frame_state.predicted_display_time)
+    }
+
+    pub fn stereo_views(&self) -> Result<[View; 2]> {
+        self.context
+            .get_stereo_views(self.pre_render_info.frame_state.predicted_display_time)
+    }
+
+    pub fn record(
+        &mut self,
+        pipeline_layout: PipelineLayout,
+        pipeline: Pipeline,
+        mesh: &MeshBuffers,
+        descriptor_set: DescriptorSet,
+        command_buffer: CommandBuffer,
+        rendering_finished_fence: Fence,
+    ) -> Result<()> {
+        self.context.record_hmd(
+            self.pre_render_info,
+            pipeline_layout,
+            pipeline,
+            mesh,
+            descriptor_set,
+            command_buffer,
+            rendering_finished_fence,
+        )
+    }
+
+    pub fn record_scene(
+        &mut self,
+        pipeline_layout: PipelineLayout,
+        pipeline: Pipeline,
+        draws: &[DrawCall],
+        command_buffer: CommandBuffer,
+        rendering_finished_fence: Fence,
+    ) -> Result<()> {
+        self.context.record_hmd_scene(
+            self.pre_render_info,
+            pipeline_layout,
+            pipeline,
+            draws,
+            command_buffer,
+            rendering_finished_fence,
+        )
+    }
+
+    pub fn submit(
+        mut self,
+        views: &[View],
+        command_buffer: CommandBuffer,
+        rendering_finished_fence: Fence,
+    ) -> Result<()> {
+        // even if this fails, the next pre_render_hmd cleans up
+        self.finished = true;
+        self.context.submit_hmd(
+            self.pre_render_info,
+            views,
+            command_buffer,
+            rendering_finished_fence,
+        )
+    }
+
+    // shows a plain color instead of a rendered frame, e.g. while loading
+    pub fn clear_and_submit(mut self, color: [f32; 4]) -> Result<()> {
+        // dropping still ends the frame if there is no image
+        let image_index = self
+            .pre_render_info
+            .image_index
+            .ok_or(Error::msg("Shouldn't render, says OpenXR"))?;
+        self.finished = true;
+        self.context.clear_hmd_image(image_index, color)?;
+        let views = self.views()?;
+        self.context.post_render_hmd(self.pre_render_info, &views)
+    }
+
+    pub fn skip(mut self) -> Result<()> {
+        self.finished = true;
+        self.context.skip_render_hmd(self.pre_render_info)
+    }
+}
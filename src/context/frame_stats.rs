@@ -0,0 +1,242 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+#[cfg(feature = "gpu_timestamps")]
+use ash::vk::{QueryPoolCreateInfo, QueryType};
+use ash::{
+    vk::{self, CommandBuffer, PipelineStageFlags, QueryPool, QueryResultFlags},
+    Device,
+};
+use openxr::Time;
+
+use crate::wrap_vulkan;
+
+use super::Context;
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Timings of the last submitted HMD frame, in milliseconds
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameStats {
+    // between the predicted display times of this and the previous frame,
+    // zero for the first frame
+    pub display_period_ms: f64,
+    // pre_render_hmd, mostly blocked on the runtime's frame pacing
+    pub wait_ms: f64,
+    pub record_ms: f64,
+    // the queue submission and ending the OpenXR frame
+    pub submit_ms: f64,
+    // From timestamps around the HMD render pass (and the passes after it).
+    // The GPU runs behind, so this belongs to an earlier frame, one per frame in flight.
+    // None without the gpu_timestamps feature or if the queue has no timestamps.
+    pub gpu_ms: Option<f64>,
+}
+
+// Only the low valid_bits of a timestamp count, the counter wraps around at them.
+fn timestamp_delta_ms(start: u64, end: u64, valid_bits: u32, period: f32) -> f64 {
+    let mask = if valid_bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << valid_bits) - 1
+    };
+    (end.wrapping_sub(start) & mask) as f64 * period as f64 / 1_000_000.0
+}
+
+// two timestamps per frame in flight, read back before the slot is written again
+struct GpuTimer {
+    pool: QueryPool,
+    // nanoseconds per tick
+    period: f32,
+    // of the queue's timestamps
+    valid_bits: u32,
+    written: Vec<bool>,
+    next: usize,
+    device: Device,
+}
+
+impl Drop for GpuTimer {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_query_pool(self.pool, None) };
+    }
+}
+
+impl GpuTimer {
+    #[cfg(feature = "gpu_timestamps")]
+    fn new(context: &wrap_vulkan::Context, slots: usize) -> Result<Option<Self>> {
+        let period = unsafe {
+            context
+                .instance
+                .get_physical_device_properties(context.physical_device)
+        }
+        .limits
+        .timestamp_period;
+        let valid_bits = unsafe {
+            context
+                .instance
+                .get_physical_device_queue_family_properties(context.physical_device)
+        }[context.queue_family_index as usize]
+            .timestamp_valid_bits;
+        if period == 0.0 || valid_bits == 0 {
+            log::info!("Queue doesn't support timestamps, no GPU frame times");
+            return Ok(None);
+        }
+
+        let pool = unsafe {
+            context.device.create_query_pool(
+                &QueryPoolCreateInfo::builder()
+                    .query_type(QueryType::TIMESTAMP)
+                    .query_count(2 * slots as u32),
+                None,
+            )
+        }?;
+        context.name_object(pool, "HMDTimestamps".to_string())?;
+
+        Ok(Some(Self {
+            pool,
+            period,
+            valid_bits,
+            written: vec![false; slots],
+            next: 0,
+            device: context.device.clone(),
+        }))
+    }
+
+    #[cfg(not(feature = "gpu_timestamps"))]
+    fn new(_context: &wrap_vulkan::Context, _slots: usize) -> Result<Option<Self>> {
+        Ok(None)
+    }
+
+    // None if the slot was never written or the GPU isn't done with it yet
+    fn read(&self, slot: usize) -> Result<Option<f64>> {
+        if !self.written[slot] {
+            return Ok(None);
+        }
+        let mut ticks = [0u64; 2];
+        let read = unsafe {
+            self.device.get_query_pool_results(
+                self.pool,
+                2 * slot as u32,
+                2,
+                &mut ticks,
+                QueryResultFlags::TYPE_64,
+            )
+        };
+        match read {
+            Ok(()) => Ok(Some(timestamp_delta_ms(
+                ticks[0],
+                ticks[1],
+                self.valid_bits,
+                self.period,
+            ))),
+            Err(vk::Result::NOT_READY) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // outside of a render pass, returns the slot for cmd_end
+    unsafe fn cmd_begin(&mut self, command_buffer: CommandBuffer) -> usize {
+        let slot = self.next;
+        self.next = (self.next + 1) % self.written.len();
+        self.device
+            .cmd_reset_query_pool(command_buffer, self.pool, 2 * slot as u32, 2);
+        self.device.cmd_write_timestamp(
+            command_buffer,
+            PipelineStageFlags::TOP_OF_PIPE,
+            self.pool,
+            2 * slot as u32,
+        );
+        slot
+    }
+
+    unsafe fn cmd_end(&mut self, command_buffer: CommandBuffer, slot: usize) {
+        self.device.cmd_write_timestamp(
+            command_buffer,
+            PipelineStageFlags::BOTTOM_OF_PIPE,
+            self.pool,
+            2 * slot as u32 + 1,
+        );
+        self.written[slot] = true;
+    }
+}
+
+// filled in piece by piece during a frame, the finished frame is kept separately
+pub(super) struct FrameStatsCollector {
+    current: FrameStats,
+    last: FrameStats,
+    last_display_time: Option<Time>,
+    gpu: Option<GpuTimer>,
+}
+
+impl FrameStatsCollector {
+    pub fn new(context: &wrap_vulkan::Context, frames_in_flight: usize) -> Result<Self> {
+        Ok(Self {
+            current: FrameStats::default(),
+            last: FrameStats::default(),
+            last_display_time: None,
+            gpu: GpuTimer::new(context, frames_in_flight)?,
+        })
+    }
+
+    pub fn waited(&mut self, started: Instant, predicted_display_time: Time) {
+        self.current.wait_ms = ms(started.elapsed());
+        self.current.display_period_ms = match self.last_display_time {
+            Some(last) => (predicted_display_time.as_nanos() - last.as_nanos()) as f64 / 1e6,
+            None => 0.0,
+        };
+        self.last_display_time = Some(predicted_display_time);
+    }
+
+    pub fn recorded(&mut self, started: Instant) {
+        self.current.record_ms = ms(started.elapsed());
+    }
+
+    pub fn submitted(&mut self, started: Instant) {
+        self.current.submit_ms = ms(started.elapsed());
+        self.last = self.current;
+    }
+
+    // The slot is read back before it is reset, so the GPU time lags behind.
+    // Returns the slot to pass to cmd_end_gpu, None if there are no timestamps.
+    pub unsafe fn cmd_begin_gpu(&mut self, command_buffer: CommandBuffer) -> Result<Option<usize>> {
+        let gpu = match &mut self.gpu {
+            Some(gpu) => gpu,
+            None => return Ok(None),
+        };
+        if let Some(gpu_ms) = gpu.read(gpu.next)? {
+            self.current.gpu_ms = Some(gpu_ms);
+        }
+        Ok(Some(gpu.cmd_begin(command_buffer)))
+    }
+
+    pub unsafe fn cmd_end_gpu(&mut self, command_buffer: CommandBuffer, slot: Option<usize>) {
+        if let (Some(gpu), Some(slot)) = (&mut self.gpu, slot) {
+            gpu.cmd_end(command_buffer, slot);
+        }
+    }
+}
+
+impl Context {
+    // Zeroed until the first HMD frame was submitted, skipped frames don't count
+    pub fn last_frame_stats(&self) -> FrameStats {
+        self.hmd
+            .as_ref()
+            .map_or(FrameStats::default(), |hmd| hmd.frame_stats.last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_wrap_at_the_valid_bits() {
+        // one tick per microsecond
+        assert_eq!(timestamp_delta_ms(1_000, 3_000, 64, 1000.0), 2.0);
+        // a 36 bit counter that wrapped between the two, the upper bits are garbage
+        let start = (1 << 36) - 500 + (0xabc << 36);
+        let end = 1_500 + (0x123 << 36);
+        assert_eq!(timestamp_delta_ms(start, end, 36, 1000.0), 2.0);
+    }
+}
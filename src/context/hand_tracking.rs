@@ -0,0 +1,83 @@
+use anyhow::Result;
+use openxr::{Hand, HandTracker, Posef, Session, Time, Vulkan, HAND_JOINT_COUNT};
+
+use crate::wrap_openxr;
+
+use super::Context;
+
+// one tracker per hand, only exists if the runtime and the system support XR_EXT_hand_tracking
+pub struct HandTrackers {
+    trackers: [HandTracker; 2],
+}
+
+impl HandTrackers {
+    pub fn new(openxr: &wrap_openxr::Context, session: &Session<Vulkan>) -> Result<Option<Self>> {
+        if !openxr.enabled_extensions().ext_hand_tracking {
+            log::info!("XR_EXT_hand_tracking not available, no hand tracking");
+            return Ok(None);
+        }
+        if !openxr.instance.supports_hand_tracking(openxr.system_id)? {
+            log::info!("System doesn't support hand tracking");
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            trackers: [
+                session.create_hand_tracker(Hand::LEFT)?,
+                session.create_hand_tracker(Hand::RIGHT)?,
+            ],
+        }))
+    }
+
+    fn get(&self, hand: Hand) -> &HandTracker {
+        if hand == Hand::LEFT {
+            &self.trackers[0]
+        } else {
+            &self.trackers[1]
+        }
+    }
+}
+
+impl Context {
+    pub fn hand_tracking_supported(&self) -> bool {
+        self.hmd
+            .as_ref()
+            .map_or(false, |hmd| hmd.hand_trackers.is_some())
+    }
+
+    // Joints in the order of openxr::HandJoint, relative to the active reference space.
+    // None if the hand isn't tracked right now or hand tracking isn't supported.
+    pub fn locate_hand_joints(
+        &self,
+        hand: Hand,
+        time: Time,
+    ) -> Result<Option<[Posef; HAND_JOINT_COUNT]>> {
+        Ok(self
+            .locate_hand_joints_with_radii(hand, time)?
+            .map(|(poses, _)| poses))
+    }
+
+    // the radii are in meters, e.g. for spheres at the joints or touch detection
+    pub fn locate_hand_joints_with_radii(
+        &self,
+        hand: Hand,
+        time: Time,
+    ) -> Result<Option<([Posef; HAND_JOINT_COUNT], [f32; HAND_JOINT_COUNT])>> {
+        let hmd = match &self.hmd {
+            Some(hmd) => hmd,
+            None => return Ok(None),
+        };
+        let trackers = match &hmd.hand_trackers {
+            Some(trackers) => trackers,
+            None => return Ok(None),
+        };
+        Ok(hmd
+            .stage
+            .locate_hand_joints(trackers.get(hand), time)?
+            .map(|locations| {
+                (
+                    locations.map(|location| location.pose),
+                    locations.map(|location| location.radius),
+                )
+            }))
+    }
+}
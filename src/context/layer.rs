@@ -0,0 +1,31 @@
+use openxr::CompositionLayerFlags;
+
+// How the compositor treats the alpha channel of a submitted layer.
+// Premultiplied expects the fragment shader to output (rgb * a, a), which avoids dark fringes
+// where transparent UI blends over the scene. Unpremultiplied takes plain (rgb, a).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LayerAlpha {
+    // alpha is ignored, the layer covers everything below it
+    Opaque,
+    Premultiplied,
+    Unpremultiplied,
+}
+
+impl Default for LayerAlpha {
+    fn default() -> Self {
+        Self::Opaque
+    }
+}
+
+impl LayerAlpha {
+    pub fn flags(self) -> CompositionLayerFlags {
+        match self {
+            Self::Opaque => CompositionLayerFlags::EMPTY,
+            Self::Premultiplied => CompositionLayerFlags::BLEND_TEXTURE_SOURCE_ALPHA,
+            Self::Unpremultiplied => {
+                CompositionLayerFlags::BLEND_TEXTURE_SOURCE_ALPHA
+                    | CompositionLayerFlags::UNPREMULTIPLIED_ALPHA
+            }
+        }
+    }
+}
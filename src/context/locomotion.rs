@@ -0,0 +1,306 @@
+use anyhow::{bail, Result};
+use openxr::{Posef, Quaternionf, ReferenceSpaceType, SpaceLocationFlags, Time, Vector3f};
+
+use crate::VrvError;
+
+use super::{
+    actions::{apply_deadzone, State},
+    Context,
+};
+
+// positive angles turn left, counter-clockwise around +Y
+fn yaw_quaternion(angle: f32) -> Quaternionf {
+    Quaternionf {
+        x: 0.0,
+        y: (angle / 2.0).sin(),
+        z: 0.0,
+        w: (angle / 2.0).cos(),
+    }
+}
+
+fn rotate_yaw(v: Vector3f, angle: f32) -> Vector3f {
+    let (sin, cos) = angle.sin_cos();
+    Vector3f {
+        x: v.x * cos + v.z * sin,
+        y: v.y,
+        z: -v.x * sin + v.z * cos,
+    }
+}
+
+// yaw of the orientation, i.e. which way -Z points when projected onto the floor
+fn heading(orientation: Quaternionf) -> f32 {
+    let Quaternionf { x, y, z, w } = orientation;
+    // -Z rotated by the quaternion, only x and z are needed
+    let forward_x = -2.0 * (x * z + w * y);
+    let forward_z = -(1.0 - 2.0 * (x * x + y * y));
+    (-forward_x).atan2(-forward_z)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum TurnMode {
+    // turns by angle (radians) once the stick passes threshold, recentering the stick re-arms it
+    Snap { angle: f32, threshold: f32 },
+    // radians per second at full deflection
+    Smooth { speed: f32 },
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct LocomotionSettings {
+    pub move_speed: f32, // meters per second at full deflection
+    pub turn: TurnMode,
+    pub deadzone: f32,
+}
+
+impl Default for LocomotionSettings {
+    fn default() -> Self {
+        Self {
+            move_speed: 2.0,
+            turn: TurnMode::Snap {
+                angle: std::f32::consts::FRAC_PI_6,
+                threshold: 0.7,
+            },
+            deadzone: 0.15,
+        }
+    }
+}
+
+// Left stick moves relative to where the head is looking, right stick x turns around the head.
+// Tracks where the tracked stage sits in the world, apply it with Context::apply_locomotion.
+pub struct Locomotion {
+    pub settings: LocomotionSettings,
+    // the stage origin in world space, rotated around Y by yaw
+    position: Vector3f,
+    yaw: f32,
+    snap_armed: bool,
+}
+
+impl Locomotion {
+    pub fn new(settings: LocomotionSettings) -> Self {
+        Self {
+            settings,
+            position: Vector3f::default(),
+            yaw: 0.0,
+            snap_armed: true,
+        }
+    }
+
+    // head is located in the current (already offset) stage, i.e. in world space
+    // returns whether anything moved
+    pub fn update(&mut self, state: &State, head: Posef, delta_seconds: f32) -> bool {
+        // only fails for hands that don't exist
+        let [move_x, move_y] = state.stick(0, self.settings.deadzone).unwrap_or_default();
+        let (turn_x, _) = apply_deadzone(
+            state.pad_or_stick_position_x[1].current_state,
+            0.0,
+            self.settings.deadzone,
+        );
+
+        let mut moved = false;
+
+        if move_x != 0.0 || move_y != 0.0 {
+            // stick up is forward (-Z), stick right is +X, both turned to the head's heading
+            let step = rotate_yaw(
+                Vector3f {
+                    x: move_x,
+                    y: 0.0,
+                    z: -move_y,
+                },
+                heading(head.orientation),
+            );
+            let distance = self.settings.move_speed * delta_seconds;
+            self.position.x += step.x * distance;
+            self.position.z += step.z * distance;
+            moved = true;
+        }
+
+        // stick right turns right, which is clockwise
+        let turn = match self.settings.turn {
+            TurnMode::Snap { angle, threshold } => {
+                if turn_x.abs() < threshold / 2.0 {
+                    self.snap_armed = true;
+                    0.0
+                } else if self.snap_armed && turn_x.abs() >= threshold {
+                    self.snap_armed = false;
+                    -angle * turn_x.signum()
+                } else {
+                    0.0
+                }
+            }
+            TurnMode::Smooth { speed } => -turn_x * speed * delta_seconds,
+        };
+        if turn != 0.0 {
+            self.turn_around(head.position, turn);
+            moved = true;
+        }
+
+        moved
+    }
+
+    // rotates the stage around a vertical axis through pivot (world space)
+    pub fn turn_around(&mut self, pivot: Vector3f, angle: f32) {
+        let relative = Vector3f {
+            x: self.position.x - pivot.x,
+            y: 0.0,
+            z: self.position.z - pivot.z,
+        };
+        let rotated = rotate_yaw(relative, angle);
+        self.position.x = pivot.x + rotated.x;
+        self.position.z = pivot.z + rotated.z;
+        self.yaw += angle;
+    }
+
+    pub fn reset(&mut self) {
+        self.position = Vector3f::default();
+        self.yaw = 0.0;
+    }
+
+    // where the world origin is within the tracked stage, the inverse of the stage in the world
+    pub fn origin(&self) -> Posef {
+        let inverse_position = rotate_yaw(self.position, -self.yaw);
+        Posef {
+            orientation: yaw_quaternion(-self.yaw),
+            position: Vector3f {
+                x: -inverse_position.x,
+                y: -inverse_position.y,
+                z: -inverse_position.z,
+            },
+        }
+    }
+}
+
+impl Context {
+    pub fn apply_locomotion(&mut self, locomotion: &Locomotion) -> Result<()> {
+        self.set_stage_origin(locomotion.origin())
+    }
+
+    // For a "recenter view" button: where the head is and which way it faces become the origin.
+    // Only the yaw is cancelled so the horizon stays level, the height is kept as well.
+    // The result is the stage origin, to persist it store get_stage_origin
+    // and restore it with set_stage_origin. Replaces any origin set before,
+    // applying a Locomotion afterwards replaces this one.
+    pub fn recenter(&mut self, display_time: Time) -> Result<()> {
+        let session = &self.hmd.as_ref().ok_or(VrvError::NoHmd)?.session;
+        let untransformed =
+            session.create_reference_space(self.get_reference_space(), Posef::IDENTITY)?;
+        let view = session.create_reference_space(ReferenceSpaceType::VIEW, Posef::IDENTITY)?;
+        let location = view.locate(&untransformed, display_time)?;
+        if !location
+            .location_flags
+            .contains(SpaceLocationFlags::POSITION_VALID | SpaceLocationFlags::ORIENTATION_VALID)
+        {
+            bail!("Can't recenter while the head isn't tracked");
+        }
+
+        let head = location.pose;
+        self.set_stage_origin(Posef {
+            orientation: yaw_quaternion(heading(head.orientation)),
+            position: Vector3f {
+                x: head.position.x,
+                y: 0.0,
+                z: head.position.z,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    const EPSILON: f32 = 1e-5;
+
+    fn head(yaw: f32) -> Posef {
+        Posef {
+            orientation: yaw_quaternion(yaw),
+            position: Vector3f::default(),
+        }
+    }
+
+    fn smooth() -> LocomotionSettings {
+        LocomotionSettings {
+            move_speed: 2.0,
+            turn: TurnMode::Smooth { speed: 1.0 },
+            deadzone: 0.0,
+        }
+    }
+
+    #[test]
+    fn heading_of_a_yaw_rotation_is_the_yaw() {
+        for yaw in [0.0, 0.5, FRAC_PI_2, -2.0] {
+            assert!((heading(yaw_quaternion(yaw)) - yaw).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn stick_up_moves_where_the_head_looks() {
+        let mut locomotion = Locomotion::new(smooth());
+        let forward = State::with_sticks([[0.0, 1.0], [0.0, 0.0]]);
+        assert!(locomotion.update(&forward, head(0.0), 0.5));
+        assert!(locomotion.position.x.abs() < EPSILON);
+        assert!((locomotion.position.z + 1.0).abs() < EPSILON);
+
+        // turned left, forward is -X
+        locomotion.reset();
+        assert!(locomotion.update(&forward, head(FRAC_PI_2), 0.5));
+        assert!((locomotion.position.x + 1.0).abs() < EPSILON);
+        assert!(locomotion.position.z.abs() < EPSILON);
+
+        // the world moves the other way within the stage
+        let origin = locomotion.origin();
+        let back = rotate_yaw(origin.position, locomotion.yaw);
+        assert!((back.x - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn centered_sticks_do_nothing() {
+        let mut locomotion = Locomotion::new(LocomotionSettings::default());
+        let idle = State::with_sticks([[0.1, 0.0], [0.0, -0.1]]);
+        assert!(!locomotion.update(&idle, head(0.0), 1.0));
+        assert_eq!(locomotion.yaw, 0.0);
+    }
+
+    #[test]
+    fn snap_turn_needs_recentering() {
+        let angle = 0.5;
+        let mut locomotion = Locomotion::new(LocomotionSettings {
+            turn: TurnMode::Snap {
+                angle,
+                threshold: 0.7,
+            },
+            deadzone: 0.0,
+            ..Default::default()
+        });
+        let right = State::with_sticks([[0.0, 0.0], [1.0, 0.0]]);
+        let centered = State::with_sticks([[0.0, 0.0], [0.0, 0.0]]);
+
+        assert!(locomotion.update(&right, head(0.0), 0.1));
+        assert!((locomotion.yaw + angle).abs() < EPSILON);
+        // held, no second turn
+        assert!(!locomotion.update(&right, head(0.0), 0.1));
+        assert!(!locomotion.update(&centered, head(0.0), 0.1));
+        assert!(locomotion.update(&right, head(0.0), 0.1));
+        assert!((locomotion.yaw + 2.0 * angle).abs() < EPSILON);
+    }
+
+    #[test]
+    fn smooth_turn_keeps_the_head_in_place() {
+        let mut locomotion = Locomotion::new(smooth());
+        let pivot = Vector3f {
+            x: 1.0,
+            y: 1.7,
+            z: 0.0,
+        };
+        let right = State::with_sticks([[0.0, 0.0], [1.0, 0.0]]);
+        let head = Posef {
+            orientation: yaw_quaternion(0.0),
+            position: pivot,
+        };
+        assert!(locomotion.update(&right, head, FRAC_PI_2));
+        assert!((locomotion.yaw + FRAC_PI_2).abs() < EPSILON);
+        // the stage origin went around the head, a quarter turn clockwise seen from above
+        assert!((locomotion.position.x - 1.0).abs() < EPSILON);
+        assert!((locomotion.position.z + 1.0).abs() < EPSILON);
+    }
+}
@@ -0,0 +1,296 @@
+use anyhow::{bail, Result};
+use ash::{
+    vk::{
+        AccessFlags, ClearColorValue, CommandBuffer, DependencyFlags, Extent2D, Extent3D, Filter,
+        FormatFeatureFlags, Image, ImageAspectFlags, ImageCopy, ImageLayout, ImageMemoryBarrier,
+        ImageSubresourceLayers, ImageSubresourceRange, ImageTiling, ImageUsageFlags,
+        MemoryPropertyFlags, PipelineStageFlags, SampleCountFlags, QUEUE_FAMILY_IGNORED,
+    },
+    Device,
+};
+
+use crate::{
+    wrap_vulkan::{self, device_image::DeviceImageSettings, DeviceImage},
+    VrvError,
+};
+
+use super::{size_dependent::blit_filter, swapchain::SwapchainHMD, Context};
+
+// What the window shows, see Context::set_window_mirror.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MirrorSource {
+    // the window's own scene, rendered with the pipeline passed to render_window
+    #[default]
+    Desktop,
+    LeftEye,
+    RightEye,
+}
+
+impl MirrorSource {
+    // the array layer of the HMD swapchain, None for Desktop
+    pub fn layer(self) -> Option<u32> {
+        match self {
+            Self::Desktop => None,
+            Self::LeftEye => Some(0),
+            Self::RightEye => Some(1),
+        }
+    }
+}
+
+fn color_barrier(
+    image: Image,
+    layer: u32,
+    old_layout: ImageLayout,
+    new_layout: ImageLayout,
+    src_access_mask: AccessFlags,
+    dst_access_mask: AccessFlags,
+) -> ImageMemoryBarrier {
+    ImageMemoryBarrier::builder()
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_queue_family_index(QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(
+            ImageSubresourceRange::builder()
+                .aspect_mask(ImageAspectFlags::COLOR)
+                .base_array_layer(layer)
+                .level_count(1)
+                .layer_count(1)
+                .build(),
+        )
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .build()
+}
+
+// One eye of the last HMD frame for the window. The swapchain images belong to the runtime
+// once released, so the eye is copied at the end of the HMD command buffer and the window
+// blits from the copy, scaled to its extent. The copy stays in TRANSFER_SRC_OPTIMAL.
+pub struct MirrorHMD {
+    pub source: MirrorSource,
+    pub image: DeviceImage,
+    pub extent: Extent2D,
+    // for the window's blit, see blit_filter
+    pub filter: Filter,
+    layer: u32,
+    device: Device,
+}
+
+impl MirrorHMD {
+    pub fn new(
+        context: &wrap_vulkan::Context,
+        swapchain: &SwapchainHMD,
+        source: MirrorSource,
+    ) -> Result<Self> {
+        let layer = match source.layer() {
+            Some(layer) if layer < swapchain.view_count => layer,
+            Some(layer) => bail!(
+                "Can't mirror layer {} of an HMD swapchain with {} views",
+                layer,
+                swapchain.view_count
+            ),
+            None => bail!("Mirroring the desktop view doesn't need an HMD image"),
+        };
+        let blit_src = unsafe {
+            context
+                .instance
+                .get_physical_device_format_properties(context.physical_device, swapchain.format)
+        }
+        .optimal_tiling_features
+        .contains(FormatFeatureFlags::BLIT_SRC);
+        if !blit_src {
+            bail!("Can't blit from {:?} to mirror the HMD", swapchain.format);
+        }
+        let extent = swapchain.extent;
+
+        let image = DeviceImage::new(
+            context,
+            DeviceImageSettings {
+                extent,
+                format: swapchain.format,
+                tiling: ImageTiling::OPTIMAL,
+                usage: ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::TRANSFER_SRC,
+                properties: MemoryPropertyFlags::DEVICE_LOCAL,
+                aspect_flags: ImageAspectFlags::COLOR,
+                layer_count: 1,
+                view_type: None,
+                mip_levels: 1,
+                samples: SampleCountFlags::TYPE_1,
+                name: "HMDMirror".to_string(),
+            },
+        )?;
+
+        // black until the first HMD frame, the window may blit from it before that
+        let d = &context.device;
+        context.one_shot("HMDMirrorClear", |command_buffer| unsafe {
+            d.cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TOP_OF_PIPE,
+                PipelineStageFlags::TRANSFER,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[color_barrier(
+                    image.image,
+                    0,
+                    ImageLayout::UNDEFINED,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    AccessFlags::empty(),
+                    AccessFlags::TRANSFER_WRITE,
+                )],
+            );
+            d.cmd_clear_color_image(
+                command_buffer,
+                image.image,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                },
+                &[ImageSubresourceRange::builder()
+                    .aspect_mask(ImageAspectFlags::COLOR)
+                    .level_count(1)
+                    .layer_count(1)
+                    .build()],
+            );
+            d.cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::TRANSFER,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[color_barrier(
+                    image.image,
+                    0,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    AccessFlags::TRANSFER_WRITE,
+                    AccessFlags::TRANSFER_READ,
+                )],
+            );
+        })?;
+
+        Ok(Self {
+            source,
+            image,
+            extent,
+            filter: blit_filter(context, swapchain.format),
+            layer,
+            device: context.device.clone(),
+        })
+    }
+
+    // After everything that writes the acquired image. The HMD render passes (and the post pass)
+    // leave it in TRANSFER_SRC_OPTIMAL, so it's copied from that layout and stays in it.
+    pub unsafe fn record_copy(&self, command_buffer: CommandBuffer, src: Image) {
+        let d = &self.device;
+
+        d.cmd_pipeline_barrier(
+            command_buffer,
+            // the previous window blit reads the copy in TRANSFER
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::TRANSFER,
+            DependencyFlags::empty(),
+            &[],
+            &[],
+            &[
+                // no layout change, only makes the pass's writes visible to the copy
+                color_barrier(
+                    src,
+                    self.layer,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    AccessFlags::COLOR_ATTACHMENT_WRITE,
+                    AccessFlags::TRANSFER_READ,
+                ),
+                color_barrier(
+                    self.image.image,
+                    0,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    AccessFlags::empty(),
+                    AccessFlags::TRANSFER_WRITE,
+                ),
+            ],
+        );
+        d.cmd_copy_image(
+            command_buffer,
+            src,
+            ImageLayout::TRANSFER_SRC_OPTIMAL,
+            self.image.image,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[ImageCopy::builder()
+                .src_subresource(
+                    ImageSubresourceLayers::builder()
+                        .aspect_mask(ImageAspectFlags::COLOR)
+                        .base_array_layer(self.layer)
+                        .layer_count(1)
+                        .build(),
+                )
+                .dst_subresource(
+                    ImageSubresourceLayers::builder()
+                        .aspect_mask(ImageAspectFlags::COLOR)
+                        .layer_count(1)
+                        .build(),
+                )
+                .extent(Extent3D {
+                    width: self.extent.width,
+                    height: self.extent.height,
+                    depth: 1,
+                })
+                .build()],
+        );
+        d.cmd_pipeline_barrier(
+            command_buffer,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::BOTTOM_OF_PIPE,
+            DependencyFlags::empty(),
+            &[],
+            &[],
+            // made visible to the window by the semaphore it waits on
+            &[color_barrier(
+                self.image.image,
+                0,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+                AccessFlags::TRANSFER_WRITE,
+                AccessFlags::empty(),
+            )],
+        );
+    }
+}
+
+impl Context {
+    pub fn get_window_mirror(&self) -> MirrorSource {
+        self.hmd
+            .as_ref()
+            .and_then(|hmd| hmd.mirror.as_ref())
+            .map_or(MirrorSource::Desktop, |mirror| mirror.source)
+    }
+
+    // Shows one eye of the HMD in the window instead of the window's own scene.
+    // render_window then ignores the pipeline and draws, and the window waits for the HMD.
+    // Desktop goes back to rendering the window normally.
+    pub fn set_window_mirror(&mut self, source: MirrorSource) -> Result<()> {
+        let hmd = match (&mut self.hmd, source) {
+            (Some(hmd), _) => hmd,
+            (None, MirrorSource::Desktop) => return Ok(()),
+            (None, _) => return Err(VrvError::NoHmd.into()),
+        };
+        if hmd.mirror.as_ref().map(|mirror| mirror.source) == Some(source) {
+            return Ok(());
+        }
+        // recorded command buffers might still copy into it
+        self.vulkan.wait_idle()?;
+        hmd.mirror = None;
+        if source == MirrorSource::Desktop {
+            return Ok(());
+        }
+        if !self.window.size_dependent.can_blit {
+            bail!("The window surface doesn't support blitting, can't mirror the HMD");
+        }
+        hmd.mirror = Some(MirrorHMD::new(&self.vulkan, &hmd.swapchain, source)?);
+        Ok(())
+    }
+}
@@ -1,41 +1,117 @@
 pub mod actions;
+pub mod depth_layer;
+pub mod events;
+pub mod frame_hmd;
+pub mod frame_stats;
+#[cfg(feature = "hand_tracking")]
+pub mod hand_tracking;
+pub mod layer;
+pub mod locomotion;
+pub mod mirror;
+pub mod post_hmd;
+pub mod quad_layer;
 pub mod render_hmd;
 pub mod render_window;
+pub mod size_dependent;
 pub mod swapchain;
+pub mod texture;
 
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
 use ash::{
-    vk::{Extent2D, RenderPass, Semaphore, SwapchainKHR},
+    vk::{self, CommandBuffer, Extent2D, Fence, RenderPass, Semaphore, SwapchainKHR},
     Device,
 };
 
 use openxr::{
-    FrameState, FrameStream, FrameWaiter, Posef, ReferenceSpaceType, Session, Space, Time, View,
-    ViewConfigurationType, Vulkan,
+    Color4f, EnvironmentBlendMode, EventDataBuffer, FrameState, FrameStream, FrameWaiter, Posef,
+    ReferenceSpaceType, Session, SessionState, Space, Time, Vector3f, View, ViewConfigurationType,
+    Vulkan,
 };
 use winit::window::Window;
 
 use crate::{
     wrap_openxr,
     wrap_vulkan::{
-        self, create_render_pass_window, render_pass::create_render_pass_hmd,
-        sync::create_semaphore,
+        self, create_render_pass_window, create_render_pass_window_supersampled,
+        render_pass::create_render_pass_hmd,
+        sync::{create_fence, create_semaphore, create_timeline_semaphore},
+        OwnedCommandBuffers,
     },
+    VrvError,
 };
-use swapchain::{SwapchainHMD, SwapchainWindow};
+use depth_layer::DepthLayerHMD;
+use frame_stats::FrameStatsCollector;
+use layer::LayerAlpha;
+use mirror::MirrorHMD;
+use post_hmd::{PostHMD, PostSettingsHMD};
+use quad_layer::QuadLayer;
+use size_dependent::{SizeDependent, SizeDependentSettings};
+use swapchain::{PresentModePreference, SwapchainHMD};
 
-use self::actions::{Actions, State};
+use self::actions::Actions;
+
+// a few frames worth, more is never useful for measuring anything
+pub const MAX_PREDICTED_TIME_OFFSET_NANOS: i64 = 50_000_000;
 
 pub struct ContextHMD {
     pub session: Session<Vulkan>,
     frame_wait: FrameWaiter,
     frame_stream: FrameStream<Vulkan>,
+    // the active reference space, STAGE unless changed with set_reference_space
     pub stage: Space,
+    reference_space_type: ReferenceSpaceType,
+    floor_offset: f32,
+    // where the world origin is placed within the tracked stage
+    stage_origin: Posef,
+    // (scale, bias) applied to the projection layer, needs the extension
+    layer_color_scale_bias: Option<(Color4f, Color4f)>,
+    projection_layer_alpha: LayerAlpha,
+    // the scene render pass starts with it, see Context::set_clear_color
+    clear_color: [f32; 4],
+    // how the projection layer is blended with the real world
+    blend_mode: EnvironmentBlendMode,
+    // which views are located and submitted, one swapchain array layer each
+    view_configuration: ViewConfigurationType,
+    // added to the predicted display time, zero unless experimenting
+    predicted_time_offset: openxr::Duration,
+    // times the recommended resolution, see set_resolution_scale
+    resolution_scale: f32,
+    session_state: SessionState,
+    // begun and not yet ended, driven by poll_events
+    session_running: bool,
+    should_exit: bool,
+    event_storage: EventDataBuffer,
 
     pub actions: Actions,
 
     pub render_pass: RenderPass,
+    // optional FXAA/sharpen pass, goes before the swapchain it renders to
+    post: Option<PostHMD>,
     pub swapchain: SwapchainHMD,
+    // HUD panel composited after the projection layer
+    quad_layer: Option<QuadLayer>,
+    // depth submitted alongside the projection layer, needs the extension
+    depth_layer: Option<DepthLayerHMD>,
+    // copy of one eye for the window, see set_window_mirror
+    mirror: Option<MirrorHMD>,
+    // None if the runtime or system lacks hand tracking
+    #[cfg(feature = "hand_tracking")]
+    hand_trackers: Option<hand_tracking::HandTrackers>,
+
+    // CPU side frame pacing, independent of how many images the runtime gives us
+    last_used_in_flight: usize,
+    in_flight: Vec<InFlightHMD>,
+    frame_stats: FrameStatsCollector,
+    // the in flight entries only hold copies of the handles
+    pub command_buffers: OwnedCommandBuffers,
+
+    // signaled by an HMD submission and consumed by the next window submission
+    semaphore_window_dependency: Semaphore,
+    window_dependency_pending: bool,
+    // replaces the binary semaphore above if supported, every HMD submission increments it
+    // so the window can simply wait for the latest value without any bookkeeping
+    timeline: Option<Semaphore>,
+    timeline_value: u64,
 
     device: Device,
 }
@@ -43,12 +119,41 @@ pub struct ContextHMD {
 impl Drop for ContextHMD {
     fn drop(&mut self) {
         unsafe {
+            for in_flight in &self.in_flight {
+                self.device.destroy_fence(in_flight.fence, None);
+            }
+            self.device
+                .destroy_semaphore(self.semaphore_window_dependency, None);
+            if let Some(timeline) = self.timeline {
+                self.device.destroy_semaphore(timeline, None);
+            }
             self.device.destroy_render_pass(self.render_pass, None);
             // rest implements drop
         }
     }
 }
 
+// the origin of the stage is lifted by floor_offset, so positive values raise all content
+// origin moves and turns the world relative to the tracked area, e.g. for locomotion
+fn create_stage(
+    session: &Session<Vulkan>,
+    reference_space_type: ReferenceSpaceType,
+    floor_offset: f32,
+    origin: Posef,
+) -> Result<Space> {
+    Ok(session.create_reference_space(
+        reference_space_type,
+        Posef {
+            orientation: origin.orientation,
+            position: Vector3f {
+                x: origin.position.x,
+                y: origin.position.y + floor_offset,
+                z: origin.position.z,
+            },
+        },
+    )?)
+}
+
 pub struct ContextWindow {
     // the acquiring semaphores are used round-robin
     // because we need to supply a semaphore prior to knowing which frame to use
@@ -56,7 +161,21 @@ pub struct ContextWindow {
     semaphores_image_acquired: Vec<Semaphore>,
 
     pub render_pass: RenderPass,
-    pub swapchain: SwapchainWindow,
+    // compatible with render_pass, used when supersampling
+    pub render_pass_supersampled: RenderPass,
+    // both render passes and the window pipelines use it
+    pub samples: vk::SampleCountFlags,
+    pub size_dependent: SizeDependent,
+    size_dependent_settings: SizeDependentSettings,
+
+    // needed as soon as the window shows something rendered for the HMD
+    wait_for_hmd: bool,
+    clear_color: [f32; 4],
+
+    // 0x0 extent, see Context::resize
+    minimized: bool,
+    // suboptimal or out of date swapchain, see RenderOutcome::NeedsResize
+    out_of_date: bool,
 
     device: Device,
 }
@@ -68,23 +187,87 @@ impl Drop for ContextWindow {
                 self.device.destroy_semaphore(*semaphore, None);
             }
             self.device.destroy_render_pass(self.render_pass, None);
+            self.device
+                .destroy_render_pass(self.render_pass_supersampled, None);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ContextSettings {
+    pub openxr: wrap_openxr::OpenXRSettings,
+    pub vulkan: wrap_vulkan::VulkanSettings,
+    // number of command buffers and fences used round-robin for the HMD
+    pub hmd_frames_in_flight: usize,
+    // the HMD render passes use one multiview layer per array layer
+    pub hmd_swapchain: wrap_openxr::SwapchainDesc,
+    // MSAA for the window, clamped to what the device supports, see Context::get_window_samples
+    pub window_samples: vk::SampleCountFlags,
+}
+
+impl Default for ContextSettings {
+    fn default() -> Self {
+        Self {
+            openxr: wrap_openxr::OpenXRSettings::default(),
+            vulkan: wrap_vulkan::VulkanSettings::default(),
+            hmd_frames_in_flight: 2,
+            hmd_swapchain: wrap_openxr::SwapchainDesc::default(),
+            window_samples: vk::SampleCountFlags::TYPE_1,
         }
     }
 }
 
+// Without an HMD (see Context::new_windowed) hmd and openxr are None.
+// The HMD frame functions do nothing then, everything else HMD related fails with VrvError::NoHmd
+// or, for getters, returns the default.
 pub struct Context {
-    pub hmd: ContextHMD,
+    pub hmd: Option<ContextHMD>,
     pub window: ContextWindow,
 
-    pub openxr: wrap_openxr::Context,
+    pub openxr: Option<wrap_openxr::Context>,
     pub vulkan: wrap_vulkan::Context,
+
+    // kept for recreate_device
+    settings: ContextSettings,
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        // Submitted HMD and window frames might still use the fences, semaphores and command
+        // buffers destroyed with the fields. A lost device has nothing left to wait on,
+        // e.g. in recreate_device, waiting would only report the loss again.
+        if self.vulkan.is_device_lost() {
+            return;
+        }
+        if let Err(e) = self.vulkan.wait_idle() {
+            log::error!("Failed to wait for the GPU before tearing down: {}", e);
+        }
+        // the fields drop in declaration order, hmd and window before the device
+    }
+}
+
+// e.g. after a driver reset (TDR), see Context::recreate_device
+pub fn is_device_lost(error: &Error) -> bool {
+    error
+        .chain()
+        .any(|cause| VrvError::from_cause(cause) == Some(VrvError::DeviceLost))
 }
 
 #[derive(Copy, Clone)]
 pub struct PreRenderInfoWindow {
-    pub image_index: u32,
+    // None while the window is minimized, rendering is skipped then
+    pub image_index: Option<u32>,
     pub image_acquired_semaphore: Semaphore,
 }
+// The swapchain image index is for the GPU images handed out by OpenXR,
+// use the index of this for CPU side resources (uniform buffers etc.) instead.
+#[derive(Copy, Clone)]
+pub struct InFlightHMD {
+    pub index: usize,
+    pub command_buffer: CommandBuffer,
+    pub fence: Fence,
+}
+
 #[derive(Copy, Clone)]
 pub struct PreRenderInfoHMD {
     pub image_index: Option<u32>,
@@ -92,51 +275,205 @@ pub struct PreRenderInfoHMD {
 }
 
 impl Context {
+    // A minimized window reports 0x0, which no swapchain can have. Rendering the window
+    // pauses until a resize to a real size arrives, the old swapchain is kept meanwhile.
     pub fn resize(&mut self, window: &Window) -> Result<()> {
+        let wanted = Extent2D {
+            width: window.inner_size().width,
+            height: window.inner_size().height,
+        };
+        if wanted.width == 0 || wanted.height == 0 {
+            if !self.window.minimized {
+                log::info!("Window minimized, pausing window rendering");
+            }
+            self.window.minimized = true;
+            return Ok(());
+        }
+        self.window.minimized = false;
+        self.recreate_size_dependent(wanted)
+    }
+
+    // false while minimized, pre_render_window and render_window do nothing then
+    pub fn is_window_renderable(&self) -> bool {
+        !self.window.minimized
+    }
+
+    // the single place where size dependent resources are replaced
+    fn recreate_size_dependent(&mut self, wanted: Extent2D) -> Result<()> {
         self.vulkan.wait_idle()?;
+        self.window.out_of_date = false;
 
-        self.window.swapchain = SwapchainWindow::new(
+        self.window.size_dependent = SizeDependent::new(
             &self.vulkan,
             self.window.render_pass,
-            Extent2D {
-                width: window.inner_size().width,
-                height: window.inner_size().height,
-            },
-            self.window.swapchain.handle,
+            self.window.render_pass_supersampled,
+            wanted,
+            self.window.size_dependent.swapchain.handle,
+            &self.window.size_dependent_settings,
+            self.window.samples,
         )?;
         Ok(())
     }
 
+    // Checked teardown, waits for the GPU before anything is destroyed.
+    // Plain dropping does the same destruction but can't report errors.
+    pub fn destroy(self) -> Result<()> {
+        self.vulkan.wait_idle()?;
+        // fields drop in declaration order: HMD and window before OpenXR and Vulkan
+        drop(self);
+        Ok(())
+    }
+
     pub fn new(window: &Window) -> Result<Self> {
+        Self::new_with_settings(window, ContextSettings::default())
+    }
+
+    pub fn new_with_settings(window: &Window, settings: ContextSettings) -> Result<Self> {
+        Self::create(window, settings, true).map_err(VrvError::typed)
+    }
+
+    // Only the window, no OpenXR runtime is needed. For CI and windowed development,
+    // the window rendering works as usual and the HMD frame functions do nothing.
+    // get_views fails with VrvError::NoHmd, so check hmd before locating anything.
+    pub fn new_windowed(window: &Window) -> Result<Self> {
+        Self::new_windowed_with_settings(window, ContextSettings::default())
+    }
+
+    // the OpenXR and HMD settings are ignored
+    pub fn new_windowed_with_settings(window: &Window, settings: ContextSettings) -> Result<Self> {
+        Self::create(window, settings, false).map_err(VrvError::typed)
+    }
+
+    fn create(window: &Window, settings: ContextSettings, with_hmd: bool) -> Result<Self> {
+        if with_hmd && settings.hmd_frames_in_flight == 0 {
+            bail!("Need at least one HMD frame in flight");
+        }
+
         log::info!("Creating new VRV state");
 
-        let openxr = wrap_openxr::Context::new()?;
-        let vulkan = wrap_vulkan::Context::new(window, &openxr)?;
+        let openxr = if with_hmd {
+            Some(wrap_openxr::Context::new_with_settings(&settings.openxr)?)
+        } else {
+            log::info!("Creating a windowed context without HMD");
+            None
+        };
+        let vulkan = match &openxr {
+            Some(openxr) => {
+                wrap_vulkan::Context::new_with_settings(window, openxr, &settings.vulkan)?
+            }
+            None => wrap_vulkan::Context::new_without_openxr(window, &settings.vulkan)?,
+        };
 
         // Setup HMD, from this point SteamVR needs to be available
-        let hmd = {
-            let (session, frame_wait, frame_stream) = openxr.init_with_vulkan(&vulkan)?;
-            let stage =
-                session.create_reference_space(ReferenceSpaceType::STAGE, Posef::IDENTITY)?;
-            let actions = Actions::new(&openxr.instance, session.clone())?;
-
-            let render_pass = create_render_pass_hmd(&vulkan)?;
-            let swapchain = SwapchainHMD::new(&openxr, &vulkan, render_pass, &session)?;
-            ContextHMD {
-                frame_wait,
-                frame_stream,
-                render_pass,
-                swapchain,
-                session,
-                stage,
-                actions,
-                device: vulkan.device.clone(),
-            }
+        let hmd = match &openxr {
+            None => None,
+            Some(openxr) => Some({
+                let (session, frame_wait, frame_stream) = openxr.init_with_vulkan(&vulkan)?;
+                let floor_offset = 0.0;
+                let stage_origin = Posef::IDENTITY;
+                let reference_space_type = ReferenceSpaceType::STAGE;
+                let stage =
+                    create_stage(&session, reference_space_type, floor_offset, stage_origin)?;
+                let actions = Actions::new(&openxr.instance, session.clone())?;
+                #[cfg(feature = "hand_tracking")]
+                let hand_trackers = hand_tracking::HandTrackers::new(&openxr, &session)?;
+
+                let render_pass = create_render_pass_hmd(&vulkan, openxr.get_view_count()?)?;
+                let swapchain = SwapchainHMD::new(
+                    &openxr,
+                    &vulkan,
+                    render_pass,
+                    &session,
+                    &settings.hmd_swapchain,
+                    openxr.get_resolution()?,
+                )?;
+                let command_buffers = OwnedCommandBuffers::new(
+                    &vulkan,
+                    settings.hmd_frames_in_flight as u32,
+                    "HMDCommandBuffer".to_string(),
+                )?;
+                let in_flight = command_buffers
+                    .handles()
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .map(|(index, command_buffer)| {
+                        Ok(InFlightHMD {
+                            index,
+                            command_buffer,
+                            fence: create_fence(
+                                &vulkan,
+                                true,
+                                format!("HMDRenderingFinished_{}", index),
+                            )?,
+                        })
+                    })
+                    .collect::<Result<_, Error>>()?;
+                ContextHMD {
+                    frame_wait,
+                    frame_stream,
+                    render_pass,
+                    swapchain,
+                    session,
+                    stage,
+                    reference_space_type,
+                    floor_offset,
+                    stage_origin,
+                    layer_color_scale_bias: None,
+                    projection_layer_alpha: LayerAlpha::default(),
+                    blend_mode: openxr.get_blend_mode(),
+                    view_configuration: openxr.get_view_configuration(),
+                    clear_color: [0.0; 4],
+                    predicted_time_offset: openxr::Duration::from_nanos(0),
+                    resolution_scale: 1.0,
+                    session_state: SessionState::IDLE,
+                    session_running: false,
+                    should_exit: false,
+                    event_storage: EventDataBuffer::new(),
+                    actions,
+                    last_used_in_flight: 0,
+                    in_flight,
+                    frame_stats: FrameStatsCollector::new(&vulkan, settings.hmd_frames_in_flight)?,
+                    command_buffers,
+                    post: None,
+                    quad_layer: None,
+                    depth_layer: None,
+                    mirror: None,
+                    #[cfg(feature = "hand_tracking")]
+                    hand_trackers,
+                    semaphore_window_dependency: create_semaphore(
+                        &vulkan,
+                        "HMDSemaphoreWindowDependency".to_string(),
+                    )?,
+                    window_dependency_pending: false,
+                    timeline: if vulkan.timeline_semaphore_supported {
+                        Some(create_timeline_semaphore(
+                            &vulkan,
+                            0,
+                            "HMDTimeline".to_string(),
+                        )?)
+                    } else {
+                        None
+                    },
+                    timeline_value: 0,
+                    device: vulkan.device.clone(),
+                }
+            }),
         };
 
         let window = {
             let image_count = vulkan.get_image_count()?;
-            let render_pass = create_render_pass_window(&vulkan)?;
+            let samples = vulkan.clamp_sample_count(settings.window_samples);
+            if samples != settings.window_samples {
+                log::warn!(
+                    "Window sample count {:?} not supported, using {:?}",
+                    settings.window_samples,
+                    samples
+                );
+            }
+            let render_pass = create_render_pass_window(&vulkan, samples)?;
+            let render_pass_supersampled =
+                create_render_pass_window_supersampled(&vulkan, samples)?;
             ContextWindow {
                 last_used_acquire_semaphore: 0,
                 semaphores_image_acquired: (0..image_count)
@@ -149,15 +486,25 @@ impl Context {
                     })
                     .collect::<Result<_, Error>>()?,
                 render_pass,
-                swapchain: SwapchainWindow::new(
+                render_pass_supersampled,
+                samples,
+                size_dependent: SizeDependent::new(
                     &vulkan,
                     render_pass,
+                    render_pass_supersampled,
                     Extent2D {
                         width: window.inner_size().width,
                         height: window.inner_size().height,
                     },
                     SwapchainKHR::default(),
+                    &SizeDependentSettings::default(),
+                    samples,
                 )?,
+                size_dependent_settings: SizeDependentSettings::default(),
+                wait_for_hmd: false,
+                clear_color: [0.0; 4],
+                minimized: false,
+                out_of_date: false,
                 device: vulkan.device.clone(),
             }
         };
@@ -168,23 +515,459 @@ impl Context {
 
             hmd,
             window,
+
+            settings,
         })
     }
 
+    // once submitting, presenting or waiting on a fence reported the device as lost
+    pub fn is_device_lost(&self) -> bool {
+        self.vulkan.is_device_lost()
+    }
+
+    // Tears everything down and builds it again with the same settings, for when
+    // is_device_lost says submitting or presenting failed because the device is gone.
+    // None of the old Vulkan handles survive: pipelines, buffers, images, descriptor sets
+    // and command buffers have to be recreated by the caller, and so do OpenXR action states.
+    // Preserved are the settings made through this context: reference space, floor offset,
+    // stage origin, window supersampling, window waiting for the HMD, the clear color,
+    // the layer color scale/bias and alpha, the predicted time offset, the resolution scale,
+    // the HMD post pass, the depth layer, the window mirror and the quad layer settings.
+    // The quad layer isn't shown again until update_quad_layer was called.
+    pub fn recreate_device(self, window: &Window) -> Result<Self> {
+        log::warn!("Recreating the Vulkan device and everything depending on it");
+
+        let settings = self.settings.clone();
+        let hmd_settings = self.hmd.as_ref().map(|hmd| {
+            (
+                hmd.reference_space_type,
+                hmd.floor_offset,
+                hmd.stage_origin,
+                hmd.layer_color_scale_bias,
+                hmd.projection_layer_alpha,
+                hmd.predicted_time_offset,
+                hmd.resolution_scale,
+            )
+        });
+        let post_settings = self.get_hmd_post();
+        let quad_layer_settings = self.get_quad_layer();
+        let depth_layer_params = self.get_hmd_depth_layer();
+        let mirror_source = self.get_window_mirror();
+        let wait_for_hmd = self.window.wait_for_hmd;
+        let clear_color = self.get_clear_color();
+        let size_dependent_settings = self.window.size_dependent_settings;
+
+        // Drop skips waiting on a device that is known to be lost
+        drop(self);
+
+        let mut context =
+            Self::create(window, settings, hmd_settings.is_some()).map_err(VrvError::typed)?;
+        if let Some((
+            reference_space_type,
+            floor_offset,
+            stage_origin,
+            layer_color_scale_bias,
+            projection_layer_alpha,
+            predicted_time_offset,
+            resolution_scale,
+        )) = hmd_settings
+        {
+            if let Some(hmd) = &mut context.hmd {
+                hmd.floor_offset = floor_offset;
+                hmd.reference_space_type = reference_space_type;
+                hmd.layer_color_scale_bias = layer_color_scale_bias;
+                hmd.projection_layer_alpha = projection_layer_alpha;
+                hmd.predicted_time_offset = predicted_time_offset;
+            }
+            context.set_stage_origin(stage_origin)?;
+            if resolution_scale != 1.0 {
+                context.set_resolution_scale(resolution_scale)?;
+            }
+        }
+        context.set_hmd_post(post_settings)?;
+        context.set_quad_layer(quad_layer_settings)?;
+        context.set_hmd_depth_layer(depth_layer_params)?;
+        context.set_window_mirror(mirror_source)?;
+        context.window.wait_for_hmd = wait_for_hmd;
+        context.set_clear_color(clear_color);
+        if size_dependent_settings != SizeDependentSettings::default() {
+            context.set_size_dependent_settings(size_dependent_settings)?;
+        }
+        Ok(context)
+    }
+
     pub fn get_image_count_hmd(&self) -> u32 {
-        self.hmd.swapchain.elements.len() as u32
+        self.hmd
+            .as_ref()
+            .map_or(0, |hmd| hmd.swapchain.elements.len() as u32)
+    }
+
+    pub fn get_frames_in_flight_hmd(&self) -> usize {
+        self.hmd.as_ref().map_or(0, |hmd| hmd.in_flight.len())
+    }
+
+    // round-robin, the fence is waited on in record_hmd
+    pub fn next_in_flight_hmd(&mut self) -> Result<InFlightHMD> {
+        let hmd = self.hmd.as_mut().ok_or(VrvError::NoHmd)?;
+        let in_flight = hmd.in_flight[hmd.last_used_in_flight];
+        hmd.last_used_in_flight += 1;
+        hmd.last_used_in_flight %= hmd.in_flight.len();
+        Ok(in_flight)
+    }
+
+    // If enabled, the window submission waits until the last HMD submission has finished.
+    // Required when the window reads from HMD images, set_window_mirror implies it.
+    pub fn set_window_waits_for_hmd(&mut self, wait_for_hmd: bool) {
+        self.window.wait_for_hmd = wait_for_hmd;
+    }
+
+    pub fn window_waits_for_hmd(&self) -> bool {
+        self.window.wait_for_hmd
+    }
+
+    pub fn get_clear_color(&self) -> [f32; 4] {
+        self.window.clear_color
+    }
+
+    // RGBA the window and HMD scenes are cleared to, transparent black by default.
+    // The alpha matters for the HMD with a blending environment blend mode or layer alpha.
+    pub fn set_clear_color(&mut self, color: [f32; 4]) {
+        self.window.clear_color = color;
+        if let Some(hmd) = &mut self.hmd {
+            hmd.clear_color = color;
+        }
+    }
+
+    // the size of the swapchain after clamping to what the surface allows,
+    // which can differ from the window's inner size
+    pub fn window_extent(&self) -> Extent2D {
+        self.window.size_dependent.swapchain.extent
+    }
+
+    // what ContextSettings::window_samples was clamped to, pass it to the window pipelines
+    pub fn get_window_samples(&self) -> vk::SampleCountFlags {
+        self.window.samples
+    }
+
+    pub fn get_window_supersample(&self) -> f32 {
+        self.window.size_dependent_settings.supersample
+    }
+
+    // Renders the window at factor times its resolution and downsamples when presenting.
+    // The render extent is clamped to what the device supports, 1.0 disables it.
+    pub fn set_window_supersample(&mut self, factor: f32) -> Result<()> {
+        if !factor.is_finite() || factor <= 0.0 {
+            bail!("Invalid window supersample factor {}", factor);
+        }
+        self.set_size_dependent_settings(SizeDependentSettings {
+            supersample: factor,
+            ..self.window.size_dependent_settings
+        })
+    }
+
+    // the mode actually in use, the preference only ranks what the surface offers
+    pub fn get_window_present_mode(&self) -> vk::PresentModeKHR {
+        self.window.size_dependent.swapchain.present_mode
+    }
+
+    pub fn get_window_present_mode_preference(&self) -> PresentModePreference {
+        self.window.size_dependent_settings.present_mode
+    }
+
+    // kept for later resizes
+    pub fn set_window_present_mode_preference(
+        &mut self,
+        preference: PresentModePreference,
+    ) -> Result<()> {
+        self.set_size_dependent_settings(SizeDependentSettings {
+            present_mode: preference,
+            ..self.window.size_dependent_settings
+        })
+    }
+
+    // the old settings stay if recreating with the new ones fails
+    fn set_size_dependent_settings(&mut self, settings: SizeDependentSettings) -> Result<()> {
+        let previous = self.window.size_dependent_settings;
+        self.window.size_dependent_settings = settings;
+        let result = self.recreate_size_dependent(self.window.size_dependent.swapchain.extent);
+        if result.is_err() {
+            self.window.size_dependent_settings = previous;
+        }
+        result
+    }
+
+    pub fn get_hmd_post(&self) -> Option<PostSettingsHMD> {
+        self.hmd
+            .as_ref()
+            .and_then(|hmd| hmd.post.as_ref())
+            .map(|post| post.settings)
+    }
+
+    // Some enables FXAA and sharpening of the HMD images, None renders straight to the swapchain.
+    // The scene pipelines don't have to be recreated, the offscreen pass is compatible.
+    pub fn set_hmd_post(&mut self, settings: Option<PostSettingsHMD>) -> Result<()> {
+        let hmd = match (&mut self.hmd, settings) {
+            (Some(hmd), _) => hmd,
+            (None, Some(_)) => return Err(VrvError::NoHmd.into()),
+            (None, None) => return Ok(()),
+        };
+        match (settings, &mut hmd.post) {
+            (Some(settings), Some(post)) => post.settings = settings,
+            (Some(settings), None) => {
+                hmd.post = Some(PostHMD::new(&self.vulkan, &hmd.swapchain, settings)?)
+            }
+            (None, Some(_)) => {
+                // recorded command buffers might still use it
+                self.vulkan.wait_idle()?;
+                hmd.post = None;
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+
+    pub fn get_resolution_scale(&self) -> f32 {
+        self.hmd.as_ref().map_or(1.0, |hmd| hmd.resolution_scale)
+    }
+
+    // Renders the HMD at scale times the recommended resolution and lets the compositor
+    // scale it to the display, returns the new extent. Call it between frames.
+    // The render pass stays the same, but pipelines with a static viewport
+    // were made for the old extent and have to be recreated.
+    pub fn set_resolution_scale(&mut self, scale: f32) -> Result<Extent2D> {
+        if !scale.is_finite() || scale <= 0.0 {
+            bail!("Invalid resolution scale {}", scale);
+        }
+        let post_settings = self.get_hmd_post();
+        let depth_layer_params = self.get_hmd_depth_layer();
+        let mirror_source = self.get_window_mirror();
+        let (hmd, openxr) = match (&mut self.hmd, &self.openxr) {
+            (Some(hmd), Some(openxr)) => (hmd, openxr),
+            _ => return Err(VrvError::NoHmd.into()),
+        };
+        let extent = openxr.get_scaled_resolution(scale)?;
+
+        self.vulkan.wait_idle()?;
+        // built next to the old one, so nothing is lost if it fails
+        let swapchain = SwapchainHMD::new(
+            openxr,
+            &self.vulkan,
+            hmd.render_pass,
+            &hmd.session,
+            &self.settings.hmd_swapchain,
+            extent,
+        )?;
+
+        // sized like the swapchain, the post pass also holds its image views
+        hmd.post = None;
+        hmd.depth_layer = None;
+        hmd.mirror = None;
+        hmd.swapchain = swapchain;
+        hmd.resolution_scale = scale;
+
+        self.set_hmd_post(post_settings)?;
+        self.set_hmd_depth_layer(depth_layer_params)?;
+        self.set_window_mirror(mirror_source)?;
+        Ok(extent)
+    }
+
+    // see wrap_vulkan::Context::allocated_bytes
+    pub fn allocated_bytes(&self) -> u64 {
+        self.vulkan.allocated_bytes()
+    }
+
+    // lets apps streaming resources back off before running out of VRAM
+    pub fn memory_budget(&self) -> Option<wrap_vulkan::MemoryBudget> {
+        self.vulkan.memory_budget()
     }
 
     pub fn get_image_count_window(&self) -> u32 {
-        self.window.swapchain.elements.len() as u32
+        self.window.size_dependent.swapchain.elements.len() as u32
+    }
+
+    // poll_events keeps this up to date, only needed when polling the OpenXR events yourself
+    pub fn set_session_state(&mut self, session_state: SessionState) {
+        if let Some(hmd) = &mut self.hmd {
+            hmd.session_state = session_state;
+        }
+    }
+
+    pub fn get_session_state(&self) -> SessionState {
+        self.hmd
+            .as_ref()
+            .map_or(SessionState::IDLE, |hmd| hmd.session_state)
+    }
+
+    // Only worth rendering if the runtime wants a frame and the user isn't in the dashboard.
+    // (user presence isn't available in the OpenXR version used)
+    // If this is false, call skip_render_hmd instead of recording.
+    pub fn should_render(&self, pre_render_info: &PreRenderInfoHMD) -> bool {
+        pre_render_info.image_index.is_some() && self.get_session_state() == SessionState::FOCUSED
+    }
+
+    pub fn get_floor_offset(&self) -> f32 {
+        self.hmd.as_ref().map_or(0.0, |hmd| hmd.floor_offset)
+    }
+
+    pub fn get_reference_space(&self) -> ReferenceSpaceType {
+        self.hmd
+            .as_ref()
+            .map_or(ReferenceSpaceType::STAGE, |hmd| hmd.reference_space_type)
+    }
+
+    // STAGE is the tracked play area, LOCAL is relative to where the headset started,
+    // which suits seated experiences. Floor offset and stage origin apply to either.
+    // Recreates the space like set_floor_offset, an unsupported type keeps the current one.
+    pub fn set_reference_space(&mut self, reference_space_type: ReferenceSpaceType) -> Result<()> {
+        let hmd = self.hmd.as_mut().ok_or(VrvError::NoHmd)?;
+        if !hmd
+            .session
+            .enumerate_reference_spaces()?
+            .contains(&reference_space_type)
+        {
+            log::warn!(
+                "Reference space {:?} not supported by the runtime, keeping {:?}",
+                reference_space_type,
+                hmd.reference_space_type
+            );
+            return Ok(());
+        }
+        hmd.stage = create_stage(
+            &hmd.session,
+            reference_space_type,
+            hmd.floor_offset,
+            hmd.stage_origin,
+        )?;
+        hmd.reference_space_type = reference_space_type;
+        Ok(())
     }
 
-    pub fn get_views(&self, display_time: Time) -> Result<[View; 2]> {
-        let (_, view_vec) = self.hmd.session.locate_views(
-            ViewConfigurationType::PRIMARY_STEREO,
-            display_time,
-            &self.hmd.stage,
+    // recreates the reference space, poses located before are relative to the old one
+    pub fn set_floor_offset(&mut self, floor_offset: f32) -> Result<()> {
+        let hmd = self.hmd.as_mut().ok_or(VrvError::NoHmd)?;
+        hmd.stage = create_stage(
+            &hmd.session,
+            hmd.reference_space_type,
+            floor_offset,
+            hmd.stage_origin,
         )?;
-        Ok([view_vec[0], view_vec[1]])
+        hmd.floor_offset = floor_offset;
+        Ok(())
+    }
+
+    pub fn get_stage_origin(&self) -> Posef {
+        self.hmd
+            .as_ref()
+            .map_or(Posef::IDENTITY, |hmd| hmd.stage_origin)
+    }
+
+    // recreates the reference space as well
+    pub fn set_stage_origin(&mut self, origin: Posef) -> Result<()> {
+        let hmd = self.hmd.as_mut().ok_or(VrvError::NoHmd)?;
+        hmd.stage = create_stage(
+            &hmd.session,
+            hmd.reference_space_type,
+            hmd.floor_offset,
+            origin,
+        )?;
+        hmd.stage_origin = origin;
+        Ok(())
+    }
+
+    pub fn color_scale_bias_supported(&self) -> bool {
+        self.openxr.as_ref().map_or(false, |openxr| {
+            openxr
+                .enabled_extensions()
+                .khr_composition_layer_color_scale_bias
+        })
+    }
+
+    // Output color is color * scale + bias, e.g. scale all zero for a fade to black.
+    // Applied to every projection layer submitted until cleared.
+    pub fn set_layer_color_scale_bias(&mut self, scale: Color4f, bias: Color4f) -> Result<()> {
+        if !self.color_scale_bias_supported() {
+            bail!("XR_KHR_composition_layer_color_scale_bias not supported by the runtime");
+        }
+        self.hmd
+            .as_mut()
+            .ok_or(VrvError::NoHmd)?
+            .layer_color_scale_bias = Some((scale, bias));
+        Ok(())
+    }
+
+    pub fn clear_layer_color_scale_bias(&mut self) {
+        if let Some(hmd) = &mut self.hmd {
+            hmd.layer_color_scale_bias = None;
+        }
+    }
+
+    pub fn get_projection_layer_alpha(&self) -> LayerAlpha {
+        self.hmd
+            .as_ref()
+            .map_or(LayerAlpha::default(), |hmd| hmd.projection_layer_alpha)
+    }
+
+    // Only matters if something is composited below the projection layer, e.g. passthrough.
+    // The render pass clears alpha to 0, so non-opaque modes need the shaders to write alpha.
+    pub fn set_projection_layer_alpha(&mut self, alpha: LayerAlpha) {
+        if let Some(hmd) = &mut self.hmd {
+            hmd.projection_layer_alpha = alpha;
+        }
+    }
+
+    pub fn get_predicted_time_offset(&self) -> openxr::Duration {
+        self.hmd
+            .as_ref()
+            .map_or(openxr::Duration::from_nanos(0), |hmd| {
+                hmd.predicted_time_offset
+            })
+    }
+
+    // For latency experiments: shifts the predicted display time used to locate the views
+    // and to submit the frame, pre_render_hmd applies it to the frame state it returns.
+    // Anything but zero renders for the wrong moment, which shows up as judder or lag
+    // and makes people sick quickly, so don't ship it. Clamped to +-MAX_PREDICTED_TIME_OFFSET_NANOS.
+    pub fn set_predicted_time_offset(&mut self, offset: openxr::Duration) {
+        let clamped = offset.as_nanos().clamp(
+            -MAX_PREDICTED_TIME_OFFSET_NANOS,
+            MAX_PREDICTED_TIME_OFFSET_NANOS,
+        );
+        if clamped != offset.as_nanos() {
+            log::warn!(
+                "Predicted time offset of {}ns clamped to {}ns",
+                offset.as_nanos(),
+                clamped
+            );
+        }
+        if let Some(hmd) = &mut self.hmd {
+            hmd.predicted_time_offset = openxr::Duration::from_nanos(clamped);
+        }
+    }
+
+    // what the session has to be begun with, PRIMARY_STEREO for a windowed context
+    pub fn get_view_configuration(&self) -> ViewConfigurationType {
+        self.hmd
+            .as_ref()
+            .map_or(ViewConfigurationType::PRIMARY_STEREO, |hmd| {
+                hmd.view_configuration
+            })
+    }
+
+    // One per view of the view configuration, in the order of the swapchain array layers.
+    // Fails with VrvError::NoHmd for a windowed context.
+    pub fn get_views(&self, display_time: Time) -> Result<Vec<View>> {
+        let hmd = self.hmd.as_ref().ok_or(VrvError::NoHmd)?;
+        let (_, views) =
+            hmd.session
+                .locate_views(hmd.view_configuration, display_time, &hmd.stage)?;
+        Ok(views)
+    }
+
+    // for the common stereo case, fails with other view configurations
+    pub fn get_stereo_views(&self, display_time: Time) -> Result<[View; 2]> {
+        match self.get_views(display_time)?[..] {
+            [left, right] => Ok([left, right]),
+            ref views => bail!("Expected 2 views, got {}", views.len()),
+        }
     }
 }
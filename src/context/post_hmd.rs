@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use ash::{
+    vk::{
+        CommandBuffer, DescriptorSet, DescriptorType, Extent2D, Filter, Framebuffer,
+        FramebufferCreateInfo, ImageAspectFlags, ImageTiling, ImageUsageFlags, MemoryPropertyFlags,
+        Pipeline, PipelineBindPoint, PipelineLayout, Rect2D, RenderPass, RenderPassBeginInfo,
+        SampleCountFlags, Sampler, SamplerAddressMode, SamplerMipmapMode, ShaderStageFlags,
+        SubpassContents,
+    },
+    Device,
+};
+use vk_shader_macros::include_glsl;
+
+use crate::wrap_vulkan::{
+    self,
+    descriptors::{DescriptorRelated, Usage},
+    device_image::DeviceImageSettings,
+    pipeline::{
+        cmd_push_constants, create_pipeline_fullscreen, create_pipeline_layout,
+        push_constant_range, ShaderModuleGuard,
+    },
+    render_pass::{create_render_pass_hmd_offscreen, create_render_pass_hmd_post},
+    sampler::{create_sampler, SamplerSettings},
+    DeviceImage,
+};
+
+use super::swapchain::SwapchainHMD;
+
+const FULLSCREEN_VERT: &[u32] = include_glsl!("shaders/fullscreen.vert");
+const POST_HMD_FRAG: &[u32] = include_glsl!("shaders/post_hmd.frag");
+
+#[derive(Copy, Clone, Debug)]
+pub struct PostSettingsHMD {
+    pub fxaa: bool,
+    // 0.0 disables sharpening, above ~0.5 halos become visible
+    pub sharpen: f32,
+}
+
+impl Default for PostSettingsHMD {
+    fn default() -> Self {
+        Self {
+            fxaa: true,
+            sharpen: 0.25,
+        }
+    }
+}
+
+// matches the push constants in post_hmd.frag
+#[repr(C)]
+struct PushConstants {
+    texel: [f32; 2],
+    sharpen: f32,
+    fxaa: u32,
+}
+
+// The scene is rendered into an offscreen image (both views as layers) instead of the
+// swapchain image, then a full screen pass filters it into the swapchain image.
+// Costs an extra full resolution pass per eye, FrameStats::gpu_ms includes it.
+pub struct PostHMD {
+    pub settings: PostSettingsHMD,
+
+    // compatible with the regular HMD render pass
+    pub scene_render_pass: RenderPass,
+    pub scene_frame_buffer: Framebuffer,
+    // the unfiltered scene, in SHADER_READ_ONLY_OPTIMAL after the scene pass
+    pub offscreen: DeviceImage,
+
+    render_pass: RenderPass,
+    // one per swapchain image
+    frame_buffers: Vec<Framebuffer>,
+    sampler: Sampler,
+    pub descriptor_related: DescriptorRelated,
+    descriptor_set: DescriptorSet,
+    pipeline_layout: PipelineLayout,
+    pipeline: Pipeline,
+    extent: Extent2D,
+
+    device: Device,
+}
+
+impl Drop for PostHMD {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline(self.pipeline, None);
+            self.device
+                .destroy_pipeline_layout(self.pipeline_layout, None);
+            self.device.destroy_sampler(self.sampler, None);
+            for &frame_buffer in &self.frame_buffers {
+                self.device.destroy_framebuffer(frame_buffer, None);
+            }
+            self.device.destroy_render_pass(self.render_pass, None);
+            self.device
+                .destroy_framebuffer(self.scene_frame_buffer, None);
+            self.device
+                .destroy_render_pass(self.scene_render_pass, None);
+            // rest implements drop
+        }
+    }
+}
+
+impl PostHMD {
+    pub fn new(
+        context: &wrap_vulkan::Context,
+        swapchain: &SwapchainHMD,
+        settings: PostSettingsHMD,
+    ) -> Result<Self> {
+        let extent = swapchain.extent;
+        let device = &context.device;
+
+        let offscreen = DeviceImage::new(
+            context,
+            DeviceImageSettings {
+                extent,
+                format: context.find_supported_color_format()?,
+                tiling: ImageTiling::OPTIMAL,
+                usage: ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::SAMPLED,
+                properties: MemoryPropertyFlags::DEVICE_LOCAL,
+                aspect_flags: ImageAspectFlags::COLOR,
+                layer_count: swapchain.view_count,
+                view_type: None,
+                mip_levels: 1,
+                samples: SampleCountFlags::TYPE_1,
+                name: "HMDOffscreen".to_string(),
+            },
+        )?;
+
+        // the filters rely on bilinear taps between texels
+        let sampler = create_sampler(
+            context,
+            SamplerSettings {
+                filter: Filter::LINEAR,
+                mipmap_mode: SamplerMipmapMode::NEAREST,
+                address_mode: SamplerAddressMode::CLAMP_TO_EDGE,
+                max_anisotropy: None,
+                max_lod: 0.0,
+            },
+            "HMDPostSampler".to_string(),
+        )?;
+
+        let (descriptor_related, descriptor_sets) = match DescriptorRelated::new_with_sets(
+            context,
+            HashMap::from([(
+                0,
+                (
+                    DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    ShaderStageFlags::FRAGMENT,
+                    1,
+                ),
+            )]),
+            &[HashMap::from([(
+                0,
+                Usage::from_texture(&offscreen, sampler),
+            )])],
+            "HMDPostDescriptor".to_string(),
+        ) {
+            Ok(descriptor) => descriptor,
+            Err(e) => {
+                unsafe { device.destroy_sampler(sampler, None) };
+                return Err(e);
+            }
+        };
+
+        // The remaining handles are filled in one by one, so if a step fails
+        // Drop destroys what was created so far. Vulkan ignores the null handles.
+        let mut post = Self {
+            settings,
+            scene_render_pass: RenderPass::null(),
+            scene_frame_buffer: Framebuffer::null(),
+            offscreen,
+            render_pass: RenderPass::null(),
+            frame_buffers: Vec::with_capacity(swapchain.elements.len()),
+            sampler,
+            descriptor_related,
+            descriptor_set: descriptor_sets[0],
+            pipeline_layout: PipelineLayout::null(),
+            pipeline: Pipeline::null(),
+            extent,
+            device: device.clone(),
+        };
+
+        post.scene_render_pass = create_render_pass_hmd_offscreen(context, swapchain.view_count)?;
+        post.scene_frame_buffer = unsafe {
+            device.create_framebuffer(
+                &FramebufferCreateInfo::builder()
+                    .render_pass(post.scene_render_pass)
+                    .attachments(&[post.offscreen.view, swapchain.depth_image.view])
+                    .width(extent.width)
+                    .height(extent.height)
+                    .layers(1), // multiview dictates this
+                None,
+            )
+        }?;
+        context.name_object(
+            post.scene_frame_buffer,
+            "HMDOffscreenFrameBuffer".to_string(),
+        )?;
+
+        post.render_pass = create_render_pass_hmd_post(context, swapchain.view_count)?;
+        for (i, element) in swapchain.elements.iter().enumerate() {
+            let frame_buffer = unsafe {
+                device.create_framebuffer(
+                    &FramebufferCreateInfo::builder()
+                        .render_pass(post.render_pass)
+                        .attachments(&[element.view])
+                        .width(extent.width)
+                        .height(extent.height)
+                        .layers(1),
+                    None,
+                )
+            }?;
+            post.frame_buffers.push(frame_buffer);
+            context.name_object(frame_buffer, format!("HMDPostFrameBuffer_{}", i))?;
+        }
+
+        post.pipeline_layout = create_pipeline_layout(
+            context,
+            &[post.descriptor_related.layout],
+            &[push_constant_range::<PushConstants>(
+                ShaderStageFlags::FRAGMENT,
+            )],
+            "HMDPostPipelineLayout".to_string(),
+        )?;
+
+        let module_vert =
+            ShaderModuleGuard::new(context, FULLSCREEN_VERT, "FullscreenVert".to_string())?;
+        let module_frag =
+            ShaderModuleGuard::new(context, POST_HMD_FRAG, "PostHMDFrag".to_string())?;
+        post.pipeline = create_pipeline_fullscreen(
+            context,
+            post.render_pass,
+            post.pipeline_layout,
+            &module_vert,
+            &module_frag,
+            extent,
+            "HMDPostPipeline".to_string(),
+        )?;
+
+        Ok(post)
+    }
+
+    // after the scene render pass ended, writes the swapchain image
+    pub unsafe fn record(&self, command_buffer: CommandBuffer, image_index: u32) {
+        let d = &self.device;
+        let push_constants = PushConstants {
+            texel: [
+                1.0 / self.extent.width as f32,
+                1.0 / self.extent.height as f32,
+            ],
+            sharpen: self.settings.sharpen.max(0.0),
+            fxaa: self.settings.fxaa as u32,
+        };
+
+        d.cmd_begin_render_pass(
+            command_buffer,
+            &RenderPassBeginInfo::builder()
+                .render_pass(self.render_pass)
+                .framebuffer(self.frame_buffers[image_index as usize])
+                .render_area(*Rect2D::builder().extent(self.extent)),
+            SubpassContents::INLINE,
+        );
+        d.cmd_bind_pipeline(command_buffer, PipelineBindPoint::GRAPHICS, self.pipeline);
+        d.cmd_bind_descriptor_sets(
+            command_buffer,
+            PipelineBindPoint::GRAPHICS,
+            self.pipeline_layout,
+            0,
+            &[self.descriptor_set],
+            &[],
+        );
+        cmd_push_constants(
+            d,
+            command_buffer,
+            self.pipeline_layout,
+            ShaderStageFlags::FRAGMENT,
+            0,
+            &push_constants,
+        );
+        d.cmd_draw(command_buffer, 3, 1, 0, 0);
+        d.cmd_end_render_pass(command_buffer);
+    }
+}
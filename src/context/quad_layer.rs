@@ -0,0 +1,256 @@
+use anyhow::{bail, Result};
+use ash::{
+    vk::{CommandBuffer, Extent2D, Handle, Image, ImageAspectFlags, ImageView},
+    Device,
+};
+use openxr::{
+    CompositionLayerQuad, Extent2Df, Extent2Di, EyeVisibility, Offset2Di, Posef, Rect2Di,
+    ReferenceSpaceType, Session, Space, SwapchainCreateFlags, SwapchainCreateInfo,
+    SwapchainSubImage, SwapchainUsageFlags, Vector3f, Vulkan,
+};
+
+use crate::{
+    wrap_vulkan::{self, DeviceImage},
+    VrvError,
+};
+
+use super::{layer::LayerAlpha, swapchain::ImageState, Context};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QuadPlacement {
+    // the pose is in the reference space, the panel stays put in the world
+    World,
+    // the pose is relative to the head, the panel moves with it
+    Head,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct QuadLayerSettings {
+    // resolution of the panel, changing it recreates the swapchain
+    pub extent: Extent2D,
+    pub placement: QuadPlacement,
+    // the center of the panel, it faces +Z
+    pub pose: Posef,
+    // in meters
+    pub size: Extent2Df,
+    pub eye_visibility: EyeVisibility,
+    pub alpha: LayerAlpha,
+}
+
+impl Default for QuadLayerSettings {
+    fn default() -> Self {
+        Self {
+            extent: Extent2D {
+                width: 512,
+                height: 512,
+            },
+            placement: QuadPlacement::Head,
+            // half a meter in front of the eyes
+            pose: Posef {
+                position: Vector3f {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -0.5,
+                },
+                ..Posef::IDENTITY
+            },
+            size: Extent2Df {
+                width: 0.3,
+                height: 0.3,
+            },
+            eye_visibility: EyeVisibility::BOTH,
+            alpha: LayerAlpha::Premultiplied,
+        }
+    }
+}
+
+// what the record closure of Context::update_quad_layer renders into
+pub struct QuadImage {
+    pub image: Image,
+    pub view: ImageView,
+    pub extent: Extent2D,
+}
+
+// A 2D panel composited after the projection layer, e.g. for a HUD.
+// Only submitted once something was rendered into it.
+pub struct QuadLayer {
+    pub settings: QuadLayerSettings,
+    swapchain: openxr::Swapchain<Vulkan>,
+    images: Vec<QuadImage>,
+    image_state: ImageState,
+    has_content: bool,
+    view_space: Space,
+    device: Device,
+}
+
+impl Drop for QuadLayer {
+    fn drop(&mut self) {
+        unsafe {
+            // the images belong to the runtime
+            for image in &self.images {
+                self.device.destroy_image_view(image.view, None);
+            }
+        }
+    }
+}
+
+impl QuadLayer {
+    pub fn new(
+        context: &wrap_vulkan::Context,
+        session: &Session<Vulkan>,
+        settings: QuadLayerSettings,
+    ) -> Result<Self> {
+        let format = context.find_supported_color_format()?;
+        let swapchain = session.create_swapchain(&SwapchainCreateInfo {
+            create_flags: SwapchainCreateFlags::EMPTY,
+            usage_flags: SwapchainUsageFlags::COLOR_ATTACHMENT | SwapchainUsageFlags::TRANSFER_DST,
+            format: format.as_raw() as _,
+            sample_count: 1,
+            width: settings.extent.width,
+            height: settings.extent.height,
+            face_count: 1,
+            array_size: 1,
+            mip_count: 1,
+        })?;
+
+        let images = swapchain
+            .enumerate_images()?
+            .into_iter()
+            .enumerate()
+            .map(|(i, xr_image_handle)| -> Result<QuadImage> {
+                let image = Image::from_raw(xr_image_handle);
+                context.name_object(image, format!("HMDQuadImage_{}", i))?;
+                let view = DeviceImage::new_view(
+                    context,
+                    image,
+                    format,
+                    ImageAspectFlags::COLOR,
+                    1,
+                    1,
+                    format!("HMDQuadView_{}", i),
+                )?;
+                Ok(QuadImage {
+                    image,
+                    view,
+                    extent: settings.extent,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            settings,
+            swapchain,
+            images,
+            image_state: ImageState::Released,
+            has_content: false,
+            view_space: session
+                .create_reference_space(ReferenceSpaceType::VIEW, Posef::IDENTITY)?,
+            device: context.device.clone(),
+        })
+    }
+
+    // None until the first update, swapchains that never released an image are rejected
+    pub fn composition_layer<'a>(
+        &'a self,
+        reference_space: &'a Space,
+    ) -> Option<CompositionLayerQuad<'a, Vulkan>> {
+        if !self.has_content {
+            return None;
+        }
+        Some(
+            CompositionLayerQuad::new()
+                .layer_flags(self.settings.alpha.flags())
+                .space(match self.settings.placement {
+                    QuadPlacement::World => reference_space,
+                    QuadPlacement::Head => &self.view_space,
+                })
+                .eye_visibility(self.settings.eye_visibility)
+                .sub_image(
+                    SwapchainSubImage::new()
+                        .swapchain(&self.swapchain)
+                        .image_array_index(0)
+                        .image_rect(Rect2Di {
+                            offset: Offset2Di::default(),
+                            extent: Extent2Di {
+                                width: self.settings.extent.width as i32,
+                                height: self.settings.extent.height as i32,
+                            },
+                        }),
+                )
+                .pose(self.settings.pose)
+                .size(self.settings.size),
+        )
+    }
+
+    // Only usable once the wait succeeded. A failed wait leaves the image acquired,
+    // the next acquire waits on and releases it first instead of failing for good.
+    fn acquire(&mut self) -> Result<usize> {
+        let image_index = self.image_state.acquire(&mut self.swapchain)?;
+        self.image_state.wait(&mut self.swapchain)?;
+        Ok(image_index as usize)
+    }
+
+    fn release(&mut self) -> Result<()> {
+        self.image_state.release(&mut self.swapchain)
+    }
+}
+
+impl Context {
+    pub fn get_quad_layer(&self) -> Option<QuadLayerSettings> {
+        self.hmd
+            .as_ref()
+            .and_then(|hmd| hmd.quad_layer.as_ref())
+            .map(|quad| quad.settings)
+    }
+
+    // Some adds the panel to every submitted frame once it was updated, None removes it.
+    // Pose, size, visibility and alpha apply immediately, a new extent needs a new update.
+    pub fn set_quad_layer(&mut self, settings: Option<QuadLayerSettings>) -> Result<()> {
+        let hmd = match (&mut self.hmd, settings) {
+            (Some(hmd), _) => hmd,
+            (None, Some(_)) => return Err(VrvError::NoHmd.into()),
+            (None, None) => return Ok(()),
+        };
+        match (settings, &mut hmd.quad_layer) {
+            (Some(settings), Some(quad)) if settings.extent == quad.settings.extent => {
+                quad.settings = settings
+            }
+            (Some(settings), _) => {
+                // the old images might still be rendered to
+                self.vulkan.wait_idle()?;
+                hmd.quad_layer = Some(QuadLayer::new(&self.vulkan, &hmd.session, settings)?);
+            }
+            (None, Some(_)) => {
+                self.vulkan.wait_idle()?;
+                hmd.quad_layer = None;
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+
+    // Acquires the next quad image, records into it and hands it back to the runtime.
+    // The image is in COLOR_ATTACHMENT_OPTIMAL and has to be left in it.
+    // Blocks until the GPU is done, meant for panels that change now and then.
+    pub fn update_quad_layer<F: FnOnce(CommandBuffer, &QuadImage)>(
+        &mut self,
+        record: F,
+    ) -> Result<()> {
+        let hmd = self.hmd.as_mut().ok_or(VrvError::NoHmd)?;
+        let quad = match &mut hmd.quad_layer {
+            Some(quad) => quad,
+            None => bail!("No quad layer to update, see set_quad_layer"),
+        };
+
+        let image_index = quad.acquire()?;
+        let recorded = self.vulkan.one_shot("HMDQuadUpdate", |command_buffer| {
+            record(command_buffer, &quad.images[image_index])
+        });
+        // released either way, otherwise the next acquire fails
+        quad.release()?;
+        recorded?;
+
+        quad.has_content = true;
+        Ok(())
+    }
+}
@@ -1,36 +1,68 @@
 use crate::{
-    wrap_vulkan::{geometry::MeshBuffers, sync::wait_and_reset},
-    Context,
+    wrap_vulkan::{
+        geometry::{record_draws, DrawCall, DrawRange, MeshBuffers},
+        sync::wait,
+    },
+    Context, VrvError,
 };
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
 use ash::vk::{
     ClearColorValue, ClearDepthStencilValue, ClearValue, CommandBuffer, CommandBufferBeginInfo,
-    CommandBufferResetFlags, DescriptorSet, Fence, IndexType, Pipeline, PipelineBindPoint,
-    PipelineLayout, Rect2D, RenderPassBeginInfo, SubmitInfo, SubpassContents,
+    CommandBufferResetFlags, DescriptorSet, Fence, Pipeline, PipelineBindPoint, PipelineLayout,
+    Rect2D, RenderPassBeginInfo, Semaphore, SubmitInfo, SubpassContents,
+    TimelineSemaphoreSubmitInfo,
 };
 
 use openxr::{
-    CompositionLayerProjection, CompositionLayerProjectionView, Duration, EnvironmentBlendMode,
-    Extent2Di, Offset2Di, Rect2Di, SwapchainSubImage, View,
+    sys, CompositionLayerBase, CompositionLayerProjection, CompositionLayerProjectionView,
+    Duration, Extent2Di, FrameState, Offset2Di, Posef, Rect2Di, SwapchainSubImage, Time, View,
+    Vulkan,
 };
+use std::{os::raw::c_void, time::Instant};
 
 use super::PreRenderInfoHMD;
 
 impl Context {
     pub fn pre_render_hmd(&mut self) -> Result<PreRenderInfoHMD> {
-        let frame_state = self.hmd.frame_wait.wait()?;
-        self.hmd.frame_stream.begin()?;
+        let hmd = match &mut self.hmd {
+            Some(hmd) => hmd,
+            // nothing to render to, the caller sees should_render == false
+            None => {
+                return Ok(PreRenderInfoHMD {
+                    image_index: None,
+                    frame_state: FrameState {
+                        predicted_display_time: Time::from_nanos(0),
+                        predicted_display_period: Duration::from_nanos(0),
+                        should_render: false,
+                    },
+                })
+            }
+        };
+        // in case the last frame didn't make it to submission
+        hmd.swapchain.release_pending()?;
+        if let Some(depth) = &mut hmd.depth_layer {
+            depth.release()?;
+        }
+
+        let started = Instant::now();
+        let mut frame_state = hmd.frame_wait.wait()?;
+        frame_state.predicted_display_time = Time::from_nanos(
+            frame_state.predicted_display_time.as_nanos() + hmd.predicted_time_offset.as_nanos(),
+        );
+        hmd.frame_stats
+            .waited(started, frame_state.predicted_display_time);
+        hmd.frame_stream.begin()?;
 
         if !frame_state.should_render {
-            self.hmd.frame_stream.end(
-                frame_state.predicted_display_time,
-                EnvironmentBlendMode::OPAQUE,
-                &[],
-            )?;
+            hmd.frame_stream
+                .end(frame_state.predicted_display_time, hmd.blend_mode, &[])?;
         }
 
         let image_index = if frame_state.should_render {
-            Some(self.hmd.swapchain.swapchain.acquire_image()?)
+            if let Some(depth) = &mut hmd.depth_layer {
+                depth.acquire()?;
+            }
+            Some(hmd.swapchain.acquire_image()?)
         } else {
             None
         };
@@ -41,48 +73,195 @@ impl Context {
         })
     }
 
+    // The runtime expects wait/begin/end every frame, even if nothing is shown,
+    // otherwise it considers the app unresponsive. This submits a frame without layers
+    // and gives back the image if one was acquired.
+    pub fn skip_render_hmd(&mut self, pre_render_info: PreRenderInfoHMD) -> Result<()> {
+        let PreRenderInfoHMD {
+            image_index,
+            frame_state,
+        } = pre_render_info;
+        let hmd = match &mut self.hmd {
+            Some(hmd) => hmd,
+            None => return Ok(()),
+        };
+
+        // without an image, pre_render_hmd already ended the frame
+        if image_index.is_none() {
+            return Ok(());
+        }
+
+        hmd.swapchain.release_image()?;
+        if let Some(depth) = &mut hmd.depth_layer {
+            depth.release()?;
+        }
+        hmd.frame_stream
+            .end(frame_state.predicted_display_time, hmd.blend_mode, &[])?;
+
+        Ok(())
+    }
+
     pub fn post_render_hmd(
         &mut self,
         pre_render_info: PreRenderInfoHMD,
-        views: &[View; 2],
+        views: &[View],
+    ) -> Result<()> {
+        self.post_render_hmd_with_poses(
+            pre_render_info,
+            views,
+            &views.iter().map(|view| view.pose).collect::<Vec<_>>(),
+        )
+    }
+
+    // The poses in the projection layer have to be the ones used for rendering,
+    // the compositor reprojects from them. Submitting a different (e.g. newer) pose
+    // than the rendered one makes the image swim, only do that deliberately for testing.
+    pub fn post_render_hmd_with_poses(
+        &mut self,
+        pre_render_info: PreRenderInfoHMD,
+        views: &[View],
+        poses: &[Posef],
     ) -> Result<()> {
         let PreRenderInfoHMD { frame_state, .. } = pre_render_info;
+        let hmd = match &mut self.hmd {
+            Some(hmd) => hmd,
+            None => return Ok(()),
+        };
+        if views.len() != hmd.swapchain.view_count as usize || poses.len() != views.len() {
+            bail!(
+                "Got {} views and {} poses for {} swapchain layers",
+                views.len(),
+                poses.len(),
+                hmd.swapchain.view_count
+            );
+        }
 
-        self.hmd.swapchain.swapchain.release_image()?;
-
-        self.hmd.frame_stream.end(
-            frame_state.predicted_display_time,
-            EnvironmentBlendMode::OPAQUE,
-            &[&CompositionLayerProjection::new()
-                .space(&self.hmd.stage)
-                .views(
-                    &views
-                        .iter()
-                        .enumerate()
-                        .map(|(i, view)| {
-                            CompositionLayerProjectionView::new()
-                                .pose(view.pose)
-                                .fov(view.fov)
-                                .sub_image(
-                                    SwapchainSubImage::new()
-                                        .swapchain(&self.hmd.swapchain.swapchain)
-                                        .image_array_index(i as u32)
-                                        .image_rect(Rect2Di {
-                                            offset: Offset2Di::default(),
-                                            extent: Extent2Di {
-                                                width: self.hmd.swapchain.extent.width as i32,
-                                                height: self.hmd.swapchain.extent.height as i32,
-                                            },
-                                        }),
-                                )
-                        })
-                        .collect::<Vec<_>>(),
-                )],
-        )?;
+        hmd.swapchain.release_image()?;
+        if let Some(depth) = &mut hmd.depth_layer {
+            depth.release()?;
+        }
+        // chained into the projection views, so they have to outlive the frame end as well
+        let depth_infos = hmd.depth_layer.as_ref().map(|depth| depth.depth_infos());
+
+        let projection_views = views
+            .iter()
+            .zip(poses)
+            .enumerate()
+            .map(|(i, (view, &pose))| {
+                CompositionLayerProjectionView::new()
+                    .pose(pose)
+                    .fov(view.fov)
+                    .sub_image(
+                        SwapchainSubImage::new()
+                            .swapchain(&hmd.swapchain.swapchain)
+                            .image_array_index(i as u32)
+                            .image_rect(Rect2Di {
+                                offset: Offset2Di::default(),
+                                extent: Extent2Di {
+                                    width: hmd.swapchain.extent.width as i32,
+                                    height: hmd.swapchain.extent.height as i32,
+                                },
+                            }),
+                    )
+            })
+            .enumerate()
+            .map(|(i, projection_view)| match &depth_infos {
+                Some(depth_infos) => unsafe {
+                    let mut raw = projection_view.into_raw();
+                    raw.next = &depth_infos[i] as *const _ as *const c_void;
+                    CompositionLayerProjectionView::from_raw(raw)
+                },
+                None => projection_view,
+            })
+            .collect::<Vec<_>>();
+        let layer = CompositionLayerProjection::new()
+            .layer_flags(hmd.projection_layer_alpha.flags())
+            .space(&hmd.stage)
+            .views(&projection_views);
+
+        // the bindings have no builder for the next chain, so it's patched into the raw struct
+        // which means the extension struct has to live until the frame is ended
+        let color_scale_bias = hmd.layer_color_scale_bias.map(|(color_scale, color_bias)| {
+            sys::CompositionLayerColorScaleBiasKHR {
+                ty: sys::CompositionLayerColorScaleBiasKHR::TYPE,
+                next: std::ptr::null(),
+                color_scale,
+                color_bias,
+            }
+        });
+        let layer = match &color_scale_bias {
+            Some(color_scale_bias) => unsafe {
+                let mut raw = layer.into_raw();
+                raw.next = color_scale_bias as *const _ as *const c_void;
+                CompositionLayerProjection::from_raw(raw)
+            },
+            None => layer,
+        };
+
+        let quad_layer = hmd
+            .quad_layer
+            .as_ref()
+            .and_then(|quad| quad.composition_layer(&hmd.stage));
+        let mut layers: Vec<&CompositionLayerBase<Vulkan>> = vec![&layer];
+        if let Some(quad_layer) = &quad_layer {
+            layers.push(quad_layer);
+        }
+
+        hmd.frame_stream
+            .end(frame_state.predicted_display_time, hmd.blend_mode, &layers)?;
 
         Ok(())
     }
 
+    // Clears both eyes of an acquired image (and the depth layer image, if enabled) without
+    // drawing anything, e.g. while loading.
+    // Some runtimes hand out uninitialized images, so this avoids showing garbage.
+    // Blocks until the GPU is done, the image still has to be submitted (or skipped) after.
+    pub fn clear_hmd_image(&mut self, image_index: u32, color: [f32; 4]) -> Result<()> {
+        let hmd = self.hmd.as_mut().ok_or(VrvError::NoHmd)?;
+
+        // the compositor could still be reading from it
+        hmd.swapchain.wait_image()?;
+
+        let frame_buffer = hmd.swapchain.elements[image_index as usize].frame_buffer;
+        let extent = hmd.swapchain.extent;
+        let render_pass = hmd.render_pass;
+        let d = &self.vulkan.device;
+        let depth_clear_value = self.vulkan.depth_clear_value();
+        let depth_image = hmd.swapchain.depth_image.image;
+        let depth_layer = hmd.depth_layer.as_ref();
+        let mut depth_copied = Ok(());
+
+        // the render pass clears on load, takes care of the layouts and covers both layers
+        self.vulkan.one_shot("HMDClear", |command_buffer| unsafe {
+            d.cmd_begin_render_pass(
+                command_buffer,
+                &RenderPassBeginInfo::builder()
+                    .render_pass(render_pass)
+                    .framebuffer(frame_buffer)
+                    .render_area(*Rect2D::builder().extent(extent))
+                    .clear_values(&[
+                        ClearValue {
+                            color: ClearColorValue { float32: color },
+                        },
+                        ClearValue {
+                            depth_stencil: ClearDepthStencilValue {
+                                depth: depth_clear_value,
+                                stencil: 0,
+                            },
+                        },
+                    ]),
+                SubpassContents::INLINE,
+            );
+            d.cmd_end_render_pass(command_buffer);
+            // the frame still submits the depth layer, so it gets the cleared depth as well
+            if let Some(depth) = depth_layer {
+                depth_copied = depth.record_copy(command_buffer, depth_image);
+            }
+        })?;
+        depth_copied
+    }
+
     pub fn record_hmd(
         &mut self,
         pre_render_info: PreRenderInfoHMD,
@@ -92,42 +271,106 @@ impl Context {
         descriptor_set: DescriptorSet,
         command_buffer: CommandBuffer,
         rendering_finished_fence: Fence,
+    ) -> Result<()> {
+        self.record_hmd_draws(
+            pre_render_info,
+            pipeline_layout,
+            pipeline,
+            [(mesh, descriptor_set, mesh.full_range(), None)],
+            command_buffer,
+            rendering_finished_fence,
+        )
+    }
+
+    // Several objects with their own buffers and descriptor sets in one render pass,
+    // drawn with pipeline unless a draw brings its own. Meshes packed into the same
+    // buffers are drawn with DrawCall::packed and only bound once. Draws are recorded in order and
+    // blending pipelines (see BlendMode) don't write depth, so alpha blended draws have to
+    // come after the opaque ones.
+    pub fn record_hmd_scene(
+        &mut self,
+        pre_render_info: PreRenderInfoHMD,
+        pipeline_layout: PipelineLayout,
+        pipeline: Pipeline,
+        draws: &[DrawCall],
+        command_buffer: CommandBuffer,
+        rendering_finished_fence: Fence,
+    ) -> Result<()> {
+        self.record_hmd_draws(
+            pre_render_info,
+            pipeline_layout,
+            pipeline,
+            draws.iter().map(|draw| {
+                (
+                    draw.mesh,
+                    draw.descriptor_set,
+                    draw.draw_range(),
+                    draw.pipeline,
+                )
+            }),
+            command_buffer,
+            rendering_finished_fence,
+        )
+    }
+
+    fn record_hmd_draws<'a>(
+        &mut self,
+        pre_render_info: PreRenderInfoHMD,
+        pipeline_layout: PipelineLayout,
+        pipeline: Pipeline,
+        draws: impl IntoIterator<Item = (&'a MeshBuffers, DescriptorSet, DrawRange, Option<Pipeline>)>,
+        command_buffer: CommandBuffer,
+        rendering_finished_fence: Fence,
     ) -> Result<()> {
         let PreRenderInfoHMD { image_index, .. } = pre_render_info;
+        let hmd = match &mut self.hmd {
+            Some(hmd) => hmd,
+            None => return Ok(()),
+        };
 
         let image_index = image_index.ok_or(Error::msg("Shouldn't render, says OpenXR"))?;
 
         // Wait until the image is available to render to. The compositor could still be
         // reading from it.
-        self.hmd
-            .swapchain
-            .swapchain
-            .wait_image(Duration::INFINITE)?;
+        hmd.swapchain.wait_image()?;
+        // the wait belongs to the runtime's pacing, not to recording
+        let started = Instant::now();
 
-        let frame_buffer = self.hmd.swapchain.elements[image_index as usize].frame_buffer;
-        let extent = self.hmd.swapchain.extent;
+        // with the post pass the scene goes to the offscreen image first
+        let (render_pass, frame_buffer) = match &hmd.post {
+            Some(post) => (post.scene_render_pass, post.scene_frame_buffer),
+            None => (
+                hmd.render_pass,
+                hmd.swapchain.elements[image_index as usize].frame_buffer,
+            ),
+        };
+        let extent = hmd.swapchain.extent;
 
-        // wait for rendering operations
-        wait_and_reset(&self.vulkan, rendering_finished_fence)?;
+        // wait for rendering operations, the submission resets the fence
+        wait(&self.vulkan, rendering_finished_fence)?;
 
         unsafe {
             let d = &self.vulkan.device;
+            let depth_clear_value = self.vulkan.depth_clear_value();
 
             d.reset_command_buffer(command_buffer, CommandBufferResetFlags::RELEASE_RESOURCES)?;
             d.begin_command_buffer(command_buffer, &CommandBufferBeginInfo::builder())?;
+            let gpu_slot = hmd.frame_stats.cmd_begin_gpu(command_buffer)?;
             d.cmd_begin_render_pass(
                 command_buffer,
                 &RenderPassBeginInfo::builder()
-                    .render_pass(self.hmd.render_pass)
+                    .render_pass(render_pass)
                     .framebuffer(frame_buffer)
                     .render_area(*Rect2D::builder().extent(extent))
                     .clear_values(&[
                         ClearValue {
-                            color: ClearColorValue::default(),
+                            color: ClearColorValue {
+                                float32: hmd.clear_color,
+                            },
                         },
                         ClearValue {
                             depth_stencil: ClearDepthStencilValue {
-                                depth: 1.0,
+                                depth: depth_clear_value,
                                 stencil: 0,
                             },
                         },
@@ -135,41 +378,126 @@ impl Context {
                 SubpassContents::INLINE,
             );
             d.cmd_bind_pipeline(command_buffer, PipelineBindPoint::GRAPHICS, pipeline);
-            d.cmd_bind_vertex_buffers(command_buffer, 0, &[mesh.vertex.handle()], &[0]);
-            d.cmd_bind_index_buffer(command_buffer, mesh.index.handle(), 0, IndexType::UINT32);
-            d.cmd_bind_descriptor_sets(
-                command_buffer,
-                PipelineBindPoint::GRAPHICS,
-                pipeline_layout,
-                0,
-                &[descriptor_set],
-                &[],
-            );
-            d.cmd_draw_indexed(command_buffer, mesh.num_indices() as u32, 1, 0, 0, 0);
+            record_draws(d, command_buffer, pipeline, pipeline_layout, draws);
 
             d.cmd_end_render_pass(command_buffer);
+            if let Some(depth) = &hmd.depth_layer {
+                depth.record_copy(command_buffer, hmd.swapchain.depth_image.image)?;
+            }
+            if let Some(post) = &hmd.post {
+                post.record(command_buffer, image_index);
+            }
+            if let Some(mirror) = &hmd.mirror {
+                mirror.record_copy(
+                    command_buffer,
+                    hmd.swapchain.elements[image_index as usize].image,
+                );
+            }
+            hmd.frame_stats.cmd_end_gpu(command_buffer, gpu_slot);
             d.end_command_buffer(command_buffer)?;
         }
+        hmd.frame_stats.recorded(started);
         Ok(())
     }
 
     pub fn submit_hmd(
         &mut self,
         pre_render_info: PreRenderInfoHMD,
-        views: &[View; 2],
+        views: &[View],
         command_buffer: CommandBuffer,
         rendering_finished_fence: Fence,
     ) -> Result<()> {
-        unsafe {
+        self.submit_hmd_with_poses(
+            pre_render_info,
+            views,
+            &views.iter().map(|view| view.pose).collect::<Vec<_>>(),
+            command_buffer,
+            rendering_finished_fence,
+        )
+    }
+
+    // see post_render_hmd_with_poses
+    pub fn submit_hmd_with_poses(
+        &mut self,
+        pre_render_info: PreRenderInfoHMD,
+        views: &[View],
+        poses: &[Posef],
+        command_buffer: CommandBuffer,
+        rendering_finished_fence: Fence,
+    ) -> Result<()> {
+        if self.hmd.is_none() {
+            return Ok(());
+        }
+        let started = Instant::now();
+        self.submit_hmd_untimed(
+            pre_render_info,
+            views,
+            poses,
+            command_buffer,
+            rendering_finished_fence,
+        )?;
+        if let Some(hmd) = &mut self.hmd {
+            hmd.frame_stats.submitted(started);
+        }
+        Ok(())
+    }
+
+    fn submit_hmd_untimed(
+        &mut self,
+        pre_render_info: PreRenderInfoHMD,
+        views: &[View],
+        poses: &[Posef],
+        command_buffer: CommandBuffer,
+        rendering_finished_fence: Fence,
+    ) -> Result<()> {
+        let hmd = match &mut self.hmd {
+            Some(hmd) => hmd,
+            None => return Ok(()),
+        };
+        // reset as late as possible, a frame that is recorded but never submitted must leave
+        // the fence signaled or the next wait on it, e.g. in FrameRing::next, would never return
+        unsafe { self.vulkan.device.reset_fences(&[rendering_finished_fence]) }?;
+        if let Some(timeline) = hmd.timeline {
+            let value = hmd.timeline_value + 1;
+            self.vulkan.check_lost(unsafe {
+                self.vulkan.device.queue_submit(
+                    self.vulkan.queue,
+                    &[SubmitInfo::builder()
+                        .command_buffers(&[command_buffer])
+                        .signal_semaphores(&[timeline])
+                        .push_next(
+                            &mut TimelineSemaphoreSubmitInfo::builder()
+                                .signal_semaphore_values(&[value]),
+                        )
+                        .build()],
+                    rendering_finished_fence,
+                )
+            })?;
+            hmd.timeline_value = value;
+            return self.post_render_hmd_with_poses(pre_render_info, views, poses);
+        }
+
+        // a binary semaphore can't be signaled twice before the window waited on it
+        let signal_window =
+            (self.window.wait_for_hmd || hmd.mirror.is_some()) && !hmd.window_dependency_pending;
+        let signal_semaphores: &[Semaphore] = if signal_window {
+            &[hmd.semaphore_window_dependency]
+        } else {
+            &[]
+        };
+
+        self.vulkan.check_lost(unsafe {
             self.vulkan.device.queue_submit(
                 self.vulkan.queue,
                 &[SubmitInfo::builder()
                     .command_buffers(&[command_buffer])
+                    .signal_semaphores(signal_semaphores)
                     .build()],
                 rendering_finished_fence,
-            )?;
-        }
+            )
+        })?;
+        hmd.window_dependency_pending |= signal_window;
 
-        self.post_render_hmd(pre_render_info, views)
+        self.post_render_hmd_with_poses(pre_render_info, views, poses)
     }
 }
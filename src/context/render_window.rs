@@ -1,19 +1,135 @@
 use crate::{
-    wrap_vulkan::{geometry::MeshBuffers, sync::wait_and_reset},
+    wrap_vulkan::geometry::{record_draws, DrawCall, DrawRange, MeshBuffers},
     Context,
 };
 use anyhow::Result;
-use ash::vk::{
-    ClearColorValue, ClearDepthStencilValue, ClearValue, CommandBuffer, CommandBufferBeginInfo,
-    CommandBufferResetFlags, DescriptorSet, Fence, IndexType, Offset2D, Pipeline,
-    PipelineBindPoint, PipelineLayout, PipelineStageFlags, PresentInfoKHR, Rect2D,
-    RenderPassBeginInfo, Semaphore, SubmitInfo, SubpassContents, Viewport,
+use ash::{
+    vk::{
+        self, AccessFlags, ClearColorValue, ClearDepthStencilValue, ClearValue, CommandBuffer,
+        CommandBufferBeginInfo, CommandBufferResetFlags, DependencyFlags, DescriptorSet, Extent2D,
+        Fence, Filter, Image, ImageAspectFlags, ImageBlit, ImageLayout, ImageMemoryBarrier,
+        ImageSubresourceLayers, ImageSubresourceRange, Offset2D, Offset3D, Pipeline,
+        PipelineBindPoint, PipelineLayout, PipelineStageFlags, PresentInfoKHR, Rect2D,
+        RenderPassBeginInfo, Semaphore, SubmitInfo, SubpassContents, TimelineSemaphoreSubmitInfo,
+        Viewport,
+    },
+    Device,
 };
 
 use super::PreRenderInfoWindow;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RenderOutcome {
+    Presented,
+    // minimized or the image couldn't be acquired, nothing was submitted
+    Skipped,
+    // the swapchain doesn't match the surface anymore (e.g. moved to a display with another
+    // format or DPI), call Context::resize. The frame was presented if it got that far.
+    NeedsResize,
+}
+
+fn color_barrier(
+    image: Image,
+    old_layout: ImageLayout,
+    new_layout: ImageLayout,
+    src_access_mask: AccessFlags,
+    dst_access_mask: AccessFlags,
+) -> ImageMemoryBarrier {
+    ImageMemoryBarrier::builder()
+        .image(image)
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .subresource_range(
+            ImageSubresourceRange::builder()
+                .aspect_mask(ImageAspectFlags::COLOR)
+                .level_count(1)
+                .layer_count(1)
+                .build(),
+        )
+        .build()
+}
+
+fn corner(extent: Extent2D) -> Offset3D {
+    Offset3D {
+        x: extent.width as i32,
+        y: extent.height as i32,
+        z: 1,
+    }
+}
+
+// Scales src (in TRANSFER_SRC_OPTIMAL, e.g. the offscreen image after the render pass
+// or the HMD mirror) into the swapchain image and leaves that ready for presenting.
+unsafe fn record_blit_to_swapchain(
+    d: &Device,
+    command_buffer: CommandBuffer,
+    src: Image,
+    src_extent: Extent2D,
+    dst: Image,
+    dst_extent: Extent2D,
+    filter: Filter,
+) {
+    let subresource = ImageSubresourceLayers::builder()
+        .aspect_mask(ImageAspectFlags::COLOR)
+        .layer_count(1)
+        .build();
+
+    d.cmd_pipeline_barrier(
+        command_buffer,
+        PipelineStageFlags::TRANSFER,
+        PipelineStageFlags::TRANSFER,
+        DependencyFlags::empty(),
+        &[],
+        &[],
+        &[color_barrier(
+            dst,
+            ImageLayout::UNDEFINED,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            AccessFlags::empty(),
+            AccessFlags::TRANSFER_WRITE,
+        )],
+    );
+    d.cmd_blit_image(
+        command_buffer,
+        src,
+        ImageLayout::TRANSFER_SRC_OPTIMAL,
+        dst,
+        ImageLayout::TRANSFER_DST_OPTIMAL,
+        &[ImageBlit::builder()
+            .src_subresource(subresource)
+            .src_offsets([Offset3D::default(), corner(src_extent)])
+            .dst_subresource(subresource)
+            .dst_offsets([Offset3D::default(), corner(dst_extent)])
+            .build()],
+        filter,
+    );
+    d.cmd_pipeline_barrier(
+        command_buffer,
+        PipelineStageFlags::TRANSFER,
+        PipelineStageFlags::BOTTOM_OF_PIPE,
+        DependencyFlags::empty(),
+        &[],
+        &[],
+        &[color_barrier(
+            dst,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            ImageLayout::PRESENT_SRC_KHR,
+            AccessFlags::TRANSFER_WRITE,
+            AccessFlags::empty(),
+        )],
+    );
+}
+
 impl Context {
     pub fn pre_render_window(&mut self) -> Result<PreRenderInfoWindow> {
+        if self.window.minimized {
+            return Ok(PreRenderInfoWindow {
+                image_index: None,
+                image_acquired_semaphore: Semaphore::null(),
+            });
+        }
+
         // prepare semaphore
         let image_acquired_semaphore =
             self.window.semaphores_image_acquired[self.window.last_used_acquire_semaphore];
@@ -21,41 +137,88 @@ impl Context {
         self.window.last_used_acquire_semaphore %= self.window.semaphores_image_acquired.len();
 
         // acuire image
-        let (image_index, _suboptimal) = unsafe {
-            self.window.swapchain.loader.acquire_next_image(
-                self.window.swapchain.handle,
-                std::u64::MAX, // don't timeout
-                image_acquired_semaphore,
-                ash::vk::Fence::default(),
-            )
-        }?;
+        let acquired = unsafe {
+            self.window
+                .size_dependent
+                .swapchain
+                .loader
+                .acquire_next_image(
+                    self.window.size_dependent.swapchain.handle,
+                    std::u64::MAX, // don't timeout
+                    image_acquired_semaphore,
+                    ash::vk::Fence::default(),
+                )
+        };
+        let image_index = match acquired {
+            // still usable, the image is presented and the resize happens after
+            Ok((image_index, suboptimal)) => {
+                self.window.out_of_date |= suboptimal;
+                image_index
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                log::info!("Window swapchain out of date");
+                self.window.out_of_date = true;
+                return Ok(PreRenderInfoWindow {
+                    image_index: None,
+                    image_acquired_semaphore,
+                });
+            }
+            Err(e) => return self.vulkan.check_lost(Err(e)),
+        };
 
         Ok(PreRenderInfoWindow {
-            image_index,
+            image_index: Some(image_index),
             image_acquired_semaphore,
         })
     }
 
+    // set by pre_render_window and post_render_window, cleared by resizing
+    pub fn window_needs_resize(&self) -> bool {
+        self.window.out_of_date
+    }
+
+    fn window_outcome(&self, presented: bool) -> RenderOutcome {
+        if self.window.out_of_date {
+            RenderOutcome::NeedsResize
+        } else if presented {
+            RenderOutcome::Presented
+        } else {
+            RenderOutcome::Skipped
+        }
+    }
+
     pub fn post_render_window(
-        &self,
+        &mut self,
         pre_render_info: PreRenderInfoWindow,
         wait_semaphores: &[Semaphore],
-    ) -> Result<()> {
-        unsafe {
-            let _suboptimal = self.window.swapchain.loader.queue_present(
+    ) -> Result<RenderOutcome> {
+        let image_index = match pre_render_info.image_index {
+            Some(image_index) => image_index,
+            None => return Ok(self.window_outcome(false)),
+        };
+        let presented = unsafe {
+            self.window.size_dependent.swapchain.loader.queue_present(
                 self.vulkan.queue,
                 &PresentInfoKHR::builder()
                     .wait_semaphores(wait_semaphores)
-                    .swapchains(&[self.window.swapchain.handle])
-                    .image_indices(&[pre_render_info.image_index]),
-            )?;
+                    .swapchains(&[self.window.size_dependent.swapchain.handle])
+                    .image_indices(&[image_index]),
+            )
+        };
+        match presented {
+            Ok(suboptimal) => self.window.out_of_date |= suboptimal,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                log::info!("Window swapchain out of date");
+                self.window.out_of_date = true;
+            }
+            Err(e) => return self.vulkan.check_lost(Err(e)),
         }
 
-        Ok(())
+        Ok(self.window_outcome(true))
     }
 
     pub fn render_window(
-        &self,
+        &mut self,
         pre_render_info: PreRenderInfoWindow,
         pipeline_layout: PipelineLayout,
         pipeline: Pipeline,
@@ -64,93 +227,226 @@ impl Context {
         command_buffer: CommandBuffer,
         rendering_finished_fence: Fence,
         rendering_finished_semaphore: Semaphore,
-    ) -> Result<()> {
+    ) -> Result<RenderOutcome> {
+        self.render_window_draws(
+            pre_render_info,
+            pipeline_layout,
+            pipeline,
+            [(mesh, descriptor_set, mesh.full_range(), None)],
+            command_buffer,
+            rendering_finished_fence,
+            rendering_finished_semaphore,
+        )
+    }
+
+    // see record_hmd_scene, packed meshes are drawn with DrawCall::packed
+    pub fn render_window_scene(
+        &mut self,
+        pre_render_info: PreRenderInfoWindow,
+        pipeline_layout: PipelineLayout,
+        pipeline: Pipeline,
+        draws: &[DrawCall],
+        command_buffer: CommandBuffer,
+        rendering_finished_fence: Fence,
+        rendering_finished_semaphore: Semaphore,
+    ) -> Result<RenderOutcome> {
+        self.render_window_draws(
+            pre_render_info,
+            pipeline_layout,
+            pipeline,
+            draws.iter().map(|draw| {
+                (
+                    draw.mesh,
+                    draw.descriptor_set,
+                    draw.draw_range(),
+                    draw.pipeline,
+                )
+            }),
+            command_buffer,
+            rendering_finished_fence,
+            rendering_finished_semaphore,
+        )
+    }
+
+    fn render_window_draws<'a>(
+        &mut self,
+        pre_render_info: PreRenderInfoWindow,
+        pipeline_layout: PipelineLayout,
+        pipeline: Pipeline,
+        draws: impl IntoIterator<Item = (&'a MeshBuffers, DescriptorSet, DrawRange, Option<Pipeline>)>,
+        command_buffer: CommandBuffer,
+        rendering_finished_fence: Fence,
+        rendering_finished_semaphore: Semaphore,
+    ) -> Result<RenderOutcome> {
         let PreRenderInfoWindow {
             image_index,
             image_acquired_semaphore,
         } = pre_render_info;
 
+        // nothing was acquired while minimized or out of date
+        let image_index = match image_index {
+            Some(image_index) => image_index,
+            None => return Ok(self.window_outcome(false)),
+        };
+
         // get the other stuff now that we know the index
-        let frame_buffer = self.window.swapchain.elements[image_index as usize].frame_buffer;
+        let element = &self.window.size_dependent.swapchain.elements[image_index as usize];
+        let frame_buffer = element.frame_buffer;
+        let swapchain_image = element.image;
 
         // for convenience
-        let extent = self.window.swapchain.extent;
+        let extent = self.window.size_dependent.render_extent;
+        let offscreen = self
+            .window
+            .size_dependent
+            .offscreen
+            .as_ref()
+            .map(|o| o.image);
+        let render_pass = if offscreen.is_some() {
+            self.window.render_pass_supersampled
+        } else {
+            self.window.render_pass
+        };
+        // replaces the render pass, see set_window_mirror
+        let mirror = match &self.hmd {
+            Some(hmd) if self.window.size_dependent.can_blit => hmd
+                .mirror
+                .as_ref()
+                .map(|mirror| (mirror.image.image, mirror.extent, mirror.filter)),
+            _ => None,
+        };
         unsafe {
             let d = &self.vulkan.device;
 
             d.reset_command_buffer(command_buffer, CommandBufferResetFlags::RELEASE_RESOURCES)?;
             d.begin_command_buffer(command_buffer, &CommandBufferBeginInfo::builder())?;
-            d.cmd_begin_render_pass(
-                command_buffer,
-                &RenderPassBeginInfo::builder()
-                    .render_pass(self.window.render_pass)
-                    .framebuffer(frame_buffer)
-                    .render_area(*Rect2D::builder().extent(extent))
-                    .clear_values(&[
-                        ClearValue {
-                            color: ClearColorValue::default(),
-                        },
-                        ClearValue {
-                            depth_stencil: ClearDepthStencilValue {
-                                depth: 1.0,
-                                stencil: 0,
+            if let Some((mirror_image, mirror_extent, mirror_filter)) = mirror {
+                record_blit_to_swapchain(
+                    d,
+                    command_buffer,
+                    mirror_image,
+                    mirror_extent,
+                    swapchain_image,
+                    self.window.size_dependent.swapchain.extent,
+                    mirror_filter,
+                );
+            } else {
+                d.cmd_begin_render_pass(
+                    command_buffer,
+                    &RenderPassBeginInfo::builder()
+                        .render_pass(render_pass)
+                        .framebuffer(frame_buffer)
+                        .render_area(*Rect2D::builder().extent(extent))
+                        .clear_values(&[
+                            ClearValue {
+                                color: ClearColorValue {
+                                    float32: self.window.clear_color,
+                                },
+                            },
+                            ClearValue {
+                                depth_stencil: ClearDepthStencilValue {
+                                    depth: self.vulkan.depth_clear_value(),
+                                    stencil: 0,
+                                },
                             },
-                        },
-                    ]),
-                SubpassContents::INLINE,
-            );
-            d.cmd_bind_pipeline(command_buffer, PipelineBindPoint::GRAPHICS, pipeline);
-
-            // set this here so we don't have to recreate pipeline on window resize
-            d.cmd_set_viewport(
-                command_buffer,
-                0,
-                &[Viewport::builder()
-                    .x(0.0)
-                    .y(0.0)
-                    .width(extent.width as f32)
-                    .height(extent.height as f32)
-                    .min_depth(0.0)
-                    .max_depth(1.0)
-                    .build()],
-            );
-            d.cmd_set_scissor(
-                command_buffer,
-                0,
-                &[Rect2D::builder()
-                    .offset(Offset2D { x: 0, y: 0 })
-                    .extent(extent)
-                    .build()],
-            );
-
-            d.cmd_bind_vertex_buffers(command_buffer, 0, &[mesh.vertex.handle()], &[0]);
-            d.cmd_bind_index_buffer(command_buffer, mesh.index.handle(), 0, IndexType::UINT32);
-            d.cmd_bind_descriptor_sets(
-                command_buffer,
-                PipelineBindPoint::GRAPHICS,
-                pipeline_layout,
-                0,
-                &[descriptor_set],
-                &[],
-            );
-            d.cmd_draw_indexed(command_buffer, mesh.num_indices() as u32, 1, 0, 0, 0);
-            d.cmd_end_render_pass(command_buffer);
+                        ]),
+                    SubpassContents::INLINE,
+                );
+                d.cmd_bind_pipeline(command_buffer, PipelineBindPoint::GRAPHICS, pipeline);
+
+                // set this here so we don't have to recreate pipeline on window resize
+                d.cmd_set_viewport(
+                    command_buffer,
+                    0,
+                    &[Viewport::builder()
+                        .x(0.0)
+                        .y(0.0)
+                        .width(extent.width as f32)
+                        .height(extent.height as f32)
+                        .min_depth(0.0)
+                        .max_depth(1.0)
+                        .build()],
+                );
+                d.cmd_set_scissor(
+                    command_buffer,
+                    0,
+                    &[Rect2D::builder()
+                        .offset(Offset2D { x: 0, y: 0 })
+                        .extent(extent)
+                        .build()],
+                );
+
+                record_draws(d, command_buffer, pipeline, pipeline_layout, draws);
+                d.cmd_end_render_pass(command_buffer);
+                if let Some(offscreen) = offscreen {
+                    record_blit_to_swapchain(
+                        d,
+                        command_buffer,
+                        offscreen,
+                        extent,
+                        swapchain_image,
+                        self.window.size_dependent.swapchain.extent,
+                        self.window.size_dependent.blit_filter,
+                    );
+                }
+            }
             d.end_command_buffer(command_buffer)?;
 
-            self.vulkan.device.queue_submit(
+            let mut wait_semaphores = vec![image_acquired_semaphore];
+            // when supersampling or mirroring, the swapchain image is first written by the blit
+            let mut wait_stages = vec![if offscreen.is_some() || mirror.is_some() {
+                PipelineStageFlags::TRANSFER
+            } else {
+                PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            }];
+            // ignored for binary semaphores, but one value per wait semaphore is needed
+            let mut wait_values = vec![0];
+            // consume the signal even if waiting was disabled in the meantime
+            let mut uses_timeline = false;
+            if let Some(hmd) = &mut self.hmd {
+                if hmd.window_dependency_pending {
+                    wait_semaphores.push(hmd.semaphore_window_dependency);
+                    wait_stages.push(PipelineStageFlags::ALL_COMMANDS);
+                    wait_values.push(0);
+                    hmd.window_dependency_pending = false;
+                }
+                // nothing to wait for before the first HMD submission
+                if let Some(timeline) = hmd.timeline {
+                    uses_timeline = true;
+                    if (self.window.wait_for_hmd || mirror.is_some()) && hmd.timeline_value > 0 {
+                        wait_semaphores.push(timeline);
+                        wait_stages.push(PipelineStageFlags::ALL_COMMANDS);
+                        wait_values.push(hmd.timeline_value);
+                    }
+                }
+            }
+
+            let mut timeline_info =
+                TimelineSemaphoreSubmitInfo::builder().wait_semaphore_values(&wait_values);
+            let command_buffers = [command_buffer];
+            let signal_semaphores = [rendering_finished_semaphore];
+            let mut submit_info = SubmitInfo::builder()
+                .command_buffers(&command_buffers)
+                .wait_semaphores(&wait_semaphores)
+                .wait_dst_stage_mask(&wait_stages)
+                .signal_semaphores(&signal_semaphores);
+            // the struct isn't allowed without the feature
+            if uses_timeline {
+                submit_info = submit_info.push_next(&mut timeline_info);
+            }
+
+            // reset as late as possible, an early return above must leave the fence signaled
+            // or the next wait on it, e.g. in FrameRing::next, would never return
+            self.vulkan
+                .device
+                .reset_fences(&[rendering_finished_fence])?;
+            self.vulkan.check_lost(self.vulkan.device.queue_submit(
                 self.vulkan.queue,
-                &[SubmitInfo::builder()
-                    .command_buffers(&[command_buffer])
-                    .wait_semaphores(&[image_acquired_semaphore])
-                    .wait_dst_stage_mask(&[PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
-                    .signal_semaphores(&[rendering_finished_semaphore])
-                    .build()],
+                &[submit_info.build()],
                 rendering_finished_fence,
-            )?;
+            ))?;
 
-            self.post_render_window(pre_render_info, &[rendering_finished_semaphore])?;
+            self.post_render_window(pre_render_info, &[rendering_finished_semaphore])
         }
-
-        Ok(())
     }
 }
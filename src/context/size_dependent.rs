@@ -0,0 +1,197 @@
+use anyhow::Result;
+use ash::vk::{
+    Extent2D, Filter, Format, FormatFeatureFlags, ImageAspectFlags, ImageTiling, ImageUsageFlags,
+    MemoryPropertyFlags, RenderPass, SampleCountFlags, SwapchainKHR,
+};
+
+use crate::wrap_vulkan::{
+    self, context::depth_aspect_flags, device_image::DeviceImageSettings, surface::Detail,
+    DeviceImage,
+};
+
+use super::swapchain::{PresentModePreference, SwapchainWindow};
+
+// the render extent has to fit into a single image / framebuffer
+fn supersampled_extent(context: &wrap_vulkan::Context, extent: Extent2D, factor: f32) -> Extent2D {
+    let limits = unsafe {
+        context
+            .instance
+            .get_physical_device_properties(context.physical_device)
+    }
+    .limits;
+    let scale = |size: u32, max: u32| {
+        ((size as f32 * factor).round() as u32).clamp(1, max.min(limits.max_image_dimension2_d))
+    };
+    Extent2D {
+        width: scale(extent.width, limits.max_framebuffer_width),
+        height: scale(extent.height, limits.max_framebuffer_height),
+    }
+}
+
+fn optimal_tiling_features(context: &wrap_vulkan::Context, format: Format) -> FormatFeatureFlags {
+    unsafe {
+        context
+            .instance
+            .get_physical_device_format_properties(context.physical_device, format)
+    }
+    .optimal_tiling_features
+}
+
+fn blit_supported(context: &wrap_vulkan::Context, format: Format) -> bool {
+    optimal_tiling_features(context, format)
+        .contains(FormatFeatureFlags::BLIT_SRC | FormatFeatureFlags::BLIT_DST)
+}
+
+// scaling blits filter linearly only if the source format supports it
+pub fn blit_filter(context: &wrap_vulkan::Context, src_format: Format) -> Filter {
+    if optimal_tiling_features(context, src_format)
+        .contains(FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+    {
+        Filter::LINEAR
+    } else {
+        Filter::NEAREST
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SizeDependentSettings {
+    // 1.0 renders directly into the swapchain
+    pub supersample: f32,
+    pub present_mode: PresentModePreference,
+}
+
+impl Default for SizeDependentSettings {
+    fn default() -> Self {
+        Self {
+            supersample: 1.0,
+            present_mode: PresentModePreference::default(),
+        }
+    }
+}
+
+// Everything that has to follow the window size. It is only ever recreated as a whole,
+// so there is no state where some images have the old size and some the new one.
+// Field order matters, the frame buffers in the swapchain go before their attachments.
+pub struct SizeDependent {
+    pub swapchain: SwapchainWindow,
+    // differs from the swapchain extent when supersampling
+    pub render_extent: Extent2D,
+    pub depth_image: DeviceImage,
+    // rendered to instead of the swapchain images when supersampling
+    pub offscreen: Option<DeviceImage>,
+    // the swapchain images can be blitted to, needed for supersampling and mirroring
+    pub can_blit: bool,
+    // for blitting the offscreen image, see blit_filter
+    pub blit_filter: Filter,
+}
+
+impl SizeDependent {
+    pub fn new(
+        context: &wrap_vulkan::Context,
+        render_pass: RenderPass,
+        render_pass_supersampled: RenderPass,
+        wanted: Extent2D,
+        old_swapchain: SwapchainKHR,
+        settings: &SizeDependentSettings,
+        // already clamped, has to match the render passes
+        samples: SampleCountFlags,
+    ) -> Result<Self> {
+        let depth_format = context.find_supported_depth_stencil_format()?;
+        let extent = context.get_allowed_extend(wanted)?;
+
+        let Detail {
+            capabilities,
+            format,
+            ..
+        } = context.window_surface_related.get_detail(context)?;
+
+        let can_blit = capabilities
+            .supported_usage_flags
+            .contains(ImageUsageFlags::TRANSFER_DST)
+            && blit_supported(context, format.format);
+
+        // the offscreen image is blitted to the swapchain image
+        let supersample = settings.supersample != 1.0
+            && if !can_blit {
+                log::warn!("Window surface doesn't support blitting, not supersampling");
+                false
+            } else {
+                true
+            };
+
+        let render_extent = if supersample {
+            supersampled_extent(context, extent, settings.supersample)
+        } else {
+            extent
+        };
+
+        let depth_image = DeviceImage::new(
+            context,
+            DeviceImageSettings {
+                extent: render_extent,
+                format: depth_format,
+                tiling: ImageTiling::OPTIMAL,
+                usage: ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                properties: MemoryPropertyFlags::DEVICE_LOCAL,
+                aspect_flags: depth_aspect_flags(depth_format),
+                layer_count: 1,
+                view_type: None,
+                mip_levels: 1,
+                samples,
+                name: "WindowDepth".to_string(),
+            },
+        )?;
+
+        let offscreen = if supersample {
+            Some(DeviceImage::new(
+                context,
+                DeviceImageSettings {
+                    extent: render_extent,
+                    format: format.format,
+                    tiling: ImageTiling::OPTIMAL,
+                    usage: ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::TRANSFER_SRC,
+                    properties: MemoryPropertyFlags::DEVICE_LOCAL,
+                    aspect_flags: ImageAspectFlags::COLOR,
+                    layer_count: 1,
+                    view_type: None,
+                    mip_levels: 1,
+                    samples: SampleCountFlags::TYPE_1,
+                    name: "WindowOffscreen".to_string(),
+                },
+            )?)
+        } else {
+            None
+        };
+
+        let swapchain = SwapchainWindow::new(
+            context,
+            if supersample {
+                render_pass_supersampled
+            } else {
+                render_pass
+            },
+            extent,
+            old_swapchain,
+            if can_blit {
+                ImageUsageFlags::TRANSFER_DST
+            } else {
+                ImageUsageFlags::empty()
+            },
+            offscreen.as_ref().map(|offscreen| offscreen.view),
+            depth_image.view,
+            render_extent,
+            settings.present_mode,
+            samples,
+        )?;
+
+        Ok(Self {
+            swapchain,
+            render_extent,
+            depth_image,
+            offscreen,
+            can_blit,
+            // the offscreen image has the swapchain's format
+            blit_filter: blit_filter(context, format.format),
+        })
+    }
+}
@@ -1,10 +1,13 @@
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, Result};
 use ash::{
     extensions::khr::Swapchain,
     vk::{
-        CompositeAlphaFlagsKHR, Extent2D, Framebuffer, FramebufferCreateInfo, Handle, Image,
-        ImageAspectFlags, ImageTiling, ImageUsageFlags, ImageView, MemoryPropertyFlags,
-        PresentModeKHR, RenderPass, SharingMode, SwapchainCreateInfoKHR, SwapchainKHR,
+        AccessFlags, ClearDepthStencilValue, CommandBuffer, CompositeAlphaFlagsKHR,
+        DependencyFlags, Extent2D, Format, Framebuffer, FramebufferCreateInfo, Handle, Image,
+        ImageAspectFlags, ImageLayout, ImageMemoryBarrier, ImageSubresourceRange, ImageTiling,
+        ImageUsageFlags, ImageView, MemoryPropertyFlags, PipelineStageFlags, PresentModeKHR,
+        RenderPass, SampleCountFlags, SharingMode, SwapchainCreateInfoKHR, SwapchainKHR,
+        QUEUE_FAMILY_IGNORED,
     },
     Device,
 };
@@ -13,7 +16,10 @@ use openxr::{Session, Vulkan};
 
 use crate::{
     wrap_openxr,
-    wrap_vulkan::{self, device_image::DeviceImageSettings, surface::Detail, DeviceImage},
+    wrap_vulkan::{
+        self, context::depth_aspect_flags, device_image::DeviceImageSettings, surface::Detail,
+        DeviceImage,
+    },
 };
 
 pub struct SwapElement {
@@ -22,12 +28,58 @@ pub struct SwapElement {
     pub frame_buffer: Framebuffer,
 }
 
+// FIFO is the fallback for all of them, it's the only mode the spec guarantees
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PresentModePreference {
+    // IMMEDIATE, then MAILBOX, so the window never blocks the HMD rendering (tears)
+    LowLatency,
+    // MAILBOX, then IMMEDIATE, no tearing but still doesn't block
+    Mailbox,
+    // FIFO only, blocks on the display's refresh rate
+    VSync,
+}
+
+impl Default for PresentModePreference {
+    fn default() -> Self {
+        Self::LowLatency
+    }
+}
+
+impl PresentModePreference {
+    fn candidates(&self) -> &'static [PresentModeKHR] {
+        match self {
+            Self::LowLatency => &[
+                PresentModeKHR::IMMEDIATE,
+                PresentModeKHR::MAILBOX,
+                PresentModeKHR::FIFO,
+            ],
+            Self::Mailbox => &[
+                PresentModeKHR::MAILBOX,
+                PresentModeKHR::IMMEDIATE,
+                PresentModeKHR::FIFO,
+            ],
+            Self::VSync => &[PresentModeKHR::FIFO],
+        }
+    }
+
+    fn choose(&self, available: &[PresentModeKHR]) -> PresentModeKHR {
+        self.candidates()
+            .iter()
+            .copied()
+            .find(|mode| available.contains(mode))
+            .unwrap_or(PresentModeKHR::FIFO)
+    }
+}
+
 pub struct SwapchainWindow {
     pub extent: Extent2D,
-    pub depth_image: DeviceImage,
+    // chosen from the PresentModePreference
+    pub present_mode: PresentModeKHR,
     pub loader: Swapchain,
     pub handle: SwapchainKHR,
     pub elements: Vec<SwapElement>,
+    // transient, resolved into the swapchain image (or the color override) by the render pass
+    pub msaa: Option<DeviceImage>,
     device: Device,
 }
 
@@ -43,11 +95,91 @@ impl Drop for SwapchainWindow {
     }
 }
 
+// OpenXR wants acquire -> wait -> release, with only one image acquired at a time
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageState {
+    Released,
+    Acquired,
+    Waited,
+}
+
+// The calls the state machine makes on an OpenXR swapchain, a mock stands in for tests
+pub(crate) trait ImageSequence {
+    fn acquire(&mut self) -> Result<u32>;
+    fn wait(&mut self) -> Result<()>;
+    fn release(&mut self) -> Result<()>;
+}
+
+impl ImageSequence for openxr::Swapchain<Vulkan> {
+    fn acquire(&mut self) -> Result<u32> {
+        Ok(self.acquire_image()?)
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        Ok(self.wait_image(openxr::Duration::INFINITE)?)
+    }
+
+    fn release(&mut self) -> Result<()> {
+        Ok(self.release_image()?)
+    }
+}
+
+impl ImageState {
+    // A frame that was abandoned (early return, error) leaves the image acquired,
+    // this finishes the sequence so the next acquire doesn't fail.
+    pub(crate) fn release_pending(&mut self, images: &mut impl ImageSequence) -> Result<()> {
+        if *self == ImageState::Released {
+            return Ok(());
+        }
+        log::warn!("HMD swapchain image wasn't released, releasing it now");
+        self.release(images)
+    }
+
+    pub(crate) fn acquire(&mut self, images: &mut impl ImageSequence) -> Result<u32> {
+        self.release_pending(images)?;
+        let image_index = images.acquire()?;
+        *self = ImageState::Acquired;
+        Ok(image_index)
+    }
+
+    pub(crate) fn wait(&mut self, images: &mut impl ImageSequence) -> Result<()> {
+        match self {
+            ImageState::Released => bail!("Can't wait on HMD image that wasn't acquired"),
+            ImageState::Waited => Ok(()),
+            ImageState::Acquired => {
+                images.wait()?;
+                *self = ImageState::Waited;
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn release(&mut self, images: &mut impl ImageSequence) -> Result<()> {
+        if *self == ImageState::Released {
+            bail!("Can't release HMD image that wasn't acquired");
+        }
+        // releasing before waiting isn't allowed
+        self.wait(images)?;
+        images.release()?;
+        *self = ImageState::Released;
+        Ok(())
+    }
+}
+
+// Layout contract: OpenXR hands out an acquired image in COLOR_ATTACHMENT_OPTIMAL with
+// undefined contents. The crate's HMD render passes start from UNDEFINED and clear, so nothing
+// has to be done. To keep contents with a LOAD pass (create_render_pass_hmd_load), record
+// cmd_transition_acquired around whatever writes the image first, and cmd_clear_depth_only.
 pub struct SwapchainHMD {
     pub extent: Extent2D,
+    // array layers of the images, one per view
+    pub view_count: u32,
+    pub format: Format,
     pub swapchain: openxr::Swapchain<Vulkan>,
     pub depth_image: DeviceImage,
+    pub depth_format: Format,
     pub elements: Vec<SwapElement>,
+    image_state: ImageState,
     device: Device,
 }
 
@@ -65,41 +197,40 @@ impl Drop for SwapchainHMD {
 }
 
 impl SwapchainWindow {
+    // The attachments are owned by the caller (see SizeDependent), color_override
+    // replaces the swapchain images as color attachment, e.g. an offscreen image.
+    // With more than one sample the render pass resolves into those instead.
     pub fn new(
         context: &wrap_vulkan::Context,
         render_pass: RenderPass,
-        wanted: Extent2D,
+        extent: Extent2D,
         old_swapchain: SwapchainKHR,
+        extra_usage: ImageUsageFlags,
+        color_override: Option<ImageView>,
+        depth_view: ImageView,
+        render_extent: Extent2D,
+        present_mode_preference: PresentModePreference,
+        samples: SampleCountFlags,
     ) -> Result<Self> {
-        let depth_format = context.find_supported_depth_stencil_format()?;
-        let extent = context.get_allowed_extend(wanted)?;
-
-        let depth_image = DeviceImage::new(
-            context,
-            DeviceImageSettings {
-                extent: extent,
-                format: depth_format,
-                tiling: ImageTiling::OPTIMAL,
-                usage: ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-                properties: MemoryPropertyFlags::DEVICE_LOCAL,
-                aspect_flags: ImageAspectFlags::DEPTH,
-                layer_count: 1,
-                name: "WindowDepth".to_string(),
-            },
-        )?;
-
         let Detail {
             capabilities,
             present_modes,
             image_count,
             format,
         } = context.window_surface_related.get_detail(context)?;
+        log::info!(
+            "Window surface format: {:?} {:?}",
+            format.format,
+            format.color_space
+        );
 
-        // we don't want the window to block our rendering
-        let present_mode = *present_modes
-            .iter()
-            .find(|&&m| m == PresentModeKHR::IMMEDIATE)
-            .ok_or(Error::msg("No suitable present mode"))?;
+        let present_mode = present_mode_preference.choose(&present_modes);
+        log::info!(
+            "Window present mode {:?} for {:?}, available: {:?}",
+            present_mode,
+            present_mode_preference,
+            present_modes
+        );
         let loader = Swapchain::new(&context.instance, &context.device);
         let handle = unsafe {
             loader.create_swapchain(
@@ -109,7 +240,7 @@ impl SwapchainWindow {
                     .image_color_space(format.color_space)
                     .image_format(format.format)
                     .image_extent(extent)
-                    .image_usage(ImageUsageFlags::COLOR_ATTACHMENT)
+                    .image_usage(ImageUsageFlags::COLOR_ATTACHMENT | extra_usage)
                     .image_sharing_mode(SharingMode::EXCLUSIVE) // change this if present queue fam. differs
                     .pre_transform(capabilities.current_transform)
                     .composite_alpha(CompositeAlphaFlagsKHR::OPAQUE)
@@ -132,6 +263,28 @@ impl SwapchainWindow {
             bail!("Somehow the number of images in the swapchain doesn't add up");
         }
 
+        let msaa = if samples != SampleCountFlags::TYPE_1 {
+            Some(DeviceImage::new(
+                context,
+                DeviceImageSettings {
+                    extent: render_extent,
+                    format: format.format,
+                    tiling: ImageTiling::OPTIMAL,
+                    usage: ImageUsageFlags::COLOR_ATTACHMENT
+                        | ImageUsageFlags::TRANSIENT_ATTACHMENT,
+                    properties: MemoryPropertyFlags::DEVICE_LOCAL,
+                    aspect_flags: ImageAspectFlags::COLOR,
+                    layer_count: 1,
+                    view_type: None,
+                    mip_levels: 1,
+                    samples,
+                    name: "WindowMSAA".to_string(),
+                },
+            )?)
+        } else {
+            None
+        };
+
         let elements = (0..images.len())
             .into_iter()
             .map(|i| -> Result<SwapElement> {
@@ -142,16 +295,23 @@ impl SwapchainWindow {
                     format.format,
                     ImageAspectFlags::COLOR,
                     1,
+                    1,
                     format!("WindowSwapchainView_{}", i),
                 )?;
 
+                // all frame buffers share an override, like the depth image
+                let target = color_override.unwrap_or(view);
+                let attachments = match &msaa {
+                    Some(msaa) => vec![msaa.view, depth_view, target],
+                    None => vec![target, depth_view],
+                };
                 let frame_buffer = unsafe {
                     context.device.create_framebuffer(
                         &FramebufferCreateInfo::builder()
                             .render_pass(render_pass)
-                            .attachments(&[view, depth_image.view])
-                            .width(extent.width)
-                            .height(extent.height)
+                            .attachments(&attachments)
+                            .width(render_extent.width)
+                            .height(render_extent.height)
                             .layers(1),
                         None,
                     )?
@@ -168,10 +328,11 @@ impl SwapchainWindow {
 
         Ok(Self {
             extent,
-            depth_image,
+            present_mode,
             loader,
             handle,
             elements,
+            msaa,
             device: context.device.clone(),
         })
     }
@@ -183,23 +344,33 @@ impl SwapchainHMD {
         vk_context: &wrap_vulkan::Context,
         render_pass: RenderPass,
         session: &Session<Vulkan>,
+        desc: &wrap_openxr::SwapchainDesc,
+        extent: Extent2D,
     ) -> Result<Self> {
-        let extent = xr_context.get_resolution()?;
+        let view_count = xr_context.get_view_count()?;
 
         let format = vk_context.find_supported_color_format()?;
+        let depth_format = vk_context.find_supported_depth_stencil_format()?;
 
-        let swapchain = wrap_openxr::Context::get_swapchain(session, extent, format)?;
+        let swapchain = xr_context.get_swapchain(session, extent, format, desc)?;
 
         let depth_image = DeviceImage::new(
             vk_context,
             DeviceImageSettings {
                 extent: extent,
-                format: vk_context.find_supported_depth_stencil_format()?,
+                format: depth_format,
                 tiling: ImageTiling::OPTIMAL,
-                usage: ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                // copied into the runtime's depth swapchain, see DepthLayerHMD,
+                // and cleared outside of a render pass, see cmd_clear_depth_only
+                usage: ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+                    | ImageUsageFlags::TRANSFER_SRC
+                    | ImageUsageFlags::TRANSFER_DST,
                 properties: MemoryPropertyFlags::DEVICE_LOCAL,
-                aspect_flags: ImageAspectFlags::DEPTH,
-                layer_count: 2,
+                aspect_flags: depth_aspect_flags(depth_format),
+                layer_count: view_count,
+                view_type: None,
+                mip_levels: 1,
+                samples: SampleCountFlags::TYPE_1,
                 name: "HMDDepth".to_string(),
             },
         )?;
@@ -217,7 +388,8 @@ impl SwapchainHMD {
                     image,
                     format,
                     ImageAspectFlags::COLOR,
-                    2,
+                    view_count,
+                    1,
                     format!("HMDSwapchainView_{}", i),
                 )?;
 
@@ -244,10 +416,216 @@ impl SwapchainHMD {
 
         Ok(Self {
             extent,
+            view_count,
+            format,
             swapchain,
             depth_image,
+            depth_format,
             elements,
+            image_state: ImageState::Released,
             device: vk_context.device.clone(),
         })
     }
+
+    pub fn image_state(&self) -> ImageState {
+        self.image_state
+    }
+
+    // All views of an acquired image, e.g. from COLOR_ATTACHMENT_OPTIMAL to TRANSFER_DST_OPTIMAL
+    // for a copy and back before a LOAD pass. Only record it after wait_image.
+    pub unsafe fn cmd_transition_acquired(
+        &self,
+        command_buffer: CommandBuffer,
+        image_index: u32,
+        old_layout: ImageLayout,
+        new_layout: ImageLayout,
+    ) {
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            // not worth narrowing down, this happens once per frame at most
+            PipelineStageFlags::ALL_COMMANDS,
+            PipelineStageFlags::ALL_COMMANDS,
+            DependencyFlags::empty(),
+            &[],
+            &[],
+            &[ImageMemoryBarrier::builder()
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(QUEUE_FAMILY_IGNORED)
+                .image(self.elements[image_index as usize].image)
+                .subresource_range(
+                    ImageSubresourceRange::builder()
+                        .aspect_mask(ImageAspectFlags::COLOR)
+                        .level_count(1)
+                        .layer_count(self.view_count)
+                        .build(),
+                )
+                .src_access_mask(AccessFlags::MEMORY_WRITE)
+                .dst_access_mask(AccessFlags::MEMORY_READ | AccessFlags::MEMORY_WRITE)
+                .build()],
+        );
+    }
+
+    // Clears the shared depth image for a LOAD pass and leaves it in
+    // DEPTH_STENCIL_ATTACHMENT_OPTIMAL. Formats without stencil only touch the depth aspect.
+    pub unsafe fn cmd_clear_depth_only(&self, command_buffer: CommandBuffer, depth: f32) {
+        let range = ImageSubresourceRange::builder()
+            .aspect_mask(depth_aspect_flags(self.depth_format))
+            .level_count(1)
+            .layer_count(self.view_count)
+            .build();
+        let barrier = |old_layout, new_layout, src_access_mask, dst_access_mask| {
+            ImageMemoryBarrier::builder()
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(QUEUE_FAMILY_IGNORED)
+                .image(self.depth_image.image)
+                .subresource_range(range)
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .build()
+        };
+        let depth_tests =
+            PipelineStageFlags::EARLY_FRAGMENT_TESTS | PipelineStageFlags::LATE_FRAGMENT_TESTS;
+
+        // the previous frame's pass and depth layer copy are done with it
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            depth_tests | PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::TRANSFER,
+            DependencyFlags::empty(),
+            &[],
+            &[],
+            &[barrier(
+                ImageLayout::UNDEFINED,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                AccessFlags::TRANSFER_WRITE,
+            )],
+        );
+        self.device.cmd_clear_depth_stencil_image(
+            command_buffer,
+            self.depth_image.image,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            &ClearDepthStencilValue { depth, stencil: 0 },
+            &[range],
+        );
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            PipelineStageFlags::TRANSFER,
+            depth_tests,
+            DependencyFlags::empty(),
+            &[],
+            &[],
+            &[barrier(
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                AccessFlags::TRANSFER_WRITE,
+                AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )],
+        );
+    }
+
+    // see ImageState::release_pending
+    pub fn release_pending(&mut self) -> Result<()> {
+        self.image_state.release_pending(&mut self.swapchain)
+    }
+
+    pub fn acquire_image(&mut self) -> Result<u32> {
+        self.image_state.acquire(&mut self.swapchain)
+    }
+
+    pub fn wait_image(&mut self) -> Result<()> {
+        self.image_state.wait(&mut self.swapchain)
+    }
+
+    pub fn release_image(&mut self) -> Result<()> {
+        self.image_state.release(&mut self.swapchain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockImages {
+        calls: Vec<&'static str>,
+        // the next wait times out
+        fail_wait: bool,
+    }
+
+    impl ImageSequence for MockImages {
+        fn acquire(&mut self) -> Result<u32> {
+            self.calls.push("acquire");
+            Ok(0)
+        }
+
+        fn wait(&mut self) -> Result<()> {
+            self.calls.push("wait");
+            if std::mem::take(&mut self.fail_wait) {
+                bail!("Timed out");
+            }
+            Ok(())
+        }
+
+        fn release(&mut self) -> Result<()> {
+            self.calls.push("release");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn skipped_release_is_finished_before_acquiring() {
+        let mut images = MockImages::default();
+        let mut state = ImageState::Released;
+        state.acquire(&mut images).unwrap();
+        state.wait(&mut images).unwrap();
+        // the frame returned early here
+        state.acquire(&mut images).unwrap();
+        assert_eq!(state, ImageState::Acquired);
+        assert_eq!(images.calls, ["acquire", "wait", "release", "acquire"]);
+    }
+
+    #[test]
+    fn failed_wait_is_retried_before_the_next_acquire() {
+        let mut images = MockImages {
+            fail_wait: true,
+            ..Default::default()
+        };
+        let mut state = ImageState::Released;
+        state.acquire(&mut images).unwrap();
+        assert!(state.wait(&mut images).is_err());
+        assert_eq!(state, ImageState::Acquired);
+        state.acquire(&mut images).unwrap();
+        state.wait(&mut images).unwrap();
+        assert_eq!(state, ImageState::Waited);
+        assert_eq!(
+            images.calls,
+            ["acquire", "wait", "wait", "release", "acquire", "wait"]
+        );
+    }
+
+    #[test]
+    fn release_waits_first() {
+        let mut images = MockImages::default();
+        let mut state = ImageState::Released;
+        state.acquire(&mut images).unwrap();
+        state.release(&mut images).unwrap();
+        assert_eq!(state, ImageState::Released);
+        assert_eq!(images.calls, ["acquire", "wait", "release"]);
+    }
+
+    #[test]
+    fn released_image_can_not_be_waited_on_or_released() {
+        let mut images = MockImages::default();
+        let mut state = ImageState::Released;
+        assert!(state.wait(&mut images).is_err());
+        assert!(state.release(&mut images).is_err());
+        state.release_pending(&mut images).unwrap();
+        assert!(images.calls.is_empty());
+    }
 }
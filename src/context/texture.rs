@@ -0,0 +1,373 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use ash::vk::{
+    AccessFlags, BufferImageCopy, BufferUsageFlags, DependencyFlags, Extent2D, Extent3D, Filter,
+    Format, FormatFeatureFlags, ImageAspectFlags, ImageBlit, ImageLayout, ImageMemoryBarrier,
+    ImageSubresourceLayers, ImageSubresourceRange, ImageTiling, ImageUsageFlags, ImageViewType,
+    MemoryPropertyFlags, Offset3D, PipelineStageFlags, SampleCountFlags, QUEUE_FAMILY_IGNORED,
+};
+use image::DynamicImage;
+
+use crate::wrap_vulkan::{
+    self,
+    buffers::MappedDeviceBuffer,
+    device_image::{mip_levels_for, DeviceImageSettings},
+    geometry::TextureData,
+    ColorEncoding, DeviceImage,
+};
+
+// only the formats textures are created with, others aren't checked
+fn bytes_per_texel(format: Format) -> Option<usize> {
+    match format {
+        Format::R8_UNORM | Format::R8_SRGB => Some(1),
+        Format::R8G8B8A8_UNORM
+        | Format::R8G8B8A8_SRGB
+        | Format::B8G8R8A8_UNORM
+        | Format::B8G8R8A8_SRGB => Some(4),
+        Format::R32G32B32A32_SFLOAT => Some(16),
+        _ => None,
+    }
+}
+
+fn texture_barrier(
+    texture: &DeviceImage,
+    base_mip_level: u32,
+    level_count: u32,
+    layer_count: u32,
+    old_layout: ImageLayout,
+    new_layout: ImageLayout,
+    src_access_mask: AccessFlags,
+    dst_access_mask: AccessFlags,
+) -> ImageMemoryBarrier {
+    // a range short of the image leaves the rest in the old layout
+    debug_assert!(base_mip_level + level_count <= texture.get_mip_levels());
+    debug_assert!(layer_count <= texture.get_layer_count());
+    ImageMemoryBarrier::builder()
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_queue_family_index(QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(QUEUE_FAMILY_IGNORED)
+        .image(texture.image)
+        .subresource_range(
+            ImageSubresourceRange::builder()
+                .aspect_mask(ImageAspectFlags::COLOR)
+                .base_mip_level(base_mip_level)
+                .level_count(level_count)
+                .layer_count(layer_count)
+                .build(),
+        )
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .build()
+}
+
+fn mip_extent(extent: Extent2D, level: u32) -> Offset3D {
+    Offset3D {
+        x: (extent.width >> level).max(1) as i32,
+        y: (extent.height >> level).max(1) as i32,
+        z: 1,
+    }
+}
+
+fn mip_subresource(level: u32, base_array_layer: u32, layer_count: u32) -> ImageSubresourceLayers {
+    ImageSubresourceLayers::builder()
+        .aspect_mask(ImageAspectFlags::COLOR)
+        .mip_level(level)
+        .base_array_layer(base_array_layer)
+        .layer_count(layer_count)
+        .build()
+}
+
+fn check_layers(layers: u32, view_type: ImageViewType, extent: Extent2D) -> Result<()> {
+    let valid = match view_type {
+        ImageViewType::TYPE_2D => layers == 1,
+        ImageViewType::TYPE_2D_ARRAY => layers >= 1,
+        ImageViewType::CUBE => layers == 6 && extent.width == extent.height,
+        ImageViewType::CUBE_ARRAY => {
+            layers >= 6 && layers % 6 == 0 && extent.width == extent.height
+        }
+        _ => false,
+    };
+    if !valid {
+        bail!(
+            "{:?} texture can't have {} layers of {}x{}",
+            view_type,
+            layers,
+            extent.width,
+            extent.height
+        );
+    }
+    Ok(())
+}
+
+// Uploads tightly packed texel data through a staging buffer,
+// the image is left in SHADER_READ_ONLY_OPTIMAL.
+// The layers follow each other in data, for cubes in the order +X, -X, +Y, -Y, +Z, -Z.
+// With mipmaps the smaller levels are blitted from the uploaded one,
+// which needs the format to support linear filtering.
+pub fn create_texture(
+    context: &wrap_vulkan::Context,
+    data: &[u8],
+    extent: Extent2D,
+    format: Format,
+    layers: u32,
+    view_type: ImageViewType,
+    mipmaps: bool,
+    name: String,
+) -> Result<DeviceImage> {
+    check_layers(layers, view_type, extent)?;
+    if let Some(bytes) = bytes_per_texel(format) {
+        let expected = layers as usize * extent.width as usize * extent.height as usize * bytes;
+        if data.len() != expected {
+            bail!(
+                "Texture {} has {} bytes, expected {} for {} layers of {}x{} {:?}",
+                name,
+                data.len(),
+                expected,
+                layers,
+                extent.width,
+                extent.height,
+                format
+            );
+        }
+    } else if data.len() % layers as usize != 0 {
+        bail!(
+            "Texture {} has {} bytes, which can't be split into {} layers",
+            name,
+            data.len(),
+            layers
+        );
+    }
+    let layer_size = data.len() / layers as usize;
+
+    let mip_levels = if mipmaps {
+        context
+            .find_supported_format(
+                &[format],
+                ImageTiling::OPTIMAL,
+                FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR
+                    | FormatFeatureFlags::BLIT_SRC
+                    | FormatFeatureFlags::BLIT_DST,
+            )
+            .map_err(|_| anyhow!("Texture {} format {:?} can't be mipmapped", name, format))?;
+        mip_levels_for(extent)
+    } else {
+        1
+    };
+
+    let staging = MappedDeviceBuffer::<u8>::new(
+        context,
+        BufferUsageFlags::TRANSFER_SRC,
+        data.len(),
+        format!("{}Staging", name),
+    )?;
+    staging.write(data);
+
+    let texture = DeviceImage::new(
+        context,
+        DeviceImageSettings {
+            extent,
+            format,
+            tiling: ImageTiling::OPTIMAL,
+            usage: if mip_levels > 1 {
+                ImageUsageFlags::TRANSFER_SRC
+                    | ImageUsageFlags::TRANSFER_DST
+                    | ImageUsageFlags::SAMPLED
+            } else {
+                ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::SAMPLED
+            },
+            properties: MemoryPropertyFlags::DEVICE_LOCAL,
+            aspect_flags: ImageAspectFlags::COLOR,
+            layer_count: layers,
+            view_type: Some(view_type),
+            mip_levels,
+            samples: SampleCountFlags::TYPE_1,
+            name: name.clone(),
+        },
+    )?;
+
+    let d = &context.device;
+    context.one_shot(&format!("{}Upload", name), |command_buffer| unsafe {
+        d.cmd_pipeline_barrier(
+            command_buffer,
+            PipelineStageFlags::TOP_OF_PIPE,
+            PipelineStageFlags::TRANSFER,
+            DependencyFlags::empty(),
+            &[],
+            &[],
+            &[texture_barrier(
+                &texture,
+                0,
+                mip_levels,
+                layers,
+                ImageLayout::UNDEFINED,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                AccessFlags::empty(),
+                AccessFlags::TRANSFER_WRITE,
+            )],
+        );
+        d.cmd_copy_buffer_to_image(
+            command_buffer,
+            staging.handle(),
+            texture.image,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            &(0..layers)
+                .map(|layer| {
+                    BufferImageCopy::builder()
+                        .buffer_offset((layer as usize * layer_size) as u64)
+                        .image_subresource(mip_subresource(0, layer, 1))
+                        .image_extent(Extent3D {
+                            width: extent.width,
+                            height: extent.height,
+                            depth: 1,
+                        })
+                        .build()
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        // each level is read by the next blit once it's written
+        for level in 1..mip_levels {
+            d.cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::TRANSFER,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[texture_barrier(
+                    &texture,
+                    level - 1,
+                    1,
+                    layers,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    AccessFlags::TRANSFER_WRITE,
+                    AccessFlags::TRANSFER_READ,
+                )],
+            );
+            d.cmd_blit_image(
+                command_buffer,
+                texture.image,
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+                texture.image,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[ImageBlit::builder()
+                    .src_subresource(mip_subresource(level - 1, 0, layers))
+                    .src_offsets([Offset3D::default(), mip_extent(extent, level - 1)])
+                    .dst_subresource(mip_subresource(level, 0, layers))
+                    .dst_offsets([Offset3D::default(), mip_extent(extent, level)])
+                    .build()],
+                Filter::LINEAR,
+            );
+        }
+
+        // all but the last level were blit sources
+        let mut final_barriers = vec![texture_barrier(
+            &texture,
+            mip_levels - 1,
+            1,
+            layers,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            AccessFlags::TRANSFER_WRITE,
+            AccessFlags::SHADER_READ,
+        )];
+        if mip_levels > 1 {
+            final_barriers.push(texture_barrier(
+                &texture,
+                0,
+                mip_levels - 1,
+                layers,
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                AccessFlags::TRANSFER_READ,
+                AccessFlags::SHADER_READ,
+            ));
+        }
+        d.cmd_pipeline_barrier(
+            command_buffer,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::FRAGMENT_SHADER,
+            DependencyFlags::empty(),
+            &[],
+            &[],
+            &final_barriers,
+        );
+    })?;
+
+    Ok(texture)
+}
+
+// Decodes a PNG or JPEG, everything with 8 bit channels is expanded to RGBA.
+// Files are meant to be sampled in the scene, so they always get mipmaps.
+// The texels are stored as they are (R8G8B8A8_UNORM), which suits data like normal or
+// roughness maps. Color textures are usually sRGB encoded, see create_texture_from_file_encoded.
+pub fn create_texture_from_file<P: AsRef<Path>>(
+    context: &wrap_vulkan::Context,
+    path: P,
+    name: String,
+) -> Result<DeviceImage> {
+    create_texture_from_file_encoded(context, path, ColorEncoding::Unorm, name)
+}
+
+// With Srgb sampling decodes the texels and returns linear values, like for base colors.
+pub fn create_texture_from_file_encoded<P: AsRef<Path>>(
+    context: &wrap_vulkan::Context,
+    path: P,
+    encoding: ColorEncoding,
+    name: String,
+) -> Result<DeviceImage> {
+    let path = path.as_ref();
+    let decoded = image::open(path)
+        .map_err(|e| anyhow!("Failed to load texture {}: {}", path.display(), e))?;
+
+    let rgba = match decoded {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => decoded.to_rgba8(),
+        _ => bail!(
+            "Texture {} has unsupported color type {:?}, only 8 bit channels are supported",
+            path.display(),
+            decoded.color()
+        ),
+    };
+
+    create_texture(
+        context,
+        rgba.as_raw(),
+        Extent2D {
+            width: rgba.width(),
+            height: rgba.height(),
+        },
+        match encoding {
+            ColorEncoding::Srgb => Format::R8G8B8A8_SRGB,
+            ColorEncoding::Unorm => Format::R8G8B8A8_UNORM,
+        },
+        1,
+        ImageViewType::TYPE_2D,
+        true,
+        name,
+    )
+}
+
+// e.g. the base color texture of Mesh::load_gltf_model, with mipmaps like files.
+// glTF stores base colors sRGB encoded, so the texture is R8G8B8A8_SRGB.
+pub fn create_texture_from_data(
+    context: &wrap_vulkan::Context,
+    data: &TextureData,
+    name: String,
+) -> Result<DeviceImage> {
+    create_texture(
+        context,
+        &data.rgba,
+        data.extent,
+        Format::R8G8B8A8_SRGB,
+        1,
+        ImageViewType::TYPE_2D,
+        true,
+        name,
+    )
+}
@@ -0,0 +1,137 @@
+use std::{error::Error as StdError, fmt};
+
+use ash::vk;
+use openxr::sys;
+
+// The failure kinds a caller might want to react to. Functions keep returning anyhow::Result,
+// the variants travel inside it, see VrvError::find. The constructors of the contexts
+// return them directly, so error.downcast_ref::<VrvError>() works on their errors.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VrvError {
+    // none of the candidate formats has the needed features
+    NoSuitableFormat,
+    // the surface reports no present modes
+    NoSuitablePresentMode,
+    NoSuitableMemoryType,
+    // no queue family does graphics, transfer and present
+    NoSuitableQueue,
+    // there is no Vulkan capable GPU at all
+    NoSuitablePhysicalDevice,
+    // an HMD function was called on a context from Context::new_windowed
+    NoHmd,
+    // e.g. after a driver reset (TDR), see Context::recreate_device
+    DeviceLost,
+    OpenXr(sys::Result),
+    Vulkan(vk::Result),
+}
+
+impl fmt::Display for VrvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuitableFormat => write!(f, "Couldn't find supported format"),
+            Self::NoSuitablePresentMode => write!(f, "No suitable present mode"),
+            Self::NoSuitableMemoryType => write!(f, "Failed to find suitable memory type"),
+            Self::NoSuitableQueue => write!(f, "Vulkan device has no suitable queue"),
+            Self::NoSuitablePhysicalDevice => write!(f, "No Vulkan physical device"),
+            Self::NoHmd => write!(f, "Context was created without an HMD"),
+            Self::DeviceLost => write!(f, "Vulkan device lost"),
+            Self::OpenXr(result) => write!(f, "OpenXR error: {}", result),
+            Self::Vulkan(result) => write!(f, "Vulkan error: {}", result),
+        }
+    }
+}
+
+impl StdError for VrvError {}
+
+impl From<vk::Result> for VrvError {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_DEVICE_LOST => Self::DeviceLost,
+            _ => Self::Vulkan(result),
+        }
+    }
+}
+
+impl From<sys::Result> for VrvError {
+    fn from(result: sys::Result) -> Self {
+        Self::OpenXr(result)
+    }
+}
+
+impl VrvError {
+    pub(crate) fn from_cause(cause: &(dyn StdError + 'static)) -> Option<Self> {
+        if let Some(&error) = cause.downcast_ref::<Self>() {
+            Some(error)
+        } else if let Some(&result) = cause.downcast_ref::<vk::Result>() {
+            Some(result.into())
+        } else {
+            cause
+                .downcast_ref::<sys::Result>()
+                .map(|&result| result.into())
+        }
+    }
+
+    // Turns a plain Vulkan or OpenXR result propagated with ? into the matching variant,
+    // other errors are returned as they are.
+    pub(crate) fn typed(error: anyhow::Error) -> anyhow::Error {
+        match error.downcast::<vk::Result>() {
+            Ok(result) => Self::from(result).into(),
+            Err(error) => match error.downcast::<sys::Result>() {
+                Ok(result) => Self::from(result).into(),
+                Err(error) => error,
+            },
+        }
+    }
+
+    // The first cause in the chain that maps to a variant. Plain Vulkan and OpenXR results
+    // propagated with ? count too, so a lost device is found wherever it surfaced.
+    pub fn find(error: &anyhow::Error) -> Option<Self> {
+        error.chain().find_map(Self::from_cause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Result};
+
+    use super::*;
+
+    #[test]
+    fn propagated_results_become_variants() {
+        let lost = || -> Result<()> { Err(vk::Result::ERROR_DEVICE_LOST)? };
+        let typed = VrvError::typed(lost().unwrap_err());
+        assert_eq!(
+            typed.downcast_ref::<VrvError>(),
+            Some(&VrvError::DeviceLost)
+        );
+
+        let oom = || -> Result<()> { Err(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)? };
+        let typed = VrvError::typed(oom().unwrap_err());
+        assert_eq!(
+            typed.downcast_ref::<VrvError>(),
+            Some(&VrvError::Vulkan(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY))
+        );
+
+        let xr = || -> Result<()> { Err(sys::Result::ERROR_FORM_FACTOR_UNAVAILABLE)? };
+        let typed = VrvError::typed(xr().unwrap_err());
+        assert_eq!(
+            typed.downcast_ref::<VrvError>(),
+            Some(&VrvError::OpenXr(
+                sys::Result::ERROR_FORM_FACTOR_UNAVAILABLE
+            ))
+        );
+    }
+
+    #[test]
+    fn other_errors_stay() {
+        let typed = VrvError::typed(VrvError::NoSuitableQueue.into());
+        assert_eq!(
+            typed.downcast_ref::<VrvError>(),
+            Some(&VrvError::NoSuitableQueue)
+        );
+
+        let typed = VrvError::typed(anyhow!("Something else"));
+        assert!(typed.downcast_ref::<VrvError>().is_none());
+        assert_eq!(typed.to_string(), "Something else");
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::{bail, Result};
+
+use crate::wrap_vulkan::{
+    geometry::{DrawRange, Mesh, MeshBuffers, MeshMemory},
+    Context, Vertex,
+};
+
+// A monospaced font as a grid of equally sized cells in a texture, row by row.
+// Only the UVs are needed, so any tool (or font library) can produce the atlas.
+#[derive(Copy, Clone, Debug)]
+pub struct FontAtlas {
+    // the character in the first cell, the following ones come in char order
+    pub first_char: char,
+    pub glyph_count: u32,
+    pub columns: u32,
+    // top left of the first cell and the size of a cell, in UV
+    pub origin_uv: [f32; 2],
+    pub cell_uv: [f32; 2],
+    // drawn for characters that aren't in the atlas, None skips them
+    pub fallback: Option<char>,
+}
+
+impl FontAtlas {
+    // the usual ASCII atlas, 16 columns starting at the space
+    pub fn ascii_grid(columns: u32, rows: u32) -> Self {
+        Self {
+            first_char: ' ',
+            glyph_count: 95, // up to '~'
+            columns,
+            origin_uv: [0.0, 0.0],
+            cell_uv: [1.0 / columns as f32, 1.0 / rows as f32],
+            fallback: Some('?'),
+        }
+    }
+
+    // top left and bottom right
+    pub fn glyph_uv(&self, c: char) -> Option<[[f32; 2]; 2]> {
+        let index = (c as u32).checked_sub(self.first_char as u32)?;
+        if index >= self.glyph_count {
+            return None;
+        }
+        let left = self.origin_uv[0] + (index % self.columns) as f32 * self.cell_uv[0];
+        let top = self.origin_uv[1] + (index / self.columns) as f32 * self.cell_uv[1];
+        Some([[left, top], [left + self.cell_uv[0], top + self.cell_uv[1]]])
+    }
+}
+
+// Where the text goes, in a 2D space with +Y up like view space, e.g. NDC that the HUD
+// vertex shader flips into Vulkan's y-down clip space (like fov_to_projection does),
+// or pixels counted from the bottom left. Lines go down along -Y.
+#[derive(Copy, Clone, Debug)]
+pub struct HudSettings {
+    // top left of the first glyph, the default is the top left corner of NDC
+    pub origin: [f32; 2],
+    pub glyph_size: [f32; 2],
+    // 1.0 puts lines right below each other
+    pub line_spacing: f32,
+    pub color: [f32; 3],
+}
+
+impl Default for HudSettings {
+    fn default() -> Self {
+        Self {
+            origin: [-0.95, 0.95],
+            glyph_size: [0.03, 0.05],
+            line_spacing: 1.2,
+            color: [1.0, 1.0, 1.0],
+        }
+    }
+}
+
+// Two triangles per glyph in the regular Vertex format, so it draws with any pipeline
+// that samples the atlas with the uv and multiplies by col, e.g. for FPS or debug text.
+// The triangles are counter-clockwise with +Y up, front facing with the default RasterOptions.
+pub fn text_mesh(text: &str, atlas: &FontAtlas, settings: &HudSettings) -> Mesh {
+    let mut mesh = Mesh {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+    };
+    let [width, height] = settings.glyph_size;
+    let (mut column, mut line) = (0, 0);
+    for c in text.chars() {
+        if c == '\n' {
+            column = 0;
+            line += 1;
+            continue;
+        }
+        let uv = atlas
+            .glyph_uv(c)
+            .or_else(|| atlas.fallback.and_then(|fallback| atlas.glyph_uv(fallback)));
+        // still takes up the space, like the unknown characters without fallback
+        if let (Some([uv_min, uv_max]), false) = (uv, c == ' ') {
+            let left = settings.origin[0] + column as f32 * width;
+            let top = settings.origin[1] - line as f32 * height * settings.line_spacing;
+            let first = mesh.vertices.len() as u32;
+            for (x, y, u, v) in [
+                (left, top, uv_min[0], uv_min[1]),
+                (left + width, top, uv_max[0], uv_min[1]),
+                (left + width, top - height, uv_max[0], uv_max[1]),
+                (left, top - height, uv_min[0], uv_max[1]),
+            ] {
+                mesh.vertices.push(Vertex {
+                    pos: [x, y, 0.0],
+                    col: settings.color,
+                    normal: [0.0, 0.0, 1.0],
+                    uv: [u, v],
+                    ..Vertex::default()
+                });
+            }
+            mesh.indices
+                .extend([0, 3, 2, 2, 1, 0].iter().map(|i| first + i));
+        }
+        column += 1;
+    }
+    mesh
+}
+
+// Text in mapped buffers that grow as needed, draw it with draw_range
+// since the buffers can be bigger than the current text.
+// There are buffers for every frame in flight, frame is the index of one,
+// e.g. Frame::index, so a frame can be rewritten while the others are still drawn.
+pub struct Hud {
+    pub atlas: FontAtlas,
+    pub settings: HudSettings,
+    meshes: Vec<MeshBuffers>,
+    index_counts: Vec<u32>,
+}
+
+impl Hud {
+    pub fn new(
+        context: &Context,
+        atlas: FontAtlas,
+        frames_in_flight: usize,
+        name: String,
+    ) -> Result<Self> {
+        Self::new_with_settings(
+            context,
+            atlas,
+            HudSettings::default(),
+            frames_in_flight,
+            name,
+        )
+    }
+
+    pub fn new_with_settings(
+        context: &Context,
+        atlas: FontAtlas,
+        settings: HudSettings,
+        frames_in_flight: usize,
+        name: String,
+    ) -> Result<Self> {
+        if frames_in_flight == 0 {
+            bail!("A HUD needs buffers for at least one frame");
+        }
+        // enough for a few lines before the first resize
+        const GLYPHS: usize = 128;
+        let meshes = (0..frames_in_flight)
+            .map(|frame| {
+                MeshBuffers::new(
+                    context,
+                    GLYPHS * 4,
+                    GLYPHS * 6,
+                    MeshMemory::Mapped,
+                    format!("{}_{}", name, frame),
+                )
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            atlas,
+            settings,
+            meshes,
+            index_counts: vec![0; frames_in_flight],
+        })
+    }
+
+    fn check_frame(&self, frame: usize) -> Result<()> {
+        if frame >= self.meshes.len() {
+            bail!(
+                "Frame {} is out of range, the HUD has buffers for {}",
+                frame,
+                self.meshes.len()
+            );
+        }
+        Ok(())
+    }
+
+    // Rewrites (or recreates) the buffers of frame, so the GPU must be done with that frame,
+    // e.g. call it after waiting for the frame's fence.
+    pub fn set_text(&mut self, context: &Context, frame: usize, text: &str) -> Result<()> {
+        self.check_frame(frame)?;
+        let mesh = text_mesh(text, &self.atlas, &self.settings);
+        // a failed write leaves the buffers in an unknown state, nothing is drawn then
+        self.index_counts[frame] = 0;
+        if !mesh.indices.is_empty() {
+            self.meshes[frame].write(context, &mesh)?;
+        }
+        self.index_counts[frame] = mesh.indices.len() as u32;
+        Ok(())
+    }
+
+    pub fn mesh(&self, frame: usize) -> Result<&MeshBuffers> {
+        self.check_frame(frame)?;
+        Ok(&self.meshes[frame])
+    }
+
+    // only the current text of frame, empty if there is nothing to draw
+    pub fn draw_range(&self, frame: usize) -> Result<DrawRange> {
+        self.check_frame(frame)?;
+        Ok(DrawRange {
+            first: 0,
+            count: self.index_counts[frame],
+            vertex_offset: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glyph_triangles_are_counter_clockwise() {
+        let atlas = FontAtlas::ascii_grid(16, 6);
+        let mesh = text_mesh("H i\n!", &atlas, &HudSettings::default());
+        // the space takes no quad
+        assert_eq!(mesh.vertices.len(), 3 * 4);
+        for triangle in mesh.indices.chunks(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| mesh.vertices[triangle[i] as usize].pos);
+            let area = (b[0] - a[0]) * (c[1] - a[1]) - (c[0] - a[0]) * (b[1] - a[1]);
+            assert!(area > 0.0);
+        }
+    }
+
+    #[test]
+    fn glyphs_are_found_by_their_cell() {
+        let atlas = FontAtlas::ascii_grid(16, 6);
+        let [cell_u, cell_v] = atlas.cell_uv;
+        assert_eq!(atlas.glyph_uv(' ').unwrap(), [[0.0, 0.0], [cell_u, cell_v]]);
+        // 'A' is 33 cells after the space, the second cell of the third row
+        assert_eq!(
+            atlas.glyph_uv('A').unwrap(),
+            [[cell_u, 2.0 * cell_v], [2.0 * cell_u, 3.0 * cell_v]]
+        );
+        assert!(atlas.glyph_uv('~').is_some());
+        assert!(atlas.glyph_uv('\u{7f}').is_none());
+        assert!(atlas.glyph_uv('\t').is_none());
+    }
+
+    #[test]
+    fn unknown_characters_fall_back_or_leave_a_gap() {
+        let mut atlas = FontAtlas::ascii_grid(16, 6);
+        let settings = HudSettings::default();
+        let fallback = atlas.glyph_uv('?').unwrap();
+
+        let mesh = text_mesh("a\u{e9}b", &atlas, &settings);
+        assert_eq!(mesh.vertices.len(), 3 * 4);
+        assert_eq!(mesh.vertices[4].uv, fallback[0]);
+
+        // without fallback the character is skipped but still takes its column, like a space
+        atlas.fallback = None;
+        for text in ["a\u{e9}b", "a b"] {
+            let mesh = text_mesh(text, &atlas, &settings);
+            assert_eq!(mesh.vertices.len(), 2 * 4);
+            assert_eq!(mesh.indices.len(), 2 * 6);
+            let second_left = mesh.vertices[4].pos[0];
+            let expected = settings.origin[0] + 2.0 * settings.glyph_size[0];
+            assert!((second_left - expected).abs() < 1e-6);
+        }
+    }
+}
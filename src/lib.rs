@@ -1,8 +1,14 @@
+pub mod camera;
 pub mod context;
+pub mod error;
+pub mod hud;
+mod math;
+pub mod projection;
 pub mod wrap_openxr;
 pub mod wrap_vulkan;
 
-pub use context::Context;
+pub use context::{events::VrvEvent, Context};
+pub use error::VrvError;
 
 pub use ash;
 pub use openxr;
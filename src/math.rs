@@ -0,0 +1,31 @@
+// The few vector operations the meshes and cameras need, on plain arrays
+
+pub(crate) fn add(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+pub(crate) fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+pub(crate) fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub(crate) fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+// None for (nearly) zero vectors, which have no direction
+pub(crate) fn normalize(a: [f32; 3]) -> Option<[f32; 3]> {
+    let len = dot(a, a).sqrt();
+    if len <= f32::EPSILON {
+        None
+    } else {
+        Some([a[0] / len, a[1] / len, a[2] / len])
+    }
+}
@@ -0,0 +1,185 @@
+use openxr::Fovf;
+
+#[derive(Copy, Clone, Debug)]
+pub struct ProjectionParams {
+    pub near: f32,
+    pub far: f32,
+    // depth 1 at near and 0 at far, which spreads float precision much better over distance.
+    // Needs VulkanSettings::reverse_z, for CompareOp::GREATER and a depth clear value of 0.
+    pub reverse_z: bool,
+}
+
+impl Default for ProjectionParams {
+    fn default() -> Self {
+        Self {
+            near: 0.1,
+            far: 100.0,
+            reverse_z: false,
+        }
+    }
+}
+
+// Column-major, as expected by GLSL and e.g. cgmath's From<[[f32; 4]; 4]>.
+// Vulkan clip space: y points down and depth goes from 0 at near to 1 at far (unless reversed).
+// There are 4 angles to consider instead of one, the left and right eye are mirrored,
+// so the off-center terms in the third column differ in sign between them.
+pub fn fov_to_projection(fov: Fovf, params: ProjectionParams) -> [[f32; 4]; 4] {
+    let ProjectionParams {
+        near,
+        far,
+        reverse_z,
+    } = params;
+
+    let tan_left = fov.angle_left.tan();
+    let tan_right = fov.angle_right.tan();
+    let tan_down = fov.angle_down.tan();
+    let tan_up = fov.angle_up.tan();
+
+    let tan_width = tan_right - tan_left;
+    let tan_height = tan_down - tan_up;
+
+    let (depth_scale, depth_offset) = if reverse_z {
+        (near / (far - near), (far * near) / (far - near))
+    } else {
+        (-far / (far - near), -(far * near) / (far - near))
+    };
+
+    [
+        [2.0 / tan_width, 0.0, 0.0, 0.0],
+        [0.0, 2.0 / tan_height, 0.0, 0.0],
+        [
+            (tan_right + tan_left) / tan_width,
+            (tan_up + tan_down) / tan_height,
+            depth_scale,
+            -1.0,
+        ],
+        [0.0, 0.0, depth_offset, 0.0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-5;
+
+    // a left eye as headsets report it, it reaches further outwards than towards the nose
+    const LEFT: Fovf = Fovf {
+        angle_left: -0.96,
+        angle_right: 0.82,
+        angle_up: 0.89,
+        angle_down: -0.95,
+    };
+
+    fn mirrored(fov: Fovf) -> Fovf {
+        Fovf {
+            angle_left: -fov.angle_right,
+            angle_right: -fov.angle_left,
+            ..fov
+        }
+    }
+
+    // view space point to NDC, looking along -Z
+    fn project(m: [[f32; 4]; 4], p: [f32; 3]) -> [f32; 3] {
+        let clip = |row: usize| (0..3).map(|col| m[col][row] * p[col]).sum::<f32>() + m[3][row];
+        let w = clip(3);
+        [clip(0) / w, clip(1) / w, clip(2) / w]
+    }
+
+    // the direction along the given angles, at distance
+    fn point(angle_x: f32, angle_y: f32, distance: f32) -> [f32; 3] {
+        [
+            angle_x.tan() * distance,
+            angle_y.tan() * distance,
+            -distance,
+        ]
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < EPSILON,
+            "{} isn't {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn frustum_edges_map_to_the_ndc_borders() {
+        let m = fov_to_projection(LEFT, ProjectionParams::default());
+        for distance in [0.5, 2.0, 50.0] {
+            let [x, y, _] = project(m, point(LEFT.angle_left, LEFT.angle_up, distance));
+            assert_near(x, -1.0);
+            // Vulkan's y points down
+            assert_near(y, -1.0);
+            let [x, y, _] = project(m, point(LEFT.angle_right, LEFT.angle_down, distance));
+            assert_near(x, 1.0);
+            assert_near(y, 1.0);
+        }
+    }
+
+    #[test]
+    fn straight_ahead_is_off_center() {
+        let m = fov_to_projection(LEFT, ProjectionParams::default());
+        let [x, y, _] = project(m, [0.0, 0.0, -1.0]);
+        // more of the frustum is to the left, so the center of view lands right of NDC 0
+        let (tan_left, tan_right) = (LEFT.angle_left.tan(), LEFT.angle_right.tan());
+        assert_near(x, -(tan_right + tan_left) / (tan_right - tan_left));
+        assert!(x > 0.0);
+        // and more is below, it lands above NDC 0
+        assert!(y < 0.0);
+    }
+
+    #[test]
+    fn depth_goes_from_near_to_far() {
+        let params = ProjectionParams {
+            near: 0.1,
+            far: 100.0,
+            reverse_z: false,
+        };
+        let m = fov_to_projection(LEFT, params);
+        assert_near(project(m, point(0.3, -0.2, params.near))[2], 0.0);
+        assert_near(project(m, point(0.3, -0.2, params.far))[2], 1.0);
+        let middle = project(m, point(0.0, 0.0, 10.0))[2];
+        assert!(0.0 < middle && middle < 1.0);
+
+        let reversed = fov_to_projection(
+            LEFT,
+            ProjectionParams {
+                reverse_z: true,
+                ..params
+            },
+        );
+        assert_near(project(reversed, point(0.3, -0.2, params.near))[2], 1.0);
+        assert_near(project(reversed, point(0.3, -0.2, params.far))[2], 0.0);
+        // x and y don't depend on the depth mapping
+        let p = [0.3, -0.2, -1.5];
+        assert_near(project(reversed, p)[0], project(m, p)[0]);
+        assert_near(project(reversed, p)[1], project(m, p)[1]);
+    }
+
+    #[test]
+    fn eyes_differ_only_by_the_asymmetry() {
+        let params = ProjectionParams::default();
+        let left = fov_to_projection(LEFT, params);
+        let right = fov_to_projection(mirrored(LEFT), params);
+        for col in 0..4 {
+            for row in 0..4 {
+                if (col, row) == (2, 0) {
+                    // the horizontal off-center term flips its sign
+                    assert_near(right[col][row], -left[col][row]);
+                } else {
+                    assert_near(right[col][row], left[col][row]);
+                }
+            }
+        }
+
+        // a mirrored point lands mirrored in NDC
+        let p = [0.3, -0.2, -1.5];
+        let [x, y, z] = project(left, p);
+        let [mirrored_x, mirrored_y, mirrored_z] = project(right, [-p[0], p[1], p[2]]);
+        assert_near(mirrored_x, -x);
+        assert_near(mirrored_y, y);
+        assert_near(mirrored_z, z);
+    }
+}
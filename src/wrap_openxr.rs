@@ -5,9 +5,10 @@ use ash::vk::{
 };
 use openxr::{
     vulkan::{Requirements, SessionCreateInfo},
-    ApplicationInfo, Entry, EnvironmentBlendMode, ExtensionSet, FormFactor, FrameStream,
-    FrameWaiter, Instance, Session, Swapchain, SwapchainCreateFlags, SwapchainCreateInfo,
-    SwapchainUsageFlags, SystemId, ViewConfigurationType, Vulkan,
+    ApiLayerProperties, ApplicationInfo, Entry, EnvironmentBlendMode, ExtensionSet, FormFactor,
+    FrameStream, FrameWaiter, Instance, Session, Swapchain, SwapchainCreateFlags,
+    SwapchainCreateInfo, SwapchainUsageFlags, SystemId, SystemProperties, ViewConfigurationType,
+    ViewConfigurationView, Vulkan,
 };
 
 #[cfg(feature = "validation_openxr")]
@@ -115,19 +116,152 @@ mod debug {
 #[cfg(feature = "validation_openxr")]
 use debug::Debug;
 
-use crate::wrap_vulkan;
+use crate::{wrap_vulkan, VrvError};
+
+#[derive(Clone)]
+pub struct OpenXRSettings {
+    // the runtime picks its default system for the form factor
+    pub form_factor: FormFactor,
+    // the first one the system supports is used, e.g. ALPHA_BLEND first for passthrough
+    pub blend_mode_preference: Vec<EnvironmentBlendMode>,
+    // Same for the view configuration, e.g. PRIMARY_QUAD_VARJO first for foveated rendering.
+    // The views are rendered with multiview, so the shaders have to handle the view count.
+    pub view_configuration_preference: Vec<ViewConfigurationType>,
+}
+
+impl Default for OpenXRSettings {
+    fn default() -> Self {
+        Self {
+            form_factor: FormFactor::HEAD_MOUNTED_DISPLAY,
+            blend_mode_preference: vec![
+                EnvironmentBlendMode::OPAQUE,
+                EnvironmentBlendMode::ALPHA_BLEND,
+                EnvironmentBlendMode::ADDITIVE,
+            ],
+            view_configuration_preference: vec![ViewConfigurationType::PRIMARY_STEREO],
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SwapchainDesc {
+    // one layer per view for multiview, None takes the view count of the chosen
+    // view configuration, anything else fails unless it matches
+    pub array_size: Option<u32>,
+    // only 1 for now, the HMD render passes have no MSAA resolve
+    pub sample_count: u32,
+    pub mip_count: u32,
+    pub usage: SwapchainUsageFlags,
+}
+
+impl Default for SwapchainDesc {
+    fn default() -> Self {
+        Self {
+            array_size: None,
+            sample_count: 1,
+            mip_count: 1,
+            // copied from for the window mirror
+            usage: SwapchainUsageFlags::COLOR_ATTACHMENT
+                | SwapchainUsageFlags::SAMPLED
+                | SwapchainUsageFlags::TRANSFER_SRC,
+        }
+    }
+}
+
+// All views share one array swapchain, so it's sized for the most demanding view
+// and limited by the most restrictive one as well as the swapchain limit of the system.
+// With quad views the inner (focus) views are the ones asking for more pixels.
+// views must not be empty.
+fn combine_views(
+    views: &[ViewConfigurationView],
+    max_swapchain: Extent2D,
+) -> ViewConfigurationView {
+    let largest = |f: fn(&ViewConfigurationView) -> u32| views.iter().map(f).max().unwrap();
+    let smallest = |f: fn(&ViewConfigurationView) -> u32| views.iter().map(f).min().unwrap();
+    let max_width = smallest(|view| view.max_image_rect_width).min(max_swapchain.width);
+    let max_height = smallest(|view| view.max_image_rect_height).min(max_swapchain.height);
+    ViewConfigurationView {
+        recommended_image_rect_width: largest(|view| view.recommended_image_rect_width)
+            .min(max_width),
+        max_image_rect_width: max_width,
+        recommended_image_rect_height: largest(|view| view.recommended_image_rect_height)
+            .min(max_height),
+        max_image_rect_height: max_height,
+        recommended_swapchain_sample_count: largest(|view| view.recommended_swapchain_sample_count),
+        max_swapchain_sample_count: smallest(|view| view.max_swapchain_sample_count),
+    }
+}
+
+// max is the smallest max_swapchain_sample_count of the views
+fn check_sample_count(sample_count: u32, max: u32) -> Result<()> {
+    if sample_count == 0 || sample_count > max {
+        bail!(
+            "Swapchain sample count {} isn't supported, the views allow 1 to {}",
+            sample_count,
+            max
+        );
+    }
+    if sample_count > 1 {
+        bail!(
+            "Swapchain sample count {} needs an MSAA resolve, which the HMD render passes don't have",
+            sample_count
+        );
+    }
+    Ok(())
+}
+
+// falls back to whatever the system lists first if none of the preferred ones is there
+fn choose_preferred<T: Copy + PartialEq>(available: &[T], preference: &[T]) -> Option<T> {
+    preference
+        .iter()
+        .find(|mode| available.contains(mode))
+        .or_else(|| available.first())
+        .copied()
+}
+
+// falls back to whatever the system lists first, e.g. ADDITIVE on see-through headsets
+fn choose_blend_mode(
+    available: &[EnvironmentBlendMode],
+    preference: &[EnvironmentBlendMode],
+) -> Result<EnvironmentBlendMode> {
+    let blend_mode = choose_preferred(available, preference)
+        .ok_or(Error::msg("OpenXR system has no environment blend modes"))?;
+    if !preference.contains(&blend_mode) {
+        log::warn!(
+            "None of the preferred blend modes {:?} available, using {:?}",
+            preference,
+            blend_mode
+        );
+    }
+    Ok(blend_mode)
+}
 
 pub struct Context {
+    // None if neither the runtime nor the layer provide debug utils
     #[cfg(feature = "validation_openxr")]
-    pub debug: Debug,
+    pub debug: Option<Debug>,
 
     pub entry: Entry,
     pub instance: Instance,
     pub system_id: SystemId,
+
+    available_extensions: ExtensionSet,
+    available_layers: Vec<ApiLayerProperties>,
+    enabled_extensions: ExtensionSet,
+    blend_mode: EnvironmentBlendMode,
+    view_configuration: ViewConfigurationType,
 }
 
 impl Context {
     pub fn new() -> Result<Self> {
+        Self::new_with_settings(&OpenXRSettings::default())
+    }
+
+    pub fn new_with_settings(settings: &OpenXRSettings) -> Result<Self> {
+        Self::create(settings).map_err(VrvError::typed)
+    }
+
+    fn create(settings: &OpenXRSettings) -> Result<Self> {
         const VALIDATION_LAYER_NAME: &'static str = "XR_APILAYER_LUNARG_core_validation";
 
         log::info!("Creating new OpenXR Context");
@@ -139,22 +273,45 @@ impl Context {
         log::trace!("OpenXR available extensions: {:?}", available_extensions);
         log::trace!("OpenXR available layers: {:?}", available_layers);
 
-        assert!(available_extensions.khr_vulkan_enable2);
+        if !available_extensions.khr_vulkan_enable2 {
+            bail!("OpenXR runtime doesn't support Vulkan (XR_KHR_vulkan_enable2 is missing)");
+        }
 
-        #[cfg(feature = "validation_openxr")]
-        assert!(
-            available_layers
+        // like for Vulkan, a build with the feature still runs where the layer isn't installed
+        let validation_layer = cfg!(feature = "validation_openxr")
+            && available_layers
                 .iter()
-                .find(|l| l.layer_name == VALIDATION_LAYER_NAME)
-                .is_some(),
-            "Validation layer not found, did you set XR_API_LAYER_PATH?"
-        );
+                .any(|l| l.layer_name == VALIDATION_LAYER_NAME);
+        if cfg!(feature = "validation_openxr") && !validation_layer {
+            log::warn!(
+                "OpenXR validation layer {} not found, continuing without it. \
+                Did you set XR_API_LAYER_PATH?",
+                VALIDATION_LAYER_NAME
+            );
+        }
 
         let mut enabled_extensions = ExtensionSet::default();
         enabled_extensions.khr_vulkan_enable2 = true;
+        // provided by the runtime or the layer, only for the messages
         if cfg!(feature = "validation_openxr") {
-            enabled_extensions.ext_debug_utils = true;
+            enabled_extensions.ext_debug_utils =
+                available_extensions.ext_debug_utils || validation_layer;
+        }
+        // optional, used for fading
+        enabled_extensions.khr_composition_layer_color_scale_bias =
+            available_extensions.khr_composition_layer_color_scale_bias;
+        // optional, submitting depth improves reprojection
+        enabled_extensions.khr_composition_layer_depth =
+            available_extensions.khr_composition_layer_depth;
+        // optional, the system still has to support it
+        if cfg!(feature = "hand_tracking") {
+            enabled_extensions.ext_hand_tracking = available_extensions.ext_hand_tracking;
         }
+        // only if asked for, the system still has to offer the view configuration
+        enabled_extensions.varjo_quad_views = available_extensions.varjo_quad_views
+            && settings
+                .view_configuration_preference
+                .contains(&ViewConfigurationType::PRIMARY_QUAD_VARJO);
         let instance = entry.create_instance(
             &ApplicationInfo {
                 application_name: "VRV App",
@@ -163,7 +320,7 @@ impl Context {
                 engine_version: 0,
             },
             &enabled_extensions,
-            if cfg!(feature = "validation_openxr") {
+            if validation_layer {
                 &[VALIDATION_LAYER_NAME]
             } else {
                 &[]
@@ -171,7 +328,11 @@ impl Context {
         )?;
 
         #[cfg(feature = "validation_openxr")]
-        let debug = Debug::new(&entry, &instance)?;
+        let debug = if enabled_extensions.ext_debug_utils {
+            Some(Debug::new(&entry, &instance)?)
+        } else {
+            None
+        };
 
         let instance_props = instance.properties()?;
         log::info!(
@@ -181,15 +342,54 @@ impl Context {
         );
 
         // Request a form factor from the device (HMD, Handheld, etc.)
-        let system_id = instance.system(FormFactor::HEAD_MOUNTED_DISPLAY)?;
-        if instance
-            .enumerate_environment_blend_modes(system_id, ViewConfigurationType::PRIMARY_STEREO)?
-            .into_iter()
-            .find(|&mode| mode == EnvironmentBlendMode::OPAQUE)
-            == None
+        let system_id = instance.system(settings.form_factor)?;
+        log::info!(
+            "OpenXR system id {:?} for form factor {:?}",
+            system_id,
+            settings.form_factor
+        );
+
+        let system_properties = instance.system_properties(system_id)?;
+        log::info!(
+            "OpenXR system: {} (vendor {}), orientation tracking: {}, position tracking: {}, max swapchain: {}x{}",
+            system_properties.system_name,
+            system_properties.vendor_id,
+            system_properties.tracking_properties.orientation_tracking,
+            system_properties.tracking_properties.position_tracking,
+            system_properties.graphics_properties.max_swapchain_image_width,
+            system_properties.graphics_properties.max_swapchain_image_height,
+        );
+        let view_configurations = instance.enumerate_view_configurations(system_id)?;
+        for &view_configuration in &view_configurations {
+            log::info!(
+                "OpenXR view configuration {:?}, blend modes: {:?}",
+                view_configuration,
+                instance.enumerate_environment_blend_modes(system_id, view_configuration)?
+            );
+        }
+
+        let view_configuration = choose_preferred(
+            &view_configurations,
+            &settings.view_configuration_preference,
+        )
+        .ok_or(Error::msg("OpenXR system has no view configurations"))?;
+        if !settings
+            .view_configuration_preference
+            .contains(&view_configuration)
         {
-            bail!("Only OPAQUE mode allowed");
+            log::warn!(
+                "None of the preferred view configurations {:?} available, using {:?}",
+                settings.view_configuration_preference,
+                view_configuration
+            );
         }
+        log::info!("OpenXR view configuration: {:?}", view_configuration);
+
+        let blend_mode = choose_blend_mode(
+            &instance.enumerate_environment_blend_modes(system_id, view_configuration)?,
+            &settings.blend_mode_preference,
+        )?;
+        log::info!("OpenXR environment blend mode: {:?}", blend_mode);
 
         Ok(Self {
             #[cfg(feature = "validation_openxr")]
@@ -198,9 +398,63 @@ impl Context {
             entry,
             instance,
             system_id,
+
+            available_extensions,
+            available_layers,
+            enabled_extensions,
+            blend_mode,
+            view_configuration,
         })
     }
 
+    // what the runtime offers, not what was enabled
+    pub fn available_extensions(&self) -> &ExtensionSet {
+        &self.available_extensions
+    }
+
+    // the subset of the available extensions the instance was created with
+    pub fn enabled_extensions(&self) -> &ExtensionSet {
+        &self.enabled_extensions
+    }
+
+    // what frames are submitted with, see OpenXRSettings::blend_mode_preference
+    pub fn get_blend_mode(&self) -> EnvironmentBlendMode {
+        self.blend_mode
+    }
+
+    // see OpenXRSettings::view_configuration_preference
+    pub fn get_view_configuration(&self) -> ViewConfigurationType {
+        self.view_configuration
+    }
+
+    pub fn get_view_count(&self) -> Result<u32> {
+        Ok(self.get_views()?.len() as u32)
+    }
+
+    pub fn available_layers(&self) -> &[ApiLayerProperties] {
+        &self.available_layers
+    }
+
+    // name, vendor, tracking capabilities (3DoF vs 6DoF) and max swapchain size
+    pub fn system_properties(&self) -> Result<SystemProperties> {
+        Ok(self.instance.system_properties(self.system_id)?)
+    }
+
+    pub fn view_configurations(&self) -> Result<Vec<ViewConfigurationType>> {
+        Ok(self
+            .instance
+            .enumerate_view_configurations(self.system_id)?)
+    }
+
+    pub fn environment_blend_modes(
+        &self,
+        view_configuration: ViewConfigurationType,
+    ) -> Result<Vec<EnvironmentBlendMode>> {
+        Ok(self
+            .instance
+            .enumerate_environment_blend_modes(self.system_id, view_configuration)?)
+    }
+
     pub fn get_graphics_requirements(&self) -> Result<Requirements> {
         Ok(self
             .instance
@@ -259,27 +513,56 @@ impl Context {
         ))
     }
 
-    pub fn get_resolution(&self) -> Result<Extent2D> {
-        let views = self.instance.enumerate_view_configuration_views(
-            self.system_id,
-            ViewConfigurationType::PRIMARY_STEREO,
-        )?;
-
-        if views.len() != 2 {
-            bail!("Views are not 2");
-        }
-        if views[0].recommended_image_rect_width != views[1].recommended_image_rect_width
-            || views[0].recommended_image_rect_height != views[1].recommended_image_rect_height
-        {
-            bail!("Views don't have equal resolution?");
+    fn get_views(&self) -> Result<Vec<ViewConfigurationView>> {
+        let views = self
+            .instance
+            .enumerate_view_configuration_views(self.system_id, self.view_configuration)?;
+        if views.is_empty() {
+            bail!(
+                "View configuration {:?} has no views",
+                self.view_configuration
+            );
         }
+        Ok(views)
+    }
+
+    fn get_combined_view(&self) -> Result<ViewConfigurationView> {
+        let graphics = self.system_properties()?.graphics_properties;
+        Ok(combine_views(
+            &self.get_views()?,
+            Extent2D {
+                width: graphics.max_swapchain_image_width,
+                height: graphics.max_swapchain_image_height,
+            },
+        ))
+    }
 
+    pub fn get_resolution(&self) -> Result<Extent2D> {
+        let view = self.get_combined_view()?;
         Ok(Extent2D {
-            width: views[0].recommended_image_rect_width,
-            height: views[0].recommended_image_rect_height,
+            width: view.recommended_image_rect_width,
+            height: view.recommended_image_rect_height,
         })
     }
 
+    // the recommended resolution times scale, clamped to the maximum of the runtime
+    pub fn get_scaled_resolution(&self, scale: f32) -> Result<Extent2D> {
+        let view = self.get_combined_view()?;
+        let scaled =
+            |recommended: u32, max: u32| ((recommended as f32 * scale).round() as u32).min(max);
+        let extent = Extent2D {
+            width: scaled(view.recommended_image_rect_width, view.max_image_rect_width),
+            height: scaled(
+                view.recommended_image_rect_height,
+                view.max_image_rect_height,
+            ),
+        };
+        if extent.width == 0 || extent.height == 0 {
+            bail!("Resolution scale {} results in an empty extent", scale);
+        }
+        Ok(extent)
+    }
+
     pub fn find_supported_format(
         session: &Session<Vulkan>,
         candidates: &[Format],
@@ -294,8 +577,8 @@ impl Context {
                     .find(|&supported| *supported == wanted.as_raw() as u32)
                     .is_some()
             })
-            .ok_or(Error::msg("Couldn't find supported format"))
             .cloned()
+            .ok_or(VrvError::NoSuitableFormat.into())
     }
 
     pub fn init_with_vulkan(
@@ -318,21 +601,129 @@ impl Context {
         }?)
     }
 
+    // the swapchain for the projection layer, one array layer per view for multiview
     pub fn get_swapchain(
+        &self,
         session: &Session<Vulkan>,
         extent: Extent2D,
         format: Format,
+        desc: &SwapchainDesc,
     ) -> Result<Swapchain<Vulkan>> {
+        let view_count = self.get_view_count()?;
+        let array_size = desc.array_size.unwrap_or(view_count);
+        if array_size != view_count {
+            bail!(
+                "Swapchain array size {} doesn't match the {} views",
+                array_size,
+                view_count
+            );
+        }
+        check_sample_count(
+            desc.sample_count,
+            self.get_combined_view()?.max_swapchain_sample_count,
+        )?;
+        if desc.mip_count == 0 {
+            bail!("Swapchain needs at least one mip level");
+        }
+
         Ok(session.create_swapchain(&SwapchainCreateInfo {
             create_flags: SwapchainCreateFlags::EMPTY,
-            usage_flags: SwapchainUsageFlags::COLOR_ATTACHMENT | SwapchainUsageFlags::SAMPLED,
+            usage_flags: desc.usage,
             format: format.as_raw() as _,
-            sample_count: 1,
+            sample_count: desc.sample_count,
             width: extent.width,
             height: extent.height,
             face_count: 1,
-            array_size: 2, // Multiview for two eyes
-            mip_count: 1,
+            array_size,
+            mip_count: desc.mip_count,
         })?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_mode_follows_the_preference() {
+        let preference = OpenXRSettings::default().blend_mode_preference;
+        let all = [
+            EnvironmentBlendMode::ADDITIVE,
+            EnvironmentBlendMode::ALPHA_BLEND,
+            EnvironmentBlendMode::OPAQUE,
+        ];
+        // the order the runtime lists them in doesn't matter
+        assert_eq!(
+            choose_blend_mode(&all, &preference).unwrap(),
+            EnvironmentBlendMode::OPAQUE
+        );
+        assert_eq!(
+            choose_blend_mode(&all[..2], &preference).unwrap(),
+            EnvironmentBlendMode::ALPHA_BLEND
+        );
+        let passthrough = [
+            EnvironmentBlendMode::ALPHA_BLEND,
+            EnvironmentBlendMode::ADDITIVE,
+        ];
+        assert_eq!(
+            choose_blend_mode(&all, &passthrough).unwrap(),
+            EnvironmentBlendMode::ALPHA_BLEND
+        );
+    }
+
+    #[test]
+    fn blend_mode_falls_back_to_the_first_available() {
+        let available = [EnvironmentBlendMode::ADDITIVE, EnvironmentBlendMode::OPAQUE];
+        assert_eq!(
+            choose_blend_mode(&available, &[EnvironmentBlendMode::ALPHA_BLEND]).unwrap(),
+            EnvironmentBlendMode::ADDITIVE
+        );
+        assert_eq!(
+            choose_blend_mode(&available, &[]).unwrap(),
+            EnvironmentBlendMode::ADDITIVE
+        );
+        assert!(choose_blend_mode(&[], &[EnvironmentBlendMode::OPAQUE]).is_err());
+    }
+
+    #[test]
+    fn combined_view_stays_within_the_swapchain_limits() {
+        let view = |recommended: u32, max: u32| ViewConfigurationView {
+            recommended_image_rect_width: recommended,
+            max_image_rect_width: max,
+            recommended_image_rect_height: recommended,
+            max_image_rect_height: max,
+            recommended_swapchain_sample_count: 1,
+            max_swapchain_sample_count: 4,
+        };
+        let unlimited = Extent2D {
+            width: u32::MAX,
+            height: u32::MAX,
+        };
+
+        // the focus view asks for more than the context view allows
+        let combined = combine_views(&[view(1000, 2000), view(3000, 4000)], unlimited);
+        assert_eq!(combined.recommended_image_rect_width, 2000);
+        assert_eq!(combined.max_image_rect_width, 2000);
+
+        let combined = combine_views(
+            &[view(1000, 4000), view(1500, 4000)],
+            Extent2D {
+                width: 1200,
+                height: 4096,
+            },
+        );
+        assert_eq!(combined.recommended_image_rect_width, 1200);
+        assert_eq!(combined.max_image_rect_width, 1200);
+        assert_eq!(combined.recommended_image_rect_height, 1500);
+        assert_eq!(combined.max_image_rect_height, 4000);
+    }
+
+    #[test]
+    fn only_single_sampled_swapchains_are_allowed() {
+        assert!(check_sample_count(1, 4).is_ok());
+        assert!(check_sample_count(0, 4).is_err());
+        // supported by the runtime, but nothing would resolve it
+        assert!(check_sample_count(4, 4).is_err());
+        assert!(check_sample_count(8, 4).is_err());
+    }
+}
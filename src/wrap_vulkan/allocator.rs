@@ -0,0 +1,464 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use ash::{
+    vk::{
+        self, DeviceMemory, DeviceSize, MemoryAllocateInfo, MemoryMapFlags, MemoryPropertyFlags,
+        MemoryRequirements, WHOLE_SIZE,
+    },
+    Device,
+};
+
+use super::{buffers::align_up, Context};
+
+// Big enough that a scene needs only a handful, drivers allow as few as 4096 allocations.
+// Smaller heaps get smaller blocks, see Allocator::allocate.
+const BLOCK_SIZE: DeviceSize = 64 * 1024 * 1024;
+
+// only the address of a persistent mapping, the memory itself is owned by the block
+#[derive(Copy, Clone)]
+struct MappedPtr(*mut u8);
+
+// the pointer is never dereferenced without the allocation that guards the range
+unsafe impl Send for MappedPtr {}
+
+struct Block {
+    memory: DeviceMemory,
+    memory_type_index: u32,
+    // of the memory type, can have more than what was asked for
+    properties: MemoryPropertyFlags,
+    size: DeviceSize,
+    // host visible memory is mapped for the block's lifetime, it can't be mapped twice
+    mapped: Option<MappedPtr>,
+    // offset and size, sorted by offset and merged with their neighbours on free
+    free: Vec<(DeviceSize, DeviceSize)>,
+    // holds a single allocation too big for sharing, freed with it
+    dedicated: bool,
+}
+
+impl Block {
+    // first fit, the gap in front of an aligned offset stays free
+    fn carve(&mut self, size: DeviceSize, alignment: DeviceSize) -> Option<DeviceSize> {
+        let (i, offset) = self
+            .free
+            .iter()
+            .enumerate()
+            .find_map(|(i, &(start, len))| {
+                let offset = align_up(start, alignment);
+                (offset + size <= start + len).then_some((i, offset))
+            })?;
+        let (start, len) = self.free.remove(i);
+        let end = start + len;
+        if offset + size < end {
+            self.free.insert(i, (offset + size, end - offset - size));
+        }
+        if start < offset {
+            self.free.insert(i, (start, offset - start));
+        }
+        Some(offset)
+    }
+
+    fn release(&mut self, offset: DeviceSize, size: DeviceSize) {
+        let i = self.free.partition_point(|&(start, _)| start < offset);
+        self.free.insert(i, (offset, size));
+        // with the next one first, so i stays valid
+        if i + 1 < self.free.len() && offset + size == self.free[i + 1].0 {
+            self.free[i].1 += self.free.remove(i + 1).1;
+        }
+        if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == offset {
+            self.free[i - 1].1 += self.free.remove(i).1;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.free == [(0, self.size)]
+    }
+}
+
+// Sub-allocates buffers and images from a few large blocks per memory type instead of one
+// VkDeviceMemory each. Every offset and size is a multiple of bufferImageGranularity and
+// nonCoherentAtomSize, so linear and optimal resources never share a page and flushed
+// ranges never overlap a neighbour. One empty shared block per memory type is kept, so
+// resources recreated e.g. on every resize don't go to the driver each time. Further empty
+// blocks and dedicated ones are freed right away.
+pub struct Allocator {
+    // index is the block id of an Allocation, None once freed
+    blocks: Vec<Option<Block>>,
+    granularity: DeviceSize,
+    allocated: DeviceSize,
+    device: Device,
+}
+
+impl Allocator {
+    pub fn new(device: &Device, granularity: DeviceSize) -> Self {
+        Self {
+            blocks: Vec::new(),
+            granularity: granularity.max(1),
+            allocated: 0,
+            device: device.clone(),
+        }
+    }
+
+    // the sum of the allocations rounded up to the granularity, not of the blocks
+    pub fn allocated_bytes(&self) -> DeviceSize {
+        self.allocated
+    }
+
+    // the blocks that are currently allocated from the driver
+    pub fn block_count(&self) -> usize {
+        self.blocks.iter().flatten().count()
+    }
+
+    // None names it after the memory type and id, it is shared
+    fn new_block(
+        &mut self,
+        context: &Context,
+        memory_type_index: u32,
+        size: DeviceSize,
+        name: Option<String>,
+    ) -> Result<usize> {
+        let memory = unsafe {
+            context.device.allocate_memory(
+                &MemoryAllocateInfo::builder()
+                    .allocation_size(size)
+                    .memory_type_index(memory_type_index),
+                None,
+            )
+        }?;
+
+        let properties = unsafe {
+            context
+                .instance
+                .get_physical_device_memory_properties(context.physical_device)
+        }
+        .memory_types[memory_type_index as usize]
+            .property_flags;
+        let mapped = if properties.contains(MemoryPropertyFlags::HOST_VISIBLE) {
+            let mapped = unsafe {
+                context
+                    .device
+                    .map_memory(memory, 0, WHOLE_SIZE, MemoryMapFlags::empty())
+            };
+            match mapped {
+                Ok(ptr) => Some(MappedPtr(ptr as *mut u8)),
+                Err(e) => {
+                    unsafe { context.device.free_memory(memory, None) };
+                    return Err(e.into());
+                }
+            }
+        } else {
+            None
+        };
+
+        let block = Block {
+            memory,
+            memory_type_index,
+            properties,
+            size,
+            mapped,
+            free: vec![(0, size)],
+            dedicated: name.is_some(),
+        };
+        // reuse the ids of freed blocks, the list doesn't grow with churn
+        let id = match self.blocks.iter().position(Option::is_none) {
+            Some(id) => {
+                self.blocks[id] = Some(block);
+                id
+            }
+            None => {
+                self.blocks.push(Some(block));
+                self.blocks.len() - 1
+            }
+        };
+        let named = context.name_object(
+            memory,
+            name.unwrap_or_else(|| format!("MemoryBlock_{}_{}", memory_type_index, id)),
+        );
+        if let Err(e) = named {
+            self.blocks[id] = None;
+            unsafe { context.device.free_memory(memory, None) };
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    fn allocate(
+        &mut self,
+        context: &Context,
+        requirements: MemoryRequirements,
+        memory_type_index: u32,
+        name: &str,
+    ) -> Result<(usize, DeviceSize, DeviceSize)> {
+        let alignment = requirements.alignment.max(self.granularity);
+        let size = align_up(requirements.size, self.granularity);
+
+        let found = self.blocks.iter_mut().enumerate().find_map(|(id, block)| {
+            let block = block.as_mut()?;
+            if size > BLOCK_SIZE / 2
+                || block.dedicated
+                || block.memory_type_index != memory_type_index
+            {
+                return None;
+            }
+            block.carve(size, alignment).map(|offset| (id, offset))
+        });
+        let (id, offset) = match found {
+            Some(found) => found,
+            None => {
+                // too big to share, it gets a block of its own
+                let (mut block_size, block_name) = if size > BLOCK_SIZE / 2 {
+                    (size, Some(format!("{}Memory", name)))
+                } else {
+                    (
+                        BLOCK_SIZE
+                            .min(heap_size(context, memory_type_index))
+                            .max(size),
+                        None,
+                    )
+                };
+                // a shared block is halved while the driver can't provide it,
+                // down to what this allocation needs
+                let id = loop {
+                    match self.new_block(context, memory_type_index, block_size, block_name.clone())
+                    {
+                        Err(e)
+                            if block_name.is_none()
+                                && block_size / 2 >= size
+                                && e.downcast_ref::<vk::Result>()
+                                    == Some(&vk::Result::ERROR_OUT_OF_DEVICE_MEMORY) =>
+                        {
+                            block_size /= 2;
+                        }
+                        result => break result?,
+                    }
+                };
+                let offset = self.blocks[id]
+                    .as_mut()
+                    .and_then(|block| block.carve(size, alignment))
+                    .ok_or_else(|| anyhow!("{} doesn't fit into a new memory block", name))?;
+                (id, offset)
+            }
+        };
+        self.allocated += size;
+        Ok((id, offset, size))
+    }
+
+    fn free(&mut self, id: usize, offset: DeviceSize, size: DeviceSize) {
+        self.allocated -= size;
+        // gone with free_all if the allocation outlived the Context
+        match self.blocks.get_mut(id) {
+            Some(Some(block)) => block.release(offset, size),
+            _ => return,
+        }
+        if let Some(memory) = empty_block_to_free(&self.blocks, id) {
+            // unmapped implicitly
+            unsafe { self.device.free_memory(memory, None) };
+            self.blocks[id] = None;
+        }
+    }
+
+    // called by Context before destroying the device, everything should be dropped by then
+    pub(crate) fn free_all(&mut self) {
+        for block in self.blocks.drain(..).flatten() {
+            if !block.is_empty() {
+                log::warn!("Freeing a memory block that is still in use");
+            }
+            unsafe { self.device.free_memory(block.memory, None) };
+        }
+    }
+}
+
+// The memory of block id if it's empty and can go: dedicated blocks always,
+// shared ones unless they are the only spare of their memory type.
+fn empty_block_to_free(blocks: &[Option<Block>], id: usize) -> Option<DeviceMemory> {
+    let block = blocks.get(id)?.as_ref()?;
+    if !block.is_empty() {
+        return None;
+    }
+    let spare = |other: &Block| {
+        !other.dedicated && other.memory_type_index == block.memory_type_index && other.is_empty()
+    };
+    let other_spare = blocks
+        .iter()
+        .enumerate()
+        .any(|(other, candidate)| other != id && candidate.as_ref().map_or(false, spare));
+    (block.dedicated || other_spare).then_some(block.memory)
+}
+
+fn heap_size(context: &Context, memory_type_index: u32) -> DeviceSize {
+    let properties = unsafe {
+        context
+            .instance
+            .get_physical_device_memory_properties(context.physical_device)
+    };
+    let heap_index = properties.memory_types[memory_type_index as usize].heap_index;
+    properties.memory_heaps[heap_index as usize].size
+}
+
+// A range of a block, given back on drop. Has to be dropped before the Context.
+pub struct Allocation {
+    pub memory: DeviceMemory,
+    pub offset: DeviceSize,
+    pub properties: MemoryPropertyFlags,
+    size: DeviceSize,
+    mapped: Option<MappedPtr>,
+    block: usize,
+    allocator: Arc<Mutex<Allocator>>,
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        match self.allocator.lock() {
+            Ok(mut allocator) => allocator.free(self.block, self.offset, self.size),
+            Err(_) => log::error!("Memory allocator poisoned, leaking an allocation"),
+        }
+    }
+}
+
+impl Allocation {
+    // a multiple of the granularity, can be more than requested
+    pub fn size(&self) -> DeviceSize {
+        self.size
+    }
+
+    // None unless the memory type is HOST_VISIBLE
+    pub fn mapped_ptr(&self) -> Option<*mut u8> {
+        self.mapped
+            .map(|MappedPtr(ptr)| unsafe { ptr.add(self.offset as usize) })
+    }
+}
+
+impl Context {
+    // Memory for a buffer or image, bind it at allocation.offset.
+    // The name is used for dedicated allocations, shared blocks are named after the type.
+    pub fn allocate(
+        &self,
+        requirements: MemoryRequirements,
+        properties: MemoryPropertyFlags,
+        name: &str,
+    ) -> Result<Allocation> {
+        let memory_type_index = self.find_memory_type_index(
+            MemoryPropertyFlags::from_raw(requirements.memory_type_bits),
+            properties,
+        )?;
+        let mut allocator = self
+            .allocator
+            .lock()
+            .map_err(|_| anyhow!("Memory allocator poisoned"))?;
+        let (block, offset, size) =
+            allocator.allocate(self, requirements, memory_type_index, name)?;
+        // the block was just used, it exists
+        let block_ref = allocator.blocks[block].as_ref().unwrap();
+        Ok(Allocation {
+            memory: block_ref.memory,
+            offset,
+            properties: block_ref.properties,
+            size,
+            mapped: block_ref.mapped,
+            block,
+            allocator: self.allocator.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(size: DeviceSize, memory_type_index: u32, dedicated: bool) -> Block {
+        Block {
+            memory: DeviceMemory::null(),
+            memory_type_index,
+            properties: MemoryPropertyFlags::DEVICE_LOCAL,
+            size,
+            mapped: None,
+            free: vec![(0, size)],
+            dedicated,
+        }
+    }
+
+    #[test]
+    fn aligned_carve_leaves_the_gap_free() {
+        let mut block = block(1024, 0, false);
+        assert_eq!(block.carve(100, 1), Some(0));
+        // 100 isn't aligned, the allocation starts at 256
+        assert_eq!(block.carve(64, 256), Some(256));
+        assert_eq!(block.free, [(100, 156), (320, 704)]);
+        // the gap is used by what fits into it
+        assert_eq!(block.carve(156, 1), Some(100));
+        assert_eq!(block.free, [(320, 704)]);
+        assert_eq!(block.carve(1024, 1), None);
+    }
+
+    #[test]
+    fn release_merges_with_both_neighbours() {
+        let mut block = block(300, 0, false);
+        let offsets = [0, 1, 2].map(|_| block.carve(100, 1).unwrap());
+        assert_eq!(offsets, [0, 100, 200]);
+        assert!(block.free.is_empty());
+
+        block.release(0, 100);
+        block.release(200, 100);
+        assert_eq!(block.free, [(0, 100), (200, 100)]);
+        assert!(!block.is_empty());
+        // the middle one joins both sides into a single range
+        block.release(100, 100);
+        assert_eq!(block.free, [(0, 300)]);
+        assert!(block.is_empty());
+    }
+
+    #[test]
+    fn empty_after_releasing_everything() {
+        let mut block = block(1024, 0, false);
+        let a = block.carve(10, 1).unwrap();
+        let b = block.carve(20, 64).unwrap();
+        let c = block.carve(30, 1).unwrap();
+        for (offset, size) in [(b, 20), (a, 10), (c, 30)] {
+            assert!(!block.is_empty());
+            block.release(offset, size);
+        }
+        assert!(block.is_empty());
+    }
+
+    #[test]
+    fn one_spare_block_per_memory_type() {
+        let mut blocks: Vec<_> = [
+            block(1024, 0, false),
+            block(1024, 0, false),
+            block(1024, 1, false),
+            block(4096, 0, true),
+        ]
+        .into_iter()
+        .map(|mut block| {
+            block.carve(512, 1).unwrap();
+            Some(block)
+        })
+        .collect();
+        let release = |blocks: &mut [Option<Block>], id: usize| {
+            blocks[id].as_mut().unwrap().release(0, 512);
+            let freed = empty_block_to_free(blocks, id).is_some();
+            if freed {
+                blocks[id] = None;
+            }
+            freed
+        };
+
+        // still in use
+        assert_eq!(empty_block_to_free(&blocks, 0), None);
+        // the first empty one of a type stays
+        assert!(!release(&mut blocks, 0));
+        assert!(release(&mut blocks, 1));
+        assert!(!release(&mut blocks, 2));
+        // dedicated blocks never become spares
+        assert!(release(&mut blocks, 3));
+
+        let spares = |memory_type_index: u32| {
+            blocks
+                .iter()
+                .flatten()
+                .filter(|block| block.memory_type_index == memory_type_index && block.is_empty())
+                .count()
+        };
+        assert_eq!((spares(0), spares(1)), (1, 1));
+    }
+}
@@ -1,19 +1,20 @@
 use std::{marker::PhantomData, mem::size_of};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use ash::{
     vk::{
-        Buffer, BufferCreateInfo, BufferUsageFlags, DeviceMemory, DeviceSize, MemoryAllocateInfo,
-        MemoryMapFlags, MemoryPropertyFlags, SharingMode, WHOLE_SIZE,
+        Buffer, BufferCopy, BufferCreateInfo, BufferUsageFlags, DeviceSize, MappedMemoryRange,
+        MemoryPropertyFlags, SharingMode,
     },
     Device,
 };
 
-use super::Context;
+use super::{allocator::Allocation, Context};
 
+// The memory is a range of a shared block, see Allocator, bound at allocation.offset.
 pub struct DeviceBuffer<T> {
     pub handle: Buffer,
-    pub memory: DeviceMemory,
+    pub allocation: Allocation,
     pub len: usize,
     pub _phantom: PhantomData<T>, // to store the type that is stored
     device: Device,
@@ -23,14 +24,17 @@ impl<T> Drop for DeviceBuffer<T> {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_buffer(self.handle, None);
-            self.device.free_memory(self.memory, None);
         }
+        // the allocation gives the range back afterwards
     }
 }
 
+// Mapped for its whole lifetime. Without HOST_COHERENT, writes have to be flushed
+// before the GPU reads them and reads are invalidated first, see flush and read_into.
 pub struct MappedDeviceBuffer<T> {
     buffer: DeviceBuffer<T>,
     mapped_ptr: *mut T,
+    coherent: bool,
 }
 
 impl<T> DeviceBuffer<T> {
@@ -54,29 +58,26 @@ impl<T> DeviceBuffer<T> {
         }?;
         context.name_object(handle, format!("{}Handle", name))?;
 
-        let memory = unsafe {
-            let requirements = context.device.get_buffer_memory_requirements(handle);
-            context.device.allocate_memory(
-                &MemoryAllocateInfo::builder()
-                    .allocation_size(requirements.size)
-                    .memory_type_index(context.find_memory_type_index(
-                        MemoryPropertyFlags::from_raw(requirements.memory_type_bits),
-                        properties,
-                    )?),
-                None,
-            )
+        let requirements = unsafe { context.device.get_buffer_memory_requirements(handle) };
+        let allocation = context.allocate(requirements, properties, &name)?;
+        unsafe {
+            context
+                .device
+                .bind_buffer_memory(handle, allocation.memory, allocation.offset)
         }?;
-        context.name_object(memory, format!("{}Memory", name))?;
-
-        unsafe { context.device.bind_buffer_memory(handle, memory, 0) }?;
         Ok(Self {
             handle,
-            memory,
+            allocation,
             len,
             _phantom: PhantomData,
             device: context.device.clone(),
         })
     }
+
+    // of the memory, can be more than len * size_of::<T>()
+    pub fn allocation_size(&self) -> DeviceSize {
+        self.allocation.size()
+    }
 }
 
 impl<T> MappedDeviceBuffer<T> {
@@ -86,35 +87,371 @@ impl<T> MappedDeviceBuffer<T> {
         len: usize,
         name: String,
     ) -> Result<Self> {
-        let buffer = DeviceBuffer::new(
+        Self::new_with_properties(
             context,
             usage,
             MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
             len,
-            name.clone(),
+            name,
+        )
+    }
+
+    // HOST_VISIBLE is added, e.g. HOST_CACHED for reading back results of the GPU
+    pub fn new_with_properties(
+        context: &Context,
+        usage: BufferUsageFlags,
+        properties: MemoryPropertyFlags,
+        len: usize,
+        name: String,
+    ) -> Result<Self> {
+        let buffer = DeviceBuffer::new(
+            context,
+            usage,
+            properties | MemoryPropertyFlags::HOST_VISIBLE,
+            len,
+            name,
         )?;
-        let mapped_ptr = unsafe {
-            context
-                .device
-                .map_memory(buffer.memory, 0, WHOLE_SIZE, MemoryMapFlags::empty())
-        }? as *mut T;
+        // the block is mapped as a whole, it can't be mapped again per buffer
+        let mapped_ptr = buffer
+            .allocation
+            .mapped_ptr()
+            .ok_or_else(|| anyhow!("Memory of a mapped buffer isn't host visible"))?
+            as *mut T;
+        let coherent = buffer
+            .allocation
+            .properties
+            .contains(MemoryPropertyFlags::HOST_COHERENT);
 
-        Ok(Self { buffer, mapped_ptr })
+        Ok(Self {
+            buffer,
+            mapped_ptr,
+            coherent,
+        })
     }
 
     pub fn handle(&self) -> Buffer {
         self.buffer.handle
     }
 
+    // without HOST_COHERENT, follow up with flush
     pub fn write(&self, data: &[T]) {
-        assert!(data.len() <= self.buffer.len);
+        assert!(
+            data.len() <= self.buffer.len,
+            "Writing {} elements to a buffer of {}",
+            data.len(),
+            self.buffer.len
+        );
+        unsafe {
+            self.mapped_ptr
+                .copy_from_nonoverlapping(data.as_ptr(), data.len());
+        }
+    }
+
+    // offset in elements, e.g. the slot of a ring-buffered uniform
+    pub fn write_at(&self, offset: usize, data: &[T]) {
+        assert!(
+            offset + data.len() <= self.buffer.len,
+            "Writing {} elements at {} to a buffer of {}",
+            data.len(),
+            offset,
+            self.buffer.len
+        );
         unsafe {
             self.mapped_ptr
+                .add(offset)
                 .copy_from_nonoverlapping(data.as_ptr(), data.len());
         }
     }
 
+    // Makes the writes visible to the GPU, call before submitting. Nothing to do if coherent.
+    pub fn flush(&self) -> Result<()> {
+        if self.coherent {
+            return Ok(());
+        }
+        unsafe {
+            self.buffer
+                .device
+                .flush_mapped_memory_ranges(&[self.whole_range()])
+        }?;
+        Ok(())
+    }
+
+    // Makes the writes of the GPU visible to the host, read_into does it already.
+    pub fn invalidate(&self) -> Result<()> {
+        if self.coherent {
+            return Ok(());
+        }
+        unsafe {
+            self.buffer
+                .device
+                .invalidate_mapped_memory_ranges(&[self.whole_range()])
+        }?;
+        Ok(())
+    }
+
+    // the allocation is aligned to nonCoherentAtomSize, neighbours aren't touched
+    fn whole_range(&self) -> MappedMemoryRange {
+        MappedMemoryRange::builder()
+            .memory(self.buffer.allocation.memory)
+            .offset(self.buffer.allocation.offset)
+            .size(self.buffer.allocation.size())
+            .build()
+    }
+
+    pub fn is_coherent(&self) -> bool {
+        self.coherent
+    }
+
+    pub fn size(&self) -> usize {
+        self.buffer.len
+    }
+}
+
+impl<T: Copy> MappedDeviceBuffer<T> {
+    // like write, converting on the way, e.g. narrowing indices without a temporary Vec
+    pub fn write_iter(&self, data: impl ExactSizeIterator<Item = T>) {
+        assert!(
+            data.len() <= self.buffer.len,
+            "Writing {} elements to a buffer of {}",
+            data.len(),
+            self.buffer.len
+        );
+        // take guards against an iterator reporting the wrong length
+        for (i, element) in data.take(self.buffer.len).enumerate() {
+            unsafe { self.mapped_ptr.add(i).write(element) };
+        }
+    }
+
+    // Copies the first out.len() elements, the GPU has to be done writing them,
+    // e.g. wait for the fence of the submission.
+    pub fn read_into(&self, out: &mut [T]) -> Result<()> {
+        assert!(
+            out.len() <= self.buffer.len,
+            "Reading {} elements from a buffer of {}",
+            out.len(),
+            self.buffer.len
+        );
+        self.invalidate()?;
+        unsafe {
+            out.as_mut_ptr()
+                .copy_from_nonoverlapping(self.mapped_ptr, out.len());
+        }
+        Ok(())
+    }
+
+    pub fn read(&self) -> Result<Vec<T>> {
+        let mut out: Vec<T> = Vec::with_capacity(self.buffer.len);
+        self.invalidate()?;
+        unsafe {
+            out.as_mut_ptr()
+                .copy_from_nonoverlapping(self.mapped_ptr, self.buffer.len);
+            out.set_len(self.buffer.len);
+        }
+        Ok(out)
+    }
+}
+
+// Lives in DEVICE_LOCAL memory, which is faster to read on discrete GPUs.
+// Every write goes through a staging buffer and waits for the copy,
+// so this is for data that is written once or rarely.
+pub struct DeviceLocalBuffer<T> {
+    buffer: DeviceBuffer<T>,
+    name: String,
+}
+
+impl<T> DeviceLocalBuffer<T> {
+    pub fn new(
+        context: &Context,
+        usage: BufferUsageFlags,
+        len: usize,
+        name: String,
+    ) -> Result<Self> {
+        let buffer = DeviceBuffer::new(
+            context,
+            usage | BufferUsageFlags::TRANSFER_DST,
+            MemoryPropertyFlags::DEVICE_LOCAL,
+            len,
+            name.clone(),
+        )?;
+        Ok(Self { buffer, name })
+    }
+
+    pub fn handle(&self) -> Buffer {
+        self.buffer.handle
+    }
+
+    pub fn write(&self, context: &Context, data: &[T]) -> Result<()> {
+        self.upload(context, data.len(), |staging| staging.write(data))
+    }
+
+    // len elements, fill writes them into the staging buffer
+    fn upload(
+        &self,
+        context: &Context,
+        len: usize,
+        fill: impl FnOnce(&MappedDeviceBuffer<T>),
+    ) -> Result<()> {
+        if len > self.buffer.len {
+            bail!(
+                "Writing {} elements to {}, which holds {}",
+                len,
+                self.name,
+                self.buffer.len
+            );
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        let staging = MappedDeviceBuffer::new(
+            context,
+            BufferUsageFlags::TRANSFER_SRC,
+            len,
+            format!("{}Staging", self.name),
+        )?;
+        fill(&staging);
+
+        context.one_shot(&format!("{}Upload", self.name), |command_buffer| unsafe {
+            context.device.cmd_copy_buffer(
+                command_buffer,
+                staging.handle(),
+                self.buffer.handle,
+                &[BufferCopy::builder()
+                    .size((len * size_of::<T>()) as DeviceSize)
+                    .build()],
+            );
+        })
+    }
+
     pub fn size(&self) -> usize {
         self.buffer.len
     }
 }
+
+impl<T: Copy> DeviceLocalBuffer<T> {
+    // see MappedDeviceBuffer::write_iter
+    pub fn write_iter(
+        &self,
+        context: &Context,
+        data: impl ExactSizeIterator<Item = T>,
+    ) -> Result<()> {
+        self.upload(context, data.len(), |staging| staging.write_iter(data))
+    }
+}
+
+// rounds size up to the next multiple of alignment, which Vulkan guarantees to be a power of two
+pub fn align_up(size: DeviceSize, alignment: DeviceSize) -> DeviceSize {
+    if alignment == 0 {
+        return size;
+    }
+    debug_assert!(alignment.is_power_of_two());
+    (size + alignment - 1) & !(alignment - 1)
+}
+
+// One uniform per frame in a single buffer, meant for UNIFORM_BUFFER_DYNAMIC descriptors.
+// Each frame starts at a multiple of minUniformBufferOffsetAlignment,
+// otherwise the dynamic offsets would be invalid.
+pub struct UniformRing<T> {
+    buffer: MappedDeviceBuffer<u8>,
+    stride: DeviceSize,
+    frames: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> UniformRing<T> {
+    pub fn new(context: &Context, frames: usize, name: String) -> Result<Self> {
+        let alignment = unsafe {
+            context
+                .instance
+                .get_physical_device_properties(context.physical_device)
+        }
+        .limits
+        .min_uniform_buffer_offset_alignment;
+        let stride = align_up(size_of::<T>() as DeviceSize, alignment);
+
+        let buffer = MappedDeviceBuffer::new(
+            context,
+            BufferUsageFlags::UNIFORM_BUFFER,
+            stride as usize * frames,
+            name,
+        )?;
+
+        Ok(Self {
+            buffer,
+            stride,
+            frames,
+            _phantom: PhantomData,
+        })
+    }
+
+    pub fn handle(&self) -> Buffer {
+        self.buffer.handle()
+    }
+
+    pub fn stride(&self) -> DeviceSize {
+        self.stride
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    // the dynamic offset to bind for this frame
+    pub fn offset(&self, frame: usize) -> Result<u32> {
+        if frame >= self.frames {
+            bail!("No frame {} in a uniform ring of {}", frame, self.frames);
+        }
+        dynamic_offset(self.stride, frame)
+    }
+
+    pub fn offsets(&self) -> Result<Vec<u32>> {
+        (0..self.frames).map(|frame| self.offset(frame)).collect()
+    }
+
+    pub fn write(&self, frame: usize, data: &T) -> Result<()> {
+        let offset = self.offset(frame)? as usize;
+        unsafe {
+            (self.buffer.mapped_ptr.add(offset) as *mut T).copy_from_nonoverlapping(data, 1);
+        }
+        Ok(())
+    }
+}
+
+// dynamic offsets are only 32 bit
+fn dynamic_offset(stride: DeviceSize, frame: usize) -> Result<u32> {
+    let offset = stride
+        .checked_mul(frame as DeviceSize)
+        .ok_or_else(|| anyhow!("Uniform offset of frame {} overflows", frame))?;
+    Ok(u32::try_from(offset)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_up_rounds_to_the_alignment() {
+        for alignment in [1, 16, 64, 256] {
+            assert_eq!(align_up(0, alignment), 0);
+            assert_eq!(align_up(1, alignment), alignment);
+            assert_eq!(align_up(alignment, alignment), alignment);
+            assert_eq!(align_up(alignment + 1, alignment), 2 * alignment);
+        }
+        // a limit of 0 means no alignment
+        assert_eq!(align_up(12, 0), 12);
+    }
+
+    #[test]
+    fn dynamic_offsets_are_aligned_and_fit() {
+        // e.g. two mat4 with the common alignments
+        for alignment in [16, 64, 256] {
+            let stride = align_up(128, alignment);
+            for frame in 0..3 {
+                let offset = dynamic_offset(stride, frame).unwrap() as DeviceSize;
+                assert_eq!(offset % alignment, 0);
+                assert_eq!(offset, frame as DeviceSize * stride);
+            }
+        }
+        assert!(dynamic_offset(1 << 31, 2).is_err());
+    }
+}
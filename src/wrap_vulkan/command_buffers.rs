@@ -0,0 +1,50 @@
+use anyhow::Result;
+use ash::{
+    vk::{CommandBuffer, CommandPool},
+    Device,
+};
+
+use super::Context;
+
+// Frees the buffers on drop instead of relying on the pool being destroyed.
+// Has to be dropped before the pool, and the GPU must be done with the buffers by then.
+// Context::alloc_command_buffers stays for handing out raw buffers.
+pub struct OwnedCommandBuffers {
+    buffers: Vec<CommandBuffer>,
+    pool: CommandPool,
+    device: Device,
+}
+
+impl Drop for OwnedCommandBuffers {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.free_command_buffers(self.pool, &self.buffers);
+        }
+    }
+}
+
+impl OwnedCommandBuffers {
+    pub fn new(context: &Context, count: u32, name: String) -> Result<Self> {
+        Ok(Self {
+            buffers: context.alloc_command_buffers(count, name)?,
+            pool: context.pool,
+            device: context.device.clone(),
+        })
+    }
+
+    pub fn get(&self, index: usize) -> CommandBuffer {
+        self.buffers[index]
+    }
+
+    pub fn handles(&self) -> &[CommandBuffer] {
+        &self.buffers
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+}
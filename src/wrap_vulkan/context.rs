@@ -1,8 +1,13 @@
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, Result};
 use std::{
     ffi::{CStr, CString},
     mem::ManuallyDrop,
     ops::BitAnd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 use winit::window::Window;
 
@@ -10,22 +15,33 @@ use winit::window::Window;
 use ash::extensions::ext::DebugUtils;
 use ash::{
     extensions::khr::Swapchain,
+    prelude::VkResult,
     vk::{
         api_version_major, api_version_minor, make_api_version, ApplicationInfo, CommandBuffer,
-        CommandBufferAllocateInfo, CommandBufferLevel, CommandPool, CommandPoolCreateFlags,
-        CommandPoolCreateInfo, DeviceCreateInfo, DeviceQueueCreateInfo, Extent2D, Format,
-        FormatFeatureFlags, Handle, ImageTiling, InstanceCreateInfo, MemoryPropertyFlags,
-        PhysicalDevice, PhysicalDeviceBufferDeviceAddressFeatures, PhysicalDeviceFeatures2,
-        PhysicalDeviceMultiviewFeatures, Queue, QueueFlags, TRUE,
+        CommandBufferAllocateInfo, CommandBufferBeginInfo, CommandBufferLevel,
+        CommandBufferUsageFlags, CommandPool, CommandPoolCreateFlags, CommandPoolCreateInfo,
+        CompareOp, DeviceCreateInfo, DeviceQueueCreateInfo, ExtMemoryBudgetFn, Extent2D, Format,
+        FormatFeatureFlags, Handle, ImageAspectFlags, ImageTiling, InstanceCreateInfo,
+        MemoryHeapFlags, MemoryPropertyFlags, PhysicalDevice,
+        PhysicalDeviceBufferDeviceAddressFeatures, PhysicalDeviceFeatures, PhysicalDeviceFeatures2,
+        PhysicalDeviceMemoryBudgetPropertiesEXT, PhysicalDeviceMemoryProperties2,
+        PhysicalDeviceMultiviewFeatures, PhysicalDeviceTimelineSemaphoreFeatures,
+        PhysicalDeviceType, Queue, QueueFlags, SampleCountFlags, SubmitInfo,
+        SurfaceCapabilitiesKHR, TRUE,
     },
     Device, Entry, Instance,
 };
 
-use crate::wrap_openxr;
+use crate::{wrap_openxr, VrvError};
 
 #[cfg(feature = "validation_vulkan")]
 use super::Debug;
-use super::{surface::Detail, SurfaceRelated};
+use super::{
+    allocator::Allocator,
+    surface::{ColorEncoding, Detail},
+    sync::create_fence,
+    SurfaceRelated,
+};
 
 pub struct Context {
     pub entry: Entry,
@@ -41,6 +57,45 @@ pub struct Context {
 
     pub pool: CommandPool,
     pub queue: Queue,
+
+    // the targeted one, the device might support a newer version
+    pub api_version: u32,
+
+    // VK_EXT_memory_budget is optional
+    pub memory_budget_supported: bool,
+    // needs a 1.2 target
+    pub timeline_semaphore_supported: bool,
+    // the optional core features the device was created with, e.g. sampler_anisotropy
+    pub enabled_features: PhysicalDeviceFeatures,
+    // preferred for the window surface and the HMD images
+    pub color_encoding: ColorEncoding,
+    // see VulkanSettings::reverse_z, depth_compare_op and depth_clear_value follow it
+    pub reverse_z: bool,
+    // all DeviceBuffers and DeviceImages get their memory from it, see allocate
+    pub(crate) allocator: Arc<Mutex<Allocator>>,
+    // set by check_lost, nothing submitted to the device completes anymore
+    device_lost: AtomicBool,
+}
+
+// summed over all device-local heaps, in bytes
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryBudget {
+    pub used: u64,
+    pub budget: u64,
+}
+
+impl MemoryBudget {
+    pub fn available(&self) -> u64 {
+        self.budget.saturating_sub(self.used)
+    }
+
+    // fraction of the budget in use, can exceed 1.0 when over budget
+    pub fn pressure(&self) -> f32 {
+        if self.budget == 0 {
+            return 1.0;
+        }
+        self.used as f32 / self.budget as f32
+    }
 }
 
 impl Drop for Context {
@@ -48,6 +103,9 @@ impl Drop for Context {
         unsafe {
             ManuallyDrop::drop(&mut self.window_surface_related);
             self.device.destroy_command_pool(self.pool, None);
+            if let Ok(mut allocator) = self.allocator.lock() {
+                allocator.free_all();
+            }
             #[cfg(feature = "validation_vulkan")]
             ManuallyDrop::drop(&mut self.debug);
             self.device.destroy_device(None);
@@ -56,36 +114,181 @@ impl Drop for Context {
     }
 }
 
+#[cfg(feature = "validation_vulkan")]
+const VALIDATION_LAYER_NAME: &str = "VK_LAYER_KHRONOS_validation";
+
+// The feature is the master switch, with it compiled in
+// only VRV_VALIDATION=1 enables the layer, it's off by default.
+#[cfg(feature = "validation_vulkan")]
+fn validation_layer_wanted(entry: &Entry, layer_name: &CStr) -> Result<bool> {
+    if std::env::var("VRV_VALIDATION").map_or(true, |value| value != "1") {
+        log::info!("Vulkan validation compiled in, set VRV_VALIDATION=1 to enable it");
+        return Ok(false);
+    }
+
+    if !entry
+        .enumerate_instance_layer_properties()?
+        .iter()
+        .any(|prop| unsafe { CStr::from_ptr(prop.layer_name.as_ptr()) } == layer_name)
+    {
+        log::warn!(
+            "Vulkan validation layer {:?} not available, continuing without it",
+            layer_name
+        );
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+fn allowed_extent(capabilities: &SurfaceCapabilitiesKHR, wanted: Extent2D) -> Extent2D {
+    if capabilities.current_extent.height == std::u32::MAX {
+        Extent2D {
+            width: std::cmp::max(
+                capabilities.min_image_extent.width,
+                std::cmp::min(capabilities.max_image_extent.width, wanted.width),
+            ),
+            height: std::cmp::max(
+                capabilities.min_image_extent.height,
+                std::cmp::min(capabilities.max_image_extent.height, wanted.height),
+            ),
+        }
+    } else {
+        // The extent of the swapchain can't be choosen freely, wanted is ignored
+        capabilities.current_extent
+    }
+}
+
+pub fn has_stencil(format: Format) -> bool {
+    matches!(
+        format,
+        Format::S8_UINT
+            | Format::D16_UNORM_S8_UINT
+            | Format::D24_UNORM_S8_UINT
+            | Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+// the combined formats need both aspects in views and barriers, even if only depth is used
+pub fn depth_aspect_flags(format: Format) -> ImageAspectFlags {
+    match format {
+        Format::S8_UINT => ImageAspectFlags::STENCIL,
+        format if has_stencil(format) => ImageAspectFlags::DEPTH | ImageAspectFlags::STENCIL,
+        _ => ImageAspectFlags::DEPTH,
+    }
+}
+
+// Destroys the instance if Context::create bails before the Context owns it.
+// What is created from the instance is declared after the guard, so it is dropped first.
+struct InstanceGuard<'a> {
+    instance: &'a Instance,
+}
+
+impl InstanceGuard<'_> {
+    fn disarm(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for InstanceGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { self.instance.destroy_instance(None) };
+    }
+}
+
+// the backoff for the physical device doesn't grow beyond this
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct VulkanSettings {
+    // at least 1.1 for multiview, newer versions unlock e.g. timeline semaphores
+    pub api_version: u32,
+    // OpenXR might not be ready to hand out the physical device right after the runtime started
+    pub physical_device_attempts: u32,
+    pub physical_device_retry_delay: Duration, // doubled after each attempt, up to MAX_RETRY_DELAY
+    // enabled on top of what the window, OpenXR and the crate need, checked for availability
+    pub extra_instance_extensions: Vec<CString>,
+    pub extra_device_extensions: Vec<CString>,
+    // sRGB by default for the window and the HMD alike, shaders output linear color then
+    pub color_encoding: ColorEncoding,
+    // Has to match ProjectionParams::reverse_z, the pipelines compare depth with GREATER
+    // and the HMD and window passes clear depth to 0 then.
+    pub reverse_z: bool,
+}
+
+impl Default for VulkanSettings {
+    fn default() -> Self {
+        Self {
+            api_version: make_api_version(0, 1, 1, 0),
+            physical_device_attempts: 5,
+            physical_device_retry_delay: Duration::from_millis(100),
+            extra_instance_extensions: Vec::new(),
+            extra_device_extensions: Vec::new(),
+            color_encoding: ColorEncoding::default(),
+            reverse_z: false,
+        }
+    }
+}
+
 impl Context {
     pub fn new(window: &Window, wrap_openxr: &wrap_openxr::Context) -> Result<Context> {
-        #[cfg(feature = "validation_vulkan")]
-        const VALIDATION_LAYER_NAME: &'static str = "VK_LAYER_KHRONOS_validation";
-        #[cfg(feature = "validation_vulkan")]
-        let c_str_layer_name = CString::new(VALIDATION_LAYER_NAME).unwrap();
-        #[cfg(feature = "validation_vulkan")]
-        let c_str_layer_names = [c_str_layer_name.as_ptr()];
+        Self::new_with_settings(window, wrap_openxr, &VulkanSettings::default())
+    }
 
-        #[cfg(not(feature = "validation_vulkan"))]
-        let c_str_layer_names = [];
+    pub fn new_with_settings(
+        window: &Window,
+        wrap_openxr: &wrap_openxr::Context,
+        settings: &VulkanSettings,
+    ) -> Result<Context> {
+        Self::create(window, Some(wrap_openxr), settings).map_err(VrvError::typed)
+    }
 
-        log::info!("Creating new Vulkan State");
+    // Without a runtime to ask, instance and device are created directly
+    // and the first discrete GPU is preferred. For running windowed only, e.g. in CI.
+    pub fn new_without_openxr(window: &Window, settings: &VulkanSettings) -> Result<Context> {
+        Self::create(window, None, settings).map_err(VrvError::typed)
+    }
 
-        let vk_target_version = make_api_version(0, 1, 1, 0); // seems good enough for multiview
+    fn create(
+        window: &Window,
+        wrap_openxr: Option<&wrap_openxr::Context>,
+        settings: &VulkanSettings,
+    ) -> Result<Context> {
+        log::info!("Creating new Vulkan State");
 
-        let reqs = wrap_openxr.get_graphics_requirements()?;
-        let xr_vk_target_version = openxr::Version::new(
-            api_version_major(vk_target_version) as u16,
-            api_version_minor(vk_target_version) as u16,
-            0,
+        let vk_target_version = settings.api_version;
+        let (target_major, target_minor) = (
+            api_version_major(vk_target_version),
+            api_version_minor(vk_target_version),
         );
+        if vk_target_version < make_api_version(0, 1, 1, 0) {
+            bail!(
+                "Vulkan target version {}.{} is too old, multiview needs 1.1",
+                target_major,
+                target_minor
+            );
+        }
+        log::info!("Targeting Vulkan {}.{}", target_major, target_minor);
 
-        if reqs.min_api_version_supported > xr_vk_target_version
-            || reqs.max_api_version_supported < xr_vk_target_version
-        {
-            bail!("OpenXR needs other Vulkan version");
+        if let Some(wrap_openxr) = wrap_openxr {
+            let reqs = wrap_openxr.get_graphics_requirements()?;
+            let xr_vk_target_version =
+                openxr::Version::new(target_major as u16, target_minor as u16, 0);
+
+            if reqs.min_api_version_supported > xr_vk_target_version
+                || reqs.max_api_version_supported < xr_vk_target_version
+            {
+                bail!(
+                    "OpenXR supports Vulkan {} to {}, not the target {}.{}",
+                    reqs.min_api_version_supported,
+                    reqs.max_api_version_supported,
+                    target_major,
+                    target_minor
+                );
+            }
         }
 
-        let instance_extensions: Vec<CString> = [
+        let mut instance_extensions: Vec<CString> = [
             ash_window::enumerate_required_extensions(window)?
                 .iter()
                 .map(|&x| -> CString { unsafe { CStr::from_ptr(x) }.into() }) // new rust version
@@ -96,40 +299,81 @@ impl Context {
         ]
         .concat::<CString>();
 
+        let entry = unsafe { Entry::load() }?;
+
+        let available_instance_extensions = entry.enumerate_instance_extension_properties(None)?;
+        for extra in &settings.extra_instance_extensions {
+            if !available_instance_extensions.iter().any(
+                |prop| unsafe { CStr::from_ptr(prop.extension_name.as_ptr()) } == extra.as_c_str(),
+            ) {
+                bail!(
+                    "Vulkan instance doesn't support requested extension: {:?}",
+                    extra
+                );
+            }
+            if !instance_extensions.contains(extra) {
+                log::info!("Enabling extra Vulkan instance extension {:?}", extra);
+                instance_extensions.push(extra.clone());
+            }
+        }
+
         log::trace!("Vulkan instance extensions: {:?}", instance_extensions);
 
-        let entry = unsafe { Entry::load() }?;
+        // a 1.0 loader doesn't know this query
+        let instance_version = entry
+            .try_enumerate_instance_version()?
+            .unwrap_or(make_api_version(0, 1, 0, 0));
+        if instance_version < vk_target_version {
+            bail!(
+                "Vulkan loader only supports {}.{}, not the target {}.{}",
+                api_version_major(instance_version),
+                api_version_minor(instance_version),
+                target_major,
+                target_minor
+            );
+        }
+
+        #[cfg(feature = "validation_vulkan")]
+        let c_str_layer_name = CString::new(VALIDATION_LAYER_NAME).unwrap();
+        #[cfg(feature = "validation_vulkan")]
+        let c_str_layer_names = if validation_layer_wanted(&entry, &c_str_layer_name)? {
+            vec![c_str_layer_name.as_ptr()]
+        } else {
+            vec![]
+        };
+
+        #[cfg(not(feature = "validation_vulkan"))]
+        let c_str_layer_names: Vec<*const std::os::raw::c_char> = vec![];
 
         #[cfg(feature = "validation_vulkan")]
         let mut debug_info = Debug::info();
 
+        let application_info = ApplicationInfo::builder().api_version(vk_target_version);
+        let instance_extension_names = instance_extensions
+            .iter()
+            .map(|ext| ext.as_c_str().as_ptr())
+            .collect::<Vec<_>>();
         // I really couldn't find a better way to do this
         // the problem is that push_next can't take a "null object"
-        let instance = unsafe {
-            wrap_openxr.get_vulkan_instance(
-                &entry,
-                #[cfg(feature = "validation_vulkan")]
-                &InstanceCreateInfo::builder()
-                    .application_info(&ApplicationInfo::builder().api_version(vk_target_version))
-                    .enabled_extension_names(
-                        &instance_extensions
-                            .iter()
-                            .map(|ext| ext.as_c_str().as_ptr())
-                            .collect::<Vec<_>>(),
-                    )
-                    .enabled_layer_names(&c_str_layer_names)
-                    .push_next(&mut debug_info),
-                #[cfg(not(feature = "validation_vulkan"))]
-                &InstanceCreateInfo::builder()
-                    .application_info(&ApplicationInfo::builder().api_version(vk_target_version))
-                    .enabled_extension_names(
-                        &instance_extensions
-                            .iter()
-                            .map(|ext| ext.as_c_str().as_ptr())
-                            .collect::<Vec<_>>(),
-                    ),
-            )
-        }?;
+        #[cfg(feature = "validation_vulkan")]
+        let instance_create_info = InstanceCreateInfo::builder()
+            .application_info(&application_info)
+            .enabled_extension_names(&instance_extension_names)
+            .enabled_layer_names(&c_str_layer_names)
+            .push_next(&mut debug_info);
+        #[cfg(not(feature = "validation_vulkan"))]
+        let instance_create_info = InstanceCreateInfo::builder()
+            .application_info(&application_info)
+            .enabled_extension_names(&instance_extension_names);
+        let instance = match wrap_openxr {
+            Some(wrap_openxr) => {
+                unsafe { wrap_openxr.get_vulkan_instance(&entry, &instance_create_info) }?
+            }
+            None => unsafe { entry.create_instance(&instance_create_info, None) }?,
+        };
+        let instance_guard = InstanceGuard {
+            instance: &instance,
+        };
 
         #[cfg(feature = "validation_vulkan")]
         let debug = Debug::new(&entry, &instance)?;
@@ -144,7 +388,39 @@ impl Context {
         }
 
         // leverage OpenXR to choose for us
-        let physical_device = wrap_openxr.get_vulkan_physical_device(&instance)?;
+        let physical_device = if let Some(wrap_openxr) = wrap_openxr {
+            let mut delay = settings.physical_device_retry_delay;
+            let mut attempt = 1;
+            loop {
+                match wrap_openxr.get_vulkan_physical_device(&instance) {
+                    Ok(physical_device) => break physical_device,
+                    Err(e) if attempt < settings.physical_device_attempts => {
+                        log::warn!(
+                            "Getting physical device from OpenXR failed (attempt {}/{}): {}, retrying in {:?}",
+                            attempt,
+                            settings.physical_device_attempts,
+                            e,
+                            delay
+                        );
+                        std::thread::sleep(delay);
+                        delay = delay.saturating_mul(2).min(MAX_RETRY_DELAY);
+                        attempt += 1;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        } else {
+            let physical_devices = unsafe { instance.enumerate_physical_devices() }?;
+            physical_devices
+                .iter()
+                .copied()
+                .find(|&physical_device| {
+                    unsafe { instance.get_physical_device_properties(physical_device) }.device_type
+                        == PhysicalDeviceType::DISCRETE_GPU
+                })
+                .or_else(|| physical_devices.first().copied())
+                .ok_or(VrvError::NoSuitablePhysicalDevice)?
+        };
 
         let physical_device_extension_properties =
             unsafe { instance.enumerate_device_extension_properties(physical_device) }?;
@@ -154,7 +430,13 @@ impl Context {
             });
         }
 
-        let device_extensions: Vec<CString> = vec![Swapchain::name().into()];
+        let mut device_extensions: Vec<CString> = vec![Swapchain::name().into()];
+        for extra in &settings.extra_device_extensions {
+            if !device_extensions.contains(extra) {
+                log::info!("Enabling extra Vulkan device extension {:?}", extra);
+                device_extensions.push(extra.clone());
+            }
+        }
 
         log::trace!("Vulkan device extensions: {:?}", device_extensions);
 
@@ -167,24 +449,84 @@ impl Context {
                 bail!("Physical device doesn't support extension: {:?}", req_ext);
             }
         }
+
+        let memory_budget_supported = physical_device_extension_properties.iter().any(|prop| {
+            let name = unsafe { CStr::from_ptr(prop.extension_name.as_ptr()) };
+            name == ExtMemoryBudgetFn::name()
+        });
+        // might already be among the extra extensions
+        let memory_budget_enabled = device_extensions
+            .iter()
+            .any(|ext| ext.as_c_str() == ExtMemoryBudgetFn::name());
+        if memory_budget_supported && !memory_budget_enabled {
+            device_extensions.push(ExtMemoryBudgetFn::name().into());
+        } else if !memory_budget_supported {
+            log::info!("VK_EXT_memory_budget not available, no memory budget reporting");
+        }
         let physical_device_properties =
             unsafe { instance.get_physical_device_properties(physical_device) };
         if physical_device_properties.api_version < vk_target_version {
-            unsafe { instance.destroy_instance(None) };
-            bail!("Vulkan phyiscal device doesn't support target version");
+            bail!(
+                "Vulkan phyiscal device only supports {}.{}, not the target {}.{}",
+                api_version_major(physical_device_properties.api_version),
+                api_version_minor(physical_device_properties.api_version),
+                target_major,
+                target_minor
+            );
         }
 
         // check for buffer_device_address support
         let mut physical_device_buffer_device_address_features =
             PhysicalDeviceBufferDeviceAddressFeatures::default();
+        // Timeline semaphores are core in 1.2 and optional there. The struct isn't known before,
+        // so it is neither queried nor enabled then (VK_KHR_timeline_semaphore isn't used).
+        let timeline_semaphore_core = vk_target_version >= make_api_version(0, 1, 2, 0);
+        let mut physical_device_timeline_semaphore_features =
+            PhysicalDeviceTimelineSemaphoreFeatures::default();
         let mut physical_device_features2 = PhysicalDeviceFeatures2::builder()
             .push_next(&mut physical_device_buffer_device_address_features);
+        if timeline_semaphore_core {
+            physical_device_features2 = physical_device_features2
+                .push_next(&mut physical_device_timeline_semaphore_features);
+        }
         unsafe {
             instance.get_physical_device_features2(physical_device, &mut physical_device_features2)
         };
+        let supported_features = physical_device_features2.features;
         if physical_device_buffer_device_address_features.buffer_device_address != TRUE {
             bail!("Vulkan phyiscal device doesn't support VkPhysicalDeviceBufferDeviceAddressFeaturesKHR::bufferDeviceAddress");
         }
+        let timeline_semaphore_supported = timeline_semaphore_core
+            && physical_device_timeline_semaphore_features.timeline_semaphore == TRUE;
+        log::info!(
+            "Vulkan timeline semaphores {}",
+            if timeline_semaphore_supported {
+                "enabled"
+            } else {
+                "not available, using binary semaphores"
+            }
+        );
+
+        // Only enabled if supported, samplers fall back to no anisotropy.
+        // Wireframe and wide lines fail at pipeline creation without them.
+        let enabled_features = PhysicalDeviceFeatures::builder()
+            .sampler_anisotropy(supported_features.sampler_anisotropy == TRUE)
+            .fill_mode_non_solid(supported_features.fill_mode_non_solid == TRUE)
+            .wide_lines(supported_features.wide_lines == TRUE)
+            .build();
+        let availability = |enabled| {
+            if enabled == TRUE {
+                "enabled"
+            } else {
+                "not available"
+            }
+        };
+        log::info!(
+            "Vulkan sampler anisotropy {}, fill mode non solid {}, wide lines {}",
+            availability(enabled_features.sampler_anisotropy),
+            availability(enabled_features.fill_mode_non_solid),
+            availability(enabled_features.wide_lines),
+        );
 
         let surface_related = SurfaceRelated::new(&entry, &instance, window)?;
 
@@ -216,39 +558,47 @@ impl Context {
                         None
                     }
                 })
-                .ok_or(Error::msg("Vulkan device has no suitable queue"))?;
+                .ok_or(VrvError::NoSuitableQueue)?;
 
         log::trace!("Using queue nr. {}", queue_family_index);
 
         let mut physical_device_multiview_features =
             PhysicalDeviceMultiviewFeatures::builder().multiview(true);
-        let device = unsafe {
-            wrap_openxr.get_vulkan_device(
-                &entry,
-                &instance,
-                physical_device,
-                &DeviceCreateInfo::builder()
-                    .queue_create_infos(&[DeviceQueueCreateInfo::builder()
-                        .queue_family_index(queue_family_index)
-                        .queue_priorities(&[1.0])
-                        .build()])
-                    .enabled_extension_names(
-                        &device_extensions
-                            .iter()
-                            .map(|ext| ext.as_ptr())
-                            .collect::<Vec<_>>(),
-                    )
-                    .enabled_layer_names(if cfg!(feature = "validation_vulkan") {
-                        &c_str_layer_names
-                    } else {
-                        &[]
-                    })
-                    .push_next(&mut physical_device_multiview_features)
-                    .push_next(&mut physical_device_buffer_device_address_features),
-            )
-        }?;
+        let mut physical_device_timeline_semaphore_features =
+            PhysicalDeviceTimelineSemaphoreFeatures::builder().timeline_semaphore(true);
+        let queue_create_infos = [DeviceQueueCreateInfo::builder()
+            .queue_family_index(queue_family_index)
+            .queue_priorities(&[1.0])
+            .build()];
+        let device_extension_names = device_extensions
+            .iter()
+            .map(|ext| ext.as_ptr())
+            .collect::<Vec<_>>();
+        let mut device_create_info = DeviceCreateInfo::builder()
+            .queue_create_infos(&queue_create_infos)
+            .enabled_extension_names(&device_extension_names)
+            .enabled_layer_names(&c_str_layer_names)
+            .enabled_features(&enabled_features)
+            .push_next(&mut physical_device_multiview_features)
+            .push_next(&mut physical_device_buffer_device_address_features);
+        // only with 1.2, see timeline_semaphore_core
+        if timeline_semaphore_supported {
+            device_create_info =
+                device_create_info.push_next(&mut physical_device_timeline_semaphore_features);
+        }
+        let device = match wrap_openxr {
+            Some(wrap_openxr) => unsafe {
+                wrap_openxr.get_vulkan_device(
+                    &entry,
+                    &instance,
+                    physical_device,
+                    &device_create_info,
+                )
+            }?,
+            None => unsafe { instance.create_device(physical_device, &device_create_info, None) }?,
+        };
 
-        let pool = unsafe {
+        let pool = match unsafe {
             device.create_command_pool(
                 &CommandPoolCreateInfo::builder()
                     .flags(
@@ -258,11 +608,25 @@ impl Context {
                     .queue_family_index(queue_family_index),
                 None,
             )
-        }?;
+        } {
+            Ok(pool) => pool,
+            Err(e) => {
+                unsafe { device.destroy_device(None) };
+                return Err(e.into());
+            }
+        };
 
         let queue = unsafe { device.get_device_queue(queue_family_index, 0) };
 
-        Ok(Self {
+        // sub-allocations at multiples of both never share a page or a flushed range
+        let limits = unsafe { instance.get_physical_device_properties(physical_device) }.limits;
+        let granularity = limits
+            .buffer_image_granularity
+            .max(limits.non_coherent_atom_size);
+        let allocator = Arc::new(Mutex::new(Allocator::new(&device, granularity)));
+
+        instance_guard.disarm();
+        let context = Self {
             entry,
             instance,
             physical_device,
@@ -276,7 +640,64 @@ impl Context {
 
             pool,
             queue,
-        })
+
+            api_version: vk_target_version,
+
+            memory_budget_supported,
+            timeline_semaphore_supported,
+            enabled_features,
+            color_encoding: settings.color_encoding,
+            reverse_z: settings.reverse_z,
+            allocator,
+            device_lost: AtomicBool::new(false),
+        };
+        if let Some(budget) = context.memory_budget() {
+            log::info!(
+                "Vulkan device-local memory: {} MiB used, {} MiB budget, {} MiB available",
+                budget.used >> 20,
+                budget.budget >> 20,
+                budget.available() >> 20
+            );
+        }
+        Ok(context)
+    }
+
+    // What the crate's buffers and images allocated, unlike memory_budget this is always
+    // available but misses everything allocated elsewhere, e.g. swapchains and the runtime.
+    pub fn allocated_bytes(&self) -> u64 {
+        self.allocator
+            .lock()
+            .map_or(0, |allocator| allocator.allocated_bytes())
+    }
+
+    // None if VK_EXT_memory_budget isn't supported.
+    // The values are only updated by the driver now and then, so this is a rough estimate.
+    pub fn memory_budget(&self) -> Option<MemoryBudget> {
+        if !self.memory_budget_supported {
+            return None;
+        }
+
+        let mut budget_properties = PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        let mut properties =
+            PhysicalDeviceMemoryProperties2::builder().push_next(&mut budget_properties);
+        unsafe {
+            self.instance
+                .get_physical_device_memory_properties2(self.physical_device, &mut properties)
+        };
+        let memory_properties = properties.memory_properties;
+
+        Some(
+            (0..memory_properties.memory_heap_count as usize)
+                .filter(|&i| {
+                    memory_properties.memory_heaps[i]
+                        .flags
+                        .contains(MemoryHeapFlags::DEVICE_LOCAL)
+                })
+                .fold(MemoryBudget { used: 0, budget: 0 }, |acc, i| MemoryBudget {
+                    used: acc.used + budget_properties.heap_usage[i],
+                    budget: acc.budget + budget_properties.heap_budget[i],
+                }),
+        )
     }
 
     #[cfg(feature = "validation_vulkan")]
@@ -334,19 +755,28 @@ impl Context {
                     None
                 }
             })
-            .ok_or(Error::msg("Couldn't find supported format"))
+            .ok_or(VrvError::NoSuitableFormat.into())
     }
 
+    // for the HMD images, in the preferred encoding if possible
     pub fn find_supported_color_format(&self) -> Result<Format> {
-        self.find_supported_format(
-            &[
-                // not sure if this is a good idea tbh
-                Format::B8G8R8A8_SRGB,
-                Format::R8G8B8A8_SRGB,
-            ],
-            ImageTiling::OPTIMAL,
-            FormatFeatureFlags::COLOR_ATTACHMENT,
-        )
+        let find = |encoding: ColorEncoding| {
+            self.find_supported_format(
+                encoding.color_candidates(),
+                ImageTiling::OPTIMAL,
+                FormatFeatureFlags::COLOR_ATTACHMENT,
+            )
+        };
+        find(self.color_encoding).or_else(|_| {
+            let other = self.color_encoding.other();
+            let format = find(other)?;
+            log::warn!(
+                "No {:?} color format supported, falling back to {:?}",
+                self.color_encoding,
+                format
+            );
+            Ok(format)
+        })
     }
 
     pub fn find_supported_depth_stencil_format(&self) -> Result<Format> {
@@ -361,6 +791,13 @@ impl Context {
         )
     }
 
+    // whether find_supported_depth_stencil_format picks a format with stencil,
+    // without any depth format nothing can be rendered anyway
+    pub fn depth_format_has_stencil(&self) -> bool {
+        self.find_supported_depth_stencil_format()
+            .map_or(false, has_stencil)
+    }
+
     pub fn find_memory_type_index(
         &self,
         memory_type_bits: MemoryPropertyFlags,
@@ -380,38 +817,129 @@ impl Context {
                         .bitand(required_properties)
                         == required_properties
             })
-            .ok_or(Error::msg("Failed to find suitable memory type"))
+            .ok_or(VrvError::NoSuitableMemoryType.into())
     }
 
     pub fn get_allowed_extend(&self, wanted: Extent2D) -> Result<Extent2D> {
         let Detail { capabilities, .. } = self.window_surface_related.get_detail(&self)?;
-        Ok(if capabilities.current_extent.height == std::u32::MAX {
-            Extent2D {
-                width: std::cmp::max(
-                    capabilities.min_image_extent.width,
-                    std::cmp::min(capabilities.max_image_extent.width, wanted.width),
-                ),
-                height: std::cmp::max(
-                    capabilities.min_image_extent.height,
-                    std::cmp::min(capabilities.max_image_extent.height, wanted.height),
-                ),
-            }
+        Ok(allowed_extent(&capabilities, wanted))
+    }
+
+    // passes when closer than what is in the depth image
+    pub fn depth_compare_op(&self) -> CompareOp {
+        if self.reverse_z {
+            CompareOp::GREATER
         } else {
-            // The extent of the swapchain can't be choosen freely, wanted is ignored
-            capabilities.current_extent
-        })
+            CompareOp::LESS
+        }
+    }
+
+    // the far plane, e.g. for SwapchainHMD::cmd_clear_depth_only
+    pub fn depth_clear_value(&self) -> f32 {
+        if self.reverse_z {
+            0.0
+        } else {
+            1.0
+        }
     }
 
     pub fn get_surface_format(&self) -> Result<Format> {
         Ok(self.window_surface_related.get_detail(&self)?.format.format)
     }
 
+    // the highest count not above requested that color and depth attachments both support
+    pub fn clamp_sample_count(&self, requested: SampleCountFlags) -> SampleCountFlags {
+        let limits = unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+        }
+        .limits;
+        let supported =
+            limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+        [
+            SampleCountFlags::TYPE_64,
+            SampleCountFlags::TYPE_32,
+            SampleCountFlags::TYPE_16,
+            SampleCountFlags::TYPE_8,
+            SampleCountFlags::TYPE_4,
+            SampleCountFlags::TYPE_2,
+        ]
+        .into_iter()
+        .find(|&samples| samples.as_raw() <= requested.as_raw() && supported.contains(samples))
+        .unwrap_or(SampleCountFlags::TYPE_1)
+    }
+
     pub fn get_image_count(&self) -> Result<u32> {
         Ok(self.window_surface_related.get_detail(&self)?.image_count)
     }
 
     pub fn wait_idle(&self) -> Result<()> {
-        Ok(unsafe { self.device.queue_wait_idle(self.queue) }?)
+        self.check_lost(unsafe { self.device.queue_wait_idle(self.queue) })
+    }
+
+    // For the results of submitting, presenting and waiting: ERROR_DEVICE_LOST becomes
+    // VrvError::DeviceLost and is remembered, see is_device_lost.
+    // Wrap own waits on fences or semaphores with it too.
+    pub fn check_lost<T>(&self, result: VkResult<T>) -> Result<T> {
+        match result {
+            Err(ash::vk::Result::ERROR_DEVICE_LOST) => {
+                self.device_lost.store(true, Ordering::Relaxed);
+                Err(VrvError::DeviceLost.into())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    // once true, the device has to be recreated, see Context::recreate_device
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Relaxed)
+    }
+
+    // Records with record, submits and blocks until the GPU is done.
+    // Meant for setup work like uploads and transitions, not for per frame rendering.
+    pub fn one_shot<F: FnOnce(CommandBuffer)>(&self, name: &str, record: F) -> Result<()> {
+        let command_buffer = self.alloc_command_buffers(1, format!("{}CommandBuffer", name))?[0];
+        let fence = match create_fence(self, false, format!("{}Fence", name)) {
+            Ok(fence) => fence,
+            Err(e) => {
+                unsafe {
+                    self.device
+                        .free_command_buffers(self.pool, &[command_buffer])
+                };
+                return Err(e);
+            }
+        };
+
+        let result = unsafe {
+            self.device
+                .begin_command_buffer(
+                    command_buffer,
+                    &CommandBufferBeginInfo::builder()
+                        .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+                )
+                .and_then(|_| {
+                    record(command_buffer);
+                    self.device.end_command_buffer(command_buffer)
+                })
+                .and_then(|_| {
+                    self.device.queue_submit(
+                        self.queue,
+                        &[SubmitInfo::builder()
+                            .command_buffers(&[command_buffer])
+                            .build()],
+                        fence,
+                    )
+                })
+                .and_then(|_| self.device.wait_for_fences(&[fence], true, std::u64::MAX))
+        };
+
+        unsafe {
+            self.device.destroy_fence(fence, None);
+            self.device
+                .free_command_buffers(self.pool, &[command_buffer]);
+        }
+
+        self.check_lost(result)
     }
 
     pub fn alloc_command_buffers(&self, count: u32, name: String) -> Result<Vec<CommandBuffer>> {
@@ -431,3 +959,67 @@ impl Context {
         Ok(buffers)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(width: u32, height: u32) -> Extent2D {
+        Extent2D { width, height }
+    }
+
+    #[test]
+    fn wanted_extent_is_clamped_to_the_surface() {
+        let capabilities = SurfaceCapabilitiesKHR {
+            current_extent: extent(std::u32::MAX, std::u32::MAX),
+            min_image_extent: extent(64, 64),
+            max_image_extent: extent(1920, 1080),
+            ..Default::default()
+        };
+        assert_eq!(
+            allowed_extent(&capabilities, extent(800, 600)),
+            extent(800, 600)
+        );
+        assert_eq!(
+            allowed_extent(&capabilities, extent(2560, 1440)),
+            extent(1920, 1080)
+        );
+        assert_eq!(
+            allowed_extent(&capabilities, extent(32, 2000)),
+            extent(64, 1080)
+        );
+    }
+
+    #[test]
+    fn fixed_surface_extent_wins() {
+        let capabilities = SurfaceCapabilitiesKHR {
+            current_extent: extent(1280, 720),
+            min_image_extent: extent(1280, 720),
+            max_image_extent: extent(1280, 720),
+            ..Default::default()
+        };
+        assert_eq!(
+            allowed_extent(&capabilities, extent(800, 600)),
+            extent(1280, 720)
+        );
+    }
+
+    #[test]
+    fn aspects_follow_the_depth_format() {
+        assert_eq!(
+            depth_aspect_flags(Format::D32_SFLOAT),
+            ImageAspectFlags::DEPTH
+        );
+        assert_eq!(
+            depth_aspect_flags(Format::D24_UNORM_S8_UINT),
+            ImageAspectFlags::DEPTH | ImageAspectFlags::STENCIL
+        );
+        // stencil only, a depth aspect would be invalid
+        assert_eq!(
+            depth_aspect_flags(Format::S8_UINT),
+            ImageAspectFlags::STENCIL
+        );
+        assert!(has_stencil(Format::S8_UINT));
+        assert!(!has_stencil(Format::D16_UNORM));
+    }
+}
@@ -1,22 +1,24 @@
 use std::collections::HashMap;
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, bail, Error, Result};
 use ash::{
     vk::{
         Buffer, DescriptorBufferInfo, DescriptorImageInfo, DescriptorPool,
-        DescriptorPoolCreateInfo, DescriptorPoolSize, DescriptorSet, DescriptorSetAllocateInfo,
-        DescriptorSetLayout, DescriptorSetLayoutBinding, DescriptorSetLayoutCreateInfo,
-        DescriptorType, ImageLayout, ImageView, Sampler, ShaderStageFlags, WriteDescriptorSet,
-        WHOLE_SIZE,
+        DescriptorPoolCreateInfo, DescriptorPoolResetFlags, DescriptorPoolSize, DescriptorSet,
+        DescriptorSetAllocateInfo, DescriptorSetLayout, DescriptorSetLayoutBinding,
+        DescriptorSetLayoutCreateInfo, DescriptorType, ImageLayout, ImageView, Sampler,
+        ShaderStageFlags, WriteDescriptorSet, WHOLE_SIZE,
     },
     Device,
 };
 
-use super::Context;
+use super::{Context, DeviceImage};
 
 pub struct DescriptorRelated {
     pub layout: DescriptorSetLayout,
     pool: DescriptorPool,
+    setup: HashMap<u32, (DescriptorType, ShaderStageFlags, u32)>,
+    name: String,
     device: Device,
 }
 
@@ -29,158 +31,308 @@ impl Drop for DescriptorRelated {
     }
 }
 
-#[derive(Clone, Copy)]
+// the array variants fill a binding declared with a count > 1, element by element
+#[derive(Clone)]
 pub enum Usage {
     Buffer(Buffer),
     ImageSampler(ImageLayout, ImageView, Sampler),
+    Buffers(Vec<Buffer>),
+    ImageSamplers(Vec<(ImageLayout, ImageView, Sampler)>),
+}
+
+impl Usage {
+    // expects the image to be transitioned for sampling already
+    pub fn from_texture(texture: &DeviceImage, sampler: Sampler) -> Self {
+        Self::ImageSampler(ImageLayout::SHADER_READ_ONLY_OPTIMAL, texture.view, sampler)
+    }
+
+    pub fn from_textures(textures: &[&DeviceImage], sampler: Sampler) -> Self {
+        Self::ImageSamplers(
+            textures
+                .iter()
+                .map(|texture| (ImageLayout::SHADER_READ_ONLY_OPTIMAL, texture.view, sampler))
+                .collect(),
+        )
+    }
+
+    pub fn count(&self) -> u32 {
+        match self {
+            Self::Buffer(_) | Self::ImageSampler(..) => 1,
+            Self::Buffers(buffers) => buffers.len() as u32,
+            Self::ImageSamplers(image_samplers) => image_samplers.len() as u32,
+        }
+    }
+
+    fn is_buffer(&self) -> bool {
+        matches!(self, Self::Buffer(_) | Self::Buffers(_))
+    }
+}
+
+// the descriptor types that are written with buffer infos, the rest take image infos
+fn takes_buffers(ty: DescriptorType) -> bool {
+    matches!(
+        ty,
+        DescriptorType::UNIFORM_BUFFER
+            | DescriptorType::STORAGE_BUFFER
+            | DescriptorType::UNIFORM_BUFFER_DYNAMIC
+            | DescriptorType::STORAGE_BUFFER_DYNAMIC
+    )
+}
+
+// the binding has to be in the setup, with the same count and the matching kind of usage
+fn check_usage(
+    setup: &HashMap<u32, (DescriptorType, ShaderStageFlags, u32)>,
+    binding: u32,
+    usage: &Usage,
+    name: &str,
+) -> Result<()> {
+    let &(ty, _, count) = setup
+        .get(&binding)
+        .ok_or_else(|| anyhow!("{} uses binding {} which isn't in the setup", name, binding))?;
+    if usage.count() != count {
+        bail!(
+            "{} binding {} is declared with {} descriptors but {} were provided",
+            name,
+            binding,
+            count,
+            usage.count()
+        );
+    }
+    if usage.is_buffer() != takes_buffers(ty) {
+        bail!(
+            "{} binding {} is declared as {:?} which doesn't fit the provided {}",
+            name,
+            binding,
+            ty,
+            if usage.is_buffer() {
+                "buffers"
+            } else {
+                "images"
+            }
+        );
+    }
+    Ok(())
+}
+
+fn create_layout(
+    context: &Context,
+    setup: &HashMap<u32, (DescriptorType, ShaderStageFlags, u32)>,
+    name: &str,
+) -> Result<DescriptorSetLayout> {
+    let layout = unsafe {
+        context.device.create_descriptor_set_layout(
+            &DescriptorSetLayoutCreateInfo::builder().bindings(
+                &setup
+                    .iter()
+                    .map(|(&binding, &(ty, stage_flags, count))| {
+                        DescriptorSetLayoutBinding::builder()
+                            .binding(binding)
+                            .descriptor_type(ty)
+                            .descriptor_count(count)
+                            .stage_flags(stage_flags)
+                            .build()
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            None,
+        )
+    }?;
+    if let Err(e) = context.name_object(layout, format!("{}Layout", name)) {
+        unsafe { context.device.destroy_descriptor_set_layout(layout, None) };
+        return Err(e);
+    }
+    Ok(layout)
+}
+
+// big enough for num_sets sets of the setup
+fn create_pool(
+    context: &Context,
+    setup: &HashMap<u32, (DescriptorType, ShaderStageFlags, u32)>,
+    num_sets: u32,
+    name: String,
+) -> Result<DescriptorPool> {
+    let pool = unsafe {
+        context.device.create_descriptor_pool(
+            &DescriptorPoolCreateInfo::builder()
+                .pool_sizes(
+                    &[
+                        DescriptorType::SAMPLER,
+                        DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        DescriptorType::SAMPLED_IMAGE,
+                        DescriptorType::STORAGE_IMAGE,
+                        DescriptorType::UNIFORM_TEXEL_BUFFER,
+                        DescriptorType::STORAGE_TEXEL_BUFFER,
+                        DescriptorType::UNIFORM_BUFFER,
+                        DescriptorType::STORAGE_BUFFER,
+                        DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+                        DescriptorType::STORAGE_BUFFER_DYNAMIC,
+                        DescriptorType::INPUT_ATTACHMENT,
+                    ]
+                    .iter()
+                    .filter_map(|&ty| {
+                        let match_count = setup
+                            .values()
+                            .filter(|&&(used_ty, _, _)| ty == used_ty)
+                            .map(|&(_, _, count)| count)
+                            .sum::<u32>();
+                        if match_count > 0 {
+                            Some(
+                                DescriptorPoolSize::builder()
+                                    .ty(ty)
+                                    .descriptor_count(match_count * num_sets)
+                                    .build(),
+                            )
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>(),
+                )
+                .max_sets(num_sets),
+            None,
+        )
+    }?;
+    if let Err(e) = context.name_object(pool, name) {
+        unsafe { context.device.destroy_descriptor_pool(pool, None) };
+        return Err(e);
+    }
+    Ok(pool)
+}
+
+fn allocate_set(
+    context: &Context,
+    pool: DescriptorPool,
+    layout: DescriptorSetLayout,
+    setup: &HashMap<u32, (DescriptorType, ShaderStageFlags, u32)>,
+    usage_map: &HashMap<u32, Usage>,
+    name: String,
+) -> Result<DescriptorSet> {
+    for (&binding, usage) in usage_map {
+        check_usage(setup, binding, usage, &name)?;
+    }
+
+    let set = unsafe {
+        context.device.allocate_descriptor_sets(
+            &DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(pool)
+                .set_layouts(&[layout]),
+        )
+    }?[0];
+    context.name_object(set, name)?;
+
+    write_set(&context.device, set, setup, usage_map);
+
+    Ok(set)
+}
+
+// the usages have to be checked against the setup already
+fn write_set(
+    device: &Device,
+    set: DescriptorSet,
+    setup: &HashMap<u32, (DescriptorType, ShaderStageFlags, u32)>,
+    usage_map: &HashMap<u32, Usage>,
+) {
+    struct Info {
+        binding: u32,
+        buffer_infos: Vec<DescriptorBufferInfo>,
+        image_infos: Vec<DescriptorImageInfo>,
+    }
+
+    let infos: Vec<Info> = usage_map
+        .iter()
+        .map(|(&binding, usage)| {
+            let buffer_info = |&buffer: &Buffer| {
+                DescriptorBufferInfo::builder()
+                    .buffer(buffer)
+                    .offset(0)
+                    .range(WHOLE_SIZE)
+                    .build()
+            };
+            let image_info =
+                |&(image_layout, image_view, sampler): &(ImageLayout, ImageView, Sampler)| {
+                    DescriptorImageInfo::builder()
+                        .image_layout(image_layout)
+                        .image_view(image_view)
+                        .sampler(sampler)
+                        .build()
+                };
+            match usage {
+                Usage::Buffer(buffer) => Info {
+                    binding,
+                    buffer_infos: vec![buffer_info(buffer)],
+                    image_infos: vec![],
+                },
+                Usage::ImageSampler(image_layout, image_view, sampler) => Info {
+                    binding,
+                    buffer_infos: vec![],
+                    image_infos: vec![image_info(&(*image_layout, *image_view, *sampler))],
+                },
+                Usage::Buffers(buffers) => Info {
+                    binding,
+                    buffer_infos: buffers.iter().map(buffer_info).collect(),
+                    image_infos: vec![],
+                },
+                Usage::ImageSamplers(image_samplers) => Info {
+                    binding,
+                    buffer_infos: vec![],
+                    image_infos: image_samplers.iter().map(image_info).collect(),
+                },
+            }
+        })
+        .collect();
+
+    unsafe {
+        device.update_descriptor_sets(
+            &infos
+                .iter()
+                .map(|info| {
+                    let incomplete = WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(info.binding)
+                        .dst_array_element(0)
+                        // bindings were checked against the setup above
+                        .descriptor_type(setup[&info.binding].0);
+                    if !info.buffer_infos.is_empty() {
+                        return incomplete.buffer_info(info.buffer_infos.as_slice()).build();
+                    }
+                    if !info.image_infos.is_empty() {
+                        return incomplete.image_info(info.image_infos.as_slice()).build();
+                    }
+                    panic!("No buffer infos and no image infos");
+                })
+                .collect::<Vec<_>>(),
+            &[], // no copies
+        )
+    }
 }
 
 impl DescriptorRelated {
     pub fn new_with_sets(
         context: &Context,
-        setup: HashMap<u32, (DescriptorType, ShaderStageFlags)>,
+        setup: HashMap<u32, (DescriptorType, ShaderStageFlags, u32)>,
         usages: &[HashMap<u32, Usage>],
         name: String,
     ) -> Result<(Self, Vec<DescriptorSet>)> {
-        let layout = unsafe {
-            context.device.create_descriptor_set_layout(
-                &DescriptorSetLayoutCreateInfo::builder().bindings(
-                    &setup
-                        .iter()
-                        .map(|(&binding, &(ty, stage_flags))| {
-                            DescriptorSetLayoutBinding::builder()
-                                .binding(binding)
-                                .descriptor_type(ty)
-                                .descriptor_count(1)
-                                .stage_flags(stage_flags)
-                                .build()
-                        })
-                        .collect::<Vec<_>>(),
-                ),
-                None,
-            )
-        }?;
-        context.name_object(layout, format!("{}Layout", name))?;
-
-        let num_sets = usages.len() as u32;
-        let pool = unsafe {
-            context.device.create_descriptor_pool(
-                &DescriptorPoolCreateInfo::builder()
-                    .pool_sizes(
-                        &[
-                            DescriptorType::SAMPLER,
-                            DescriptorType::COMBINED_IMAGE_SAMPLER,
-                            DescriptorType::SAMPLED_IMAGE,
-                            DescriptorType::STORAGE_IMAGE,
-                            DescriptorType::UNIFORM_TEXEL_BUFFER,
-                            DescriptorType::STORAGE_TEXEL_BUFFER,
-                            DescriptorType::UNIFORM_BUFFER,
-                            DescriptorType::STORAGE_BUFFER,
-                            DescriptorType::UNIFORM_BUFFER_DYNAMIC,
-                            DescriptorType::STORAGE_BUFFER_DYNAMIC,
-                            DescriptorType::INPUT_ATTACHMENT,
-                        ]
-                        .iter()
-                        .filter_map(|&ty| {
-                            let match_count = setup
-                                .iter()
-                                .filter(|&(_, &(used_ty, _))| ty == used_ty)
-                                .count() as u32;
-                            if match_count > 0 {
-                                Some(
-                                    DescriptorPoolSize::builder()
-                                        .ty(ty)
-                                        .descriptor_count(match_count * num_sets)
-                                        .build(),
-                                )
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>(),
-                    )
-                    .max_sets(num_sets),
-                None,
-            )
-        }?;
-        context.name_object(pool, format!("{}Pool", name))?;
+        let layout = create_layout(context, &setup, &name)?;
+        let pool = create_pool(
+            context,
+            &setup,
+            usages.len() as u32,
+            format!("{}Pool", name),
+        )?;
 
         let sets: Vec<DescriptorSet> = usages
             .iter()
             .enumerate()
             .map(|(i, usage_map)| {
-                let set = unsafe {
-                    context.device.allocate_descriptor_sets(
-                        &DescriptorSetAllocateInfo::builder()
-                            .descriptor_pool(pool)
-                            .set_layouts(&[layout]),
-                    )
-                }?[0];
-                context.name_object(set, format!("{}Set_{}", name, i))?;
-
-                struct Info {
-                    binding: u32,
-                    buffer_infos: Vec<DescriptorBufferInfo>,
-                    image_infos: Vec<DescriptorImageInfo>,
-                }
-
-                let infos: Vec<Info> = usage_map
-                    .iter()
-                    .map(|(&binding, &usage)| match usage {
-                        Usage::Buffer(buffer) => Info {
-                            binding,
-                            buffer_infos: vec![DescriptorBufferInfo::builder()
-                                .buffer(buffer)
-                                .offset(0)
-                                .range(WHOLE_SIZE)
-                                .build()],
-                            image_infos: vec![],
-                        },
-                        Usage::ImageSampler(image_layout, image_view, sampler) => Info {
-                            binding,
-                            buffer_infos: vec![],
-                            image_infos: vec![DescriptorImageInfo::builder()
-                                .image_layout(image_layout)
-                                .image_view(image_view)
-                                .sampler(sampler)
-                                .build()],
-                        },
-                    })
-                    .collect();
-
-                unsafe {
-                    context.device.update_descriptor_sets(
-                        &infos
-                            .iter()
-                            .map(|info| {
-                                let incomplete = WriteDescriptorSet::builder()
-                                    .dst_set(set)
-                                    .dst_binding(info.binding)
-                                    .dst_array_element(0)
-                                    .descriptor_type(
-                                        setup
-                                            .get(&info.binding)
-                                            .expect("Invalid binding for descriptor")
-                                            .0,
-                                    );
-                                if !info.buffer_infos.is_empty() {
-                                    return incomplete
-                                        .buffer_info(info.buffer_infos.as_slice())
-                                        .build();
-                                }
-                                if !info.image_infos.is_empty() {
-                                    return incomplete
-                                        .image_info(info.image_infos.as_slice())
-                                        .build();
-                                }
-                                panic!("No buffer infos and no image infos");
-                            })
-                            .collect::<Vec<_>>(),
-                        &[], // no copies
-                    )
-                }
-
-                Ok(set)
+                allocate_set(
+                    context,
+                    pool,
+                    layout,
+                    &setup,
+                    usage_map,
+                    format!("{}Set_{}", name, i),
+                )
             })
             .collect::<Result<_, Error>>()?;
 
@@ -188,9 +340,130 @@ impl DescriptorRelated {
             Self {
                 layout,
                 pool,
+                setup,
+                name,
                 device: context.device.clone(),
             },
             sets,
         ))
     }
+
+    // Rewrites a single binding of one of the sets, e.g. when a streamed texture changed.
+    // Only call while the GPU isn't using the set, e.g. after waiting for the frame's fence.
+    pub fn update(&self, set: DescriptorSet, binding: u32, usage: Usage) -> Result<()> {
+        check_usage(&self.setup, binding, &usage, &self.name)?;
+        write_set(
+            &self.device,
+            set,
+            &self.setup,
+            &HashMap::from([(binding, usage)]),
+        );
+        Ok(())
+    }
+}
+
+// Alternative to DescriptorRelated for sets that are rebuilt every frame, e.g. when the
+// number of textures changes. Every frame in flight has its own pool which is reset as a whole,
+// so sets the GPU may still read from are never touched.
+pub struct DescriptorFrames {
+    pub layout: DescriptorSetLayout,
+    setup: HashMap<u32, (DescriptorType, ShaderStageFlags, u32)>,
+    pools: Vec<DescriptorPool>,
+    allocated: Vec<u32>,
+    sets_per_frame: u32,
+    name: String,
+    device: Device,
+}
+
+impl Drop for DescriptorFrames {
+    fn drop(&mut self) {
+        unsafe {
+            for &pool in &self.pools {
+                self.device.destroy_descriptor_pool(pool, None);
+            }
+            self.device.destroy_descriptor_set_layout(self.layout, None);
+        }
+    }
+}
+
+impl DescriptorFrames {
+    pub fn new(
+        context: &Context,
+        setup: HashMap<u32, (DescriptorType, ShaderStageFlags, u32)>,
+        frames: usize,
+        sets_per_frame: u32,
+        name: String,
+    ) -> Result<Self> {
+        let layout = create_layout(context, &setup, &name)?;
+        // filled one by one, if a pool fails Drop destroys the layout and the pools so far
+        let mut descriptor_frames = Self {
+            layout,
+            setup,
+            pools: Vec::with_capacity(frames),
+            allocated: vec![0; frames],
+            sets_per_frame,
+            name,
+            device: context.device.clone(),
+        };
+        for frame in 0..frames {
+            let pool = create_pool(
+                context,
+                &descriptor_frames.setup,
+                sets_per_frame,
+                format!("{}Pool_{}", descriptor_frames.name, frame),
+            )?;
+            descriptor_frames.pools.push(pool);
+        }
+        Ok(descriptor_frames)
+    }
+
+    fn check_frame(&self, frame: usize) -> Result<()> {
+        if frame >= self.pools.len() {
+            bail!(
+                "No frame {} in {}, which has {}",
+                frame,
+                self.name,
+                self.pools.len()
+            );
+        }
+        Ok(())
+    }
+
+    // Frees all sets of the frame, only call once the GPU is done with them,
+    // e.g. after waiting for the fence of the frame.
+    pub fn reset(&mut self, frame: usize) -> Result<()> {
+        self.check_frame(frame)?;
+        unsafe {
+            self.device
+                .reset_descriptor_pool(self.pools[frame], DescriptorPoolResetFlags::empty())
+        }?;
+        self.allocated[frame] = 0;
+        Ok(())
+    }
+
+    pub fn allocate(
+        &mut self,
+        context: &Context,
+        frame: usize,
+        usage_map: &HashMap<u32, Usage>,
+    ) -> Result<DescriptorSet> {
+        self.check_frame(frame)?;
+        if self.allocated[frame] == self.sets_per_frame {
+            bail!(
+                "{} ran out of descriptor sets, only {} per frame",
+                self.name,
+                self.sets_per_frame
+            );
+        }
+        let set = allocate_set(
+            context,
+            self.pools[frame],
+            self.layout,
+            &self.setup,
+            usage_map,
+            format!("{}Set_{}_{}", self.name, frame, self.allocated[frame]),
+        )?;
+        self.allocated[frame] += 1;
+        Ok(set)
+    }
 }
@@ -1,20 +1,23 @@
 use anyhow::Result;
 use ash::{
     vk::{
-        DeviceMemory, Extent2D, Extent3D, Format, Image, ImageAspectFlags, ImageCreateInfo,
-        ImageLayout, ImageSubresourceRange, ImageTiling, ImageType, ImageUsageFlags, ImageView,
-        ImageViewCreateInfo, ImageViewType, MemoryAllocateInfo, MemoryPropertyFlags,
+        DeviceSize, Extent2D, Extent3D, Format, Image, ImageAspectFlags, ImageCreateFlags,
+        ImageCreateInfo, ImageLayout, ImageSubresourceRange, ImageTiling, ImageType,
+        ImageUsageFlags, ImageView, ImageViewCreateInfo, ImageViewType, MemoryPropertyFlags,
         SampleCountFlags, SharingMode,
     },
     Device,
 };
 
-use super::Context;
+use super::{allocator::Allocation, Context};
 
+// The memory is a range of a shared block, see Allocator, bound at allocation.offset.
 pub struct DeviceImage {
     pub image: Image,
-    pub memory: DeviceMemory,
+    pub allocation: Allocation,
     pub view: ImageView,
+    layer_count: u32,
+    mip_levels: u32,
     device: Device,
 }
 
@@ -26,16 +29,34 @@ pub struct DeviceImageSettings {
     pub properties: MemoryPropertyFlags,
     pub aspect_flags: ImageAspectFlags,
     pub layer_count: u32, // 2 for hmd
+    // None picks 2D for a single layer and 2D_ARRAY otherwise,
+    // CUBE and CUBE_ARRAY also make the image cube compatible
+    pub view_type: Option<ImageViewType>,
+    pub mip_levels: u32, // 1 for anything that isn't sampled minified
+    pub samples: SampleCountFlags,
     pub name: String,
 }
 
+// full chain down to 1x1
+pub fn mip_levels_for(extent: Extent2D) -> u32 {
+    32 - extent.width.max(extent.height).max(1).leading_zeros()
+}
+
 impl Drop for DeviceImage {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_image_view(self.view, None);
             self.device.destroy_image(self.image, None);
-            self.device.free_memory(self.memory, None);
         }
+        // the allocation gives the range back afterwards
+    }
+}
+
+fn default_view_type(layer_count: u32) -> ImageViewType {
+    if layer_count == 1 {
+        ImageViewType::TYPE_2D
+    } else {
+        ImageViewType::TYPE_2D_ARRAY
     }
 }
 
@@ -46,23 +67,42 @@ impl DeviceImage {
         format: Format,
         aspect_flags: ImageAspectFlags,
         layer_count: u32,
+        mip_levels: u32,
+        name: String,
+    ) -> Result<ImageView> {
+        Self::new_view_with_type(
+            context,
+            image,
+            default_view_type(layer_count),
+            format,
+            aspect_flags,
+            layer_count,
+            mip_levels,
+            name,
+        )
+    }
+
+    pub fn new_view_with_type(
+        context: &Context,
+        image: Image,
+        view_type: ImageViewType,
+        format: Format,
+        aspect_flags: ImageAspectFlags,
+        layer_count: u32,
+        mip_levels: u32,
         name: String,
     ) -> Result<ImageView> {
         let view = unsafe {
             context.device.create_image_view(
                 &ImageViewCreateInfo::builder()
                     .image(image)
-                    .view_type(if layer_count == 1 {
-                        ImageViewType::TYPE_2D
-                    } else {
-                        ImageViewType::TYPE_2D_ARRAY
-                    })
+                    .view_type(view_type)
                     .format(format)
                     .subresource_range(
                         ImageSubresourceRange::builder()
                             .aspect_mask(aspect_flags)
                             .base_mip_level(0)
-                            .level_count(1)
+                            .level_count(mip_levels)
                             .base_array_layer(0)
                             .layer_count(layer_count)
                             .build(),
@@ -75,58 +115,80 @@ impl DeviceImage {
     }
 
     pub fn new(context: &Context, settings: DeviceImageSettings) -> Result<Self> {
+        let view_type = settings
+            .view_type
+            .unwrap_or_else(|| default_view_type(settings.layer_count));
         let image = unsafe {
             context.device.create_image(
                 &ImageCreateInfo::builder()
+                    .flags(
+                        if view_type == ImageViewType::CUBE
+                            || view_type == ImageViewType::CUBE_ARRAY
+                        {
+                            ImageCreateFlags::CUBE_COMPATIBLE
+                        } else {
+                            ImageCreateFlags::empty()
+                        },
+                    )
                     .image_type(ImageType::TYPE_2D)
                     .extent(Extent3D {
                         width: settings.extent.width,
                         height: settings.extent.height,
                         depth: 1,
                     })
-                    .mip_levels(1)
+                    .mip_levels(settings.mip_levels)
                     .array_layers(settings.layer_count)
                     .format(settings.format)
                     .tiling(settings.tiling)
                     .initial_layout(ImageLayout::UNDEFINED)
                     .usage(settings.usage)
                     .sharing_mode(SharingMode::EXCLUSIVE)
-                    .samples(SampleCountFlags::TYPE_1),
+                    .samples(settings.samples),
                 None,
             )
         }?;
         context.name_object(image, format!("{}Image", settings.name.clone()))?;
 
         let memory_requirements = unsafe { context.device.get_image_memory_requirements(image) };
-        let memory = unsafe {
-            context.device.allocate_memory(
-                &MemoryAllocateInfo::builder()
-                    .allocation_size(memory_requirements.size)
-                    .memory_type_index(context.find_memory_type_index(
-                        MemoryPropertyFlags::from_raw(memory_requirements.memory_type_bits),
-                        settings.properties,
-                    )?),
-                None,
-            )?
-        };
-        context.name_object(memory, format!("{}Memory", settings.name.clone()))?;
-
-        unsafe { context.device.bind_image_memory(image, memory, 0) }?;
+        let allocation =
+            context.allocate(memory_requirements, settings.properties, &settings.name)?;
+        unsafe {
+            context
+                .device
+                .bind_image_memory(image, allocation.memory, allocation.offset)
+        }?;
 
-        let view = Self::new_view(
+        let view = Self::new_view_with_type(
             context,
             image,
+            view_type,
             settings.format,
             settings.aspect_flags,
             settings.layer_count,
+            settings.mip_levels,
             format!("{}View", settings.name.clone()),
         )?;
 
         Ok(Self {
             image,
-            memory,
+            allocation,
             view,
+            layer_count: settings.layer_count,
+            mip_levels: settings.mip_levels,
             device: context.device.clone(),
         })
     }
+
+    // barriers and copies have to stay within these
+    pub fn get_layer_count(&self) -> u32 {
+        self.layer_count
+    }
+
+    pub fn get_mip_levels(&self) -> u32 {
+        self.mip_levels
+    }
+
+    pub fn get_allocation_size(&self) -> DeviceSize {
+        self.allocation.size()
+    }
 }
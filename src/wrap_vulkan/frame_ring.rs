@@ -0,0 +1,105 @@
+use anyhow::{bail, Result};
+use ash::{
+    vk::{CommandBuffer, Fence, Semaphore},
+    Device,
+};
+
+use super::{
+    sync::{create_fence, create_semaphore},
+    Context, OwnedCommandBuffers,
+};
+
+// One slot of a FrameRing, data is whatever the caller needs per frame,
+// e.g. the uniform buffer that is written before recording.
+pub struct Frame<T> {
+    pub command_buffer: CommandBuffer,
+    pub fence: Fence,
+    pub semaphore: Semaphore,
+    pub data: T,
+    pub index: usize,
+    device: Device,
+}
+
+impl<T> Drop for Frame<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_semaphore(self.semaphore, None);
+            self.device.destroy_fence(self.fence, None);
+        }
+    }
+}
+
+// Round-robin over frames in flight, for rendering that isn't managed by the context.
+// The fences start signaled and next only waits, the submission resets them.
+// The GPU must be done with all frames before dropping, e.g. Context::wait_idle.
+pub struct FrameRing<T> {
+    // declared first, the frames go before their command buffers
+    frames: Vec<Frame<T>>,
+    next: usize,
+    command_buffers: OwnedCommandBuffers,
+}
+
+impl<T> FrameRing<T> {
+    pub fn new(
+        context: &Context,
+        count: usize,
+        name: String,
+        mut create: impl FnMut(usize) -> Result<T>,
+    ) -> Result<Self> {
+        // next would have nothing to hand out
+        if count == 0 {
+            bail!("A frame ring needs at least one frame");
+        }
+        let command_buffers =
+            OwnedCommandBuffers::new(context, count as u32, format!("{}CommandBuffer", name))?;
+        let frames = (0..count)
+            .map(|index| {
+                Ok(Frame {
+                    command_buffer: command_buffers.get(index),
+                    // signaled, the first next doesn't block
+                    fence: create_fence(context, true, format!("{}Fence_{}", name, index))?,
+                    semaphore: create_semaphore(context, format!("{}Semaphore_{}", name, index))?,
+                    data: create(index)?,
+                    index,
+                    device: context.device.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            frames,
+            next: 0,
+            command_buffers,
+        })
+    }
+
+    // Waits until the GPU is done with the frame that comes next, it is free to be written.
+    pub fn next(&mut self, context: &Context) -> Result<&mut Frame<T>> {
+        let index = self.next;
+        self.next = (self.next + 1) % self.frames.len();
+        let frame = &mut self.frames[index];
+        context.check_lost(unsafe {
+            context.device.wait_for_fences(
+                &[frame.fence],
+                true,          // wait all
+                std::u64::MAX, // don't timeout
+            )
+        })?;
+        Ok(frame)
+    }
+
+    pub fn frames(&self) -> &[Frame<T>] {
+        &self.frames
+    }
+
+    pub fn command_buffers(&self) -> &OwnedCommandBuffers {
+        &self.command_buffers
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
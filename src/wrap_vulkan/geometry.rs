@@ -1,21 +1,35 @@
-use anyhow::Result;
-use gltf::import;
+use anyhow::{anyhow, bail, Result};
+use gltf::{buffer, image, import, Document};
 use itertools::izip;
-use std::{mem::size_of, path::Path};
+use std::{mem::size_of, ops::Range, path::Path, ptr};
 
-use ash::vk::{
-    Buffer, BufferUsageFlags, Format, VertexInputAttributeDescription,
-    VertexInputBindingDescription, VertexInputRate,
+use ash::{
+    vk::{
+        Buffer, BufferUsageFlags, CommandBuffer, DescriptorSet, Extent2D, Format, IndexType,
+        Pipeline, PipelineBindPoint, PipelineLayout, VertexInputAttributeDescription,
+        VertexInputBindingDescription, VertexInputRate,
+    },
+    Device,
 };
 use memoffset::offset_of;
 
-use super::{buffers::MappedDeviceBuffer, Context};
+use crate::math::{add, cross, dot, normalize, sub};
 
-#[derive(Debug)]
+use super::{
+    buffers::{DeviceLocalBuffer, MappedDeviceBuffer},
+    Context,
+};
+
+#[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 pub struct Vertex {
     pub pos: [f32; 3],
     pub col: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2],
+    // For normal mapping, along +U. w is the handedness, the bitangent is
+    // cross(normal, tangent.xyz) * w. Zero until generated, see Mesh::generate_tangents.
+    pub tangent: [f32; 4],
 }
 
 impl Vertex {
@@ -41,146 +55,917 @@ impl Vertex {
                 .format(Format::R32G32B32_SFLOAT)
                 .offset(offset_of!(Self, col) as u32)
                 .build(),
+            VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(2)
+                .format(Format::R32G32B32_SFLOAT)
+                .offset(offset_of!(Self, normal) as u32)
+                .build(),
+            VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(3)
+                .format(Format::R32G32_SFLOAT)
+                .offset(offset_of!(Self, uv) as u32)
+                .build(),
+            VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(4)
+                .format(Format::R32G32B32A32_SFLOAT)
+                .offset(offset_of!(Self, tangent) as u32)
+                .build(),
         ]
     }
 }
 
+pub struct Line {
+    pub from: [f32; 3],
+    pub to: [f32; 3],
+    pub col: [f32; 3],
+}
+
+// For Mesh::thick_lines. Without falloff the quads have hard edges and draw with any pipeline,
+// with it they fade out towards the edges, draw them with create_pipeline_thick_lines.
+#[derive(Copy, Clone, Debug)]
+pub struct ThickLineSettings {
+    // in world units
+    pub width: f32,
+    // the part of the half width that fades out, 0.0 for hard edges and 1.0 from the center
+    pub falloff: f32,
+}
+
+impl Default for ThickLineSettings {
+    fn default() -> Self {
+        Self {
+            width: 0.005,
+            falloff: 0.0,
+        }
+    }
+}
+
+// Part of the buffers, e.g. one of several meshes packed into the same buffers.
+// first and count are in indices for indexed meshes and in vertices otherwise.
+// vertex_offset is added to each index, so packed meshes can keep their own indices.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DrawRange {
+    pub first: u32,
+    pub count: u32,
+    pub vertex_offset: i32,
+}
+
+// One object of a scene, index_range is in indices for indexed meshes and in vertices otherwise.
+// The descriptor set is bound as set 0 of the pipeline layout.
+#[derive(Clone)]
+pub struct DrawCall<'a> {
+    pub mesh: &'a MeshBuffers,
+    pub descriptor_set: DescriptorSet,
+    pub index_range: Range<u32>,
+    // see DrawRange, for meshes packed into the same buffers
+    pub vertex_offset: i32,
+    // None draws with the pipeline of the scene, Some with another one of the same layout,
+    // e.g. a blending one for the transparent objects after the opaque ones
+    pub pipeline: Option<Pipeline>,
+}
+
+impl<'a> DrawCall<'a> {
+    // everything that was last written to the mesh, see MeshBuffers::full_range
+    pub fn full(mesh: &'a MeshBuffers, descriptor_set: DescriptorSet) -> Self {
+        Self::packed(mesh, descriptor_set, mesh.full_range())
+    }
+
+    // one of several meshes in the same buffers, e.g. with the range from Mesh::append
+    pub fn packed(mesh: &'a MeshBuffers, descriptor_set: DescriptorSet, range: DrawRange) -> Self {
+        Self {
+            mesh,
+            descriptor_set,
+            index_range: range.first..range.first + range.count,
+            vertex_offset: range.vertex_offset,
+            pipeline: None,
+        }
+    }
+
+    pub fn draw_range(&self) -> DrawRange {
+        DrawRange {
+            first: self.index_range.start,
+            count: self.index_range.len() as u32,
+            vertex_offset: self.vertex_offset,
+        }
+    }
+}
+
+// Records the draws inside a render pass, with pipeline already bound.
+// Pipeline, buffers and descriptor set are only bound when they differ from the previous draw,
+// so draws sorted by pipeline, mesh and set need the fewest binds.
+pub(crate) fn record_draws<'a>(
+    device: &Device,
+    command_buffer: CommandBuffer,
+    pipeline: Pipeline,
+    pipeline_layout: PipelineLayout,
+    draws: impl IntoIterator<Item = (&'a MeshBuffers, DescriptorSet, DrawRange, Option<Pipeline>)>,
+) {
+    let mut bound_pipeline = pipeline;
+    let mut bound_mesh: Option<&MeshBuffers> = None;
+    let mut bound_set = None;
+    for (mesh, descriptor_set, range, draw_pipeline) in draws {
+        let draw_pipeline = draw_pipeline.unwrap_or(pipeline);
+        if draw_pipeline != bound_pipeline {
+            unsafe {
+                device.cmd_bind_pipeline(command_buffer, PipelineBindPoint::GRAPHICS, draw_pipeline)
+            };
+            bound_pipeline = draw_pipeline;
+        }
+        if bound_set != Some(descriptor_set) {
+            unsafe {
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    PipelineBindPoint::GRAPHICS,
+                    pipeline_layout,
+                    0,
+                    &[descriptor_set],
+                    &[],
+                );
+            }
+            bound_set = Some(descriptor_set);
+        }
+        if !bound_mesh.map_or(false, |bound| ptr::eq(bound, mesh)) {
+            mesh.bind(device, command_buffer);
+            bound_mesh = Some(mesh);
+        }
+        mesh.record_draw_range(device, command_buffer, range);
+    }
+}
+
+// an empty index list means the vertices are drawn in order
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
 }
 
+// decoded and tightly packed, 4 bytes per texel
+pub struct TextureData {
+    pub rgba: Vec<u8>,
+    pub extent: Extent2D,
+}
+
+impl TextureData {
+    // 8 bit channels are expanded to RGBA, missing color channels are zero and alpha is opaque
+    fn from_gltf(data: &image::Data) -> Result<Self> {
+        let channels = match data.format {
+            image::Format::R8 => 1,
+            image::Format::R8G8 => 2,
+            image::Format::R8G8B8 => 3,
+            image::Format::R8G8B8A8 => 4,
+            format => bail!(
+                "glTF image format {:?} unsupported, only 8 bit channels are",
+                format
+            ),
+        };
+        let rgba = data
+            .pixels
+            .chunks_exact(channels)
+            .flat_map(|texel| {
+                let mut expanded = [0, 0, 0, u8::MAX];
+                expanded[..channels].copy_from_slice(texel);
+                expanded
+            })
+            .collect();
+        Ok(Self {
+            rgba,
+            extent: Extent2D {
+                width: data.width,
+                height: data.height,
+            },
+        })
+    }
+}
+
+// a mesh with the texture its material samples, see Mesh::load_gltf_model
+pub struct Model {
+    pub mesh: Mesh,
+    pub base_color_texture: Option<TextureData>,
+}
+
 impl Mesh {
+    pub fn is_indexed(&self) -> bool {
+        !self.indices.is_empty()
+    }
+
+    // Packs other behind the existing data, draw it with the returned range.
+    // Both have to be indexed or both non-indexed, an empty mesh takes either.
+    pub fn append(&mut self, other: &Mesh) -> Result<DrawRange> {
+        if !self.vertices.is_empty() && self.is_indexed() != other.is_indexed() {
+            bail!("Can't pack indexed and non-indexed meshes together");
+        }
+        let range = if other.is_indexed() {
+            DrawRange {
+                first: self.indices.len() as u32,
+                count: other.indices.len() as u32,
+                vertex_offset: self.vertices.len() as i32,
+            }
+        } else {
+            DrawRange {
+                first: self.vertices.len() as u32,
+                count: other.vertices.len() as u32,
+                vertex_offset: 0,
+            }
+        };
+        self.vertices.extend_from_slice(&other.vertices);
+        self.indices.extend_from_slice(&other.indices);
+        Ok(range)
+    }
+
+    // Like append, but rebases the indices of other so the result draws as a whole
+    // with full_range, e.g. to merge the meshes of a scene.
+    pub fn concat(&mut self, other: &Mesh) -> Result<()> {
+        let first_index = self.indices.len();
+        let base = self.vertices.len() as u32;
+        self.append(other)?;
+        for index in &mut self.indices[first_index..] {
+            *index += base;
+        }
+        Ok(())
+    }
+
+    // centered at the origin with edge length size, every face has its own color
+    pub fn cube(size: f32) -> Self {
+        let h = size / 2.0;
+        // normal, u and v with u x v = normal, so the corners below are CCW seen from outside
+        let faces: [([f32; 3], [f32; 3], [f32; 3], [f32; 3]); 6] = [
+            (
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0],
+                [0.0, 0.0, 1.0],
+                [1.0, 0.0, 0.0],
+            ),
+            (
+                [-1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0],
+                [0.0, 1.0, 0.0],
+                [0.0, 1.0, 1.0],
+            ),
+            (
+                [0.0, 1.0, 0.0],
+                [0.0, 0.0, 1.0],
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0],
+            ),
+            (
+                [0.0, -1.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0],
+                [1.0, 0.0, 1.0],
+            ),
+            (
+                [0.0, 0.0, 1.0],
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0],
+                [0.0, 0.0, 1.0],
+            ),
+            (
+                [0.0, 0.0, -1.0],
+                [0.0, 1.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [1.0, 1.0, 0.0],
+            ),
+        ];
+
+        let mut vertices = Vec::with_capacity(24);
+        let mut indices = Vec::with_capacity(36);
+        for (normal, u, v, col) in faces {
+            let base = vertices.len() as u32;
+            for (su, sv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                vertices.push(Vertex {
+                    pos: [
+                        h * (normal[0] + su * u[0] + sv * v[0]),
+                        h * (normal[1] + su * u[1] + sv * v[1]),
+                        h * (normal[2] + su * u[2] + sv * v[2]),
+                    ],
+                    col,
+                    normal,
+                    uv: [(su + 1.0) * 0.5, (sv + 1.0) * 0.5],
+                    tangent: [0.0; 4],
+                });
+            }
+            indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+        }
+
+        Self { vertices, indices }
+    }
+
     pub fn debug_triangle() -> Self {
         let vertices = vec![
             Vertex {
                 pos: [0.0, -0.5, 0.0].into(),
                 col: [1.0, 0.0, 0.0].into(),
+                normal: [0.0, 0.0, 1.0],
+                uv: [0.5, 0.0],
+                tangent: [0.0; 4],
             },
             Vertex {
                 pos: [0.5, 0.5, 0.0].into(),
                 col: [0.0, 1.0, 0.0].into(),
+                normal: [0.0, 0.0, 1.0],
+                uv: [1.0, 1.0],
+                tangent: [0.0; 4],
             },
             Vertex {
                 pos: [-0.5, 0.5, 0.0].into(),
                 col: [0.0, 0.0, 1.0].into(),
+                normal: [0.0, 0.0, 1.0],
+                uv: [0.0, 1.0],
+                tangent: [0.0; 4],
             },
         ];
         let indices = vec![0, 1, 2];
         Self { vertices, indices }
     }
 
+    // The line topology is one pixel wide and aliases badly in the HMD, so each line becomes
+    // a quad facing the eye instead, which doesn't need wideLines either. For the HMD, pass
+    // the head position, the quads are then wide enough for both eyes.
+    // Regenerate when the eye moves. The uv carries the edge falloff, see ThickLineSettings.
+    pub fn thick_lines(lines: &[Line], settings: ThickLineSettings, eye: [f32; 3]) -> Self {
+        let ThickLineSettings { width, falloff } = settings;
+        // alpha = uv.x - |uv.y| in the fragment shader, 1 everywhere without falloff
+        let (inside, edge) = if falloff > 0.0 {
+            let falloff = falloff.min(1.0);
+            (1.0 / falloff, 1.0 / falloff)
+        } else {
+            (1.0, 0.0)
+        };
+
+        let mut vertices = Vec::with_capacity(lines.len() * 4);
+        let mut indices = Vec::with_capacity(lines.len() * 6);
+
+        for line in lines {
+            let center = [
+                (line.from[0] + line.to[0]) * 0.5,
+                (line.from[1] + line.to[1]) * 0.5,
+                (line.from[2] + line.to[2]) * 0.5,
+            ];
+
+            // perpendicular to the line and the view direction
+            let side = match normalize(cross(sub(eye, center), sub(line.to, line.from))) {
+                Some(side) => side,
+                None => continue, // degenerate or looking straight along the line
+            };
+            let offset = [
+                side[0] * width * 0.5,
+                side[1] * width * 0.5,
+                side[2] * width * 0.5,
+            ];
+
+            let first = vertices.len() as u32;
+            for (p, sign) in [
+                (line.from, 1.0),
+                (line.from, -1.0),
+                (line.to, -1.0),
+                (line.to, 1.0),
+            ] {
+                vertices.push(Vertex {
+                    pos: [
+                        p[0] + sign * offset[0],
+                        p[1] + sign * offset[1],
+                        p[2] + sign * offset[2],
+                    ],
+                    col: line.col,
+                    uv: [inside, sign * edge],
+                    // the quad faces the eye, there is no fixed normal
+                    ..Default::default()
+                });
+            }
+            // counter-clockwise as seen from the eye, same as the loaded meshes
+            indices.extend([first, first + 1, first + 2, first, first + 2, first + 3]);
+        }
+
+        Self { vertices, indices }
+    }
+
+    // Per-triangle tangents from positions and UVs, averaged per vertex and made perpendicular
+    // to the normal. Does nothing if there are no UVs, i.e. they are all zero.
+    pub fn generate_tangents(&mut self) {
+        if self.vertices.iter().all(|vertex| vertex.uv == [0.0, 0.0]) {
+            return;
+        }
+
+        let triangles: Vec<[usize; 3]> = if self.is_indexed() {
+            self.indices
+                .chunks_exact(3)
+                .map(|t| [t[0] as usize, t[1] as usize, t[2] as usize])
+                .collect()
+        } else {
+            (0..self.vertices.len() / 3)
+                .map(|t| [3 * t, 3 * t + 1, 3 * t + 2])
+                .collect()
+        };
+
+        // along +U and +V, summed over the adjacent triangles
+        let mut along_u = vec![[0.0f32; 3]; self.vertices.len()];
+        let mut along_v = vec![[0.0f32; 3]; self.vertices.len()];
+        for [a, b, c] in triangles {
+            let (va, vb, vc) = (&self.vertices[a], &self.vertices[b], &self.vertices[c]);
+            let e1 = sub(vb.pos, va.pos);
+            let e2 = sub(vc.pos, va.pos);
+            let (du1, dv1) = (vb.uv[0] - va.uv[0], vb.uv[1] - va.uv[1]);
+            let (du2, dv2) = (vc.uv[0] - va.uv[0], vc.uv[1] - va.uv[1]);
+            let denominator = du1 * dv2 - du2 * dv1;
+            // degenerate in UV space, no direction to take from it
+            if denominator.abs() < f32::EPSILON {
+                continue;
+            }
+            let r = 1.0 / denominator;
+            let u = [0, 1, 2].map(|i| (e1[i] * dv2 - e2[i] * dv1) * r);
+            let v = [0, 1, 2].map(|i| (e2[i] * du1 - e1[i] * du2) * r);
+            for i in [a, b, c] {
+                along_u[i] = add(along_u[i], u);
+                along_v[i] = add(along_v[i], v);
+            }
+        }
+
+        for (vertex, u, v) in izip!(&mut self.vertices, along_u, along_v) {
+            let n = vertex.normal;
+            // Gram-Schmidt
+            let t = match normalize(sub(u, n.map(|c| c * dot(n, u)))) {
+                Some(t) => t,
+                None => continue,
+            };
+            let w = if dot(cross(n, t), v) < 0.0 { -1.0 } else { 1.0 };
+            vertex.tangent = [t[0], t[1], t[2], w];
+        }
+    }
+
+    // the narrowest index type that can address every vertex,
+    // halves the index memory for the many small meshes
+    pub fn index_type(&self) -> IndexType {
+        index_type_for(self.vertices.len())
+    }
+
     pub fn load_gltf<P: AsRef<Path>>(filename: P) -> Result<Self> {
-        let (gltf, buffers, _) = import(filename)?;
+        let path = filename.as_ref();
+        let (gltf, buffers, _) = import_gltf(path)?;
+        Self::read_gltf(path, &gltf, &buffers)
+    }
+
+    // Like load_gltf, plus the base color texture of the materials, embedded or from an external
+    // file. There is only one texture for the merged mesh, the first primitive that has one wins.
+    pub fn load_gltf_model<P: AsRef<Path>>(filename: P) -> Result<Model> {
+        let path = filename.as_ref();
+        let (gltf, buffers, images) = import_gltf(path)?;
+        let mesh = Self::read_gltf(path, &gltf, &buffers)?;
+
+        let mut textures = gltf
+            .meshes()
+            .flat_map(|mesh| mesh.primitives())
+            .filter_map(|primitive| {
+                primitive
+                    .material()
+                    .pbr_metallic_roughness()
+                    .base_color_texture()
+            })
+            .map(|info| info.texture().source().index());
+        let base_color_texture = match textures.next() {
+            Some(image_index) => {
+                if textures.any(|other| other != image_index) {
+                    log::warn!(
+                        "glTF file {} uses several base color textures, only the first is kept",
+                        path.display()
+                    );
+                }
+                Some(TextureData::from_gltf(&images[image_index])?)
+            }
+            None => None,
+        };
+
+        Ok(Model {
+            mesh,
+            base_color_texture,
+        })
+    }
+
+    // One entry per mesh a node of the default scene (or the first one) places,
+    // with the node transforms baked into positions and normals.
+    // A mesh used by several nodes shows up once per node.
+    pub fn load_gltf_scene<P: AsRef<Path>>(filename: P) -> Result<Vec<(String, Mesh)>> {
+        let path = filename.as_ref();
+        let (gltf, buffers, _) = import_gltf(path)?;
+        Self::read_gltf_scene(path, &gltf, &buffers)
+    }
+
+    // all meshes of the scene merged into one
+    fn read_gltf(path: &Path, gltf: &Document, buffers: &[buffer::Data]) -> Result<Self> {
+        let mut merged = Self {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+        };
+        for (_, mesh) in Self::read_gltf_scene(path, gltf, buffers)? {
+            merged.concat(&mesh)?;
+        }
+        Ok(merged)
+    }
+
+    fn read_gltf_scene(
+        path: &Path,
+        gltf: &Document,
+        buffers: &[buffer::Data],
+    ) -> Result<Vec<(String, Mesh)>> {
+        let scene = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+            Some(scene) => scene,
+            // nothing places the meshes, so they stay as they are
+            None => {
+                return gltf
+                    .meshes()
+                    .map(|mesh| {
+                        Ok((
+                            mesh_name(&mesh),
+                            Self::read_gltf_mesh(path, &mesh, buffers)?,
+                        ))
+                    })
+                    .collect()
+            }
+        };
+
+        let mut meshes = Vec::new();
+        let mut nodes: Vec<_> = scene.nodes().map(|node| (node, IDENTITY)).collect();
+        while let Some((node, parent)) = nodes.pop() {
+            let transform = mat_mul(&parent, &node.transform().matrix());
+            if let Some(mesh) = node.mesh() {
+                let mut read = Self::read_gltf_mesh(path, &mesh, buffers)?;
+                read.transform(&transform);
+                meshes.push((mesh_name(&mesh), read));
+            }
+            nodes.extend(node.children().map(|child| (child, transform)));
+        }
+        Ok(meshes)
+    }
+
+    fn read_gltf_mesh(path: &Path, mesh: &gltf::Mesh, buffers: &[buffer::Data]) -> Result<Self> {
+        let missing = |what: &str| anyhow!("glTF file {} has no {}", path.display(), what);
 
         let mut vertices = Vec::new();
         let mut indices = Vec::new();
 
-        for mesh in gltf.meshes() {
-            log::debug!("Reading mesh: {}", mesh.name().or(Some("NO NAME")).unwrap());
+        log::debug!("Reading mesh: {}", mesh.name().or(Some("NO NAME")).unwrap());
 
-            for primitive in mesh.primitives() {
-                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        for primitive in mesh.primitives() {
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
 
-                indices.extend(
-                    reader
-                        .read_indices()
-                        .expect("didn't find indices")
-                        .into_u32()
-                        .map(|i| i + vertices.len() as u32),
-                );
+            indices.extend(
+                reader
+                    .read_indices()
+                    .ok_or_else(|| missing("indices"))?
+                    .into_u32()
+                    .map(|i| i + vertices.len() as u32),
+            );
 
-                if reader.read_colors(0).is_some() {
-                    vertices.extend(
-                        izip!(
-                            reader.read_positions().expect("didn't find positions"),
-                            reader.read_normals().expect("didn't find normals"),
-                            reader
-                                .read_colors(0)
-                                .expect("didn't find colors")
-                                .into_rgb_f32(), // TODO what is the color set?
-                        )
-                        .map(|(p, _n, c)| Vertex {
-                            // TODO use normal
-                            pos: p.into(),
-                            col: c.into(),
-                        }),
-                    );
-                } else {
+            let positions = reader
+                .read_positions()
+                .ok_or_else(|| missing("positions"))?
+                .collect::<Vec<_>>();
+            let normals = reader.read_normals().ok_or_else(|| missing("normals"))?;
+            let colors = match reader.read_colors(0) {
+                Some(colors) => colors.into_rgb_f32().collect(), // TODO what is the color set?
+                None => {
                     log::warn!("Didn't find no colors");
-                    vertices.extend(
-                        izip!(
-                            reader.read_positions().expect("didn't find positions"),
-                            reader.read_normals().expect("didn't find normals"),
-                        )
-                        .map(|(p, _n)| Vertex {
-                            // TODO use normal
-                            pos: p.into(),
-                            col: [0.1, 0.2, 0.8], // blue-ish
-                        }),
-                    );
+                    vec![[0.1, 0.2, 0.8]; positions.len()] // blue-ish
                 }
-            }
+            };
+            // untextured meshes still load, they just sample the corner
+            let uvs = match reader.read_tex_coords(0) {
+                Some(uvs) => uvs.into_f32().collect(),
+                None => vec![[0.0, 0.0]; positions.len()],
+            };
+            let tangents = match reader.read_tangents() {
+                Some(tangents) => tangents.collect(),
+                None => vec![[0.0; 4]; positions.len()],
+            };
+
+            vertices.extend(izip!(positions, normals, colors, uvs, tangents).map(
+                |(pos, normal, col, uv, tangent)| Vertex {
+                    pos,
+                    col,
+                    normal,
+                    uv,
+                    tangent,
+                },
+            ));
         }
 
         Ok(Self { vertices, indices })
     }
+
+    // Column-major like glTF. Normals go through the cofactor matrix,
+    // which keeps them perpendicular under non-uniform scaling.
+    fn transform(&mut self, matrix: &[[f32; 4]; 4]) {
+        let m = |row: usize, col: usize| matrix[col][row];
+        let cofactor = |row: usize, col: usize| {
+            let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+            let (c0, c1) = ((col + 1) % 3, (col + 2) % 3);
+            m(r0, c0) * m(r1, c1) - m(r0, c1) * m(r1, c0)
+        };
+        let determinant: f32 = (0..3).map(|col| m(0, col) * cofactor(0, col)).sum();
+        for vertex in &mut self.vertices {
+            let [x, y, z] = vertex.pos;
+            vertex.pos =
+                [0, 1, 2].map(|row| m(row, 0) * x + m(row, 1) * y + m(row, 2) * z + m(row, 3));
+            let [x, y, z] = vertex.normal;
+            let normal = [0, 1, 2]
+                .map(|row| cofactor(row, 0) * x + cofactor(row, 1) * y + cofactor(row, 2) * z);
+            let length = normal.iter().map(|c| c * c).sum::<f32>().sqrt();
+            // the inverse transpose is the cofactor matrix over the determinant, keep its sign
+            if length > 0.0 {
+                vertex.normal = normal.map(|c| c * determinant.signum() / length);
+            }
+            let [x, y, z, w] = vertex.tangent;
+            let tangent = [0, 1, 2].map(|row| m(row, 0) * x + m(row, 1) * y + m(row, 2) * z);
+            let length = tangent.iter().map(|c| c * c).sum::<f32>().sqrt();
+            if length > 0.0 {
+                let [x, y, z] = tangent.map(|c| c / length);
+                vertex.tangent = [x, y, z, w * determinant.signum()];
+            }
+        }
+        // a mirroring transform flips the winding
+        if determinant < 0.0 {
+            for triangle in self.indices.chunks_exact_mut(3) {
+                triangle.swap(1, 2);
+            }
+        }
+    }
+}
+
+const IDENTITY: [[f32; 4]; 4] = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+// column-major, a applied after b
+fn mat_mul(a: &[[f32; 4]; 4], b: &[[f32; 4]; 4]) -> [[f32; 4]; 4] {
+    [0, 1, 2, 3].map(|col| [0, 1, 2, 3].map(|row| (0..4).map(|k| a[k][row] * b[col][k]).sum()))
+}
+
+fn mesh_name(mesh: &gltf::Mesh) -> String {
+    mesh.name()
+        .map_or_else(|| format!("Mesh{}", mesh.index()), str::to_string)
+}
+
+fn import_gltf(path: &Path) -> Result<(Document, Vec<buffer::Data>, Vec<image::Data>)> {
+    import(path).map_err(|e| {
+        // relative paths are the usual suspect
+        let cwd = std::env::current_dir()
+            .map_or("<unknown>".to_string(), |dir| dir.display().to_string());
+        anyhow!(
+            "Failed to load glTF file {} (working directory {}): {}",
+            path.display(),
+            cwd,
+            e
+        )
+    })
+}
+
+// Where the mesh data lives. Mapped buffers are written directly and suit meshes that change
+// every frame, device local buffers are faster to read but every write waits for a copy.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MeshMemory {
+    Mapped,
+    DeviceLocal,
+}
+
+impl MeshMemory {
+    // below this the staging copy isn't worth it
+    pub const DEVICE_LOCAL_MIN_VERTICES: usize = 1024;
+
+    pub fn for_static_mesh(mesh: &Mesh) -> Self {
+        if mesh.vertices.len() >= Self::DEVICE_LOCAL_MIN_VERTICES {
+            Self::DeviceLocal
+        } else {
+            Self::Mapped
+        }
+    }
+}
+
+// see Mesh::index_type
+pub fn index_type_for(vertex_count: usize) -> IndexType {
+    if vertex_count <= u16::MAX as usize + 1 {
+        IndexType::UINT16
+    } else {
+        IndexType::UINT32
+    }
+}
+
+pub enum GeometryBuffer<T> {
+    Mapped(MappedDeviceBuffer<T>),
+    DeviceLocal(DeviceLocalBuffer<T>),
+}
+
+impl<T> GeometryBuffer<T> {
+    pub fn new(
+        context: &Context,
+        usage: BufferUsageFlags,
+        memory: MeshMemory,
+        len: usize,
+        name: String,
+    ) -> Result<Self> {
+        Ok(match memory {
+            MeshMemory::Mapped => Self::Mapped(MappedDeviceBuffer::new(context, usage, len, name)?),
+            MeshMemory::DeviceLocal => {
+                Self::DeviceLocal(DeviceLocalBuffer::new(context, usage, len, name)?)
+            }
+        })
+    }
+
+    pub fn handle(&self) -> Buffer {
+        match self {
+            Self::Mapped(buffer) => buffer.handle(),
+            Self::DeviceLocal(buffer) => buffer.handle(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Mapped(buffer) => buffer.size(),
+            Self::DeviceLocal(buffer) => buffer.size(),
+        }
+    }
+
+    pub fn write(&self, context: &Context, data: &[T]) -> Result<()> {
+        match self {
+            Self::Mapped(buffer) => {
+                buffer.write(data);
+                Ok(())
+            }
+            Self::DeviceLocal(buffer) => buffer.write(context, data),
+        }
+    }
+}
+
+impl<T: Copy> GeometryBuffer<T> {
+    pub fn write_iter(
+        &self,
+        context: &Context,
+        data: impl ExactSizeIterator<Item = T>,
+    ) -> Result<()> {
+        match self {
+            Self::Mapped(buffer) => {
+                buffer.write_iter(data);
+                Ok(())
+            }
+            Self::DeviceLocal(buffer) => buffer.write_iter(context, data),
+        }
+    }
+}
+
+pub enum IndexBuffer {
+    U16(GeometryBuffer<u16>),
+    U32(GeometryBuffer<u32>),
+}
+
+impl IndexBuffer {
+    pub fn new(
+        context: &Context,
+        index_type: IndexType,
+        memory: MeshMemory,
+        len: usize,
+        name: String,
+    ) -> Result<Self> {
+        Ok(match index_type {
+            IndexType::UINT16 => Self::U16(GeometryBuffer::new(
+                context,
+                BufferUsageFlags::INDEX_BUFFER,
+                memory,
+                len,
+                name,
+            )?),
+            IndexType::UINT32 => Self::U32(GeometryBuffer::new(
+                context,
+                BufferUsageFlags::INDEX_BUFFER,
+                memory,
+                len,
+                name,
+            )?),
+            _ => bail!("Unsupported index type {:?}", index_type),
+        })
+    }
+
+    pub fn index_type(&self) -> IndexType {
+        match self {
+            Self::U16(_) => IndexType::UINT16,
+            Self::U32(_) => IndexType::UINT32,
+        }
+    }
+
+    pub fn handle(&self) -> Buffer {
+        match self {
+            Self::U16(buffer) => buffer.handle(),
+            Self::U32(buffer) => buffer.handle(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U16(buffer) => buffer.size(),
+            Self::U32(buffer) => buffer.size(),
+        }
+    }
+
+    // indices have to fit the type, see Mesh::index_type
+    pub fn write(&self, context: &Context, indices: &[u32]) -> Result<()> {
+        match self {
+            Self::U16(buffer) => buffer.write_iter(context, indices.iter().map(|&i| i as u16)),
+            Self::U32(buffer) => buffer.write(context, indices),
+        }
+    }
 }
 
 pub struct MeshBuffers {
-    pub vertex: MappedDeviceBuffer<Vertex>,
-    pub index: MappedDeviceBuffer<u32>,
+    pub vertex: GeometryBuffer<Vertex>,
+    pub index: Option<IndexBuffer>, // None for non-indexed drawing
+    pub memory: MeshMemory,
     pub name: String,
+    // by the last write, for full_range
+    written_vertices: usize,
+    written_indices: usize,
 }
 
 impl MeshBuffers {
-    pub fn new(context: &Context, vertices: usize, indices: usize, name: String) -> Result<Self> {
-        let vertex = MappedDeviceBuffer::new(
+    pub fn new(
+        context: &Context,
+        vertices: usize,
+        indices: usize,
+        memory: MeshMemory,
+        name: String,
+    ) -> Result<Self> {
+        let vertex = GeometryBuffer::new(
             context,
             BufferUsageFlags::VERTEX_BUFFER,
+            memory,
             vertices,
             format!("{}Vertex", name),
         )?;
-        let index = MappedDeviceBuffer::new(
-            context,
-            BufferUsageFlags::INDEX_BUFFER,
-            indices,
-            format!("{}Index", name),
-        )?;
+        // what write would pick for a mesh filling the vertex buffer
+        let index_type = index_type_for(vertices);
+        let index = Self::new_index(context, indices, index_type, memory, &name)?;
 
         Ok(Self {
             vertex,
             index,
+            memory,
             name,
+            written_vertices: 0,
+            written_indices: 0,
         })
     }
 
+    pub fn new_non_indexed(context: &Context, vertices: usize, name: String) -> Result<Self> {
+        Self::new(context, vertices, 0, MeshMemory::Mapped, name)
+    }
+
+    // can't create a zero sized buffer
+    fn new_index(
+        context: &Context,
+        len: usize,
+        index_type: IndexType,
+        memory: MeshMemory,
+        name: &str,
+    ) -> Result<Option<IndexBuffer>> {
+        if len == 0 {
+            return Ok(None);
+        }
+        Ok(Some(IndexBuffer::new(
+            context,
+            index_type,
+            memory,
+            len,
+            format!("{}Index", name),
+        )?))
+    }
+
     pub fn resize_vertex(&mut self, context: &Context, new_size: usize) -> Result<()> {
         if self.vertex.size() == new_size {
             return Ok(());
         }
 
-        self.vertex = MappedDeviceBuffer::new(
+        self.vertex = GeometryBuffer::new(
             context,
             BufferUsageFlags::VERTEX_BUFFER,
+            self.memory,
             new_size,
             format!("{}Vertex", self.name),
         )?;
+        self.written_vertices = 0;
 
         Ok(())
     }
 
     pub fn resize_index(&mut self, context: &Context, new_size: usize) -> Result<()> {
-        if self.index.size() == new_size {
+        if self.num_indices() == new_size {
             return Ok(());
         }
 
-        self.index = MappedDeviceBuffer::new(
-            context,
-            BufferUsageFlags::INDEX_BUFFER,
-            new_size,
-            format!("{}Index", self.name),
-        )?;
+        let index_type = self
+            .index_type()
+            .unwrap_or_else(|| index_type_for(self.vertex.size()));
+        self.index = Self::new_index(context, new_size, index_type, self.memory, &self.name)?;
+        self.written_indices = 0;
 
         Ok(())
     }
@@ -190,29 +975,335 @@ impl MeshBuffers {
             self.resize_vertex(context, mesh.vertices.len())?;
         }
 
-        if self.index.size() < mesh.indices.len() {
-            self.resize_index(context, mesh.indices.len())?;
+        if mesh.indices.is_empty() {
+            // drawn in order from now on
+            self.index = None;
+        } else {
+            let index_type = mesh.index_type();
+            if self.num_indices() < mesh.indices.len() || self.index_type() != Some(index_type) {
+                self.index = Self::new_index(
+                    context,
+                    mesh.indices.len(),
+                    index_type,
+                    self.memory,
+                    &self.name,
+                )?;
+            }
         }
 
-        self.vertex.write(&mesh.vertices);
-        self.index.write(&mesh.indices);
+        self.vertex.write(context, &mesh.vertices)?;
+        if let Some(index) = &self.index {
+            index.write(context, &mesh.indices)?;
+        }
+        self.written_vertices = mesh.vertices.len();
+        self.written_indices = mesh.indices.len();
 
         Ok(())
     }
 
+    pub fn is_indexed(&self) -> bool {
+        self.index.is_some()
+    }
+
     pub fn num_vertices(&self) -> usize {
         self.vertex.size()
     }
 
     pub fn num_indices(&self) -> usize {
-        self.index.size()
+        self.index.as_ref().map_or(0, |index| index.size())
     }
 
     pub fn vertex_buffer(&self) -> Buffer {
         self.vertex.handle()
     }
 
-    pub fn index_buffer(&self) -> Buffer {
-        self.index.handle()
+    pub fn index_buffer(&self) -> Option<Buffer> {
+        self.index.as_ref().map(|index| index.handle())
+    }
+
+    pub fn index_type(&self) -> Option<IndexType> {
+        self.index.as_ref().map(|index| index.index_type())
+    }
+
+    // What the last write put into the buffers, which can be less than they hold.
+    // Empty until the first write.
+    pub fn full_range(&self) -> DrawRange {
+        DrawRange {
+            first: 0,
+            count: if self.is_indexed() {
+                self.written_indices
+            } else {
+                self.written_vertices
+            } as u32,
+            vertex_offset: 0,
+        }
+    }
+
+    pub fn bind(&self, device: &Device, command_buffer: CommandBuffer) {
+        unsafe {
+            device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex.handle()], &[0]);
+            if let Some(index) = &self.index {
+                device.cmd_bind_index_buffer(command_buffer, index.handle(), 0, index.index_type());
+            }
+        }
+    }
+
+    // expects the buffers to be bound already
+    pub fn record_draw_range(
+        &self,
+        device: &Device,
+        command_buffer: CommandBuffer,
+        range: DrawRange,
+    ) {
+        unsafe {
+            if self.is_indexed() {
+                device.cmd_draw_indexed(
+                    command_buffer,
+                    range.count,
+                    1,
+                    range.first,
+                    range.vertex_offset,
+                    0,
+                );
+            } else {
+                device.cmd_draw(
+                    command_buffer,
+                    range.count,
+                    1,
+                    (range.first as i32 + range.vertex_offset) as u32,
+                    0,
+                );
+            }
+        }
+    }
+
+    // binds the buffers and issues either cmd_draw_indexed or cmd_draw
+    // the command buffer has to be recording inside a render pass
+    pub fn record_draw(&self, device: &Device, command_buffer: CommandBuffer) {
+        self.bind(device, command_buffer);
+        self.record_draw_range(device, command_buffer, self.full_range());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // in the XY plane facing +Z, corners counter-clockwise from the bottom left
+    fn quad(uvs: [[f32; 2]; 4]) -> Mesh {
+        let corners = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+        Mesh {
+            vertices: corners
+                .iter()
+                .zip(uvs)
+                .map(|(&[x, y], uv)| Vertex {
+                    pos: [x, y, 0.0],
+                    normal: [0.0, 0.0, 1.0],
+                    uv,
+                    ..Vertex::default()
+                })
+                .collect(),
+            indices: vec![0, 1, 2, 2, 3, 0],
+        }
+    }
+
+    fn assert_tangents(mesh: &Mesh, expected: [f32; 4]) {
+        for vertex in &mesh.vertices {
+            assert!(
+                izip!(vertex.tangent, expected).all(|(t, e)| (t - e).abs() < 1e-6),
+                "{:?} isn't {:?}",
+                vertex.tangent,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn tangents_point_along_u() {
+        // U to the right, V down like images
+        let mut mesh = quad([[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]]);
+        mesh.generate_tangents();
+        assert_tangents(&mesh, [1.0, 0.0, 0.0, -1.0]);
+
+        // rotated UVs, U runs up the quad now
+        let mut mesh = quad([[0.0, 0.0], [0.0, 1.0], [1.0, 1.0], [1.0, 0.0]]);
+        mesh.generate_tangents();
+        assert_tangents(&mesh, [0.0, 1.0, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn index_type_addresses_every_vertex() {
+        assert_eq!(index_type_for(3), IndexType::UINT16);
+        // the last index is one less than the count
+        assert_eq!(index_type_for(1 << 16), IndexType::UINT16);
+        assert_eq!(index_type_for((1 << 16) + 1), IndexType::UINT32);
+    }
+
+    #[test]
+    fn tangents_need_uvs() {
+        let mut mesh = quad([[0.0, 0.0]; 4]);
+        mesh.generate_tangents();
+        assert!(mesh
+            .vertices
+            .iter()
+            .all(|vertex| vertex.tangent == Vertex::default().tangent));
+    }
+
+    #[test]
+    fn cube_has_four_vertices_per_face() {
+        let cube = Mesh::cube(2.0);
+        assert_eq!(cube.vertices.len(), 24);
+        assert_eq!(cube.indices.len(), 36);
+        assert!(cube
+            .indices
+            .iter()
+            .all(|&i| (i as usize) < cube.vertices.len()));
+        // edge length 2, so every corner is one away from the center on each axis
+        assert!(cube
+            .vertices
+            .iter()
+            .all(|vertex| vertex.pos.iter().all(|p| (p.abs() - 1.0).abs() < 1e-6)));
+        // one color per face
+        for face in cube.vertices.chunks_exact(4) {
+            assert!(face.iter().all(|vertex| vertex.col == face[0].col));
+        }
+    }
+
+    #[test]
+    fn packed_meshes_draw_their_own_vertices() {
+        let mut packed = Mesh {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+        };
+        let triangle = Mesh::debug_triangle();
+        let cube = Mesh::cube(1.0);
+        let first = packed.append(&triangle).unwrap();
+        let second = packed.append(&cube).unwrap();
+
+        assert_eq!(
+            first,
+            DrawRange {
+                first: 0,
+                count: 3,
+                vertex_offset: 0,
+            }
+        );
+        assert_eq!(
+            second,
+            DrawRange {
+                first: 3,
+                count: 36,
+                vertex_offset: 3,
+            }
+        );
+        // what cmd_draw_indexed fetches for each range
+        for (mesh, range) in [(&triangle, first), (&cube, second)] {
+            let indices = &packed.indices[range.first as usize..][..range.count as usize];
+            for (&index, original) in indices.iter().zip(&mesh.indices) {
+                let vertex = &packed.vertices[(index as i32 + range.vertex_offset) as usize];
+                assert_eq!(vertex.pos, mesh.vertices[*original as usize].pos);
+            }
+        }
+
+        let non_indexed = Mesh {
+            vertices: triangle.vertices.clone(),
+            indices: Vec::new(),
+        };
+        assert!(packed.append(&non_indexed).is_err());
+    }
+
+    // the alpha the thick line fragment shader computes
+    fn alpha(vertex: &Vertex) -> f32 {
+        (vertex.uv[0] - vertex.uv[1].abs()).clamp(0.0, 1.0)
+    }
+
+    #[test]
+    fn thick_lines_fade_out_at_the_edges() {
+        let lines = [Line {
+            from: [0.0, 0.0, 0.0],
+            to: [1.0, 0.0, 0.0],
+            col: [1.0, 1.0, 1.0],
+        }];
+        let eye = [0.5, 0.0, 1.0];
+
+        let hard = Mesh::thick_lines(&lines, ThickLineSettings::default(), eye);
+        assert_eq!(hard.vertices.len(), 4);
+        assert!(hard.vertices.iter().all(|vertex| alpha(vertex) == 1.0));
+
+        let settings = ThickLineSettings {
+            width: 0.1,
+            falloff: 0.5,
+        };
+        let soft = Mesh::thick_lines(&lines, settings, eye);
+        assert!(soft.vertices.iter().all(|vertex| alpha(vertex) == 0.0));
+        // interpolated halfway to the center, the end of the falloff
+        let (a, b) = (&soft.vertices[0], &soft.vertices[1]);
+        let center = Vertex {
+            uv: [(a.uv[0] + b.uv[0]) * 0.5, (a.uv[1] + b.uv[1]) * 0.5],
+            ..*a
+        };
+        let quarter = Vertex {
+            uv: [
+                (a.uv[0] + center.uv[0]) * 0.5,
+                (a.uv[1] + center.uv[1]) * 0.5,
+            ],
+            ..*a
+        };
+        assert_eq!(alpha(&center), 1.0);
+        assert!((alpha(&quarter) - 1.0).abs() < 1e-6);
+    }
+
+    // one triangle placed by two nodes, the second one moved along X
+    const TWO_NODES: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0, 1] }],
+        "nodes": [
+            { "mesh": 0 },
+            { "mesh": 0, "translation": [10.0, 0.0, 0.0] }
+        ],
+        "meshes": [{
+            "name": "Triangle",
+            "primitives": [{ "attributes": { "POSITION": 0, "NORMAL": 1 }, "indices": 2 }]
+        }],
+        "buffers": [{
+            "byteLength": 80,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAAAAAAAAAAAAIA/AAAAAAAAAAAAAIA/AAAAAAAAAAAAAIA/AAABAAIAAAA="
+        }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 72, "byteLength": 6 }
+        ],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+              "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0] },
+            { "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3" },
+            { "bufferView": 2, "componentType": 5123, "count": 3, "type": "SCALAR" }
+        ]
+    }"#;
+
+    #[test]
+    fn merged_scene_indices_are_rebased() {
+        let (gltf, buffers, _) = gltf::import_slice(TWO_NODES.as_bytes()).unwrap();
+        let mesh = Mesh::read_gltf(Path::new("two_nodes.gltf"), &gltf, &buffers).unwrap();
+        assert_eq!(mesh.vertices.len(), 6);
+        // drawn with full_range, so the second triangle has to address its own vertices
+        assert_eq!(mesh.indices, [0, 1, 2, 3, 4, 5]);
+        // each triangle's first corner sits at its node's origin
+        let corners = [0, 3].map(|i| mesh.vertices[mesh.indices[i] as usize].pos[0]);
+        assert_eq!((corners[0] - corners[1]).abs(), 10.0);
+    }
+
+    #[test]
+    fn concat_rebases_but_append_does_not() {
+        let mut concatenated = quad([[0.0, 0.0]; 4]);
+        concatenated.concat(&quad([[0.0, 0.0]; 4])).unwrap();
+        assert_eq!(concatenated.indices[6..], [4, 5, 6, 6, 7, 4]);
+
+        let mut appended = quad([[0.0, 0.0]; 4]);
+        let range = appended.append(&quad([[0.0, 0.0]; 4])).unwrap();
+        assert_eq!(appended.indices[6..], [0, 1, 2, 2, 3, 0]);
+        assert_eq!(range.vertex_offset, 4);
     }
 }
@@ -1,21 +1,30 @@
+pub mod allocator;
 pub mod buffers;
+pub mod command_buffers;
 pub mod context;
 #[cfg(feature = "validation_vulkan")]
 pub mod debug;
 pub mod descriptors;
 pub mod device_image;
+pub mod frame_ring;
 pub mod geometry;
 pub mod pipeline;
+pub mod pipeline_reloader;
 pub mod render_pass;
+pub mod sampler;
 pub mod surface;
 pub mod sync;
 
-pub use context::Context;
+pub use command_buffers::OwnedCommandBuffers;
+pub use context::{Context, MemoryBudget, VulkanSettings};
 #[cfg(feature = "validation_vulkan")]
 pub use debug::Debug;
 pub use device_image::DeviceImage;
+pub use frame_ring::{Frame, FrameRing};
 pub use geometry::Vertex;
 pub use pipeline::create_pipeline;
 pub use pipeline::create_pipeline_layout;
-pub use render_pass::create_render_pass_window;
-pub use surface::SurfaceRelated;
+pub use pipeline_reloader::{PipelineReloader, PipelineSettings};
+pub use render_pass::{create_render_pass_window, create_render_pass_window_supersampled};
+pub use sampler::{create_sampler, SamplerSettings};
+pub use surface::{ColorEncoding, SurfaceRelated};
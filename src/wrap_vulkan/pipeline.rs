@@ -1,29 +1,42 @@
-use std::ffi::CString;
-
-use anyhow::Result;
-use ash::vk::{
-    BlendFactor, BlendOp, ColorComponentFlags, CompareOp, CullModeFlags, DescriptorSetLayout,
-    DynamicState, Extent2D, FrontFace, GraphicsPipelineCreateInfo, LogicOp, Offset2D, Pipeline,
-    PipelineCache, PipelineColorBlendAttachmentState, PipelineColorBlendStateCreateInfo,
-    PipelineDepthStencilStateCreateInfo, PipelineDynamicStateCreateInfo,
-    PipelineInputAssemblyStateCreateInfo, PipelineLayout, PipelineLayoutCreateInfo,
-    PipelineMultisampleStateCreateInfo, PipelineRasterizationStateCreateInfo,
-    PipelineShaderStageCreateInfo, PipelineVertexInputStateCreateInfo,
-    PipelineViewportStateCreateInfo, PolygonMode, PrimitiveTopology, Rect2D, RenderPass,
-    SampleCountFlags, ShaderModule, ShaderModuleCreateInfo, Viewport,
+use std::{ffi::CString, fs::File, mem::size_of, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use ash::{
+    util::read_spv,
+    vk::{
+        BlendFactor, BlendOp, ColorComponentFlags, CommandBuffer, CullModeFlags,
+        DescriptorSetLayout, DynamicState, Extent2D, FrontFace, GraphicsPipelineCreateInfo,
+        LogicOp, Offset2D, Pipeline, PipelineCache, PipelineColorBlendAttachmentState,
+        PipelineColorBlendStateCreateInfo, PipelineDepthStencilStateCreateInfo,
+        PipelineDynamicStateCreateInfo, PipelineInputAssemblyStateCreateInfo, PipelineLayout,
+        PipelineLayoutCreateInfo, PipelineMultisampleStateCreateInfo,
+        PipelineRasterizationStateCreateInfo, PipelineShaderStageCreateInfo,
+        PipelineVertexInputStateCreateInfo, PipelineViewportStateCreateInfo, PolygonMode,
+        PrimitiveTopology, PushConstantRange, Rect2D, RenderPass, SampleCountFlags, ShaderModule,
+        ShaderModuleCreateInfo, ShaderStageFlags, Viewport, TRUE,
+    },
+    Device,
 };
 
+use vk_shader_macros::include_glsl;
+
 use super::{Context, Vertex};
 
-// later we can add push constants
+const THICK_LINE_FRAG: &[u32] = include_glsl!("shaders/thick_line.frag");
+
+// push constants are for small per draw data, like the model matrix.
+// Set i of the shaders uses set_layouts[i], e.g. one set per frame and one per material.
 pub fn create_pipeline_layout(
     context: &Context,
-    set_layout: DescriptorSetLayout,
+    set_layouts: &[DescriptorSetLayout],
+    push_constant_ranges: &[PushConstantRange],
     name: String,
 ) -> Result<PipelineLayout> {
     let layout = unsafe {
         context.device.create_pipeline_layout(
-            &PipelineLayoutCreateInfo::builder().set_layouts(&[set_layout]),
+            &PipelineLayoutCreateInfo::builder()
+                .set_layouts(set_layouts)
+                .push_constant_ranges(push_constant_ranges),
             None,
         )
     }?;
@@ -31,6 +44,33 @@ pub fn create_pipeline_layout(
     Ok(layout)
 }
 
+// the range for a single T starting at 0
+pub fn push_constant_range<T>(stage_flags: ShaderStageFlags) -> PushConstantRange {
+    PushConstantRange::builder()
+        .stage_flags(stage_flags)
+        .offset(0)
+        .size(size_of::<T>() as u32)
+        .build()
+}
+
+// T has to be repr(C) and match the layout in the shader
+pub unsafe fn cmd_push_constants<T>(
+    device: &Device,
+    command_buffer: CommandBuffer,
+    layout: PipelineLayout,
+    stage_flags: ShaderStageFlags,
+    offset: u32,
+    data: &T,
+) {
+    device.cmd_push_constants(
+        command_buffer,
+        layout,
+        stage_flags,
+        offset,
+        std::slice::from_raw_parts(data as *const T as *const u8, size_of::<T>()),
+    );
+}
+
 pub fn create_shader_module(
     context: &Context,
     spirv: &[u32],
@@ -45,19 +85,204 @@ pub fn create_shader_module(
     Ok(module)
 }
 
+// Reads compiled SPIR-V at runtime, e.g. from glslc, instead of embedding it at build time.
+// The object name is the path.
+pub fn create_shader_module_from_file(
+    context: &Context,
+    path: impl AsRef<Path>,
+) -> Result<ShaderModule> {
+    let path = path.as_ref();
+    let spirv = read_spirv_file(path)?;
+    create_shader_module(context, &spirv, path.display().to_string())
+}
+
+fn read_spirv_file(path: &Path) -> Result<Vec<u32>> {
+    let mut file =
+        File::open(path).map_err(|e| anyhow!("Failed to open shader {}: {}", path.display(), e))?;
+    // checks the size and the magic number, and takes care of the alignment
+    read_spv(&mut file).map_err(|e| anyhow!("Failed to read SPIR-V {}: {}", path.display(), e))
+}
+
+// Destroys the module when dropped, pipelines created from it don't need it anymore.
+// Pass a reference where a ShaderModule is expected, e.g. create_pipeline.
+pub struct ShaderModuleGuard {
+    module: ShaderModule,
+    device: Device,
+}
+
+impl Drop for ShaderModuleGuard {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_shader_module(self.module, None);
+        }
+    }
+}
+
+impl ShaderModuleGuard {
+    pub fn new(context: &Context, spirv: &[u32], name: String) -> Result<Self> {
+        Ok(Self {
+            module: create_shader_module(context, spirv, name)?,
+            device: context.device.clone(),
+        })
+    }
+
+    pub fn from_file(context: &Context, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            module: create_shader_module_from_file(context, path)?,
+            device: context.device.clone(),
+        })
+    }
+
+    pub fn handle(&self) -> ShaderModule {
+        self.module
+    }
+}
+
+impl From<&ShaderModuleGuard> for ShaderModule {
+    fn from(guard: &ShaderModuleGuard) -> Self {
+        guard.handle()
+    }
+}
+
+// how triangles are turned into fragments, the default fills front faces of
+// counter-clockwise triangles
+#[derive(Copy, Clone, Debug)]
+pub struct RasterOptions {
+    // LINE and POINT need the fillModeNonSolid feature
+    pub polygon_mode: PolygonMode,
+    pub cull_mode: CullModeFlags,
+    // Counter-clockwise like glTF and the built-in meshes. The winding is judged in framebuffer
+    // coordinates, where Vulkan's y points down. The projections here (fov_to_projection, or
+    // cgmath's with proj[1][1] negated) flip y to compensate, which keeps counter-clockwise
+    // meshes front facing. Without that flip, or with a mirroring model matrix, use flipped.
+    pub front_face: FrontFace,
+    // anything but 1.0 needs the wideLines feature
+    pub line_width: f32,
+}
+
+impl Default for RasterOptions {
+    fn default() -> Self {
+        Self {
+            polygon_mode: PolygonMode::FILL,
+            cull_mode: CullModeFlags::BACK,
+            front_face: FrontFace::COUNTER_CLOCKWISE,
+            line_width: 1.0,
+        }
+    }
+}
+
+impl RasterOptions {
+    // e.g. for debugging geometry, both sides so nothing disappears
+    pub fn wireframe() -> Self {
+        Self {
+            polygon_mode: PolygonMode::LINE,
+            cull_mode: CullModeFlags::NONE,
+            ..Default::default()
+        }
+    }
+
+    // the other winding is front facing, e.g. for meshes exported clockwise
+    pub fn flipped(self) -> Self {
+        Self {
+            front_face: match self.front_face {
+                FrontFace::CLOCKWISE => FrontFace::COUNTER_CLOCKWISE,
+                _ => FrontFace::CLOCKWISE,
+            },
+            ..self
+        }
+    }
+
+    fn check(&self, context: &Context) -> Result<()> {
+        if self.polygon_mode != PolygonMode::FILL
+            && context.enabled_features.fill_mode_non_solid != TRUE
+        {
+            bail!(
+                "Polygon mode {:?} needs fillModeNonSolid, which the device doesn't support",
+                self.polygon_mode
+            );
+        }
+        if self.line_width != 1.0 && context.enabled_features.wide_lines != TRUE {
+            bail!(
+                "Line width {} needs wideLines, which the device doesn't support",
+                self.line_width
+            );
+        }
+        Ok(())
+    }
+}
+
+// How the fragment color is combined with the attachment. The blending modes don't write
+// depth, so they are drawn after the opaque geometry, see Context::record_hmd_scene.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BlendMode {
+    #[default]
+    Opaque,
+    // src * src_alpha + dst * (1 - src_alpha), e.g. UI
+    AlphaBlend,
+    // src * src_alpha + dst, e.g. particles
+    Additive,
+}
+
+impl BlendMode {
+    fn attachment_state(self) -> PipelineColorBlendAttachmentState {
+        let builder = PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(
+                ColorComponentFlags::R
+                    | ColorComponentFlags::G
+                    | ColorComponentFlags::B
+                    | ColorComponentFlags::A,
+            )
+            .color_blend_op(BlendOp::ADD)
+            .alpha_blend_op(BlendOp::ADD);
+        match self {
+            Self::Opaque => builder
+                .blend_enable(false)
+                .src_color_blend_factor(BlendFactor::ONE)
+                .dst_color_blend_factor(BlendFactor::ZERO)
+                .src_alpha_blend_factor(BlendFactor::ONE)
+                .dst_alpha_blend_factor(BlendFactor::ZERO),
+            Self::AlphaBlend => builder
+                .blend_enable(true)
+                .src_color_blend_factor(BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(BlendFactor::ONE_MINUS_SRC_ALPHA)
+                .src_alpha_blend_factor(BlendFactor::ONE)
+                .dst_alpha_blend_factor(BlendFactor::ONE_MINUS_SRC_ALPHA),
+            Self::Additive => builder
+                .blend_enable(true)
+                .src_color_blend_factor(BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(BlendFactor::ONE)
+                .src_alpha_blend_factor(BlendFactor::ZERO)
+                .dst_alpha_blend_factor(BlendFactor::ONE),
+        }
+        .build()
+    }
+
+    // blended geometry is still depth tested against the opaque one
+    fn writes_depth(self) -> bool {
+        self == Self::Opaque
+    }
+}
+
 pub fn create_pipeline(
     context: &Context,
     render_pass: RenderPass,
     layout: PipelineLayout,
-    module_vert: ShaderModule,
-    module_frag: ShaderModule,
+    module_vert: impl Into<ShaderModule>,
+    module_frag: impl Into<ShaderModule>,
     initial_extent: Extent2D,
     dynamic_states: &[DynamicState],
+    // has to match the render pass, TYPE_1 for the HMD
+    samples: SampleCountFlags,
+    raster: RasterOptions,
+    blend: BlendMode,
     name: String,
 ) -> Result<Pipeline> {
+    raster.check(context)?;
     let vertex_bindings = Vertex::get_binding_description();
     let vertex_attributes = Vertex::get_attribute_description();
 
+    let module_vert: ShaderModule = module_vert.into();
+    let module_frag: ShaderModule = module_frag.into();
     let entry_point = CString::new("main").unwrap();
     let pipeline = unsafe {
         context.device.create_graphics_pipelines(
@@ -104,10 +329,10 @@ pub fn create_pipeline(
                     &PipelineRasterizationStateCreateInfo::builder()
                         .depth_clamp_enable(false)
                         .rasterizer_discard_enable(false)
-                        .polygon_mode(PolygonMode::FILL)
-                        .line_width(1.0)
-                        .cull_mode(CullModeFlags::BACK)
-                        .front_face(FrontFace::COUNTER_CLOCKWISE)
+                        .polygon_mode(raster.polygon_mode)
+                        .line_width(raster.line_width)
+                        .cull_mode(raster.cull_mode)
+                        .front_face(raster.front_face)
                         .depth_bias_enable(false)
                         .depth_bias_constant_factor(0.0)
                         .depth_bias_clamp(0.0)
@@ -116,7 +341,7 @@ pub fn create_pipeline(
                 .multisample_state(
                     &PipelineMultisampleStateCreateInfo::builder()
                         .sample_shading_enable(false)
-                        .rasterization_samples(SampleCountFlags::TYPE_1)
+                        .rasterization_samples(samples)
                         .min_sample_shading(1.0)
                         .alpha_to_coverage_enable(false)
                         .alpha_to_one_enable(false),
@@ -125,28 +350,14 @@ pub fn create_pipeline(
                     &PipelineColorBlendStateCreateInfo::builder()
                         .logic_op_enable(false)
                         .logic_op(LogicOp::COPY)
-                        .attachments(&[PipelineColorBlendAttachmentState::builder()
-                            .color_write_mask(
-                                ColorComponentFlags::R
-                                    | ColorComponentFlags::G
-                                    | ColorComponentFlags::B
-                                    | ColorComponentFlags::A,
-                            )
-                            .blend_enable(false)
-                            .src_color_blend_factor(BlendFactor::ONE)
-                            .dst_color_blend_factor(BlendFactor::ZERO)
-                            .color_blend_op(BlendOp::ADD)
-                            .src_alpha_blend_factor(BlendFactor::ONE)
-                            .dst_alpha_blend_factor(BlendFactor::ZERO)
-                            .alpha_blend_op(BlendOp::ADD)
-                            .build()])
+                        .attachments(&[blend.attachment_state()])
                         .blend_constants([0.0, 0.0, 0.0, 0.0]),
                 )
                 .depth_stencil_state(
                     &PipelineDepthStencilStateCreateInfo::builder()
                         .depth_test_enable(true)
-                        .depth_write_enable(true)
-                        .depth_compare_op(CompareOp::LESS)
+                        .depth_write_enable(blend.writes_depth())
+                        .depth_compare_op(context.depth_compare_op())
                         .depth_bounds_test_enable(false)
                         .min_depth_bounds(0.0)
                         .max_depth_bounds(1.0)
@@ -167,3 +378,122 @@ pub fn create_pipeline(
 
     Ok(pipeline)
 }
+
+// For Mesh::thick_lines with falloff, the crate's fragment shader turns the uv into alpha.
+// The vertex shader has to pass the color at location 0 and the uv at location 1.
+// Blended and not culled, so the lines are drawn after the opaque geometry.
+pub fn create_pipeline_thick_lines(
+    context: &Context,
+    render_pass: RenderPass,
+    layout: PipelineLayout,
+    module_vert: impl Into<ShaderModule>,
+    initial_extent: Extent2D,
+    dynamic_states: &[DynamicState],
+    samples: SampleCountFlags,
+    name: String,
+) -> Result<Pipeline> {
+    let module_frag = ShaderModuleGuard::new(context, THICK_LINE_FRAG, format!("{}Frag", name))?;
+    create_pipeline(
+        context,
+        render_pass,
+        layout,
+        module_vert,
+        &module_frag,
+        initial_extent,
+        dynamic_states,
+        samples,
+        RasterOptions {
+            cull_mode: CullModeFlags::NONE,
+            ..Default::default()
+        },
+        BlendMode::AlphaBlend,
+        name,
+    )
+}
+
+// For full screen passes, the vertex shader makes up a triangle covering the viewport
+// from gl_VertexIndex, so there are no vertex buffers. Draw with 3 vertices.
+pub fn create_pipeline_fullscreen(
+    context: &Context,
+    render_pass: RenderPass,
+    layout: PipelineLayout,
+    module_vert: impl Into<ShaderModule>,
+    module_frag: impl Into<ShaderModule>,
+    extent: Extent2D,
+    name: String,
+) -> Result<Pipeline> {
+    let module_vert: ShaderModule = module_vert.into();
+    let module_frag: ShaderModule = module_frag.into();
+    let entry_point = CString::new("main").unwrap();
+    let pipeline = unsafe {
+        context.device.create_graphics_pipelines(
+            PipelineCache::default(),
+            &[GraphicsPipelineCreateInfo::builder()
+                .stages(&[
+                    PipelineShaderStageCreateInfo::builder()
+                        .stage(ash::vk::ShaderStageFlags::VERTEX)
+                        .module(module_vert)
+                        .name(&entry_point)
+                        .build(),
+                    PipelineShaderStageCreateInfo::builder()
+                        .stage(ash::vk::ShaderStageFlags::FRAGMENT)
+                        .module(module_frag)
+                        .name(&entry_point)
+                        .build(),
+                ])
+                .vertex_input_state(&PipelineVertexInputStateCreateInfo::builder())
+                .input_assembly_state(
+                    &PipelineInputAssemblyStateCreateInfo::builder()
+                        .topology(PrimitiveTopology::TRIANGLE_LIST)
+                        .primitive_restart_enable(false),
+                )
+                .viewport_state(
+                    &PipelineViewportStateCreateInfo::builder()
+                        .viewports(&[Viewport::builder()
+                            .x(0.0)
+                            .y(0.0)
+                            .width(extent.width as f32)
+                            .height(extent.height as f32)
+                            .min_depth(0.0)
+                            .max_depth(1.0)
+                            .build()])
+                        .scissors(&[Rect2D::builder()
+                            .offset(Offset2D { x: 0, y: 0 })
+                            .extent(extent)
+                            .build()]),
+                )
+                .rasterization_state(
+                    &PipelineRasterizationStateCreateInfo::builder()
+                        .polygon_mode(PolygonMode::FILL)
+                        .line_width(1.0)
+                        .cull_mode(CullModeFlags::NONE),
+                )
+                .multisample_state(
+                    &PipelineMultisampleStateCreateInfo::builder()
+                        .rasterization_samples(SampleCountFlags::TYPE_1),
+                )
+                .color_blend_state(
+                    &PipelineColorBlendStateCreateInfo::builder().attachments(&[
+                        PipelineColorBlendAttachmentState::builder()
+                            .color_write_mask(
+                                ColorComponentFlags::R
+                                    | ColorComponentFlags::G
+                                    | ColorComponentFlags::B
+                                    | ColorComponentFlags::A,
+                            )
+                            .blend_enable(false)
+                            .build(),
+                    ]),
+                )
+                .layout(layout)
+                .render_pass(render_pass)
+                .subpass(0)
+                .build()],
+            None,
+        )
+    }
+    .map_err(|(_, e)| e)?[0];
+    context.name_object(pipeline, name)?;
+
+    Ok(pipeline)
+}
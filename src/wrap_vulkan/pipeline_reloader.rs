@@ -0,0 +1,138 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Result};
+use ash::{
+    vk::{DynamicState, Extent2D, Pipeline, PipelineLayout, RenderPass, SampleCountFlags},
+    Device,
+};
+
+use super::{
+    create_pipeline,
+    pipeline::{BlendMode, RasterOptions, ShaderModuleGuard},
+    Context,
+};
+
+// Everything create_pipeline takes apart from the shaders.
+#[derive(Clone, Debug)]
+pub struct PipelineSettings {
+    pub render_pass: RenderPass,
+    pub layout: PipelineLayout,
+    pub initial_extent: Extent2D,
+    pub dynamic_states: Vec<DynamicState>,
+    pub samples: SampleCountFlags,
+    pub raster: RasterOptions,
+    pub blend: BlendMode,
+    pub name: String,
+}
+
+// Owns a pipeline built from SPIR-V files and rebuilds it when they change on disk,
+// so shaders can be recompiled (e.g. with glslc) while the application keeps running.
+// Destroys the pipeline on drop, the GPU must be done with it by then.
+pub struct PipelineReloader {
+    pipeline: Pipeline,
+    settings: PipelineSettings,
+    paths: [PathBuf; 2],
+    modified: [SystemTime; 2],
+    device: Device,
+}
+
+impl Drop for PipelineReloader {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline(self.pipeline, None);
+        }
+    }
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| anyhow!("Failed to check shader {}: {}", path.display(), e))
+}
+
+impl PipelineReloader {
+    pub fn new(
+        context: &Context,
+        path_vert: impl AsRef<Path>,
+        path_frag: impl AsRef<Path>,
+        settings: PipelineSettings,
+    ) -> Result<Self> {
+        let paths = [
+            path_vert.as_ref().to_path_buf(),
+            path_frag.as_ref().to_path_buf(),
+        ];
+        let modified = [modified(&paths[0])?, modified(&paths[1])?];
+        Ok(Self {
+            pipeline: Self::build(context, &paths, &settings)?,
+            settings,
+            paths,
+            modified,
+            device: context.device.clone(),
+        })
+    }
+
+    fn build(
+        context: &Context,
+        paths: &[PathBuf; 2],
+        settings: &PipelineSettings,
+    ) -> Result<Pipeline> {
+        let module_vert = ShaderModuleGuard::from_file(context, &paths[0])?;
+        let module_frag = ShaderModuleGuard::from_file(context, &paths[1])?;
+        create_pipeline(
+            context,
+            settings.render_pass,
+            settings.layout,
+            &module_vert,
+            &module_frag,
+            settings.initial_extent,
+            &settings.dynamic_states,
+            settings.samples,
+            settings.raster,
+            settings.blend,
+            settings.name.clone(),
+        )
+    }
+
+    pub fn pipeline(&self) -> Pipeline {
+        self.pipeline
+    }
+
+    pub fn settings(&self) -> &PipelineSettings {
+        &self.settings
+    }
+
+    // Call once per frame, before recording. Returns whether the pipeline was replaced.
+    // If the new shaders fail to load, e.g. while the compiler is still writing them,
+    // the error is returned and the old pipeline stays, the next call tries again.
+    pub fn reload_if_changed(&mut self, context: &Context) -> Result<bool> {
+        let modified = [modified(&self.paths[0])?, modified(&self.paths[1])?];
+        if modified == self.modified {
+            return Ok(false);
+        }
+        self.reload(context)?;
+        self.modified = modified;
+        Ok(true)
+    }
+
+    // Rebuilds regardless of the modification times. Waits for the GPU, command buffers
+    // recorded with the old pipeline can't be submitted afterwards.
+    pub fn reload(&mut self, context: &Context) -> Result<()> {
+        let pipeline = Self::build(context, &self.paths, &self.settings)?;
+        context.wait_idle()?;
+        unsafe {
+            context.device.destroy_pipeline(self.pipeline, None);
+        }
+        self.pipeline = pipeline;
+        log::info!(
+            "Reloaded {} from {} and {}",
+            self.settings.name,
+            self.paths[0].display(),
+            self.paths[1].display()
+        );
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ash::vk::{
     AccessFlags, AttachmentDescription, AttachmentLoadOp, AttachmentReference, AttachmentStoreOp,
     ImageLayout, PipelineBindPoint, PipelineStageFlags, RenderPass, RenderPassCreateInfo,
@@ -8,43 +8,246 @@ use ash::vk::{
 
 use super::Context;
 
-pub fn create_render_pass_window(context: &Context) -> Result<RenderPass> {
+// Both passes clear color and depth, the depth image is shared between frames.
+// So the previous frame's depth writes (late tests) have to finish before we clear again (early tests).
+pub fn external_dependency() -> SubpassDependency {
+    SubpassDependency::builder()
+        .src_subpass(SUBPASS_EXTERNAL)
+        .dst_subpass(0)
+        .src_stage_mask(
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                | PipelineStageFlags::LATE_FRAGMENT_TESTS,
+        )
+        .src_access_mask(AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+        .dst_stage_mask(
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                | PipelineStageFlags::LATE_FRAGMENT_TESTS,
+        )
+        .dst_access_mask(
+            AccessFlags::COLOR_ATTACHMENT_WRITE
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        )
+        .build()
+}
+
+// With more than one sample the pipelines need the same count, see Context::clamp_sample_count
+pub fn create_render_pass_window(
+    context: &Context,
+    samples: SampleCountFlags,
+) -> Result<RenderPass> {
+    create_render_pass_window_with(
+        context,
+        samples,
+        ImageLayout::PRESENT_SRC_KHR,
+        &[external_dependency()],
+        "RenderPassWindow",
+    )
+}
+
+// Renders into an offscreen image that is blitted to the swapchain afterwards.
+// Compatible with the regular window pass, so the same pipelines can be used.
+pub fn create_render_pass_window_supersampled(
+    context: &Context,
+    samples: SampleCountFlags,
+) -> Result<RenderPass> {
+    let mut incoming = external_dependency();
+    // the previous frame's blit reads the offscreen image we are about to clear
+    incoming.src_stage_mask |= PipelineStageFlags::TRANSFER;
+    let outgoing = SubpassDependency::builder()
+        .src_subpass(0)
+        .dst_subpass(SUBPASS_EXTERNAL)
+        .src_stage_mask(PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_stage_mask(PipelineStageFlags::TRANSFER)
+        .dst_access_mask(AccessFlags::TRANSFER_READ)
+        .build();
+    create_render_pass_window_with(
+        context,
+        samples,
+        ImageLayout::TRANSFER_SRC_OPTIMAL,
+        &[incoming, outgoing],
+        "RenderPassWindowSupersampled",
+    )
+}
+
+// Attachments are color, depth and, when multisampled, the resolve target.
+// The multisampled color is only needed during the pass, the resolve target is what's kept.
+fn create_render_pass_window_with(
+    context: &Context,
+    samples: SampleCountFlags,
+    final_layout: ImageLayout,
+    dependencies: &[SubpassDependency],
+    name: &str,
+) -> Result<RenderPass> {
+    let format = context.get_surface_format()?;
+    let multisampled = samples != SampleCountFlags::TYPE_1;
+
+    let mut attachments = vec![
+        AttachmentDescription::builder()
+            .format(format)
+            .samples(samples)
+            .load_op(AttachmentLoadOp::CLEAR)
+            .store_op(if multisampled {
+                AttachmentStoreOp::DONT_CARE
+            } else {
+                AttachmentStoreOp::STORE
+            })
+            .stencil_load_op(AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(AttachmentStoreOp::DONT_CARE)
+            .initial_layout(ImageLayout::UNDEFINED)
+            .final_layout(if multisampled {
+                ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            } else {
+                final_layout
+            })
+            .build(),
+        AttachmentDescription::builder()
+            .format(context.find_supported_depth_stencil_format()?)
+            .samples(samples)
+            .load_op(AttachmentLoadOp::CLEAR)
+            .store_op(AttachmentStoreOp::DONT_CARE)
+            .stencil_load_op(AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(AttachmentStoreOp::DONT_CARE)
+            .initial_layout(ImageLayout::UNDEFINED)
+            .final_layout(ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .build(),
+    ];
+    if multisampled {
+        attachments.push(
+            AttachmentDescription::builder()
+                .format(format)
+                .samples(SampleCountFlags::TYPE_1)
+                .load_op(AttachmentLoadOp::DONT_CARE)
+                .store_op(AttachmentStoreOp::STORE)
+                .stencil_load_op(AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(AttachmentStoreOp::DONT_CARE)
+                .initial_layout(ImageLayout::UNDEFINED)
+                .final_layout(final_layout)
+                .build(),
+        );
+    }
+
+    let color_attachments = [AttachmentReference::builder()
+        .attachment(0)
+        .layout(ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+        .build()];
+    let resolve_attachments = [AttachmentReference::builder()
+        .attachment(2)
+        .layout(ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+        .build()];
+    let depth_attachment = AttachmentReference::builder()
+        .attachment(1)
+        .layout(ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+        .build();
+    let mut subpass = SubpassDescription::builder()
+        .pipeline_bind_point(PipelineBindPoint::GRAPHICS)
+        .color_attachments(&color_attachments)
+        .depth_stencil_attachment(&depth_attachment);
+    if multisampled {
+        subpass = subpass.resolve_attachments(&resolve_attachments);
+    }
+
     let render_pass = unsafe {
         context.device.create_render_pass(
             &RenderPassCreateInfo::builder()
-                .attachments(&[
-                    AttachmentDescription::builder()
-                        .format(context.get_surface_format()?)
-                        .samples(SampleCountFlags::TYPE_1)
-                        .load_op(AttachmentLoadOp::CLEAR)
-                        .store_op(AttachmentStoreOp::STORE)
-                        .stencil_load_op(AttachmentLoadOp::DONT_CARE)
-                        .stencil_store_op(AttachmentStoreOp::DONT_CARE)
-                        .initial_layout(ImageLayout::UNDEFINED)
-                        .final_layout(ImageLayout::PRESENT_SRC_KHR)
-                        .build(),
-                    AttachmentDescription::builder()
-                        .format(context.find_supported_depth_stencil_format()?)
-                        .samples(SampleCountFlags::TYPE_1)
-                        .load_op(AttachmentLoadOp::CLEAR)
-                        .store_op(AttachmentStoreOp::DONT_CARE)
-                        .stencil_load_op(AttachmentLoadOp::DONT_CARE)
-                        .stencil_store_op(AttachmentStoreOp::DONT_CARE)
-                        .initial_layout(ImageLayout::UNDEFINED)
-                        .final_layout(ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                        .build(),
-                ])
+                .attachments(&attachments)
+                .subpasses(&[subpass.build()])
+                .dependencies(dependencies),
+            None,
+        )
+    }?;
+    context.name_object(render_pass, name.to_string())?;
+    Ok(render_pass)
+}
+
+// one bit per view, rendered in a single pass with multiview
+pub fn multiview_mask(view_count: u32) -> Result<u32> {
+    if !(1..=32).contains(&view_count) {
+        bail!(
+            "A multiview mask can't have {} views, only 1 to 32",
+            view_count
+        );
+    }
+    Ok(u32::MAX >> (32 - view_count))
+}
+
+pub fn create_render_pass_hmd(context: &Context, view_count: u32) -> Result<RenderPass> {
+    create_render_pass_hmd_with(
+        context,
+        view_count,
+        // final layout isn't PRESENT_SRC_KHR
+        ImageLayout::TRANSFER_SRC_OPTIMAL,
+        &[external_dependency()],
+        false,
+        "RenderPassHMD",
+    )
+}
+
+// Keeps what is already in the color and depth images instead of clearing, e.g. after copying
+// a background in. Compatible with the regular HMD pass. The color image has to be in
+// COLOR_ATTACHMENT_OPTIMAL and the depth image in DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+// see SwapchainHMD::cmd_transition_acquired and SwapchainHMD::cmd_clear_depth_only.
+pub fn create_render_pass_hmd_load(context: &Context, view_count: u32) -> Result<RenderPass> {
+    create_render_pass_hmd_with(
+        context,
+        view_count,
+        ImageLayout::TRANSFER_SRC_OPTIMAL,
+        &[external_dependency()],
+        true,
+        "RenderPassHMDLoad",
+    )
+}
+
+// Renders the scene into an offscreen image that a post pass samples from.
+// Compatible with the regular HMD pass, so the same pipelines can be used.
+pub fn create_render_pass_hmd_offscreen(context: &Context, view_count: u32) -> Result<RenderPass> {
+    let mut incoming = external_dependency();
+    // the previous frame's post pass samples the offscreen image we are about to clear
+    incoming.src_stage_mask |= PipelineStageFlags::FRAGMENT_SHADER;
+    let outgoing = SubpassDependency::builder()
+        .src_subpass(0)
+        .dst_subpass(SUBPASS_EXTERNAL)
+        .src_stage_mask(PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_stage_mask(PipelineStageFlags::FRAGMENT_SHADER)
+        .dst_access_mask(AccessFlags::SHADER_READ)
+        .build();
+    create_render_pass_hmd_with(
+        context,
+        view_count,
+        ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        &[incoming, outgoing],
+        false,
+        "RenderPassHMDOffscreen",
+    )
+}
+
+// Only a color attachment that is completely overwritten, so nothing is loaded.
+pub fn create_render_pass_hmd_post(context: &Context, view_count: u32) -> Result<RenderPass> {
+    let masks = [multiview_mask(view_count)?];
+
+    let render_pass = unsafe {
+        context.device.create_render_pass(
+            &RenderPassCreateInfo::builder()
+                .attachments(&[AttachmentDescription::builder()
+                    .format(context.find_supported_color_format()?)
+                    .samples(SampleCountFlags::TYPE_1)
+                    .load_op(AttachmentLoadOp::DONT_CARE)
+                    .store_op(AttachmentStoreOp::STORE)
+                    .stencil_load_op(AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(ImageLayout::UNDEFINED)
+                    .final_layout(ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .build()])
                 .subpasses(&[SubpassDescription::builder()
                     .pipeline_bind_point(PipelineBindPoint::GRAPHICS)
                     .color_attachments(&[AttachmentReference::builder()
                         .attachment(0)
                         .layout(ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
                         .build()])
-                    .depth_stencil_attachment(
-                        &AttachmentReference::builder()
-                            .attachment(1)
-                            .layout(ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
-                    )
                     .build()])
                 .dependencies(&[SubpassDependency::builder()
                     .src_subpass(SUBPASS_EXTERNAL)
@@ -52,17 +255,42 @@ pub fn create_render_pass_window(context: &Context) -> Result<RenderPass> {
                     .src_stage_mask(PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
                     .dst_stage_mask(PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
                     .dst_access_mask(AccessFlags::COLOR_ATTACHMENT_WRITE)
-                    .build()]),
+                    .build()])
+                .push_next(
+                    &mut RenderPassMultiviewCreateInfo::builder()
+                        .view_masks(&masks)
+                        .correlation_masks(&masks),
+                ),
             None,
         )
     }?;
-    context.name_object(render_pass, "RenderPassWindow".to_string())?;
+    context.name_object(render_pass, "RenderPassHMDPost".to_string())?;
+
     Ok(render_pass)
 }
 
-pub fn create_render_pass_hmd(context: &Context) -> Result<RenderPass> {
-    // sets the 2 least significant bits
-    let masks = [!(!0 << 2)];
+fn create_render_pass_hmd_with(
+    context: &Context,
+    view_count: u32,
+    final_layout: ImageLayout,
+    dependencies: &[SubpassDependency],
+    load: bool,
+    name: &str,
+) -> Result<RenderPass> {
+    let masks = [multiview_mask(view_count)?];
+    let (load_op, color_initial_layout, depth_initial_layout) = if load {
+        (
+            AttachmentLoadOp::LOAD,
+            ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        )
+    } else {
+        (
+            AttachmentLoadOp::CLEAR,
+            ImageLayout::UNDEFINED,
+            ImageLayout::UNDEFINED,
+        )
+    };
 
     let render_pass = unsafe {
         context.device.create_render_pass(
@@ -71,22 +299,22 @@ pub fn create_render_pass_hmd(context: &Context) -> Result<RenderPass> {
                     AttachmentDescription::builder()
                         .format(context.find_supported_color_format()?)
                         .samples(SampleCountFlags::TYPE_1)
-                        .load_op(AttachmentLoadOp::CLEAR)
+                        .load_op(load_op)
                         .store_op(AttachmentStoreOp::STORE)
                         .stencil_load_op(AttachmentLoadOp::DONT_CARE)
                         .stencil_store_op(AttachmentStoreOp::DONT_CARE)
-                        .initial_layout(ImageLayout::UNDEFINED)
-                        // final layout isn't PRESENT_SRC_KHR
-                        .final_layout(ImageLayout::TRANSFER_SRC_OPTIMAL)
+                        .initial_layout(color_initial_layout)
+                        .final_layout(final_layout)
                         .build(),
                     AttachmentDescription::builder()
                         .format(context.find_supported_depth_stencil_format()?)
                         .samples(SampleCountFlags::TYPE_1)
-                        .load_op(AttachmentLoadOp::CLEAR)
-                        .store_op(AttachmentStoreOp::DONT_CARE)
+                        .load_op(load_op)
+                        // kept for the depth composition layer
+                        .store_op(AttachmentStoreOp::STORE)
                         .stencil_load_op(AttachmentLoadOp::DONT_CARE)
                         .stencil_store_op(AttachmentStoreOp::DONT_CARE)
-                        .initial_layout(ImageLayout::UNDEFINED)
+                        .initial_layout(depth_initial_layout)
                         .final_layout(ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
                         .build(),
                 ])
@@ -102,14 +330,7 @@ pub fn create_render_pass_hmd(context: &Context) -> Result<RenderPass> {
                             .layout(ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
                     )
                     .build()])
-                .dependencies(&[SubpassDependency::builder()
-                    .src_subpass(SUBPASS_EXTERNAL)
-                    .dst_subpass(0)
-                    .src_stage_mask(PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-                    .src_access_mask(AccessFlags::empty())
-                    .dst_stage_mask(PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-                    .dst_access_mask(AccessFlags::COLOR_ATTACHMENT_WRITE)
-                    .build()])
+                .dependencies(dependencies)
                 // there is no next in the window swapchain
                 .push_next(
                     &mut RenderPassMultiviewCreateInfo::builder()
@@ -119,7 +340,41 @@ pub fn create_render_pass_hmd(context: &Context) -> Result<RenderPass> {
             None,
         )
     }?;
-    context.name_object(render_pass, "RenderPassHMD".to_string())?;
+    context.name_object(render_pass, name.to_string())?;
 
     Ok(render_pass)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiview_mask_has_a_bit_per_view() {
+        assert_eq!(multiview_mask(1).unwrap(), 0b1);
+        assert_eq!(multiview_mask(2).unwrap(), 0b11);
+        assert_eq!(multiview_mask(4).unwrap(), 0b1111);
+        assert_eq!(multiview_mask(32).unwrap(), u32::MAX);
+        assert!(multiview_mask(0).is_err());
+        assert!(multiview_mask(33).is_err());
+    }
+
+    #[test]
+    fn external_dependency_covers_depth() {
+        let dependency = external_dependency();
+        let depth_stages =
+            PipelineStageFlags::EARLY_FRAGMENT_TESTS | PipelineStageFlags::LATE_FRAGMENT_TESTS;
+        assert!(dependency.src_stage_mask.contains(depth_stages));
+        assert!(dependency.dst_stage_mask.contains(depth_stages));
+        assert!(dependency
+            .src_access_mask
+            .contains(AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE));
+        assert!(dependency.dst_access_mask.contains(
+            AccessFlags::COLOR_ATTACHMENT_WRITE
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE
+        ));
+        assert_eq!(dependency.src_subpass, SUBPASS_EXTERNAL);
+        assert_eq!(dependency.dst_subpass, 0);
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use ash::vk::{
+    Filter, Sampler, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode, LOD_CLAMP_NONE, TRUE,
+};
+
+use super::Context;
+
+#[derive(Copy, Clone, Debug)]
+pub struct SamplerSettings {
+    pub filter: Filter,
+    pub mipmap_mode: SamplerMipmapMode,
+    pub address_mode: SamplerAddressMode,
+    // clamped to what the device supports, None or <= 1.0 disables it,
+    // so does a device created without sampler_anisotropy
+    pub max_anisotropy: Option<f32>,
+    pub max_lod: f32,
+}
+
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        Self {
+            filter: Filter::LINEAR,
+            mipmap_mode: SamplerMipmapMode::LINEAR,
+            address_mode: SamplerAddressMode::REPEAT,
+            max_anisotropy: None,
+            max_lod: LOD_CLAMP_NONE,
+        }
+    }
+}
+
+// None if anisotropic filtering can't or shouldn't be enabled
+fn clamp_anisotropy(requested: Option<f32>, feature_enabled: bool, limit: f32) -> Option<f32> {
+    let requested = requested.filter(|&requested| requested > 1.0)?;
+    if !feature_enabled {
+        log::warn!(
+            "Anisotropy {} requested, but the device was created without samplerAnisotropy",
+            requested
+        );
+        return None;
+    }
+    Some(requested.min(limit)).filter(|&clamped| clamped > 1.0)
+}
+
+pub fn create_sampler(
+    context: &Context,
+    settings: SamplerSettings,
+    name: String,
+) -> Result<Sampler> {
+    let limit = unsafe {
+        context
+            .instance
+            .get_physical_device_properties(context.physical_device)
+    }
+    .limits
+    .max_sampler_anisotropy;
+    let max_anisotropy = clamp_anisotropy(
+        settings.max_anisotropy,
+        context.enabled_features.sampler_anisotropy == TRUE,
+        limit,
+    );
+
+    let sampler = unsafe {
+        context.device.create_sampler(
+            &SamplerCreateInfo::builder()
+                .mag_filter(settings.filter)
+                .min_filter(settings.filter)
+                .mipmap_mode(settings.mipmap_mode)
+                .address_mode_u(settings.address_mode)
+                .address_mode_v(settings.address_mode)
+                .address_mode_w(settings.address_mode)
+                .anisotropy_enable(max_anisotropy.is_some())
+                .max_anisotropy(max_anisotropy.unwrap_or(1.0))
+                .min_lod(0.0)
+                .max_lod(settings.max_lod),
+            None,
+        )
+    }?;
+    context.name_object(sampler, name)?;
+    Ok(sampler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anisotropy_is_clamped_to_the_limit() {
+        assert_eq!(clamp_anisotropy(Some(8.0), true, 16.0), Some(8.0));
+        assert_eq!(clamp_anisotropy(Some(32.0), true, 16.0), Some(16.0));
+        // a limit of 1 means the device can't filter anisotropically
+        assert_eq!(clamp_anisotropy(Some(4.0), true, 1.0), None);
+    }
+
+    #[test]
+    fn anisotropy_needs_the_feature() {
+        assert_eq!(clamp_anisotropy(Some(8.0), false, 16.0), None);
+        assert_eq!(clamp_anisotropy(None, true, 16.0), None);
+        assert_eq!(clamp_anisotropy(Some(1.0), true, 16.0), None);
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, Result};
 use ash::{
     extensions::khr::Surface,
     vk::{
@@ -9,8 +9,53 @@ use ash::{
 };
 use winit::window::Window;
 
+use crate::VrvError;
+
 use super::Context;
 
+// How color attachments store values. With Srgb the hardware encodes the linear shader output
+// when writing (and decodes when sampling), with Unorm the shaders have to encode themselves.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ColorEncoding {
+    #[default]
+    Srgb,
+    Unorm,
+}
+
+impl ColorEncoding {
+    // in order of preference
+    fn surface_candidates(self) -> &'static [Format] {
+        match self {
+            Self::Srgb => &[
+                Format::R8G8B8A8_SRGB,
+                Format::B8G8R8A8_SRGB,
+                Format::A8B8G8R8_SRGB_PACK32,
+            ],
+            Self::Unorm => &[
+                Format::R8G8B8A8_UNORM,
+                Format::B8G8R8A8_UNORM,
+                Format::A8B8G8R8_UNORM_PACK32,
+                Format::A2B10G10R10_UNORM_PACK32,
+                Format::A2R10G10B10_UNORM_PACK32,
+            ],
+        }
+    }
+
+    pub(crate) fn color_candidates(self) -> &'static [Format] {
+        match self {
+            Self::Srgb => &[Format::B8G8R8A8_SRGB, Format::R8G8B8A8_SRGB],
+            Self::Unorm => &[Format::B8G8R8A8_UNORM, Format::R8G8B8A8_UNORM],
+        }
+    }
+
+    pub(crate) fn other(self) -> Self {
+        match self {
+            Self::Srgb => Self::Unorm,
+            Self::Unorm => Self::Srgb,
+        }
+    }
+}
+
 pub struct SurfaceRelated {
     pub loader: Surface,
     pub surface: SurfaceKHR,
@@ -33,6 +78,7 @@ impl SurfaceRelated {
         loader: &Surface,
         physical_device: PhysicalDevice,
         surface: SurfaceKHR,
+        encoding: ColorEncoding,
     ) -> Result<Detail> {
         let capabilities =
             unsafe { loader.get_physical_device_surface_capabilities(physical_device, surface) }?;
@@ -40,16 +86,13 @@ impl SurfaceRelated {
             unsafe { loader.get_physical_device_surface_formats(physical_device, surface) }?;
         let present_modes =
             unsafe { loader.get_physical_device_surface_present_modes(physical_device, surface) }?;
-        if formats.is_empty() || present_modes.is_empty() {
+        if formats.is_empty() {
             bail!("Physical device incompatible with surface")
         }
-        let format = *formats
-            .iter()
-            .find(|f| {
-                (f.format == Format::R8G8B8A8_UNORM || f.format == Format::B8G8R8A8_UNORM)
-                    && f.color_space == ColorSpaceKHR::SRGB_NONLINEAR
-            })
-            .ok_or(Error::msg("No suitable surface format"))?;
+        if present_modes.is_empty() {
+            return Err(VrvError::NoSuitablePresentMode.into());
+        }
+        let format = Self::choose_format(&formats, encoding)?;
 
         let image_count = if capabilities.max_image_count > 0 {
             3u32.min(capabilities.max_image_count)
@@ -65,6 +108,48 @@ impl SurfaceRelated {
         })
     }
 
+    fn choose_format(
+        formats: &[SurfaceFormatKHR],
+        encoding: ColorEncoding,
+    ) -> Result<SurfaceFormatKHR> {
+        // the surface doesn't care
+        if formats.len() == 1 && formats[0].format == Format::UNDEFINED {
+            return Ok(SurfaceFormatKHR {
+                format: encoding.surface_candidates()[0],
+                color_space: ColorSpaceKHR::SRGB_NONLINEAR,
+            });
+        }
+
+        let find = |encoding: ColorEncoding| {
+            encoding.surface_candidates().iter().find_map(|&candidate| {
+                formats.iter().find(|f| {
+                    f.format == candidate && f.color_space == ColorSpaceKHR::SRGB_NONLINEAR
+                })
+            })
+        };
+        if let Some(format) = find(encoding) {
+            return Ok(*format);
+        }
+        // the window then looks brighter or darker than the HMD
+        if let Some(format) = find(encoding.other()) {
+            log::warn!(
+                "No {:?} surface format available, falling back to {:?}",
+                encoding,
+                format.format
+            );
+            return Ok(*format);
+        }
+
+        // unusual drivers or remote displays, colors might be off but better than nothing
+        let format = *formats.first().ok_or(VrvError::NoSuitableFormat)?;
+        log::warn!(
+            "No preferred surface format available, falling back to {:?} {:?}",
+            format.format,
+            format.color_space
+        );
+        Ok(format)
+    }
+
     pub fn new(entry: &Entry, instance: &Instance, window: &Window) -> Result<Self> {
         let loader = Surface::new(entry, instance);
         let surface = unsafe { ash_window::create_surface(entry, instance, &window, None) }?;
@@ -73,6 +158,87 @@ impl SurfaceRelated {
     }
 
     pub fn get_detail(&self, context: &Context) -> Result<Detail> {
-        Self::detail(&self.loader, context.physical_device, self.surface)
+        Self::detail(
+            &self.loader,
+            context.physical_device,
+            self.surface,
+            context.color_encoding,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn srgb_nonlinear(format: Format) -> SurfaceFormatKHR {
+        SurfaceFormatKHR {
+            format,
+            color_space: ColorSpaceKHR::SRGB_NONLINEAR,
+        }
+    }
+
+    fn choose(formats: &[SurfaceFormatKHR], encoding: ColorEncoding) -> Format {
+        SurfaceRelated::choose_format(formats, encoding)
+            .unwrap()
+            .format
+    }
+
+    #[test]
+    fn the_wanted_encoding_is_preferred() {
+        let formats = [
+            srgb_nonlinear(Format::B8G8R8A8_UNORM),
+            srgb_nonlinear(Format::B8G8R8A8_SRGB),
+        ];
+        assert_eq!(choose(&formats, ColorEncoding::Srgb), Format::B8G8R8A8_SRGB);
+        assert_eq!(
+            choose(&formats, ColorEncoding::Unorm),
+            Format::B8G8R8A8_UNORM
+        );
+        // the order of the candidates counts, not the one of the surface
+        let formats = [
+            srgb_nonlinear(Format::B8G8R8A8_SRGB),
+            srgb_nonlinear(Format::R8G8B8A8_SRGB),
+        ];
+        assert_eq!(choose(&formats, ColorEncoding::Srgb), Format::R8G8B8A8_SRGB);
+    }
+
+    #[test]
+    fn falls_back_to_the_other_encoding() {
+        let formats = [
+            SurfaceFormatKHR {
+                format: Format::B8G8R8A8_SRGB,
+                color_space: ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+            },
+            srgb_nonlinear(Format::B8G8R8A8_UNORM),
+        ];
+        assert_eq!(
+            choose(&formats, ColorEncoding::Srgb),
+            Format::B8G8R8A8_UNORM
+        );
+        assert_eq!(
+            choose(
+                &[srgb_nonlinear(Format::R8G8B8A8_SRGB)],
+                ColorEncoding::Unorm
+            ),
+            Format::R8G8B8A8_SRGB
+        );
+        // nothing known at all, the first one it is
+        let unknown = [srgb_nonlinear(Format::R16G16B16A16_SFLOAT)];
+        assert_eq!(
+            choose(&unknown, ColorEncoding::Srgb),
+            Format::R16G16B16A16_SFLOAT
+        );
+    }
+
+    #[test]
+    fn undefined_surface_takes_the_first_candidate() {
+        let formats = [srgb_nonlinear(Format::UNDEFINED)];
+        assert_eq!(choose(&formats, ColorEncoding::Srgb), Format::R8G8B8A8_SRGB);
+        assert_eq!(
+            choose(&formats, ColorEncoding::Unorm),
+            Format::R8G8B8A8_UNORM
+        );
+        assert!(SurfaceRelated::choose_format(&[], ColorEncoding::Srgb).is_err());
     }
 }
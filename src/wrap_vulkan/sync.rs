@@ -1,5 +1,8 @@
-use anyhow::Result;
-use ash::vk::{Fence, FenceCreateFlags, FenceCreateInfo, Semaphore, SemaphoreCreateInfo};
+use anyhow::{bail, Result};
+use ash::vk::{
+    Fence, FenceCreateFlags, FenceCreateInfo, Semaphore, SemaphoreCreateInfo, SemaphoreSignalInfo,
+    SemaphoreType, SemaphoreTypeCreateInfo, SemaphoreWaitInfo,
+};
 
 use super::Context;
 
@@ -28,14 +31,72 @@ pub fn create_fence(context: &Context, signaled: bool, name: String) -> Result<F
     Ok(fence)
 }
 
-pub fn wait_and_reset(context: &Context, fence: Fence) -> Result<()> {
-    unsafe {
+pub fn wait(context: &Context, fence: Fence) -> Result<()> {
+    context.check_lost(unsafe {
         context.device.wait_for_fences(
             &[fence],
             true,          // wait all
             std::u64::MAX, // don't timeout
         )
-    }?;
+    })
+}
+
+pub fn wait_and_reset(context: &Context, fence: Fence) -> Result<()> {
+    wait(context, fence)?;
     unsafe { context.device.reset_fences(&[fence]) }?;
     Ok(())
 }
+
+// Timeline semaphores carry a counter instead of a signaled bit,
+// they can be waited on any number of times and from the host.
+// Only available with Context::timeline_semaphore_supported.
+pub fn create_timeline_semaphore(
+    context: &Context,
+    initial_value: u64,
+    name: String,
+) -> Result<Semaphore> {
+    if !context.timeline_semaphore_supported {
+        bail!("Timeline semaphores aren't supported, target Vulkan 1.2");
+    }
+    let semaphore = unsafe {
+        context.device.create_semaphore(
+            &SemaphoreCreateInfo::builder().push_next(
+                &mut SemaphoreTypeCreateInfo::builder()
+                    .semaphore_type(SemaphoreType::TIMELINE)
+                    .initial_value(initial_value),
+            ),
+            None,
+        )
+    }?;
+    context.name_object(semaphore, name)?;
+    Ok(semaphore)
+}
+
+// blocks until the counter reached value
+pub fn wait_timeline(context: &Context, semaphore: Semaphore, value: u64) -> Result<()> {
+    context.check_lost(unsafe {
+        context.device.wait_semaphores(
+            &SemaphoreWaitInfo::builder()
+                .semaphores(&[semaphore])
+                .values(&[value]),
+            std::u64::MAX, // don't timeout
+        )
+    })?;
+    Ok(())
+}
+
+// sets the counter from the host, has to be larger than the current value
+pub fn signal_timeline(context: &Context, semaphore: Semaphore, value: u64) -> Result<()> {
+    unsafe {
+        context.device.signal_semaphore(
+            &SemaphoreSignalInfo::builder()
+                .semaphore(semaphore)
+                .value(value),
+        )
+    }?;
+    Ok(())
+}
+
+pub fn get_timeline_value(context: &Context, semaphore: Semaphore) -> Result<u64> {
+    Ok(unsafe { context.device.get_semaphore_counter_value(semaphore) }?)
+}